@@ -70,6 +70,7 @@ pub struct Controller {
     set_horizon_lock: qt_method!(fn(&self, lock_percent: f64, roll: f64)),
     set_preview_resolution: qt_method!(fn(&mut self, target_height: i32, player: QJSValue)),
     set_background_color: qt_method!(fn(&self, color: QString, player: QJSValue)),
+    set_background_preset: qt_method!(fn(&self, preset: i32, player: QJSValue)),
     set_integration_method: qt_method!(fn(&self, index: usize)),
 
     set_offset: qt_method!(fn(&self, timestamp_us: i64, offset_ms: f64)),
@@ -589,6 +590,19 @@ impl Controller {
         }
     }
 
+    // Log profile index (see `LogProfile`), -1 to go back to the background color
+    fn set_background_preset(&mut self, preset: i32, player: QJSValue) {
+        let preset = if preset >= 0 { Some(core::stabilization_params::LogProfile::from(preset)) } else { None };
+        self.stabilizer.set_background_preset(preset);
+
+        if let Some(vid) = player.to_qobject::<MDKVideoItem>() {
+            let vid = unsafe { &mut *vid.as_ptr() }; // vid.borrow_mut()
+
+            let bg = self.stabilizer.params.read().effective_background();
+            vid.setBackgroundColor(QColor::from_name(&format!("#{:02x}{:02x}{:02x}", bg[0] as u8, bg[1] as u8, bg[2] as u8)));
+        }
+    }
+
     fn set_smoothing_method(&mut self, index: usize) -> QJsonArray {
         let params = util::serde_json_to_qt_array(&self.stabilizer.set_smoothing_method(index));
         self.request_recompute();
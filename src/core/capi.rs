@@ -0,0 +1,119 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
+
+// C ABI for hosts outside of QML. Header: include/gyroflow_core.h (generated with `cbindgen --config cbindgen.toml --output include/gyroflow_core.h`)
+//
+// The declarations are guarded by `GYROFLOW_CAPI` and `GYROFLOW_GPU` (wgpu functions), define them to match the enabled features.
+// CI regenerates the header and fails if the committed one is out of date.
+//
+// Memory ownership:
+// - All pixel buffers are owned by the caller and must stay valid for the duration of the call.
+//   Input buffer size is `stride * height` bytes, output buffer size is `output_stride * output_height` bytes.
+// - `GyroflowWgpu` handles are created by `gyroflow_wgpu_create` and must be released with `gyroflow_wgpu_free`.
+//
+// Params layout (`params` points to `params_count * 9` floats, as produced by `FrameTransform`):
+// - row 0: fx, fy, cx, cy, k0, k1, k2, k3, radial distortion limit
+// - row 1: lens correction amount, background mode, fov, pixel aspect ratio, supersample, input orientation (0-3, clockwise 90° steps), sensor width, sensor height, identity threshold (pixels, 0 to disable)
+// - row 2: dither, super fisheye, rolling shutter debug tint, premultiply input (straight alpha), max distorted theta (radians, 0 for default), skip lens distortion (rotation only preview), sharpen amount (0 to disable), unused, interlacing (0 - progressive, 1 - top field first, 2 - bottom field first, CPU only)
+// - row 3..: inverse 3x3 rotation matrix (row-major) for each sensor row, or a single one without rolling shutter correction
+//
+// Background is 4 floats in 0-255 range, interpolation is 2 (bilinear), 4 (bicubic) or 8 (lanczos4).
+
+#[cfg(feature = "gpu")]
+use crate::gpu::wgpu::WgpuWrapper;
+use crate::undistortion::{ Undistortion, RGBA8, min_stride, validate_params };
+use nalgebra::Vector4;
+
+#[cfg(feature = "gpu")]
+pub struct GyroflowWgpu(WgpuWrapper);
+
+unsafe fn read_params(params: *const f32, params_count: usize) -> Option<Vec<[f32; 9]>> {
+    if params.is_null() || params_count < 4 { return None; }
+    Some(std::slice::from_raw_parts(params as *const [f32; 9], params_count).to_vec())
+}
+unsafe fn read_bg(bg: *const f32) -> Vector4<f32> {
+    if bg.is_null() { return Vector4::new(0.0, 0.0, 0.0, 0.0); }
+    let bg = std::slice::from_raw_parts(bg, 4);
+    Vector4::new(bg[0], bg[1], bg[2], bg[3])
+}
+
+/// Undistorts one RGBA8 frame on the CPU. Returns false if any of the pointers is null or the parameters are invalid.
+#[no_mangle]
+pub unsafe extern "C" fn gyroflow_undistort_image_cpu_rgba8(pixels: *const u8, width: usize, height: usize, stride: usize,
+                                                            out_pixels: *mut u8, output_width: usize, output_height: usize, output_stride: usize,
+                                                            params: *const f32, params_count: usize, bg: *const f32, interpolation: u32) -> bool {
+    if pixels.is_null() || out_pixels.is_null() { return false; }
+    if width == 0 || height == 0 || output_width == 0 || output_height == 0 { return false; }
+    if stride < min_stride::<RGBA8>(width) || output_stride < min_stride::<RGBA8>(output_width) { return false; }
+    let params = match read_params(params, params_count) { Some(x) => x, None => return false };
+    if validate_params(&params, height).is_err() { return false; }
+    let bg = read_bg(bg);
+
+    let pixels = std::slice::from_raw_parts(pixels, stride * height);
+    let out_pixels = std::slice::from_raw_parts_mut(out_pixels, output_stride * output_height);
+
+    match interpolation {
+        2 => Undistortion::<RGBA8>::undistort_image_cpu::<2>(pixels, out_pixels, width, height, stride, output_width, output_height, output_stride, &params, bg),
+        4 => Undistortion::<RGBA8>::undistort_image_cpu::<4>(pixels, out_pixels, width, height, stride, output_width, output_height, output_stride, &params, bg),
+        8 => Undistortion::<RGBA8>::undistort_image_cpu::<8>(pixels, out_pixels, width, height, stride, output_width, output_height, output_stride, &params, bg),
+        _ => false
+    }
+}
+
+/// Creates the wgpu context for RGBA8 frames of the given sizes. Returns null on failure.
+#[cfg(feature = "gpu")]
+#[no_mangle]
+pub unsafe extern "C" fn gyroflow_wgpu_create(width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize,
+                                              bg: *const f32, interpolation: u32) -> *mut GyroflowWgpu {
+    let format = match <RGBA8 as crate::undistortion::PixelType>::wgpu_format() { Some(x) => x, None => return std::ptr::null_mut() };
+    match WgpuWrapper::new(width, height, stride, output_width, output_height, output_stride, read_bg(bg), interpolation, format) {
+        Some(wgpu) => Box::into_raw(Box::new(GyroflowWgpu(wgpu))),
+        None => std::ptr::null_mut()
+    }
+}
+
+/// 0 - none, 1 - crc32, 2 - perceptual
+#[cfg(feature = "gpu")]
+#[no_mangle]
+pub unsafe extern "C" fn gyroflow_wgpu_set_verify(handle: *mut GyroflowWgpu, mode: i32) {
+    if let Some(handle) = handle.as_mut() {
+        handle.0.verify = match mode {
+            1 => Some(crate::gpu::wgpu::VerifyHash::Crc32),
+            2 => Some(crate::gpu::wgpu::VerifyHash::Perceptual),
+            _ => None
+        };
+    }
+}
+
+/// Undistorts one frame. Buffer sizes must be `stride * height` and `output_stride * output_height` of the ones passed to `gyroflow_wgpu_create`.
+/// Returns false if any of the pointers is null, the sizes or the parameters are invalid, or the render failed.
+/// `out_hash` (can be null) receives the output hash when verification is enabled, 0 otherwise.
+#[cfg(feature = "gpu")]
+#[no_mangle]
+pub unsafe extern "C" fn gyroflow_wgpu_undistort(handle: *mut GyroflowWgpu, pixels: *const u8, pixels_len: usize, out_pixels: *mut u8, out_pixels_len: usize,
+                                                 params: *const f32, params_count: usize, out_hash: *mut u64) -> bool {
+    let handle = match handle.as_mut() { Some(x) => x, None => return false };
+    if pixels.is_null() || out_pixels.is_null() { return false; }
+    if handle.0.buffer_sizes() != (pixels_len, out_pixels_len) { return false; }
+    let params = match read_params(params, params_count) { Some(x) => x, None => return false };
+    if validate_params(&params, handle.0.input_height()).is_err() { return false; }
+
+    let pixels = std::slice::from_raw_parts(pixels, pixels_len);
+    let out_pixels = std::slice::from_raw_parts_mut(out_pixels, out_pixels_len);
+
+    match handle.0.try_undistort_image_with_params(pixels, out_pixels, &params, params_count as u32) {
+        Ok(hash) => {
+            if let Some(out_hash) = out_hash.as_mut() { *out_hash = hash.unwrap_or(0); }
+            true
+        },
+        Err(e) => { log::error!("{}", e); false }
+    }
+}
+
+#[cfg(feature = "gpu")]
+#[no_mangle]
+pub unsafe extern "C" fn gyroflow_wgpu_free(handle: *mut GyroflowWgpu) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
@@ -3,6 +3,7 @@
 
 #[cfg(feature = "use-opencl")]
 pub mod opencl;
+#[cfg(feature = "gpu")]
 pub mod wgpu;
 
 pub fn initialize_contexts() -> Option<String> {
@@ -26,6 +27,7 @@ pub fn initialize_contexts() -> Option<String> {
         }
     }
 
+    #[cfg(feature = "gpu")]
     if std::env::var("NO_WGPU").unwrap_or_default().is_empty() {
         let wgpu = std::panic::catch_unwind(|| {
             wgpu::WgpuWrapper::initialize_context()
@@ -101,6 +101,7 @@ impl OclWrapper {
                 .bo(builders::BuildOpt::CmplrDefine { ident: "DATA_CONVERTF".into(), val: ocl_names.3.into() })
                 .bo(builders::BuildOpt::CmplrDefine { ident: "PIXEL_BYTES"  .into(), val: format!("{}", bytes_per_pixel) })
                 .bo(builders::BuildOpt::CmplrDefine { ident: "INTERPOLATION".into(), val: format!("{}", interpolation) })
+                .bo(builders::BuildOpt::CmplrDefine { ident: "HAS_ALPHA"    .into(), val: format!("{}", ocl_names.0.ends_with('4') as u8) })
                 .devices(ctx.device)
                 .build(&ctx.context)?;
 
@@ -110,7 +111,7 @@ impl OclWrapper {
             let dest_buffer = Buffer::builder().queue(queue.clone()).len(output_stride*output_height)
                 .flags(MemFlags::new().write_only().host_read_only().alloc_host_ptr()).build()?;
 
-            let params_len = 9 * (height + 2);
+            let params_len = 9 * (width.max(height) + 3); // Rows can be along the width for 90° rotated input
             let params_buf = Buffer::<f32>::builder().queue(queue.clone()).flags(MemFlags::new().read_only()).len(params_len).build()?;
 
             let mut builder = Kernel::builder();
@@ -64,10 +64,12 @@ __constant float coeffs[256] = {
 };
 #endif
 
-float2 undistort_point(float2 pos, float2 f, float2 c, float4 k, float amount) {
+float2 undistort_point(float2 pos, float2 f, float2 c, float4 k, float amount, bool super_fisheye, float max_theta_d) {
     pos = (pos - c) / f;
 
-    float theta_d = fmin(fmax(length(pos), -1.5707963267948966f), 1.5707963267948966f); // PI/2
+    // Limit comes from the lens FOV when known, PI/2 otherwise. Larger values may not converge, see cpu_undistort.rs
+    if (max_theta_d <= 0.0f) max_theta_d = 1.5707963267948966f; // PI/2
+    float theta_d = fmin(fmax(length(pos), -max_theta_d), max_theta_d);
 
     bool converged = false;
     float theta = theta_d;
@@ -101,10 +103,18 @@ float2 undistort_point(float2 pos, float2 f, float2 c, float4 k, float amount) {
         converged = true;
     }
     bool theta_flipped = (theta_d < 0.0f && theta > 0.0f) || (theta_d > 0.0f && theta < 0.0f);
+    bool behind_camera = fabs(theta) >= 1.5707963267948966f;
 
-    if (converged && !theta_flipped) {
-        // Apply only requested amount
-        scale = 1.0f + (scale - 1.0f) * (1.0f - amount);
+    if (converged && !theta_flipped && !behind_camera) {
+        // Apply only requested amount, clamped to avoid mirroring with negative amounts
+        scale = fmax(1.0f + (scale - 1.0f) * (1.0f - amount), 0.0f);
+
+        return f * pos * scale + c;
+    }
+    if ((theta_flipped || behind_camera) && super_fisheye) {
+        // Super fisheye: use the pre-flip estimate, clamped below PI/2 to keep tan() finite
+        scale = tan(fmin(theta_d, 1.55f)) / theta_d;
+        scale = fmax(1.0f + (scale - 1.0f) * (1.0f - amount), 0.0f);
 
         return f * pos * scale + c;
     }
@@ -142,35 +152,41 @@ __kernel void undistort_image(__global const uchar *srcptr, __global uchar *dstp
     float lens_correction_amount = undistortion_params[9];
     float background_mode = undistortion_params[10];
     float fov = undistortion_params[11];
+    float pixel_aspect = undistortion_params[12] > 0.0f? undistortion_params[12] : 1.0f;
+    int orientation = (int)undistortion_params[14];
+    float2 sensor_size = vload2(0, &undistortion_params[15]);
     bool edge_repeat = background_mode > 0.9 && background_mode < 1.1; // 1
     bool edge_mirror = background_mode > 1.9 && background_mode < 2.1; // 2
+    bool edge_wrap   = background_mode > 3.9 && background_mode < 4.1; // 4
+    bool skip_distortion = undistortion_params[23] > 0.5f; // Rotation only preview, plain pinhole projection
 
     if (x >= 0 && y >= 0 && x < output_width && y < output_height) {
         ///////////////////////////////////////////////////////////////////
         // Calculate source `y` for rolling shutter
         int sy = y;
-        if (params_count > 3) {
-            __global const float *params = &undistortion_params[(2 + ((params_count - 2) / 2)) * 9]; // Use middle matrix
+        if (params_count > 4) {
+            __global const float *params = &undistortion_params[(3 + ((params_count - 3) / 2)) * 9]; // Use middle matrix
             float _x = y * params[1] + params[2] + (x * params[0]);
             float _y = y * params[4] + params[5] + (x * params[3]);
             float _w = y * params[7] + params[8] + (x * params[6]);
             if (_w > 0) {
                 float2 pos = (float2)(_x, _y) / _w;
-                float2 uv = distort_point(pos, f, c, k);
-                sy = min((int)height, max(0, (int)round(uv.y)));
+                float2 uv = skip_distortion? f * pos + c : distort_point(pos, f, c, k);
+                sy = min(sensor_size.y > 0.0f? (int)sensor_size.y : (int)height, max(0, (int)round(uv.y)));
             }
         }
         ///////////////////////////////////////////////////////////////////
 
         float2 dst_point = (float2)(x, y);
-        if (lens_correction_amount < 1.0) {
+        if (lens_correction_amount < 1.0 && !skip_distortion) {
             // Add lens distortion back
             float2 factor = (float2)max(1.0 - lens_correction_amount, 0.001); // FIXME: this is close but wrong
+            float2 f2 = (f / fov) / factor * (float2)(pixel_aspect, 1.0f);
             float2 out_c = (float2)(output_width / 2.0, output_height / 2.0);
-            dst_point = undistort_point(dst_point, (f / fov) / factor, out_c, k, lens_correction_amount);
+            dst_point = undistort_point(dst_point, f2, out_c, k, lens_correction_amount, undistortion_params[19] > 0.5f, undistortion_params[22]);
         }
 
-        __global const float *params = &undistortion_params[min((sy + 2), params_count - 1) * 9];
+        __global const float *params = &undistortion_params[min((sy + 3), params_count - 1) * 9];
 
         float _x = dst_point.y * params[1] + params[2] + (dst_point.x * params[0]);
         float _y = dst_point.y * params[4] + params[5] + (dst_point.x * params[3]);
@@ -184,9 +200,17 @@ __kernel void undistort_image(__global const uchar *srcptr, __global uchar *dstp
                 *out_pix = DATA_CONVERT(bg);
                 return;
             }
-            float2 uv = distort_point(pos, f, c, k);
+            float2 uv = skip_distortion? f * pos + c : distort_point(pos, f, c, k);
 
-            if (edge_repeat) {
+            // Sensor coordinates to the stored (rotated) input buffer
+            if      (orientation == 1) uv = (float2)(sensor_size.y - 1.0f - uv.y, uv.x);
+            else if (orientation == 2) uv = (float2)(sensor_size.x - 1.0f - uv.x, sensor_size.y - 1.0f - uv.y);
+            else if (orientation == 3) uv = (float2)(uv.y, sensor_size.x - 1.0f - uv.x);
+
+            if (edge_wrap) {
+                uv.x -= floor(uv.x / width) * width;
+                uv.y = max(0.0f, min((float)(height - 1), uv.y));
+            } else if (edge_repeat) {
                 uv = max((float2)(0, 0), min((float2)(width - 1, height - 1), uv));
             } else if (edge_mirror) {
                 int rx = round(uv.x);
@@ -213,7 +237,13 @@ __kernel void undistort_image(__global const uchar *srcptr, __global uchar *dstp
             __constant float *coeffs_y = &coeffs[(sy0 & (INTER_TAB_SIZE - 1)) << shift];
 
             DATA_TYPEF sum = 0;
-            int src_index = sy * stride + sx * PIXEL_BYTES;
+            int src_index = sy * stride;
+
+            DATA_TYPEF bg_p = bg;
+            #if HAS_ALPHA
+            bool straight_alpha = undistortion_params[21] > 0.5f;
+            if (straight_alpha) bg_p.xyz *= bg_p.w;
+            #endif
 
             #pragma unroll
             for (int yp = 0; yp < INTERPOLATION; ++yp) {
@@ -221,18 +251,27 @@ __kernel void undistort_image(__global const uchar *srcptr, __global uchar *dstp
                     DATA_TYPEF xsum = 0.0f;
                     #pragma unroll
                     for (int xp = 0; xp < INTERPOLATION; ++xp) {
-                        if (sx + xp >= 0 && sx + xp < width) {
-                            xsum += DATA_CONVERTF(*(__global const DATA_TYPE *)&srcptr[src_index + PIXEL_BYTES * xp]) * coeffs_x[xp];
+                        int tx = sx + xp;
+                        if (edge_wrap) tx = ((tx % width) + width) % width; // Taps across the seam fetch from the other side
+                        if (tx >= 0 && tx < width) {
+                            DATA_TYPEF pixel = DATA_CONVERTF(*(__global const DATA_TYPE *)&srcptr[src_index + PIXEL_BYTES * tx]);
+                            #if HAS_ALPHA
+                            if (straight_alpha) pixel.xyz *= pixel.w;
+                            #endif
+                            xsum += pixel * coeffs_x[xp];
                         } else {
-                            xsum += bg * coeffs_x[xp];
+                            xsum += bg_p * coeffs_x[xp];
                         }
                     }
                     sum += xsum * coeffs_y[yp];
                 } else {
-                    sum += bg * coeffs_y[yp];
+                    sum += bg_p * coeffs_y[yp];
                 }
                 src_index += stride;
             }
+            #if HAS_ALPHA
+            if (straight_alpha && fabs(sum.w) > 1e-6f) sum.xyz /= sum.w;
+            #endif
 
             *out_pix = DATA_CONVERT(sum);
         } else {
@@ -2,6 +2,8 @@
 // Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
 
 use std::borrow::Cow;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering::SeqCst };
 use bytemuck::Pod;
 use bytemuck::Zeroable;
 use wgpu::Adapter;
@@ -18,11 +20,61 @@ struct Globals {
     output_height: u32,
     num_params: u32,
     interpolation: u32,
-    bg: [f32; 4]
+    bg: [f32; 4],
+    input_scale: f32,
+    color_mode: u32, // 0 - off, otherwise `ColorTransfer` + 1
+    color_matrix: [[f32; 4]; 3], // Rows padded to vec4
+    color_scale: f32,
+    output_scale_x: f32, // Render target pixel to params output space, 1.0 except for additional outputs
+    output_scale_y: f32,
+    lut_size: u32, // 0 - no color LUT
+    lut_scale: f32,
+    stmap_enabled: u32,
+    bg_gradient: u32, // 0 - flat `bg`
+    bg_regions: u32, // Palette size, 0 - no region mask
+    bg_center: [f32; 4],
+    bg_edge: [f32; 4],
+    bg_palette: [[f32; 4]; crate::undistortion::MAX_BACKGROUND_REGIONS],
+    ca_scale: [f32; 4] // R, G, B magnification around the lens center, w: 1 - enabled
 }
 unsafe impl Zeroable for Globals {}
 unsafe impl Pod for Globals {}
 
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct PointsGlobals {
+    count: u32,
+    rotations_count: u32,
+    super_fisheye: u32,
+    lens_correction_amount: f32,
+    f: [f32; 2],
+    c: [f32; 2],
+    k: [f32; 4]
+}
+unsafe impl Zeroable for PointsGlobals {}
+unsafe impl Pod for PointsGlobals {}
+
+// Additional render target sharing the input upload and params of the main output, e.g. a thumbnail next to the full resolution preview
+struct ExtraOutput {
+    texture: wgpu::Texture,
+    staging_buffer: wgpu::Buffer,
+    globals_buffer: wgpu::Buffer,
+    width: u32,
+    height: u32,
+    out_stride: u32,
+    padded_out_stride: u32,
+    readback_rows: Vec<usize>,
+    out_size: u64,
+}
+
+// Second render target of a wrapper built with `coverage`, R8Unorm with the share of samples taken from the input per output pixel
+struct CoverageOutput {
+    texture: wgpu::Texture,
+    staging_buffer: wgpu::Buffer,
+    padded_stride: u32,
+    readback_rows: Vec<usize>,
+}
+
 pub struct WgpuWrapper  {
     device: wgpu::Device,
     queue: wgpu::Queue,
@@ -30,31 +82,257 @@ pub struct WgpuWrapper  {
     out_pixels: wgpu::Texture,
     in_pixels: wgpu::Texture,
     params_buffer: wgpu::Buffer,
+    uploaded_params: Vec<[f32; 9]>, // Contents of `params_buffer`, so only the changed rows are written
     globals_buffer: wgpu::Buffer,
-    bind_group: wgpu::BindGroup,
-    render_pipeline: wgpu::RenderPipeline,
+    coeffs_buffer: wgpu::Buffer,
+    lut_texture: wgpu::Texture, // 1x1x1 placeholder without a LUT, the binding is always required
+    stmap_buffer: wgpu::Buffer, // Source position per output pixel, placeholder without an STMap
+    bg_mask_texture: wgpu::Texture, // R8Uint palette indices, 1x1 placeholder without background regions
+    bg_regions: Option<std::sync::Arc<crate::undistortion::BackgroundRegions>>, // Uploaded regions, to skip the upload when they didn't change
+    color_lut: Option<std::sync::Arc<crate::undistortion::ColorLut>>, // Uploaded LUT, to skip the upload when it didn't change
+    pipelines: Vec<(u32, wgpu::RenderPipeline, wgpu::BindGroup)>, // interpolation (or FAST_PREVIEW), pipeline, bind group
+    active_pipeline: usize,
+    quality: Quality,
+    sampler: wgpu::Sampler, // Hardware bilinear filtering for `Quality::FastPreview`
+    points_pipeline: Option<wgpu::ComputePipeline>,
 
     in_stride: u32,
     out_stride: u32,
     padded_out_stride: u32,
+    readback_rows: Vec<usize>, // Staging buffer offset of each output row, empty when the strides match (fast path)
     in_size: u64,
     out_size: u64,
     params_size: u64,
     bg_scaler: f32,
+    format: wgpu::TextureFormat,
+    power: wgpu::PowerPreference, // Adapter the device was created on, `recover` uses the same one
+    device_lost: Arc<AtomicBool>, // Set by the error handler or a failed readback, until `recover`
+    input_downscale: u32,
+    has_input: bool, // `in_pixels` holds a frame that can be rendered again
+    extra_outputs: Vec<ExtraOutput>,
+    coverage: Option<CoverageOutput>,
+
+    pub verify: Option<VerifyHash>,
 
     globals: Globals
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum VerifyHash {
+    Crc32,
+    Perceptual // Tolerant to ±1 LSB differences between drivers, see `PERCEPTUAL_HASH_THRESHOLD`
+}
+
+#[derive(Debug)]
+pub enum WgpuError {
+    InvalidInputSize((usize, usize, usize)),
+    InvalidOutputSize((usize, usize, usize)),
+    SizeTooLarge(usize),
+    MissingFormat,
+    UnsupportedFormat(wgpu::TextureFormat),
+    InitializationFailed(WgpuInitError),
+    DeviceLost,
+    BufferSizeMismatch((usize, usize)), // length, expected
+    InvalidParamsCount((usize, usize)), // count, available
+}
+impl std::fmt::Display for WgpuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WgpuError::InvalidInputSize(v)  => write!(f, "Invalid input size: {}x{}, stride: {}", v.0, v.1, v.2),
+            WgpuError::InvalidOutputSize(v) => write!(f, "Invalid output size: {}x{}, stride: {}", v.0, v.1, v.2),
+            WgpuError::SizeTooLarge(v)      => write!(f, "Width {} exceeds the maximum of 8192", v),
+            WgpuError::MissingFormat        => write!(f, "Texture format not set"),
+            WgpuError::UnsupportedFormat(v) => write!(f, "Texture format {:?} is not supported", v),
+            WgpuError::InitializationFailed(e) => write!(f, "Failed to initialize wgpu: {}", e),
+            WgpuError::DeviceLost           => write!(f, "GPU device was lost"),
+            WgpuError::BufferSizeMismatch(v) => write!(f, "Buffer size mismatch! {} vs {}", v.0, v.1),
+            WgpuError::InvalidParamsCount(v) => write!(f, "Invalid params count! {} of {}", v.0, v.1),
+        }
+    }
+}
+impl std::error::Error for WgpuError { }
+
+#[derive(Debug)]
+pub enum WgpuInitError {
+    NoAdapter,
+    DeviceRequestFailed(String),
+    DimensionTooLarge { dim: usize, max: usize },
+    DimensionTooSmall,
+    UnsupportedFormat(wgpu::TextureFormat),
+    StrideTooSmall { stride: usize, row_bytes: usize },
+}
+impl std::fmt::Display for WgpuInitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WgpuInitError::NoAdapter                       => write!(f, "No compatible GPU adapter found"),
+            WgpuInitError::DeviceRequestFailed(e)          => write!(f, "Failed to request device: {}", e),
+            WgpuInitError::DimensionTooLarge { dim, max }  => write!(f, "Dimension {} exceeds the maximum of {}", dim, max),
+            WgpuInitError::DimensionTooSmall               => write!(f, "Height must be at least 4 pixels and stride at least 1 byte"),
+            WgpuInitError::UnsupportedFormat(v)            => write!(f, "Texture format {:?} is not supported", v),
+            WgpuInitError::StrideTooSmall { stride, row_bytes } => write!(f, "Output stride {} is smaller than the row size of {} bytes", stride, row_bytes),
+        }
+    }
+}
+impl std::error::Error for WgpuInitError { }
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Quality {
+    Full = 0,        // Interpolation from the COEFFS table, full Newton iteration count. Always used for export
+    FastPreview = 1, // Hardware bilinear sampling and fewer Newton iterations, for real-time preview on integrated and mobile GPUs
+}
+impl Default for Quality {
+    fn default() -> Self { Quality::Full }
+}
+
+// Pipeline key of the fast preview variant, the interpolation values are 2, 4 and 8
+const FAST_PREVIEW: u32 = 0;
+const FAST_PREVIEW_NEWTON_ITERATIONS: i32 = 3;
+
+// More changed row ranges than this are uploaded as a single write from the first to the last changed row
+const MAX_PARAMS_WRITES: usize = 16;
+
+#[derive(Default)]
+pub struct WgpuWrapperBuilder {
+    input: (usize, usize, usize), // width, height, stride
+    output: (usize, usize, usize), // width, height, stride
+    background: nalgebra::Vector4<f32>,
+    interpolation: crate::undistortion::Interpolation,
+    format: Option<wgpu::TextureFormat>,
+    prebuild_interpolations: bool,
+    power_preference: Option<wgpu::PowerPreference>,
+    coverage: bool,
+}
+impl WgpuWrapperBuilder {
+    pub fn new() -> Self { Self::default() }
+    pub fn input(mut self, width: usize, height: usize, stride: usize) -> Self { self.input = (width, height, stride); self }
+    pub fn output(mut self, width: usize, height: usize, stride: usize) -> Self { self.output = (width, height, stride); self }
+    pub fn background(mut self, bg: nalgebra::Vector4<f32>) -> Self { self.background = bg; self }
+    pub fn interpolation(mut self, interpolation: crate::undistortion::Interpolation) -> Self { self.interpolation = interpolation; self }
+    pub fn format(mut self, format: wgpu::TextureFormat) -> Self { self.format = Some(format); self }
+    // Compile the pipelines for all interpolation modes upfront, so `set_interpolation` never stalls
+    pub fn prebuild_interpolations(mut self, v: bool) -> Self { self.prebuild_interpolations = v; self }
+    // Adapter to create the device on, defaults to `HighPerformance`
+    pub fn power_preference(mut self, v: wgpu::PowerPreference) -> Self { self.power_preference = Some(v); self }
+    // Render the coverage mask to a second attachment, read back with `undistort_image_with_coverage`. Additional outputs aren't supported with it
+    pub fn coverage(mut self, v: bool) -> Self { self.coverage = v; self }
+
+    pub fn build(self) -> Result<WgpuWrapper, WgpuError> {
+        let format = self.format.ok_or(WgpuError::MissingFormat)?;
+        if format_info(format).is_none() { return Err(WgpuError::UnsupportedFormat(format)); }
+        if self.input.1 < 4 || self.input.2 < 1   { return Err(WgpuError::InvalidInputSize(self.input)); }
+        if self.output.1 < 4 || self.output.2 < 1 { return Err(WgpuError::InvalidOutputSize(self.output)); }
+        if self.input.0 > 8192  { return Err(WgpuError::SizeTooLarge(self.input.0)); }
+        if self.output.0 > 8192 { return Err(WgpuError::SizeTooLarge(self.output.0)); }
+
+        let power = self.power_preference.unwrap_or(wgpu::PowerPreference::HighPerformance);
+        let mut wrapper = WgpuWrapper::try_new_with_options(self.input.0, self.input.1, self.input.2, self.output.0, self.output.1, self.output.2, self.background, self.interpolation.gpu_taps(), format, power, self.coverage)
+            .map_err(WgpuError::InitializationFailed)?;
+        if self.prebuild_interpolations {
+            use crate::undistortion::Interpolation;
+            for interpolation in [Interpolation::Bilinear, Interpolation::Bicubic, Interpolation::Lanczos4] {
+                wrapper.pipeline_index(interpolation as u32);
+            }
+        }
+        Ok(wrapper)
+    }
+}
+
 lazy_static::lazy_static! {
+    // Cached separately, so switching between the integrated and discrete GPU doesn't enumerate the adapters again
     static ref ADAPTER: RwLock<Option<Adapter>> = RwLock::new(None);
+    static ref LOW_POWER_ADAPTER: RwLock<Option<Adapter>> = RwLock::new(None);
+}
+
+fn adapter_lock(power: wgpu::PowerPreference) -> &'static RwLock<Option<Adapter>> {
+    match power {
+        wgpu::PowerPreference::LowPower => &LOW_POWER_ADAPTER,
+        _ => &ADAPTER
+    }
+}
+
+// Returns the shader SCALAR type and the background scaler for a supported texture format
+pub fn format_info(fmt: wgpu::TextureFormat) -> Option<(&'static str, f64)> {
+    use wgpu::TextureFormat::*;
+    match fmt {
+        R8Unorm | Rg8Unorm | Rgba8Unorm => Some(("f32", 255.0)),
+        R16Uint | Rg16Uint | Rgba16Uint => Some(("u32", 1.0)),
+        Rgba16Float | Rgba32Float       => Some(("f32", 255.0)),
+        Rgb10a2Unorm                    => Some(("f32", 1023.0)),
+        _ => None
+    }
+}
+
+// Checks if the adapter can sample from and render to the format. Initializes the adapter if needed
+pub fn is_format_supported(fmt: wgpu::TextureFormat) -> bool {
+    if format_info(fmt).is_none() { return false; }
+    if ADAPTER.read().is_none() { WgpuWrapper::initialize_context(); }
+    if let Some(ref adapter) = *ADAPTER.read() {
+        let features = adapter.get_texture_format_features(fmt);
+        return features.allowed_usages.contains(wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT);
+    }
+    false
+}
+
+pub fn hash_output(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+// Max Hamming distance (`(a ^ b).count_ones()`) between two `perceptual_hash_output` results of the same content rendered on different drivers
+pub const PERCEPTUAL_HASH_THRESHOLD: u32 = 3;
+
+// Blocks within this distance (share of the full scale) from the frame mean are hashed as 0, so flat areas and ±1 LSB rounding don't flip bits
+const PERCEPTUAL_HASH_DEAD_BAND: f64 = 0.004;
+
+// Luma of one pixel in 0-1 range (full scale of the format), alpha is ignored
+fn pixel_luma(px: &[u8], format: wgpu::TextureFormat) -> f64 {
+    use wgpu::TextureFormat::*;
+    let luma = |r: f64, g: f64, b: f64| 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let u16_at = |i: usize| u16::from_le_bytes([px[i * 2], px[i * 2 + 1]]) as f64 / 65535.0;
+    match format {
+        R8Unorm | Rg8Unorm => px[0] as f64 / 255.0,
+        Rgba8Unorm         => luma(px[0] as f64, px[1] as f64, px[2] as f64) / 255.0,
+        R16Uint | Rg16Uint => u16_at(0),
+        Rgba16Uint         => luma(u16_at(0), u16_at(1), u16_at(2)),
+        Rgba16Float        => { let f = |i: usize| crate::undistortion::f16_to_f32(u16::from_le_bytes([px[i * 2], px[i * 2 + 1]])) as f64; luma(f(0), f(1), f(2)) },
+        Rgba32Float        => { let f = |i: usize| f32::from_le_bytes([px[i * 4], px[i * 4 + 1], px[i * 4 + 2], px[i * 4 + 3]]) as f64; luma(f(0), f(1), f(2)) },
+        Rgb10a2Unorm       => { let v = u32::from_le_bytes([px[0], px[1], px[2], px[3]]); luma((v & 1023) as f64, ((v >> 10) & 1023) as f64, ((v >> 20) & 1023) as f64) / 1023.0 },
+        _ => 0.0
+    }
+}
+
+// Average hash of the luma downscaled to 8x8 blocks, over the visible `width` x `height` pixels only (no row padding or alpha).
+// Compare results with `(a ^ b).count_ones() <= PERCEPTUAL_HASH_THRESHOLD`
+pub fn perceptual_hash_output(data: &[u8], width: usize, height: usize, stride: usize, format: wgpu::TextureFormat) -> u64 {
+    const GRID: usize = 8;
+    let bpp = format.describe().block_size as usize;
+    if width == 0 || height == 0 || bpp == 0 || stride < width * bpp || data.len() < stride * (height - 1) + width * bpp { return 0; }
+
+    let mut sums = [(0.0f64, 0usize); GRID * GRID];
+    for y in 0..height {
+        let row = &data[y * stride..y * stride + width * bpp];
+        for (x, px) in row.chunks_exact(bpp).enumerate() {
+            let block = &mut sums[(y * GRID / height) * GRID + x * GRID / width];
+            block.0 += pixel_luma(px, format);
+            block.1 += 1;
+        }
+    }
+    let total = sums.iter().map(|x| x.0).sum::<f64>() / (width * height) as f64;
+    sums.iter().enumerate().fold(0u64, |hash, (i, &(sum, count))| {
+        if count > 0 && sum / count as f64 > total + PERCEPTUAL_HASH_DEAD_BAND { hash | (1 << i) } else { hash }
+    })
 }
 
 impl WgpuWrapper {
     pub fn initialize_context() -> Option<String> {
+        Self::initialize_context_with_power_preference(wgpu::PowerPreference::HighPerformance)
+    }
+
+    // Returns the adapter name. On single GPU systems both preferences resolve to the same adapter
+    pub fn initialize_context_with_power_preference(power: wgpu::PowerPreference) -> Option<String> {
         let instance = wgpu::Instance::new(wgpu::Backends::all());
 
         let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
-            power_preference: wgpu::PowerPreference::HighPerformance,
+            power_preference: power,
             force_fallback_adapter: false,
             compatible_surface: None,
         }))?;
@@ -63,23 +341,44 @@ impl WgpuWrapper {
 
         let name = info.name.clone();
 
-        *ADAPTER.write() = Some(adapter);
+        *adapter_lock(power).write() = Some(adapter);
         
         Some(name)
     }
 
-    pub fn new(width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, bg: nalgebra::Vector4<f32>, interpolation: u32, wgpu_format: (wgpu::TextureFormat, &str, f64)) -> Option<Self> {
-        let params_count = 9 * (height + 2);
+    pub fn new(width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, bg: nalgebra::Vector4<f32>, interpolation: u32, wgpu_format: wgpu::TextureFormat) -> Option<Self> {
+        match Self::try_new(width, height, stride, output_width, output_height, output_stride, bg, interpolation, wgpu_format) {
+            Ok(x) => Some(x),
+            Err(e) => { log::warn!("{}", e); None }
+        }
+    }
+
+    pub fn try_new(width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, bg: nalgebra::Vector4<f32>, interpolation: u32, wgpu_format: wgpu::TextureFormat) -> Result<Self, WgpuInitError> {
+        Self::try_new_with_power_preference(width, height, stride, output_width, output_height, output_stride, bg, interpolation, wgpu_format, wgpu::PowerPreference::HighPerformance)
+    }
+
+    pub fn try_new_with_power_preference(width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, bg: nalgebra::Vector4<f32>, interpolation: u32, wgpu_format: wgpu::TextureFormat, power: wgpu::PowerPreference) -> Result<Self, WgpuInitError> {
+        Self::try_new_with_options(width, height, stride, output_width, output_height, output_stride, bg, interpolation, wgpu_format, power, false)
+    }
 
-        if height < 4 || output_height < 4 || stride < 1 || width > 8192 || output_width > 8192 { return None; }
+    fn try_new_with_options(width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, bg: nalgebra::Vector4<f32>, interpolation: u32, wgpu_format: wgpu::TextureFormat, power: wgpu::PowerPreference, coverage: bool) -> Result<Self, WgpuInitError> {
+        let (scalar_type, bg_scaler) = format_info(wgpu_format).ok_or(WgpuInitError::UnsupportedFormat(wgpu_format))?;
+        let params_count = 9 * (width.max(height) + 3); // Rows can be along the width for 90° rotated input
+
+        if height < 4 || output_height < 4 || stride < 1 { return Err(WgpuInitError::DimensionTooSmall); }
+        if width > 8192        { return Err(WgpuInitError::DimensionTooLarge { dim: width, max: 8192 }); }
+        if output_width > 8192 { return Err(WgpuInitError::DimensionTooLarge { dim: output_width, max: 8192 }); }
+        // The texture copy writes whole rows at the aligned stride, a shorter caller stride would shear the readback
+        let row_bytes = output_width * wgpu_format.describe().block_size as usize;
+        if output_stride < row_bytes { return Err(WgpuInitError::StrideTooSmall { stride: output_stride, row_bytes }); }
 
         let in_size = (stride * height) as wgpu::BufferAddress;
         let out_size = (output_stride * output_height) as wgpu::BufferAddress;
         let params_size = (params_count * std::mem::size_of::<f32>()) as wgpu::BufferAddress;
 
-        let adapter_initialized = ADAPTER.read().is_some();
-        if !adapter_initialized { Self::initialize_context(); }
-        let lock = ADAPTER.read();
+        let adapter_initialized = adapter_lock(power).read().is_some();
+        if !adapter_initialized { Self::initialize_context_with_power_preference(power); }
+        let lock = adapter_lock(power).read();
         if let Some(ref adapter) = *lock {
             let (device, queue) = pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor {
                 label: None,
@@ -89,22 +388,20 @@ impl WgpuWrapper {
                     max_storage_textures_per_shader_stage: 4,
                     ..wgpu::Limits::default()
                 },
-            }, None)).ok()?;
-
-            let mut shader_str = include_str!("wgpu_undistort.wgsl").to_string();
-            shader_str = shader_str.replace("SCALAR", wgpu_format.1);
-            
-            // Replace it in source to allow for loop unrolling when compiling shader
-            shader_str = shader_str.replace("params.interpolation", &format!("{}u", interpolation));
+            }, None)).map_err(|e| WgpuInitError::DeviceRequestFailed(e.to_string()))?;
 
-            let shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-                source: wgpu::ShaderSource::Wgsl(Cow::Owned(shader_str)),
-                label: None
-            });
+            // wgpu doesn't report a driver reset (e.g. TDR on Windows) as a specific error, so any uncaptured error marks the device as lost.
+            // `try_undistort_image` returns it as `Err(WgpuError::DeviceLost)` and the caller can `recover`, instead of the default handler's panic
+            let device_lost = Arc::new(AtomicBool::new(false));
+            {
+                let device_lost = device_lost.clone();
+                device.on_uncaptured_error(move |e| {
+                    log::error!("wgpu error: {}", e);
+                    device_lost.store(true, SeqCst);
+                });
+            }
 
-            let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as usize;
-            let padding = (align - output_stride % align) % align;
-            let padded_out_stride = output_stride + padding;
+            let padded_out_stride = Self::aligned_stride(output_stride);
             let staging_size = padded_out_stride * output_height;
 
             let staging_buffer = device.create_buffer(&wgpu::BufferDescriptor { size: staging_size as u64, usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST, label: None, mapped_at_creation: false });
@@ -118,7 +415,7 @@ impl WgpuWrapper {
                 mip_level_count: 1,
                 sample_count: 1,
                 dimension: wgpu::TextureDimension::D2,
-                format: wgpu_format.0,
+                format: wgpu_format,
                 usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
             });
             let out_pixels = device.create_texture(&wgpu::TextureDescriptor {
@@ -127,51 +424,24 @@ impl WgpuWrapper {
                 mip_level_count: 1,
                 sample_count: 1,
                 dimension: wgpu::TextureDimension::D2,
-                format: wgpu_format.0,
+                format: wgpu_format,
                 usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
             });
 
-            let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                label: None,
-                layout: None,
-                vertex: wgpu::VertexState {
-                    module: &shader,
-                    entry_point: "undistort_vertex",
-                    buffers: &[],
-                },
-                fragment: Some(wgpu::FragmentState {
-                    module: &shader,
-                    entry_point: "undistort_fragment",
-                    targets: &[wgpu::ColorTargetState {
-                        format: wgpu_format.0,
-                        blend: None,
-                        write_mask: wgpu::ColorWrites::ALL,
-                    }],
-                }),
-                primitive: wgpu::PrimitiveState {
-                    topology: wgpu::PrimitiveTopology::TriangleList,
-                    ..Default::default()
-                },
-                multiview: None,
-                depth_stencil: None,
-                multisample: wgpu::MultisampleState::default(),
+            let lut_texture = Self::create_lut_texture(&device, 1);
+            let bg_mask_texture = Self::create_bg_mask_texture(&device, 1, 1);
+            let stmap_buffer = device.create_buffer(&wgpu::BufferDescriptor { size: 8, usage: BufferUsages::STORAGE | BufferUsages::COPY_DST, label: None, mapped_at_creation: false });
+            let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                ..Default::default()
             });
 
-            let view = in_pixels.create_view(&wgpu::TextureViewDescriptor::default());
+            let coverage = coverage.then(|| Self::create_coverage(&device, output_width, output_height));
+            let render_pipeline = Self::create_pipeline(&device, scalar_type, interpolation, wgpu_format, coverage.is_some());
+            let bind_group = Self::create_bind_group(&device, &render_pipeline, &globals_buffer, &params_buffer, &in_pixels, &coeffs_buffer, &lut_texture, &stmap_buffer, &bg_mask_texture, None);
 
-            let bind_group_layout = render_pipeline.get_bind_group_layout(0);
-            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-                label: None,
-                layout: &bind_group_layout,
-                entries: &[
-                    wgpu::BindGroupEntry { binding: 0, resource: globals_buffer.as_entire_binding() },
-                    wgpu::BindGroupEntry { binding: 1, resource: params_buffer.as_entire_binding() },
-                    wgpu::BindGroupEntry { binding: 2, resource: wgpu::BindingResource::TextureView(&view) },
-                    wgpu::BindGroupEntry { binding: 3, resource: coeffs_buffer.as_entire_binding() }
-                ],
-            });
-
-            let bg_scaler = wgpu_format.2 as f32;
+            let bg_scaler = bg_scaler as f32;
             let globals = Globals {
                 width: width as u32,
                 height: height as u32,
@@ -180,81 +450,832 @@ impl WgpuWrapper {
                 output_height: output_height as u32,
                 interpolation,
                 num_params: 3,
-                bg: [bg[0] / bg_scaler, bg[1] / bg_scaler, bg[2] / bg_scaler, bg[3] / bg_scaler]
+                bg: [bg[0] / bg_scaler, bg[1] / bg_scaler, bg[2] / bg_scaler, bg[3] / bg_scaler],
+                input_scale: 1.0,
+                color_mode: 0,
+                color_matrix: [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0]],
+                color_scale: 1.0,
+                output_scale_x: 1.0,
+                output_scale_y: 1.0,
+                lut_size: 0,
+                lut_scale: 1.0,
+                stmap_enabled: 0,
+                bg_gradient: 0,
+                bg_regions: 0,
+                bg_center: [0.0; 4],
+                bg_edge: [0.0; 4],
+                bg_palette: [[0.0; 4]; crate::undistortion::MAX_BACKGROUND_REGIONS],
+                ca_scale: [1.0, 1.0, 1.0, 0.0]
             };
 
-            Some(Self {
+            Ok(Self {
                 device,
                 queue,
                 staging_buffer,
                 out_pixels,
                 in_pixels,
                 params_buffer,
+                uploaded_params: Vec::new(),
                 globals_buffer,
-                bind_group,
-                render_pipeline,
+                coeffs_buffer,
+                lut_texture,
+                stmap_buffer,
+                bg_mask_texture,
+                bg_regions: None,
+                color_lut: None,
+                pipelines: vec![(interpolation, render_pipeline, bind_group)],
+                active_pipeline: 0,
+                quality: Quality::Full,
+                sampler,
+                points_pipeline: None,
                 in_size,
                 out_size,
                 params_size,
                 globals,
                 bg_scaler,
+                format: wgpu_format,
+                power,
+                device_lost,
+                input_downscale: 1,
+                has_input: false,
+                extra_outputs: Vec::new(),
+                coverage,
+                verify: None,
                 in_stride: stride as u32,
                 out_stride: output_stride as u32,
-                padded_out_stride: padded_out_stride as u32
+                padded_out_stride: padded_out_stride as u32,
+                readback_rows: Self::readback_rows(output_stride, padded_out_stride, output_height)
             })
         } else {
-            None
+            Err(WgpuInitError::NoAdapter)
         }
     }
 
+    fn create_pipeline(device: &wgpu::Device, scalar_type: &str, interpolation: u32, wgpu_format: wgpu::TextureFormat, coverage: bool) -> wgpu::RenderPipeline {
+        let mut shader_str = include_str!("wgpu_undistort.wgsl").to_string();
+        shader_str = shader_str.replace("SCALAR", scalar_type);
+
+        if interpolation == FAST_PREVIEW {
+            // Sampler and early return are only added to this variant, because `textureSampleLevel` doesn't compile for integer formats
+            shader_str = shader_str.replace("// FAST_PREVIEW_BINDINGS", "@group(0) @binding(5) @stage(fragment) var input_sampler: sampler;");
+            shader_str = shader_str.replace("// FAST_PREVIEW_SAMPLE", "
+    let tex_uv = (uv + 0.5) / vec2<f32>(tex_size);
+    if (any(tex_uv < vec2<f32>(0.0)) || any(tex_uv > vec2<f32>(1.0))) {
+        return sample_bg;
+    }
+    return textureSampleLevel(input, input_sampler, tex_uv, 0.0);");
+            shader_str = shader_str.replace("NEWTON_ITERATIONS: i32 = 10", &format!("NEWTON_ITERATIONS: i32 = {}", FAST_PREVIEW_NEWTON_ITERATIONS));
+        }
+
+        // Replace it in source to allow for loop unrolling when compiling shader
+        let interpolation = if interpolation == FAST_PREVIEW { crate::undistortion::Interpolation::Bilinear as u32 } else { interpolation };
+        shader_str = shader_str.replace("params.interpolation", &format!("{}u", interpolation));
+
+        let shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            source: wgpu::ShaderSource::Wgsl(Cow::Owned(shader_str)),
+            label: None
+        });
+
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: None,
+            layout: None,
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "undistort_vertex",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: if coverage { "undistort_fragment_coverage" } else { "undistort_fragment" },
+                targets: &[
+                    wgpu::ColorTargetState {
+                        format: wgpu_format,
+                        blend: None,
+                        write_mask: wgpu::ColorWrites::ALL,
+                    },
+                    wgpu::ColorTargetState {
+                        format: wgpu::TextureFormat::R8Unorm,
+                        blend: None,
+                        write_mask: wgpu::ColorWrites::ALL,
+                    }
+                ][..if coverage { 2 } else { 1 }],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                ..Default::default()
+            },
+            multiview: None,
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+        })
+    }
+
+    // Returns the index of the pipeline for the interpolation mode, compiling it if needed
+    fn pipeline_index(&mut self, interpolation: u32) -> usize {
+        if let Some(i) = self.pipelines.iter().position(|x| x.0 == interpolation) {
+            return i;
+        }
+        let scalar_type = format_info(self.format).map(|x| x.0).unwrap_or("f32");
+        let pipeline = Self::create_pipeline(&self.device, scalar_type, interpolation, self.format, self.coverage.is_some());
+        let sampler = (interpolation == FAST_PREVIEW).then(|| &self.sampler);
+        let bind_group = Self::create_bind_group(&self.device, &pipeline, &self.globals_buffer, &self.params_buffer, &self.in_pixels, &self.coeffs_buffer, &self.lut_texture, &self.stmap_buffer, &self.bg_mask_texture, sampler);
+        self.pipelines.push((interpolation, pipeline, bind_group));
+        self.pipelines.len() - 1
+    }
+
+    // Switches the interpolation without recreating buffers and textures. Pipelines are compiled on first use and kept
+    pub fn set_interpolation(&mut self, interpolation: crate::undistortion::Interpolation) {
+        self.globals.interpolation = interpolation.gpu_taps();
+        self.active_pipeline = self.pipeline_index(self.pipeline_key());
+    }
+
+    // Hardware filtering needs a filterable float format, so other formats always use `Quality::Full`. Returns the quality in effect
+    pub fn set_quality(&mut self, quality: Quality) -> Quality {
+        use wgpu::TextureFormat::*;
+        let filterable = matches!(self.format, R8Unorm | Rg8Unorm | Rgba8Unorm | Rgba16Float);
+        self.quality = if filterable { quality } else { Quality::Full };
+        self.active_pipeline = self.pipeline_index(self.pipeline_key());
+        self.quality
+    }
+
+    fn pipeline_key(&self) -> u32 {
+        match self.quality {
+            Quality::FastPreview => FAST_PREVIEW,
+            Quality::Full => self.globals.interpolation
+        }
+    }
+
+    // `sampler` is required for the fast preview pipeline and must be None for the others, the layout is derived from the shader
+    fn create_bind_group(device: &wgpu::Device, render_pipeline: &wgpu::RenderPipeline, globals_buffer: &wgpu::Buffer, params_buffer: &wgpu::Buffer, in_pixels: &wgpu::Texture, coeffs_buffer: &wgpu::Buffer, lut_texture: &wgpu::Texture, stmap_buffer: &wgpu::Buffer, bg_mask_texture: &wgpu::Texture, sampler: Option<&wgpu::Sampler>) -> wgpu::BindGroup {
+        let view = in_pixels.create_view(&wgpu::TextureViewDescriptor::default());
+        let lut_view = lut_texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let bg_mask_view = bg_mask_texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let mut entries = vec![
+            wgpu::BindGroupEntry { binding: 0, resource: globals_buffer.as_entire_binding() },
+            wgpu::BindGroupEntry { binding: 1, resource: params_buffer.as_entire_binding() },
+            wgpu::BindGroupEntry { binding: 2, resource: wgpu::BindingResource::TextureView(&view) },
+            wgpu::BindGroupEntry { binding: 3, resource: coeffs_buffer.as_entire_binding() },
+            wgpu::BindGroupEntry { binding: 4, resource: wgpu::BindingResource::TextureView(&lut_view) },
+            wgpu::BindGroupEntry { binding: 6, resource: stmap_buffer.as_entire_binding() },
+            wgpu::BindGroupEntry { binding: 7, resource: wgpu::BindingResource::TextureView(&bg_mask_view) }
+        ];
+        if let Some(sampler) = sampler {
+            entries.push(wgpu::BindGroupEntry { binding: 5, resource: wgpu::BindingResource::Sampler(sampler) });
+        }
+
+        let bind_group_layout = render_pipeline.get_bind_group_layout(0);
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &bind_group_layout,
+            entries: &entries,
+        })
+    }
+
+    fn input_texture_size(&self) -> (u32, u32) {
+        ((self.globals.width / self.input_downscale).max(1), (self.globals.height / self.input_downscale).max(1))
+    }
+
+    // Box-downsamples the input by an integer `factor` on upload, which saves bandwidth and reduces aliasing when the input is much larger than the output.
+    // Remap coordinates are rescaled in the shader, so the output geometry is unchanged.
+    pub fn set_input_downscale(&mut self, factor: u32) {
+        // The box filter works per scalar, packed formats are uploaded at full size
+        let factor = if self.format == wgpu::TextureFormat::Rgb10a2Unorm { 1 } else { factor.max(1) };
+        if factor == self.input_downscale { return; }
+        self.input_downscale = factor;
+        self.globals.input_scale = factor as f32;
+
+        let (w, h) = self.input_texture_size();
+        self.in_pixels = self.device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d { width: w, height: h, depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.format,
+            usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
+        });
+        self.has_input = false;
+        self.recreate_bind_groups();
+    }
+
+    fn recreate_bind_groups(&mut self) {
+        for (key, pipeline, bind_group) in self.pipelines.iter_mut() {
+            let sampler = (*key == FAST_PREVIEW).then(|| &self.sampler);
+            *bind_group = Self::create_bind_group(&self.device, pipeline, &self.globals_buffer, &self.params_buffer, &self.in_pixels, &self.coeffs_buffer, &self.lut_texture, &self.stmap_buffer, &self.bg_mask_texture, sampler);
+        }
+    }
+
+    fn create_lut_texture(device: &wgpu::Device, size: u32) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d { width: size, height: size, depth_or_array_layers: size },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D3,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
+        })
+    }
+
+    // Takes effect on the next render. The table is uploaded only when a different LUT is set
+    pub fn set_color_lut(&mut self, lut: Option<&std::sync::Arc<crate::undistortion::ColorLut>>) {
+        let lut = match lut {
+            Some(lut) => lut,
+            None => { self.globals.lut_size = 0; return; }
+        };
+        // Shader values are already divided by the background scaler
+        self.globals.lut_scale = lut.max_value / self.bg_scaler;
+        self.globals.lut_size = lut.size as u32;
+        if self.color_lut.as_ref().map(|x| std::sync::Arc::ptr_eq(x, lut)).unwrap_or(false) { return; }
+
+        let size = lut.size as u32;
+        let data: Vec<[f32; 4]> = lut.data.iter().map(|x| [x[0], x[1], x[2], 1.0]).collect();
+        self.lut_texture = Self::create_lut_texture(&self.device, size);
+        self.queue.write_texture(
+            self.lut_texture.as_image_copy(),
+            bytemuck::cast_slice(&data),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(size * 16),
+                rows_per_image: std::num::NonZeroU32::new(size),
+            },
+            wgpu::Extent3d { width: size, height: size, depth_or_array_layers: size },
+        );
+        self.color_lut = Some(lut.clone());
+        self.recreate_bind_groups();
+    }
+
+    fn create_bg_mask_texture(device: &wgpu::Device, width: u32, height: u32) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d { width, height, depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R8Uint,
+            usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
+        })
+    }
+
+    // Background color per output region, see `BackgroundRegions`, in the same range as `set_background`.
+    // Takes effect on the next render. The mask is uploaded only when different regions are set
+    pub fn set_background_regions(&mut self, regions: Option<&std::sync::Arc<crate::undistortion::BackgroundRegions>>) {
+        let regions = match regions {
+            Some(x) => x,
+            None => { self.globals.bg_regions = 0; return; }
+        };
+        let s = self.bg_scaler;
+        for (dst, c) in self.globals.bg_palette.iter_mut().zip(regions.palette.iter()) {
+            *dst = [c[0] / s, c[1] / s, c[2] / s, c[3] / s];
+        }
+        self.globals.bg_regions = regions.palette.len().min(crate::undistortion::MAX_BACKGROUND_REGIONS) as u32;
+        if self.bg_regions.as_ref().map(|x| std::sync::Arc::ptr_eq(x, regions)).unwrap_or(false) { return; }
+
+        let (width, height) = (regions.width as u32, regions.height as u32);
+        self.bg_mask_texture = Self::create_bg_mask_texture(&self.device, width, height);
+        self.queue.write_texture(
+            self.bg_mask_texture.as_image_copy(),
+            &regions.indices,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(width),
+                rows_per_image: None,
+            },
+            wgpu::Extent3d { width, height, depth_or_array_layers: 1 },
+        );
+        self.bg_regions = Some(regions.clone());
+        self.recreate_bind_groups();
+    }
+
+    // Source positions per output pixel (`output_width * output_height` entries, row-major) replacing the lens model and rotation, see `undistort_image_cpu_stmap`.
+    // Takes effect on the next render, `resize_output` disables it. Returns false if the size doesn't match the output
+    pub fn set_stmap(&mut self, stmap: Option<&[(f32, f32)]>) -> bool {
+        let stmap = match stmap {
+            Some(x) => x,
+            None => { self.globals.stmap_enabled = 0; return true; }
+        };
+        if stmap.len() != self.globals.output_width as usize * self.globals.output_height as usize {
+            log::error!("STMap size mismatch! {} vs {}x{}", stmap.len(), self.globals.output_width, self.globals.output_height);
+            return false;
+        }
+        // NaN marks background on the CPU, the shader checks for the same sentinel as `map_coord`
+        let data: Vec<[f32; 2]> = stmap.iter().map(|v| if v.0.is_finite() && v.1.is_finite() { [v.0, v.1] } else { [-99999.0, -99999.0] }).collect();
+        let size = (data.len() * std::mem::size_of::<[f32; 2]>()) as u64;
+        if self.stmap_buffer.size() < size {
+            self.stmap_buffer = self.device.create_buffer(&wgpu::BufferDescriptor { size, usage: BufferUsages::STORAGE | BufferUsages::COPY_DST, label: None, mapped_at_creation: false });
+            self.recreate_bind_groups();
+        }
+        self.queue.write_buffer(&self.stmap_buffer, 0, bytemuck::cast_slice(&data));
+        self.globals.stmap_enabled = 1;
+        true
+    }
+
+    // Staging buffer row pitch, `COPY_BYTES_PER_ROW_ALIGNMENT` aligned
+    fn aligned_stride(output_stride: usize) -> usize {
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as usize;
+        output_stride + (align - output_stride % align) % align
+    }
+
+    // Input and output buffer sizes in bytes (`stride * height`), as passed to `new`
+    pub fn buffer_sizes(&self) -> (usize, usize) {
+        (self.in_size as usize, self.out_size as usize)
+    }
+    pub fn input_height(&self) -> usize {
+        self.globals.height as usize
+    }
+
+    // Output stride that matches the GPU readback layout, so the output is copied in one piece instead of row by row.
+    // Any stride of at least `output_width * bytes per pixel` works, this one is just the fastest
+    pub fn aligned_output_stride(&self) -> usize {
+        Self::aligned_stride(self.globals.output_width as usize * self.format.describe().block_size as usize)
+    }
+
+    // Constant for a given output size, so it's computed once instead of on every readback
+    fn readback_rows(output_stride: usize, padded_out_stride: usize, output_height: usize) -> Vec<usize> {
+        if padded_out_stride == output_stride { return Vec::new(); }
+        (0..output_height).map(|y| y * padded_out_stride).collect()
+    }
+
+    // Adds an output rendered from the same input upload and params in `undistort_image_multi`. The image is scaled to the output size,
+    // so it should have the same aspect ratio as the main output. Returns the index of the output
+    pub fn add_output(&mut self, output_width: usize, output_height: usize, output_stride: usize) -> Option<usize> {
+        if self.coverage.is_some() { log::error!("Additional outputs are not supported with the coverage mask"); return None; }
+        if output_width == 0 || output_height == 0 || output_width > 8192 { log::error!("Invalid output size: {}x{}", output_width, output_height); return None; }
+        let row_bytes = output_width * self.format.describe().block_size as usize;
+        if output_stride < row_bytes { log::error!("Output stride {} is smaller than the row size of {} bytes", output_stride, row_bytes); return None; }
+
+        let padded_out_stride = Self::aligned_stride(output_stride);
+
+        self.extra_outputs.push(ExtraOutput {
+            texture: self.device.create_texture(&wgpu::TextureDescriptor {
+                label: None,
+                size: wgpu::Extent3d { width: output_width as u32, height: output_height as u32, depth_or_array_layers: 1 },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: self.format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            }),
+            staging_buffer: self.device.create_buffer(&wgpu::BufferDescriptor { size: (padded_out_stride * output_height) as u64, usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST, label: None, mapped_at_creation: false }),
+            globals_buffer: self.device.create_buffer(&wgpu::BufferDescriptor { size: std::mem::size_of::<Globals>() as u64, usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST, label: None, mapped_at_creation: false }),
+            width: output_width as u32,
+            height: output_height as u32,
+            out_stride: output_stride as u32,
+            padded_out_stride: padded_out_stride as u32,
+            readback_rows: Self::readback_rows(output_stride, padded_out_stride, output_height),
+            out_size: (output_stride * output_height) as u64,
+        });
+        Some(self.extra_outputs.len() - 1)
+    }
+    pub fn clear_outputs(&mut self) {
+        self.extra_outputs.clear();
+    }
+
+    // Reallocates only the output texture and staging buffer. Device, pipelines, input texture and bind groups are kept
+    pub fn resize_output(&mut self, output_width: usize, output_height: usize, output_stride: usize) -> bool {
+        if output_height < 4 || output_width > 8192 { log::error!("Invalid output size: {}x{}", output_width, output_height); return false; }
+        if output_width as u32 == self.globals.output_width && output_height as u32 == self.globals.output_height && output_stride as u32 == self.out_stride { return true; }
+        let row_bytes = output_width * self.format.describe().block_size as usize;
+        if output_stride < row_bytes { log::error!("Output stride {} is smaller than the row size of {} bytes", output_stride, row_bytes); return false; }
+
+        let padded_out_stride = Self::aligned_stride(output_stride);
+
+        self.staging_buffer = self.device.create_buffer(&wgpu::BufferDescriptor { size: (padded_out_stride * output_height) as u64, usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST, label: None, mapped_at_creation: false });
+        self.out_pixels = self.device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d { width: output_width as u32, height: output_height as u32, depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+        });
+
+        self.out_size = (output_stride * output_height) as u64;
+        self.out_stride = output_stride as u32;
+        self.padded_out_stride = padded_out_stride as u32;
+        self.readback_rows = Self::readback_rows(output_stride, padded_out_stride, output_height);
+        self.globals.output_width = output_width as u32;
+        self.globals.output_height = output_height as u32;
+        self.globals.stmap_enabled = 0; // The map is per output pixel, so it no longer fits
+        if self.coverage.is_some() {
+            self.coverage = Some(Self::create_coverage(&self.device, output_width, output_height));
+        }
+        true
+    }
+
+    fn create_coverage(device: &wgpu::Device, output_width: usize, output_height: usize) -> CoverageOutput {
+        let padded_stride = Self::aligned_stride(output_width);
+        CoverageOutput {
+            texture: device.create_texture(&wgpu::TextureDescriptor {
+                label: None,
+                size: wgpu::Extent3d { width: output_width as u32, height: output_height as u32, depth_or_array_layers: 1 },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::R8Unorm,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            }),
+            staging_buffer: device.create_buffer(&wgpu::BufferDescriptor { size: (padded_stride * output_height) as u64, usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST, label: None, mapped_at_creation: false }),
+            padded_stride: padded_stride as u32,
+            readback_rows: Self::readback_rows(output_width, padded_stride, output_height),
+        }
+    }
+
+    // GPU memory allocated by this instance in bytes: input/output/LUT textures, staging buffers (including the row padding), params, globals and coefficients.
+    // Pipelines, bind groups and driver overhead aren't included, so the real usage is somewhat higher
+    pub fn gpu_memory_estimate(&self) -> usize {
+        let bytes_per_pixel = self.format.describe().block_size as u64;
+        let globals_size = std::mem::size_of::<Globals>() as u64;
+        let (in_w, in_h) = self.input_texture_size();
+
+        let in_texture = in_w as u64 * in_h as u64 * bytes_per_pixel;
+        let out_texture = self.globals.output_width as u64 * self.globals.output_height as u64 * bytes_per_pixel;
+        let staging = self.padded_out_stride as u64 * self.globals.output_height as u64;
+        let lut_texture = self.color_lut.as_ref().map(|x| (x.size as u64).pow(3)).unwrap_or(1) * 16; // Rgba32Float
+        let bg_mask = self.bg_regions.as_ref().map(|x| (x.width * x.height) as u64).unwrap_or(1);
+        let coeffs = std::mem::size_of_val(&crate::undistortion::COEFFS) as u64;
+        let stmap = self.stmap_buffer.size();
+        let coverage = self.coverage.as_ref().map(|x| (self.globals.output_width as u64 + x.padded_stride as u64) * self.globals.output_height as u64).unwrap_or_default();
+        let extra: u64 = self.extra_outputs.iter().map(|x| {
+            x.width as u64 * x.height as u64 * bytes_per_pixel + x.padded_out_stride as u64 * x.height as u64 + globals_size
+        }).sum();
+
+        (in_texture + out_texture + staging + lut_texture + bg_mask + stmap + coverage + self.params_size + globals_size + coeffs + extra) as usize
+    }
+
+    fn downsample_input(&self, pixels: &[u8]) -> Vec<u8> {
+        let info = self.format.describe();
+        let bytes_per_pixel = info.block_size as usize;
+        let components = info.components as usize;
+        let scalar_bytes = bytes_per_pixel / components;
+        let is_float = matches!(info.sample_type, wgpu::TextureSampleType::Float { .. }) && scalar_bytes == 4;
+        // Half floats have to be averaged as values, not as their bit patterns
+        let is_half = self.format == wgpu::TextureFormat::Rgba16Float;
+
+        let read = |b: &[u8]| -> f32 {
+            match scalar_bytes {
+                1 => b[0] as f32,
+                2 if is_half => crate::undistortion::f16_to_f32(u16::from_ne_bytes([b[0], b[1]])),
+                2 => u16::from_ne_bytes([b[0], b[1]]) as f32,
+                _ => if is_float { f32::from_ne_bytes([b[0], b[1], b[2], b[3]]) } else { u32::from_ne_bytes([b[0], b[1], b[2], b[3]]) as f32 }
+            }
+        };
+        let write = |b: &mut [u8], v: f32| {
+            match scalar_bytes {
+                1 => b[0] = v.round().max(0.0).min(255.0) as u8,
+                2 if is_half => b.copy_from_slice(&crate::undistortion::f32_to_f16(v).to_ne_bytes()),
+                2 => b.copy_from_slice(&(v.round().max(0.0).min(65535.0) as u16).to_ne_bytes()),
+                _ => b.copy_from_slice(&if is_float { v.to_ne_bytes() } else { (v.round().max(0.0) as u32).to_ne_bytes() })
+            }
+        };
+
+        let factor = self.input_downscale as usize;
+        let (w, h) = self.input_texture_size();
+        let (w, h) = (w as usize, h as usize);
+        let out_stride = w * bytes_per_pixel;
+        let in_stride = self.in_stride as usize;
+        let norm = 1.0 / (factor * factor) as f32;
+
+        let mut out = vec![0u8; out_stride * h];
+        use rayon::prelude::ParallelSliceMut;
+        use rayon::iter::{ ParallelIterator, IndexedParallelIterator };
+        out.par_chunks_mut(out_stride).enumerate().for_each(|(y, row)| {
+            for x in 0..w {
+                for c in 0..components {
+                    let mut sum = 0.0;
+                    for yy in 0..factor {
+                        let src_row = &pixels[(y * factor + yy) * in_stride..];
+                        for xx in 0..factor {
+                            let pos = (x * factor + xx) * bytes_per_pixel + c * scalar_bytes;
+                            sum += read(&src_row[pos..pos + scalar_bytes]);
+                        }
+                    }
+                    let pos = x * bytes_per_pixel + c * scalar_bytes;
+                    write(&mut row[pos..pos + scalar_bytes], sum * norm);
+                }
+            }
+        });
+        out
+    }
+
     pub fn set_background(&mut self, bg: nalgebra::Vector4<f32>) {
         self.globals.bg = [bg[0] / self.bg_scaler, bg[1] / self.bg_scaler, bg[2] / self.bg_scaler, bg[3] / self.bg_scaler];
     }
 
-    pub fn undistort_image(&mut self, pixels: &mut [u8], output_pixels: &mut [u8], itm: &crate::undistortion::FrameTransform) {
-        let flattened_params = bytemuck::cast_slice(&itm.params);
+    // Radial background from the output center to the corners (center, edge) instead of the flat color, in the same range as `set_background`. Takes effect on the next render
+    pub fn set_background_gradient(&mut self, gradient: Option<(nalgebra::Vector4<f32>, nalgebra::Vector4<f32>)>) {
+        match gradient {
+            Some((center, edge)) => {
+                let s = self.bg_scaler;
+                self.globals.bg_gradient = 1;
+                self.globals.bg_center = [center[0] / s, center[1] / s, center[2] / s, center[3] / s];
+                self.globals.bg_edge = [edge[0] / s, edge[1] / s, edge[2] / s, edge[3] / s];
+            },
+            None => { self.globals.bg_gradient = 0; }
+        }
+    }
+
+    // Per channel magnification of the source position around the lens center (R, G, B), see `FrameParams::chromatic_aberration`. Takes effect on the next render
+    pub fn set_chromatic_aberration(&mut self, scale: Option<[f32; 3]>) {
+        self.globals.ca_scale = match scale {
+            Some(s) => [s[0], s[1], s[2], 1.0],
+            None => [1.0, 1.0, 1.0, 0.0]
+        };
+    }
+
+    // Takes effect on the next render
+    pub fn set_color_transform(&mut self, color: Option<&crate::undistortion::ColorTransform>) {
+        match color {
+            Some(c) => {
+                let m = &c.matrix;
+                self.globals.color_mode = c.transfer as u32 + 1;
+                self.globals.color_matrix = [[m[0], m[1], m[2], 0.0], [m[3], m[4], m[5], 0.0], [m[6], m[7], m[8], 0.0]];
+                // Shader values are already divided by the background scaler
+                self.globals.color_scale = c.max_value / self.bg_scaler;
+            },
+            None => { self.globals.color_mode = 0; }
+        }
+    }
+
+    pub fn device(&self) -> &wgpu::Device { &self.device }
+    pub fn queue(&self) -> &wgpu::Queue { &self.queue }
 
-        if self.in_size != pixels.len() as u64              { log::error!("Buffer size mismatch! {} vs {}", self.in_size, pixels.len()); return; }
-        if self.out_size != output_pixels.len() as u64      { log::error!("Buffer size mismatch! {} vs {}", self.out_size, output_pixels.len()); return; }
-        if self.params_size < flattened_params.len() as u64 { log::error!("Buffer size mismatch! {} vs {}", self.params_size, flattened_params.len()); return; }
+    fn upload_params(&mut self, params: &[[f32; 9]], num_params: u32) -> bool {
+        if self.is_device_lost() { return false; }
+        if num_params < 4 || num_params as usize > params.len() { log::error!("Invalid params count! {} of {}", num_params, params.len()); return false; }
+        let params = &params[..num_params as usize];
+        let flattened_params: &[u8] = bytemuck::cast_slice(params);
 
-        self.queue.write_buffer(&self.params_buffer, 0, flattened_params);
+        if self.params_size < flattened_params.len() as u64 { log::error!("Buffer size mismatch! {} vs {}", self.params_size, flattened_params.len()); return false; }
 
-        self.globals.num_params = itm.params.len() as u32;
+        // Everything changed is a single range, so it's the same full write
+        if self.uploaded_params.len() != params.len() {
+            self.queue.write_buffer(&self.params_buffer, 0, flattened_params);
+        } else {
+            // Contiguous ranges of changed rows, e.g. only the rolling shutter matrices while tuning the readout time
+            let mut ranges: Vec<std::ops::Range<usize>> = Vec::new();
+            for (i, (new, old)) in params.iter().zip(self.uploaded_params.iter()).enumerate() {
+                if new.iter().zip(old.iter()).any(|(a, b)| a.to_bits() != b.to_bits()) {
+                    match ranges.last_mut() {
+                        Some(r) if r.end == i => r.end = i + 1,
+                        _ => ranges.push(i..i + 1)
+                    }
+                }
+            }
+            if ranges.len() > MAX_PARAMS_WRITES {
+                ranges = vec![ranges[0].start..ranges[ranges.len() - 1].end];
+            }
+            let row_size = std::mem::size_of::<[f32; 9]>();
+            for r in ranges {
+                self.queue.write_buffer(&self.params_buffer, (r.start * row_size) as u64, bytemuck::cast_slice(&params[r]));
+            }
+        }
+        self.uploaded_params.clear();
+        self.uploaded_params.extend_from_slice(params);
+
+        self.globals.num_params = num_params;
         self.queue.write_buffer(&self.globals_buffer, 0, bytemuck::bytes_of(&self.globals));
+        true
+    }
+
+    fn upload_input(&mut self, pixels: &[u8], params: &[[f32; 9]], num_params: u32) -> bool {
+        if self.in_size != pixels.len() as u64 { log::error!("Buffer size mismatch! {} vs {}", self.in_size, pixels.len()); return false; }
+        if !self.upload_params(params, num_params) { return false; }
+
+        let (tex_width, tex_height) = self.input_texture_size();
+        let downsampled;
+        let (data, bytes_per_row) = if self.input_downscale > 1 {
+            downsampled = self.downsample_input(pixels);
+            (&downsampled[..], tex_width * self.format.describe().block_size as u32)
+        } else {
+            (pixels, self.in_stride)
+        };
         self.queue.write_texture(
             self.in_pixels.as_image_copy(),
-            bytemuck::cast_slice(&pixels),
+            bytemuck::cast_slice(data),
             wgpu::ImageDataLayout {
                 offset: 0,
-                bytes_per_row: std::num::NonZeroU32::new(self.in_stride),
+                bytes_per_row: std::num::NonZeroU32::new(bytes_per_row),
                 rows_per_image: None,
             },
             wgpu::Extent3d {
-                width: self.globals.width as u32,
-                height: self.globals.height as u32,
+                width: tex_width,
+                height: tex_height,
                 depth_or_array_layers: 1,
             },
         );
+        self.has_input = true;
+        true
+    }
+
+    fn encode_render_pass(&self, encoder: &mut wgpu::CommandEncoder, view: &wgpu::TextureView) {
+        let (_, pipeline, bind_group) = &self.pipelines[self.active_pipeline];
+        let coverage_view = self.coverage.as_ref().map(|x| x.texture.create_view(&wgpu::TextureViewDescriptor::default()));
+        Self::encode_render_pass_with(encoder, view, coverage_view.as_ref(), pipeline, bind_group);
+    }
+    fn encode_render_pass_with(encoder: &mut wgpu::CommandEncoder, view: &wgpu::TextureView, coverage_view: Option<&wgpu::TextureView>, pipeline: &wgpu::RenderPipeline, bind_group: &wgpu::BindGroup) {
+        let color_attachments: Vec<_> = std::iter::once(view).chain(coverage_view).map(|view| wgpu::RenderPassColorAttachment {
+            view,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                store: true,
+            },
+        }).collect();
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &color_attachments,
+            depth_stencil_attachment: None,
+        });
+        rpass.set_pipeline(pipeline);
+        rpass.set_bind_group(0, bind_group, &[]);
+        rpass.draw(0..6, 0..1);
+    }
+
+    // Renders directly into `target` without the staging buffer roundtrip.
+    // `target` must be created on `self.device()`, with the same format and size as the output texture and `RENDER_ATTACHMENT` usage.
+    pub fn undistort_to_texture(&mut self, pixels: &[u8], itm: &crate::undistortion::FrameTransform, target: &wgpu::TextureView) -> bool {
+        if !self.upload_input(pixels, &itm.params, itm.params.len() as u32) { return false; }
+
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        self.encode_render_pass(&mut encoder, target);
+        self.queue.submit(Some(encoder.finish()));
+        true
+    }
+
+    // True after a driver reset, another device loss or any uncaptured wgpu error. Rendering is skipped until `recover` succeeds
+    pub fn is_device_lost(&self) -> bool {
+        self.device_lost.load(SeqCst)
+    }
+
+    // Same as `undistort_image`, but every failure is an `Err`, `Ok` holds the hash of the output when `verify` is set.
+    // `Err(WgpuError::DeviceLost)` when the device was lost before or during the render, so the caller can `recover`
+    pub fn try_undistort_image(&mut self, pixels: &[u8], output_pixels: &mut [u8], itm: &crate::undistortion::FrameTransform) -> Result<Option<u64>, WgpuError> {
+        self.try_undistort_image_with_params(pixels, output_pixels, &itm.params, itm.params.len() as u32)
+    }
+
+    // Same as `undistort_image_with_params`, with the errors of `try_undistort_image`
+    pub fn try_undistort_image_with_params(&mut self, pixels: &[u8], output_pixels: &mut [u8], params: &[[f32; 9]], num_params: u32) -> Result<Option<u64>, WgpuError> {
+        if self.is_device_lost() { return Err(WgpuError::DeviceLost); }
+        if self.in_size != pixels.len() as u64 { return Err(WgpuError::BufferSizeMismatch((pixels.len(), self.in_size as usize))); }
+        if self.out_size != output_pixels.len() as u64 { return Err(WgpuError::BufferSizeMismatch((output_pixels.len(), self.out_size as usize))); }
+        let params_fit = (num_params as u64) * std::mem::size_of::<[f32; 9]>() as u64 <= self.params_size;
+        if num_params < 4 || num_params as usize > params.len() || !params_fit { return Err(WgpuError::InvalidParamsCount((num_params as usize, params.len()))); }
+
+        // The sizes are checked above, so the upload only fails without a device
+        if !self.upload_input(pixels, params, num_params) { return Err(WgpuError::DeviceLost); }
+        let hash = self.render_to_output(output_pixels, &mut [], None);
+        if self.is_device_lost() { return Err(WgpuError::DeviceLost); }
+        Ok(hash)
+    }
+
+    // Recreates the device with all buffers, textures and pipelines after a device loss, on the same adapter.
+    // Sizes, format, interpolation, quality, background, color transform, LUT, background regions, input downscale and additional outputs are kept.
+    // The uploaded input is gone, so `rerender_last` needs a new frame first, and an STMap has to be set again
+    pub fn recover(&mut self) -> Result<(), WgpuInitError> {
+        let mut new = match self.recreate() {
+            // The cached adapter can be stale after a driver reset, enumerate again
+            Err(WgpuInitError::DeviceRequestFailed(_)) => { *adapter_lock(self.power).write() = None; self.recreate() },
+            x => x
+        }?;
+        new.verify = self.verify;
+        new.set_input_downscale(self.input_downscale);
+        if let Some(lut) = self.color_lut.as_ref() { new.set_color_lut(Some(lut)); }
+        if let Some(regions) = self.bg_regions.as_ref() { new.set_background_regions(Some(regions)); }
+        for out in &self.extra_outputs {
+            new.add_output(out.width as usize, out.height as usize, out.out_stride as usize);
+        }
+        for (key, _, _) in &self.pipelines {
+            new.pipeline_index(*key);
+        }
+        new.active_pipeline = new.pipeline_index(self.pipelines[self.active_pipeline].0);
+        new.quality = self.quality;
+
+        // Uniforms hold the rest of the configuration
+        new.globals = self.globals;
+        if new.globals.stmap_enabled != 0 {
+            log::warn!("STMap is not restored after a device loss");
+            new.globals.stmap_enabled = 0;
+        }
+        *self = new;
+        Ok(())
+    }
+
+    fn recreate(&self) -> Result<Self, WgpuInitError> {
+        Self::try_new_with_options(self.globals.width as usize, self.globals.height as usize, self.in_stride as usize,
+                                   self.globals.output_width as usize, self.globals.output_height as usize, self.out_stride as usize,
+                                   nalgebra::Vector4::zeros(), self.globals.interpolation, self.format, self.power, self.coverage.is_some())
+    }
+
+    // Returns the hash of the output when `verify` is set. Failures are logged, see `try_undistort_image` to tell them apart from a disabled `verify`
+    pub fn undistort_image(&mut self, pixels: &[u8], output_pixels: &mut [u8], itm: &crate::undistortion::FrameTransform) -> Option<u64> {
+        self.undistort_image_with_params(pixels, output_pixels, &itm.params, itm.params.len() as u32)
+    }
+
+    // Same as `undistort_image`, but takes the raw params (lens params, additional params and per-row matrices, see `FrameTransform`),
+    // e.g. from an external solver. Only the first `num_params` rows are used
+    pub fn undistort_image_with_params(&mut self, pixels: &[u8], output_pixels: &mut [u8], params: &[[f32; 9]], num_params: u32) -> Option<u64> {
+        match self.try_undistort_image_with_params(pixels, output_pixels, params, num_params) {
+            Ok(hash) => hash,
+            Err(e) => { log::error!("{}", e); None }
+        }
+    }
+
+    // Same as `undistort_image`, and fills `coverage` with one byte per output pixel (`output_width * output_height`, no padding):
+    // 0 for background and passthrough, 255 where the input is sampled, including the edge repeat/mirror/wrap modes.
+    // Supersampled pixels on the boundary get the covered share of the samples. Requires a wrapper built with `coverage`
+    pub fn undistort_image_with_coverage(&mut self, pixels: &mut [u8], output_pixels: &mut [u8], coverage: &mut [u8], itm: &crate::undistortion::FrameTransform) -> Option<u64> {
+        if self.coverage.is_none() { log::error!("Coverage mask is not enabled, see `WgpuWrapperBuilder::coverage`"); return None; }
+        if self.out_size != output_pixels.len() as u64 { log::error!("Buffer size mismatch! {} vs {}", self.out_size, output_pixels.len()); return None; }
+        let coverage_size = self.globals.output_width as usize * self.globals.output_height as usize;
+        if coverage_size != coverage.len() { log::error!("Buffer size mismatch! {} vs {}", coverage_size, coverage.len()); return None; }
 
+        if !self.upload_input(pixels, &itm.params, itm.params.len() as u32) { return None; }
+
+        self.render_to_output(output_pixels, &mut [], Some(coverage))
+    }
+
+    // Renders the main output and all outputs added with `add_output` from a single input upload.
+    // `extra_pixels` has one buffer per added output, in the same order. Returns the hash of the main output when `verify` is set
+    pub fn undistort_image_multi(&mut self, pixels: &mut [u8], output_pixels: &mut [u8], extra_pixels: &mut [&mut [u8]], itm: &crate::undistortion::FrameTransform) -> Option<u64> {
+        if self.out_size != output_pixels.len() as u64 { log::error!("Buffer size mismatch! {} vs {}", self.out_size, output_pixels.len()); return None; }
+        if extra_pixels.len() != self.extra_outputs.len() { log::error!("Expected {} additional outputs, got {}", self.extra_outputs.len(), extra_pixels.len()); return None; }
+        for (out, buf) in self.extra_outputs.iter().zip(extra_pixels.iter()) {
+            if out.out_size != buf.len() as u64 { log::error!("Buffer size mismatch! {} vs {}", out.out_size, buf.len()); return None; }
+        }
+
+        if !self.upload_input(pixels, &itm.params, itm.params.len() as u32) { return None; }
+
+        self.render_to_output(output_pixels, extra_pixels, None)
+    }
+
+    // Forget the uploaded input, so `rerender_last` can't show a frame from before a seek
+    pub fn invalidate_input(&mut self) {
+        self.has_input = false;
+    }
+
+    // Renders the previously uploaded input again with new params, skipping the texture upload.
+    // Useful when only the transform changed between renders, e.g. during scrubbing or when the next input frame isn't ready yet
+    pub fn rerender_last(&mut self, output_pixels: &mut [u8], itm: &crate::undistortion::FrameTransform) -> Option<u64> {
+        if !self.has_input { log::error!("No input frame uploaded yet"); return None; }
+        if self.out_size != output_pixels.len() as u64 { log::error!("Buffer size mismatch! {} vs {}", self.out_size, output_pixels.len()); return None; }
+
+        if !self.upload_params(&itm.params, itm.params.len() as u32) { return None; }
+
+        self.render_to_output(output_pixels, &mut [], None)
+    }
+
+    // Copies the mapped staging buffer to the tightly packed output
+    fn copy_from_staging(data: &[u8], output_pixels: &mut [u8], readback_rows: &[usize], out_stride: usize) {
+        if readback_rows.is_empty() {
+            // Fast path
+            output_pixels.copy_from_slice(data);
+        } else {
+            use rayon::prelude::{ ParallelSliceMut, IntoParallelRefIterator };
+            use rayon::iter::{ ParallelIterator, IndexedParallelIterator };
+            output_pixels
+                .par_chunks_mut(out_stride)
+                .zip(readback_rows.par_iter())
+                .for_each(|(dest, &offset)| {
+                    dest.copy_from_slice(&data[offset..offset + out_stride]);
+                });
+        }
+    }
+
+    // `extra_pixels` is empty, or has one buffer for each of `extra_outputs`. `coverage` is only read back when set
+    fn render_to_output(&mut self, output_pixels: &mut [u8], extra_pixels: &mut [&mut [u8]], coverage: Option<&mut [u8]>) -> Option<u64> {
         let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
         let view = self.out_pixels.create_view(&wgpu::TextureViewDescriptor::default());
-        {
-            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: None,
-                color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
-                        store: true,
-                    },
-                }],
-                depth_stencil_attachment: None,
+        self.encode_render_pass(&mut encoder, &view);
+
+        // Bind groups of the additional outputs are cheap to create, so they aren't kept across pipeline and input texture changes
+        let extra_count = if extra_pixels.is_empty() { 0 } else { self.extra_outputs.len() };
+        for out in &self.extra_outputs[..extra_count] {
+            let mut globals = self.globals;
+            globals.output_scale_x = self.globals.output_width as f32 / out.width as f32;
+            globals.output_scale_y = self.globals.output_height as f32 / out.height as f32;
+            self.queue.write_buffer(&out.globals_buffer, 0, bytemuck::bytes_of(&globals));
+
+            let (key, pipeline, _) = &self.pipelines[self.active_pipeline];
+            let sampler = (*key == FAST_PREVIEW).then(|| &self.sampler);
+            let bind_group = Self::create_bind_group(&self.device, pipeline, &out.globals_buffer, &self.params_buffer, &self.in_pixels, &self.coeffs_buffer, &self.lut_texture, &self.stmap_buffer, &self.bg_mask_texture, sampler);
+            let view = out.texture.create_view(&wgpu::TextureViewDescriptor::default());
+            Self::encode_render_pass_with(&mut encoder, &view, None, pipeline, &bind_group);
+
+            encoder.copy_texture_to_buffer(out.texture.as_image_copy(), wgpu::ImageCopyBuffer {
+                buffer: &out.staging_buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(out.padded_out_stride),
+                    rows_per_image: None,
+                },
+            }, wgpu::Extent3d {
+                width: out.width,
+                height: out.height,
+                depth_or_array_layers: 1,
             });
-            rpass.set_pipeline(&self.render_pipeline);
-            rpass.set_bind_group(0, &self.bind_group, &[]);
-            rpass.draw(0..6, 0..1);
         }
 
         encoder.copy_texture_to_buffer(wgpu::ImageCopyTexture {
@@ -275,41 +1296,209 @@ impl WgpuWrapper {
             depth_or_array_layers: 1,
         });
 
+        let coverage_output = if coverage.is_some() { self.coverage.as_ref() } else { None };
+        if let Some(cov) = coverage_output {
+            encoder.copy_texture_to_buffer(cov.texture.as_image_copy(), wgpu::ImageCopyBuffer {
+                buffer: &cov.staging_buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(cov.padded_stride),
+                    rows_per_image: None,
+                },
+            }, wgpu::Extent3d {
+                width: self.globals.output_width as u32,
+                height: self.globals.output_height as u32,
+                depth_or_array_layers: 1,
+            });
+        }
+
         self.queue.submit(Some(encoder.finish()));
 
         let buffer_slice = self.staging_buffer.slice(..);
         let buffer_future = buffer_slice.map_async(wgpu::MapMode::Read);
+        let coverage_future = coverage_output.map(|cov| cov.staging_buffer.slice(..).map_async(wgpu::MapMode::Read));
+        let extra_futures: Vec<_> = self.extra_outputs[..extra_count].iter().map(|out| out.staging_buffer.slice(..).map_async(wgpu::MapMode::Read)).collect();
 
         self.device.poll(wgpu::Maintain::Wait);
 
-        if let Ok(()) = pollster::block_on(buffer_future) {
-            let data = buffer_slice.get_mapped_range();
-            if self.padded_out_stride == self.out_stride {
-                // Fast path
-                output_pixels.copy_from_slice(data.as_ref());
+        for ((out, future), dest) in self.extra_outputs.iter().zip(extra_futures).zip(extra_pixels.iter_mut()) {
+            let slice = out.staging_buffer.slice(..);
+            if let Ok(()) = pollster::block_on(future) {
+                let data = slice.get_mapped_range();
+                Self::copy_from_staging(data.as_ref(), dest, &out.readback_rows, out.out_stride as usize);
+                drop(data);
+                out.staging_buffer.unmap();
+            } else {
+                log::error!("failed to read additional output from wgpu!");
+                self.device_lost.store(true, SeqCst);
+            }
+        }
+
+        if let (Some(cov), Some(future), Some(dest)) = (coverage_output, coverage_future, coverage) {
+            let slice = cov.staging_buffer.slice(..);
+            if let Ok(()) = pollster::block_on(future) {
+                let data = slice.get_mapped_range();
+                Self::copy_from_staging(data.as_ref(), dest, &cov.readback_rows, self.globals.output_width as usize);
+                drop(data);
+                cov.staging_buffer.unmap();
             } else {
-                // data.as_ref()
-                //     .chunks(self.padded_out_stride as usize)
-                //     .zip(output_pixels.chunks_mut(self.out_stride as usize))
-                //     .for_each(|(src, dest)| {
-                //         dest.copy_from_slice(&src[0..self.out_stride as usize]);
-                //     });
-                use rayon::prelude::{ ParallelSliceMut, ParallelSlice };
-                use rayon::iter::{ ParallelIterator, IndexedParallelIterator };
-                data.as_ref()
-                    .par_chunks(self.padded_out_stride as usize)
-                    .zip(output_pixels.par_chunks_mut(self.out_stride as usize))
-                    .for_each(|(src, dest)| {
-                        dest.copy_from_slice(&src[0..self.out_stride as usize]);
-                    });
+                log::error!("failed to read coverage mask from wgpu!");
+                self.device_lost.store(true, SeqCst);
             }
+        }
+
+        if let Ok(()) = pollster::block_on(buffer_future) {
+            let data = buffer_slice.get_mapped_range();
+            Self::copy_from_staging(data.as_ref(), output_pixels, &self.readback_rows, self.out_stride as usize);
 
             // We have to make sure all mapped views are dropped before we unmap the buffer.
             drop(data);
             self.staging_buffer.unmap();
+
+            match self.verify {
+                Some(VerifyHash::Crc32)      => Some(hash_output(output_pixels) as u64),
+                Some(VerifyHash::Perceptual) => Some(perceptual_hash_output(output_pixels, self.globals.output_width as usize, self.globals.output_height as usize, self.out_stride as usize, self.format)),
+                None => None
+            }
+        } else {
+            // Mapping only fails when the device is gone
+            log::error!("failed to run compute on wgpu!");
+            self.device_lost.store(true, SeqCst);
+            None
+        }
+    }
+
+    // GPU version of `undistort_points` for dense point sets, takes the same arguments.
+    // Points that don't converge are returned as (-1000000, -1000000), same as on the CPU. Computed in f32
+    pub fn undistort_points_gpu(&mut self, distorted: &[(f64, f64)], camera_matrix: nalgebra::Matrix3<f64>, distortion_coeffs: &[f64], rotation: nalgebra::Matrix3<f64>, p: Option<nalgebra::Matrix3<f64>>, rot_per_point: Option<Vec<nalgebra::Matrix3<f64>>>, params: Option<&crate::undistortion::ComputeParams>) -> Option<Vec<(f64, f64)>> {
+        if distorted.is_empty() { return Some(Vec::new()); }
+        if distortion_coeffs.len() < 4 { return None; }
+
+        let rr = if let Some(p) = p { p * rotation } else { rotation };
+        let flatten = |m: &nalgebra::Matrix3<f64>| [
+            m[(0, 0)] as f32, m[(0, 1)] as f32, m[(0, 2)] as f32,
+            m[(1, 0)] as f32, m[(1, 1)] as f32, m[(1, 2)] as f32,
+            m[(2, 0)] as f32, m[(2, 1)] as f32, m[(2, 2)] as f32,
+        ];
+        let rotations: Vec<[f32; 9]> = match rot_per_point {
+            Some(v) => (0..distorted.len()).map(|i| flatten(v.get(i).unwrap_or(&rr))).collect(),
+            None => vec![flatten(&rr)]
+        };
+        let points: Vec<[f32; 2]> = distorted.iter().map(|p| [p.0 as f32, p.1 as f32]).collect();
+
+        let globals = PointsGlobals {
+            count: points.len() as u32,
+            rotations_count: rotations.len() as u32,
+            super_fisheye: params.map(|x| x.super_fisheye as u32).unwrap_or_default(),
+            lens_correction_amount: params.map(|x| x.lens_correction_amount as f32).unwrap_or(1.0),
+            f: [camera_matrix[(0, 0)] as f32, camera_matrix[(1, 1)] as f32],
+            c: [camera_matrix[(0, 2)] as f32, camera_matrix[(1, 2)] as f32],
+            k: [distortion_coeffs[0] as f32, distortion_coeffs[1] as f32, distortion_coeffs[2] as f32, distortion_coeffs[3] as f32],
+        };
+
+        if self.points_pipeline.is_none() {
+            let shader = self.device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("wgpu_undistort_points.wgsl"))),
+                label: None
+            });
+            self.points_pipeline = Some(self.device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: None,
+                layout: None,
+                module: &shader,
+                entry_point: "undistort_points",
+            }));
+        }
+        let pipeline = self.points_pipeline.as_ref()?;
+
+        let result_size = (points.len() * std::mem::size_of::<[f32; 2]>()) as wgpu::BufferAddress;
+        let globals_buffer   = self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor { label: None, contents: bytemuck::bytes_of(&globals), usage: BufferUsages::UNIFORM });
+        let points_buffer    = self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor { label: None, contents: bytemuck::cast_slice(&points), usage: BufferUsages::STORAGE });
+        let rotations_buffer = self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor { label: None, contents: bytemuck::cast_slice(&rotations), usage: BufferUsages::STORAGE });
+        let result_buffer    = self.device.create_buffer(&wgpu::BufferDescriptor { size: result_size, usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC, label: None, mapped_at_creation: false });
+        let staging_buffer   = self.device.create_buffer(&wgpu::BufferDescriptor { size: result_size, usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST, label: None, mapped_at_creation: false });
+
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: globals_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 1, resource: points_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 2, resource: rotations_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 3, resource: result_buffer.as_entire_binding() },
+            ],
+            label: None,
+        });
+
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+            cpass.set_pipeline(pipeline);
+            cpass.set_bind_group(0, &bind_group, &[]);
+            cpass.dispatch((points.len() as u32 + 63) / 64, 1, 1);
+        }
+        encoder.copy_buffer_to_buffer(&result_buffer, 0, &staging_buffer, 0, result_size);
+        self.queue.submit(Some(encoder.finish()));
+
+        let buffer_slice = staging_buffer.slice(..);
+        let buffer_future = buffer_slice.map_async(wgpu::MapMode::Read);
+
+        self.device.poll(wgpu::Maintain::Wait);
+
+        if let Ok(()) = pollster::block_on(buffer_future) {
+            let data = buffer_slice.get_mapped_range();
+            let ret = bytemuck::cast_slice::<u8, [f32; 2]>(&data).iter().map(|p| (p[0] as f64, p[1] as f64)).collect();
+            drop(data);
+            staging_buffer.unmap();
+            Some(ret)
         } else {
-            // TODO change to Result
-            log::error!("failed to run compute on wgpu!")
+            log::error!("failed to run compute on wgpu!");
+            self.device_lost.store(true, SeqCst);
+            None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RGBA8 frame with 16 bytes of row padding. `f` returns the gray value and the alpha of each pixel, the padding is filled with `pad`
+    fn rgba8_padded<F: Fn(usize, usize) -> (u8, u8)>(width: usize, height: usize, pad: u8, f: F) -> Vec<u8> {
+        (0..height).flat_map(|y| (0..width).flat_map(|x| { let (v, a) = f(x, y); [v, v, v, a] }).chain(std::iter::repeat(pad).take(16)).collect::<Vec<u8>>()).collect()
+    }
+
+    #[test]
+    fn perceptual_hash_tolerates_one_lsb() {
+        let (width, height) = (64, 48);
+        let stride = width * 4 + 16;
+        let hash = |buf: &[u8]| perceptual_hash_output(buf, width, height, stride, wgpu::TextureFormat::Rgba8Unorm);
+        let gradient = |x: usize, y: usize| 40 + x * 2 + y;
+        let noise = |x: usize, y: usize| (x * 7 + y * 13) % 3;
+
+        let reference = hash(&rgba8_padded(width, height, 0, |x, y| (gradient(x, y) as u8, 255)));
+        assert_ne!(reference, 0);
+        // Padding and alpha aren't part of the image
+        assert_eq!(hash(&rgba8_padded(width, height, 0xff, |x, y| (gradient(x, y) as u8, (x * 5) as u8))), reference);
+
+        let jittered = hash(&rgba8_padded(width, height, 0, |x, y| ((gradient(x, y) + noise(x, y) - 1) as u8, 255)));
+        assert!((reference ^ jittered).count_ones() <= PERCEPTUAL_HASH_THRESHOLD, "{:064b} vs {:064b}", reference, jittered);
+
+        // Flat frames hash to 0, with or without the rounding noise
+        assert_eq!(hash(&rgba8_padded(width, height, 0, |_, _| (128, 255))), 0);
+        assert_eq!(hash(&rgba8_padded(width, height, 0, |x, y| ((127 + noise(x, y)) as u8, 255))), 0);
+    }
+
+    #[test]
+    fn rgb10a2_scaler_matches_cpu() {
+        use crate::undistortion::{ PixelType, RGB10A2 };
+        use nalgebra::Vector4;
+        assert_eq!(RGB10A2::wgpu_format(), Some(wgpu::TextureFormat::Rgb10a2Unorm));
+        // The shader samples 0-1 and multiplies by the scaler, which has to land on the CPU range of `RGB10A2::to_float`
+        let (scalar, scaler) = format_info(wgpu::TextureFormat::Rgb10a2Unorm).unwrap();
+        assert_eq!(scalar, "f32");
+        let white: RGB10A2 = bytemuck::cast(u32::MAX);
+        assert_eq!(PixelType::to_float(white), Vector4::from_element(scaler as f32));
+        // Same packing as the texture: R in the low bits of a little-endian u32
+        let px = RGB10A2::from_float(Vector4::new(1023.0, 512.0, 1.0, 1023.0));
+        assert_eq!(pixel_luma(bytemuck::bytes_of(&px), wgpu::TextureFormat::Rgb10a2Unorm), (0.2126 * 1023.0 + 0.7152 * 512.0 + 0.0722 * 1.0) / 1023.0);
+    }
+}
@@ -9,19 +9,56 @@ struct Globals {
     params_count: u32;
     interpolation: u32;
     background: array<f32, 4>;
+    input_scale: f32;
+    color_mode: u32; // 0 - off, 1 - linear, 2 - BT.709, 3 - sRGB
+    color_r: vec4<f32>;
+    color_g: vec4<f32>;
+    color_b: vec4<f32>;
+    color_scale: f32;
+    output_scale_x: f32; // Render target pixel to params output space, for additional outputs of a different size
+    output_scale_y: f32;
+    lut_size: u32; // 0 - no color LUT
+    lut_scale: f32;
+    stmap_enabled: u32; // Source positions come from `stmap` instead of the lens model
+    bg_gradient: u32; // 0 - flat `background`
+    bg_regions: u32; // Palette size, 0 - no region mask
+    bg_center: vec4<f32>;
+    bg_edge: vec4<f32>;
+    bg_palette: array<vec4<f32>, 8>; // MAX_BACKGROUND_REGIONS
+    ca_scale: vec4<f32>; // R, G, B magnification around the lens center, w > 0.5 - enabled
 };
 
 @group(0) @binding(0) @stage(fragment) var<uniform> params: Globals;
 @group(0) @binding(1) @stage(fragment) var<storage, read> undistortion_params: array<f32>;
 @group(0) @binding(2) @stage(fragment) var input: texture_2d<SCALAR>;
 @group(0) @binding(3) @stage(fragment) var<storage, read> coeffs: array<f32>;
+@group(0) @binding(4) @stage(fragment) var color_lut: texture_3d<f32>;
+@group(0) @binding(6) @stage(fragment) var<storage, read> stmap: array<vec2<f32>>;
+@group(0) @binding(7) @stage(fragment) var bg_mask: texture_2d<u32>;
+
+// Background of the sample being computed, set by the fragment shader since it depends on the output position with a gradient
+var<private> sample_bg: vec4<f32>;
+// Share of the samples taken from the input, written to the second render target by `undistort_fragment_coverage`
+var<private> coverage: f32;
+// FAST_PREVIEW_BINDINGS
 
 let INTER_BITS: u32 = 5u;
 let INTER_TAB_SIZE: i32 = 32; // (1u << INTER_BITS);
-
-fn interpolate(sx: i32, sy: i32, sx0: i32, sy0: i32, width_u: i32, height_u: i32) -> vec4<SCALAR> {
-    let bg = vec4<f32>(params.background[0], params.background[1], params.background[2], params.background[3]);
+let SUPERSAMPLE_THRESHOLD: f32 = 2.828427; // 2 source pixels per output pixel, along the diagonal
+let NEWTON_ITERATIONS: i32 = 10;
+
+fn interpolate(sx: i32, sy: i32, sx0: i32, sy0: i32, width_u: i32, height_u: i32) -> vec4<f32> {
+    // Straight alpha input is premultiplied for blending. Formats without alpha read 1.0, so this is a no-op for them
+    let straight_alpha = undistortion_params[21] > 0.5;
+    let edge_wrap = undistortion_params[10] > 3.9 && undistortion_params[10] < 4.1; // 4
+    var bg = sample_bg;
+    if (straight_alpha) { bg = vec4<f32>(bg.xyz * bg.w, bg.w); }
     var sum = vec4<f32>(0.0);
+    // Unsharp mask, box average and range of the taps
+    let sharpen = undistortion_params[24];
+    var box_sum = vec4<f32>(0.0);
+    var lo = vec4<f32>(3.4e38);
+    var hi = vec4<f32>(-3.4e38);
     
     let shift = (params.interpolation >> 2u) + 1u;
     var indices: array<i32, 3> = array<i32, 3>(0, 64, 192);
@@ -35,26 +72,46 @@ fn interpolate(sx: i32, sy: i32, sx0: i32, sy0: i32, width_u: i32, height_u: i32
             var xsum = vec4<f32>(0.0, 0.0, 0.0, 0.0);
             for (var xp: i32 = 0; xp < i32(params.interpolation); xp = xp + 1) {
                 var pixel: vec4<f32>;
-                if (sx + xp >= 0 && sx + xp < width_u) {
-                    pixel = vec4<f32>(textureLoad(input, vec2<i32>(sx + xp, sy + yp), 0));
+                var tx = sx + xp;
+                if (edge_wrap) { tx = ((tx % width_u) + width_u) % width_u; } // Taps across the seam fetch from the other side
+                if (tx >= 0 && tx < width_u) {
+                    pixel = vec4<f32>(textureLoad(input, vec2<i32>(tx, sy + yp), 0));
+                    if (straight_alpha) { pixel = vec4<f32>(pixel.xyz * pixel.w, pixel.w); }
                 } else {
                     pixel = bg;
                 }
+                box_sum = box_sum + pixel;
+                lo = min(lo, pixel);
+                hi = max(hi, pixel);
                 xsum = xsum + (pixel * coeffs[coeffs_x + xp]);
             }
             sum = sum + xsum * coeffs[coeffs_y + yp];
         } else {
+            box_sum = box_sum + bg * f32(params.interpolation);
+            lo = min(lo, bg);
+            hi = max(hi, bg);
             sum = sum + bg * coeffs[coeffs_y + yp];
         }
     }
+    if (sharpen > 0.0) {
+        // Add back the difference to the box average, clamped to the tap range to avoid halos
+        let mean = box_sum.xyz / f32(params.interpolation * params.interpolation);
+        sum = vec4<f32>(clamp(sum.xyz + (sum.xyz - mean) * sharpen, lo.xyz, hi.xyz), sum.w);
+    }
+    if (straight_alpha && abs(sum.w) > 1e-6) {
+        sum = vec4<f32>(sum.xyz / sum.w, sum.w);
+    }
     
-    return vec4<SCALAR>(sum);
+    return sum;
 }
 
 fn undistort_point(pos: vec2<f32>, f: vec2<f32>, c: vec2<f32>, k: vec4<f32>, amount: f32) -> vec2<f32> {
     let pos = (pos - c) / f;
 
-    let theta_d = min(max(length(pos), -1.5707963267948966), 1.5707963267948966); // PI/2
+    // Limit comes from the lens FOV when known, PI/2 otherwise. Larger values may not converge, see cpu_undistort.rs
+    var max_theta_d = 1.5707963267948966; // PI/2
+    if (undistortion_params[22] > 0.0) { max_theta_d = undistortion_params[22]; }
+    let theta_d = min(max(length(pos), -max_theta_d), max_theta_d);
 
     var converged = false;
     var theta = theta_d;
@@ -62,7 +119,7 @@ fn undistort_point(pos: vec2<f32>, f: vec2<f32>, c: vec2<f32>, k: vec4<f32>, amo
     var scale = 0.0;
 
     if (abs(theta_d) > 1e-6) {
-        for (var i: i32 = 0; i < 10; i = i + 1) {
+        for (var i: i32 = 0; i < NEWTON_ITERATIONS; i = i + 1) {
             let theta2 = theta*theta;
             let theta4 = theta2*theta2;
             let theta6 = theta4*theta2;
@@ -88,10 +145,18 @@ fn undistort_point(pos: vec2<f32>, f: vec2<f32>, c: vec2<f32>, k: vec4<f32>, amo
         converged = true;
     }
     let theta_flipped = (theta_d < 0.0 && theta > 0.0) || (theta_d > 0.0 && theta < 0.0);
+    let behind_camera = abs(theta) >= 1.5707963267948966;
+
+    if (converged && !theta_flipped && !behind_camera) {
+        // Apply only requested amount, clamped to avoid mirroring with negative amounts
+        scale = max(1.0 + (scale - 1.0) * (1.0 - amount), 0.0);
 
-    if (converged && !theta_flipped) {
-        // Apply only requested amount
-        scale = 1.0 + (scale - 1.0) * (1.0 - amount);
+        return f * pos * scale + c;
+    }
+    if ((theta_flipped || behind_camera) && undistortion_params[19] > 0.5) {
+        // Super fisheye: use the pre-flip estimate, clamped below PI/2 to keep tan() finite
+        scale = tan(min(theta_d, 1.55)) / theta_d;
+        scale = max(1.0 + (scale - 1.0) * (1.0 - amount), 0.0);
 
         return f * pos * scale + c;
     }
@@ -127,12 +192,213 @@ fn rotate_and_distort(pos: vec2<f32>, idx: u32, f: vec2<f32>, c: vec2<f32>, k: v
         if (r_limit > 0.0 && r > r_limit) {
             return vec2<f32>(-99999.0, -99999.0);
         }
+        if (undistortion_params[23] > 0.5) {
+            // Rotation only preview, plain pinhole projection
+            return f * pos + c;
+        }
         return distort_point(pos, f, c, k);
     }
     return vec2<f32>(-99999.0, -99999.0);
 }
 
 
+// Converts sensor coordinates to the coordinates in the stored (rotated) input buffer
+fn to_buffer(pt: vec2<f32>) -> vec2<f32> {
+    let orientation = i32(undistortion_params[14]);
+    let w = undistortion_params[15];
+    let h = undistortion_params[16];
+    if (orientation == 1) { return vec2<f32>(h - 1.0 - pt.y, pt.x); }
+    if (orientation == 2) { return vec2<f32>(w - 1.0 - pt.x, h - 1.0 - pt.y); }
+    if (orientation == 3) { return vec2<f32>(pt.y, w - 1.0 - pt.x); }
+    return pt;
+}
+
+// Maps output position to the source position, returns -99999 if outside of the lens model
+fn map_coord(pos: vec2<f32>, sy: u32) -> vec2<f32> {
+    if (params.stmap_enabled != 0u) {
+        // Nearest output pixel, the map already contains buffer positions
+        let p = clamp(vec2<i32>(round(pos)), vec2<i32>(0), vec2<i32>(i32(params.output_width) - 1, i32(params.output_height) - 1));
+        return stmap[u32(p.y) * params.output_width + u32(p.x)];
+    }
+    let f = vec2<f32>(undistortion_params[0], undistortion_params[1]);
+    let c = vec2<f32>(undistortion_params[2], undistortion_params[3]);
+    let k = vec4<f32>(undistortion_params[4], undistortion_params[5], undistortion_params[6], undistortion_params[7]);
+    let r_limit = undistortion_params[8];
+    let lens_correction_amount = undistortion_params[9];
+    let fov = undistortion_params[11];
+    var pixel_aspect = undistortion_params[12];
+    if (pixel_aspect <= 0.0) { pixel_aspect = 1.0; }
+
+    var texPos = pos;
+    if (lens_correction_amount < 1.0 && undistortion_params[23] < 0.5) {
+        // Add lens distortion back
+        let factor = max(1.0 - lens_correction_amount, 0.001); // FIXME: this is close but wrong
+        let f2 = (f / fov) / factor * vec2<f32>(pixel_aspect, 1.0);
+        let out_c = vec2<f32>(f32(params.output_width) / 2.0, f32(params.output_height) / 2.0);
+        texPos = undistort_point(texPos, f2, out_c, k, lens_correction_amount);
+    }
+
+    let idx: u32 = min((sy + 3u), (params.params_count - 1u)) * 9u;
+
+    let uv = rotate_and_distort(texPos, idx, f, c, k, r_limit);
+    if (uv.x > -99998.0) {
+        return to_buffer(uv);
+    }
+    return uv;
+}
+
+fn sample_input(uv_in: vec2<f32>) -> vec4<f32> {
+    let background_mode = undistortion_params[10];
+    let edge_repeat = background_mode > 0.9 && background_mode < 1.1; // 1
+    let edge_mirror = background_mode > 1.9 && background_mode < 2.1; // 2
+    let edge_wrap = background_mode > 3.9 && background_mode < 4.1; // 4
+
+    var uv = uv_in;
+    let width_f = f32(params.width);
+    let height_f = f32(params.height);
+    if (edge_wrap) {
+        uv = vec2<f32>(uv.x - floor(uv.x / width_f) * width_f, max(0.0, min(height_f - 1.0, uv.y)));
+    } else if (edge_repeat) {
+        uv = max(vec2<f32>(0.0, 0.0), min(vec2<f32>(width_f - 1.0, height_f - 1.0), uv));
+    } else if (edge_mirror) {
+        let rx = round(uv.x);
+        let ry = round(uv.y);
+        let width3 = (width_f - 3.0);
+        let height3 = (height_f - 3.0);
+        if (rx > width3)  { uv.x = width3  - (rx - width3); }
+        if (rx < 3.0)     { uv.x = 3.0 + width_f - (width3 + rx); }
+        if (ry > height3) { uv.y = height3 - (ry - height3); }
+        if (ry < 3.0)     { uv.y = 3.0 + height_f - (height3 + ry); }
+    }
+
+    if (params.input_scale > 1.0) {
+        // Input texture was box-downsampled on upload
+        uv = (uv - (params.input_scale - 1.0) / 2.0) / params.input_scale;
+    }
+    let tex_size = textureDimensions(input);
+    // FAST_PREVIEW_SAMPLE
+
+    let sx0 = i32(round(uv.x * f32(INTER_TAB_SIZE)));
+    let sy0 = i32(round(uv.y * f32(INTER_TAB_SIZE)));
+
+    let sx = i32(sx0 >> INTER_BITS);
+    let sy = i32(sy0 >> INTER_BITS);
+
+    return interpolate(sx, sy, sx0, sy0, tex_size.x, tex_size.y);
+}
+
+// Lateral chromatic aberration correction, R, G and B are sampled at their own magnification around the lens center
+fn sample_input_ca(uv: vec2<f32>) -> vec4<f32> {
+    var pix = sample_input(uv);
+    if (params.ca_scale.w < 0.5) { return pix; }
+    let c = to_buffer(vec2<f32>(undistortion_params[2], undistortion_params[3]));
+    if (params.ca_scale.x != 1.0) { pix.x = sample_input(c + (uv - c) * params.ca_scale.x).x; }
+    if (params.ca_scale.y != 1.0) { pix.y = sample_input(c + (uv - c) * params.ca_scale.y).y; }
+    if (params.ca_scale.z != 1.0) { pix.z = sample_input(c + (uv - c) * params.ca_scale.z).z; }
+    return pix;
+}
+
+// Samples the source at the output position without any rotation or lens correction, for background mode 3
+fn sample_passthrough(pos: vec2<f32>) -> vec4<f32> {
+    let uv = vec2<f32>(
+        pos.x * f32(params.width)  / max(f32(params.output_width),  1.0),
+        pos.y * f32(params.height) / max(f32(params.output_height), 1.0)
+    );
+    return sample_input(max(vec2<f32>(0.0, 0.0), min(vec2<f32>(f32(params.width) - 1.0, f32(params.height) - 1.0), uv)));
+}
+
+// Background at the output position, same as `FrameParams::background_at`
+fn background_at(pos: vec2<f32>) -> vec4<f32> {
+    if (params.bg_regions > 0u) {
+        let mask_size = textureDimensions(bg_mask);
+        let out_size = max(vec2<f32>(f32(params.output_width), f32(params.output_height)), vec2<f32>(1.0));
+        let mask_pos = clamp(vec2<i32>((pos + 0.5) / out_size * vec2<f32>(mask_size)), vec2<i32>(0), mask_size - vec2<i32>(1));
+        let index = textureLoad(bg_mask, mask_pos, 0).r;
+        if (index < params.bg_regions) {
+            return params.bg_palette[index];
+        }
+    }
+    if (params.bg_gradient == 0u) {
+        return vec4<f32>(params.background[0], params.background[1], params.background[2], params.background[3]);
+    }
+    let center = vec2<f32>(f32(params.output_width), f32(params.output_height)) / 2.0;
+    let t = min(length(pos - center) / max(length(center), 1e-6), 1.0);
+    return mix(params.bg_center, params.bg_edge, t);
+}
+
+// 1.0 if the sample is taken from the input, including the edge repeat/mirror/wrap modes. Background and passthrough are 0.0
+fn sample_coverage(uv: vec2<f32>) -> f32 {
+    if (uv.x < -99998.0) { return 0.0; }
+    let background_mode = undistortion_params[10];
+    let edge_mode = (background_mode > 0.9 && background_mode < 2.1) || (background_mode > 3.9 && background_mode < 4.1); // 1, 2, 4
+    if (edge_mode || (uv.x >= 0.0 && uv.y >= 0.0 && uv.x <= f32(params.width) - 1.0 && uv.y <= f32(params.height) - 1.0)) {
+        return 1.0;
+    }
+    return 0.0;
+}
+
+// Pixel is on the boundary of the valid area, or its source footprint is large
+fn needs_supersampling(pos: vec2<f32>, sy: u32) -> bool {
+    let c0 = map_coord(pos + vec2<f32>(-0.5, -0.5), sy);
+    let c1 = map_coord(pos + vec2<f32>( 0.5, -0.5), sy);
+    let c2 = map_coord(pos + vec2<f32>(-0.5,  0.5), sy);
+    let c3 = map_coord(pos + vec2<f32>( 0.5,  0.5), sy);
+    let valid0 = c0.x > -99998.0;
+    if (valid0 != (c1.x > -99998.0) || valid0 != (c2.x > -99998.0) || valid0 != (c3.x > -99998.0)) {
+        return true;
+    }
+    if (!valid0) {
+        return false;
+    }
+    return max(length(c3 - c0), length(c2 - c1)) > SUPERSAMPLE_THRESHOLD;
+}
+
+fn color_to_linear(v: vec3<f32>) -> vec3<f32> {
+    let x = max(v, vec3<f32>(0.0));
+    if (params.color_mode == 2u) {
+        return select(pow((x + 0.099) / 1.099, vec3<f32>(1.0 / 0.45)), x / 4.5, x < vec3<f32>(0.081));
+    }
+    if (params.color_mode == 3u) {
+        return select(pow((x + 0.055) / 1.055, vec3<f32>(2.4)), x / 12.92, x <= vec3<f32>(0.04045));
+    }
+    return x;
+}
+fn color_from_linear(v: vec3<f32>) -> vec3<f32> {
+    let x = max(v, vec3<f32>(0.0));
+    if (params.color_mode == 2u) {
+        return select(1.099 * pow(x, vec3<f32>(0.45)) - 0.099, x * 4.5, x < vec3<f32>(0.018));
+    }
+    if (params.color_mode == 3u) {
+        return select(1.055 * pow(x, vec3<f32>(1.0 / 2.4)) - 0.055, x * 12.92, x <= vec3<f32>(0.0031308));
+    }
+    return x;
+}
+// Trilinear lookup in the 3D LUT, input is clamped to [0, 1]. Same as `ColorLut::apply`
+fn apply_lut(v: vec4<f32>) -> vec4<f32> {
+    let n = i32(params.lut_size);
+    if (n < 2) { return v; }
+    let s = max(params.lut_scale, 1e-6);
+    let p = clamp(v.xyz / s, vec3<f32>(0.0), vec3<f32>(1.0)) * f32(n - 1);
+    let i = min(vec3<i32>(p), vec3<i32>(n - 2));
+    let t = p - vec3<f32>(i);
+
+    let c00 = mix(textureLoad(color_lut, i,                          0).xyz, textureLoad(color_lut, i + vec3<i32>(1, 0, 0), 0).xyz, t.x);
+    let c10 = mix(textureLoad(color_lut, i + vec3<i32>(0, 1, 0),     0).xyz, textureLoad(color_lut, i + vec3<i32>(1, 1, 0), 0).xyz, t.x);
+    let c01 = mix(textureLoad(color_lut, i + vec3<i32>(0, 0, 1),     0).xyz, textureLoad(color_lut, i + vec3<i32>(1, 0, 1), 0).xyz, t.x);
+    let c11 = mix(textureLoad(color_lut, i + vec3<i32>(0, 1, 1),     0).xyz, textureLoad(color_lut, i + vec3<i32>(1, 1, 1), 0).xyz, t.x);
+    let c = mix(mix(c00, c10, t.y), mix(c01, c11, t.y), t.z);
+    return vec4<f32>(c * s, v.w);
+}
+
+// Output color space conversion followed by the LUT, alpha passes through. Same as `ColorTransform::apply`
+fn apply_color(v: vec4<f32>) -> vec4<f32> {
+    if (params.color_mode == 0u) { return apply_lut(v); }
+    let s = max(params.color_scale, 1e-6);
+    let lin = color_to_linear(v.xyz / s);
+    let res = vec3<f32>(dot(params.color_r.xyz, lin), dot(params.color_g.xyz, lin), dot(params.color_b.xyz, lin));
+    return apply_lut(vec4<f32>(color_from_linear(res) * s, v.w));
+}
+
 @stage(vertex)
 fn undistort_vertex(@builtin(vertex_index) in_vertex_index: u32) -> @builtin(position) vec4<f32> {
     var positions: array<vec2<f32>, 6> = array<vec2<f32>, 6>(
@@ -145,77 +411,85 @@ fn undistort_vertex(@builtin(vertex_index) in_vertex_index: u32) -> @builtin(pos
 // Adapted from OpenCV: initUndistortRectifyMap + remap 
 // https://github.com/opencv/opencv/blob/4.x/modules/calib3d/src/fisheye.cpp#L454
 // https://github.com/opencv/opencv/blob/4.x/modules/imgproc/src/opencl/remap.cl#L390
-@stage(fragment)
-fn undistort_fragment(@builtin(position) position: vec4<f32>) -> @location(0) vec4<SCALAR> {
+fn undistort_color(position: vec4<f32>) -> vec4<SCALAR> {
     let gx = i32(position.x);
     let gy = i32(position.y);
 
-    let width = params.width;
-    let height = params.height;
     let params_count = params.params_count;
-    let bg = vec4<SCALAR>(SCALAR(params.background[0]), SCALAR(params.background[1]), SCALAR(params.background[2]), SCALAR(params.background[3]));
 
-    var texPos = vec2<f32>(f32(gx), f32(gy));
-
-    let width_u = i32(width);
-    let height_u = i32(height);
+    // Pixel centers of a scaled output are mapped to pixel centers of the params output space
+    let texPos = (vec2<f32>(f32(gx), f32(gy)) + 0.5) * vec2<f32>(params.output_scale_x, params.output_scale_y) - 0.5;
 
     let f = vec2<f32>(undistortion_params[0], undistortion_params[1]);
     let c = vec2<f32>(undistortion_params[2], undistortion_params[3]);
     let k = vec4<f32>(undistortion_params[4], undistortion_params[5], undistortion_params[6], undistortion_params[7]);
     let r_limit = undistortion_params[8];
-    let lens_correction_amount = undistortion_params[9];
+    let supersample = u32(max(undistortion_params[13], 1.0));
     let background_mode = undistortion_params[10];
-    let fov = undistortion_params[11];
-    let edge_repeat = background_mode > 0.9 && background_mode < 1.1; // 1
-    let edge_mirror = background_mode > 1.9 && background_mode < 2.1; // 2
+    let passthrough = background_mode > 2.9 && background_mode < 3.1; // 3
 
     ///////////////////////////////////////////////////////////////////
     // Calculate source `y` for rolling shutter
     var sy = u32(gy);
-    if (params_count > 3u) {
-        let idx: u32 = 2u + ((params_count - 2u) / 2u) * 9u; // Use middle matrix
+    if (params_count > 4u) {
+        let idx: u32 = (3u + ((params_count - 3u) / 2u)) * 9u; // Use middle matrix
         let uv = rotate_and_distort(texPos, idx, f, c, k, r_limit);
         if (uv.x > -99998.0) {
-            sy = u32(min(height_u, max(0, i32(floor(0.5 + uv.y)))));
+            var sensor_height = i32(params.height);
+            if (undistortion_params[16] > 0.0) { sensor_height = i32(undistortion_params[16]); }
+            sy = u32(min(sensor_height, max(0, i32(floor(0.5 + uv.y)))));
         }
     }
     ///////////////////////////////////////////////////////////////////
- 
-    if (lens_correction_amount < 1.0) {
-        // Add lens distortion back
-        let factor = max(1.0 - lens_correction_amount, 0.001); // FIXME: this is close but wrong
-        let out_c = vec2<f32>(f32(params.output_width) / 2.0, f32(params.output_height) / 2.0);
-        texPos = undistort_point(texPos, (f / fov) / factor, out_c, k, lens_correction_amount);
-    }
 
-    let idx: u32 = min((sy + 2u), (params_count - 1u)) * 9u;
- 
-    var uv = rotate_and_distort(texPos, idx, f, c, k, r_limit);
+    if (supersample > 1u && needs_supersampling(texPos, sy)) {
+        var offsets: array<vec2<f32>, 4> = array<vec2<f32>, 4>(
+            vec2<f32>(-0.25, -0.25), vec2<f32>(0.25, 0.25), vec2<f32>(0.25, -0.25), vec2<f32>(-0.25, 0.25)
+        );
+        let count = min(supersample, 4u);
+        var sum = vec4<f32>(0.0);
+        coverage = 0.0;
+        for (var i: u32 = 0u; i < count; i = i + 1u) {
+            // Evaluated per sample, so the edge blends into the local gradient color
+            sample_bg = background_at(texPos + offsets[i]);
+            let uv = map_coord(texPos + offsets[i], sy);
+            coverage = coverage + sample_coverage(uv) / f32(count);
+            if (uv.x > -99998.0) {
+                sum = sum + sample_input_ca(uv);
+            } else if (passthrough) {
+                sum = sum + sample_passthrough(texPos + offsets[i]);
+            } else {
+                sum = sum + sample_bg;
+            }
+        }
+        return vec4<SCALAR>(apply_color(sum / f32(count)));
+    }
 
+    sample_bg = background_at(texPos);
+    let uv = map_coord(texPos, sy);
+    coverage = sample_coverage(uv);
     if (uv.x > -99998.0) {
-        let width_f = f32(width);
-        let height_f = f32(height);
-        if (edge_repeat) {
-            uv = max(vec2<f32>(0.0, 0.0), min(vec2<f32>(width_f - 1.0, height_f - 1.0), uv));
-        } else if (edge_mirror) {
-            let rx = round(uv.x);
-            let ry = round(uv.y);
-            let width3 = (width_f - 3.0);
-            let height3 = (height_f - 3.0);
-            if (rx > width3)  { uv.x = width3  - (rx - width3); }
-            if (rx < 3.0)     { uv.x = 3.0 + width_f - (width3 + rx); }
-            if (ry > height3) { uv.y = height3 - (ry - height3); }
-            if (ry < 3.0)     { uv.y = 3.0 + height_f - (height3 + ry); }
-        }
+        return vec4<SCALAR>(apply_color(sample_input_ca(uv)));
+    }
+    if (passthrough) {
+        return vec4<SCALAR>(apply_color(sample_passthrough(texPos)));
+    }
+    return vec4<SCALAR>(apply_color(sample_bg));
+}
 
-        let sx0 = i32(round(uv.x * f32(INTER_TAB_SIZE)));
-        let sy0 = i32(round(uv.y * f32(INTER_TAB_SIZE)));
+@stage(fragment)
+fn undistort_fragment(@builtin(position) position: vec4<f32>) -> @location(0) vec4<SCALAR> {
+    return undistort_color(position);
+}
 
-        let sx = i32(sx0 >> INTER_BITS);
-        let sy = i32(sy0 >> INTER_BITS);
+struct CoverageOutput {
+    @location(0) color: vec4<SCALAR>;
+    @location(1) coverage: vec4<f32>;
+};
 
-        return interpolate(sx, sy, sx0, sy0, width_u, height_u);
-    }
-    return bg;
+// Entry point of the pipelines of a `WgpuWrapper` built with `coverage`, the mask goes to the R8Unorm second target
+@stage(fragment)
+fn undistort_fragment_coverage(@builtin(position) position: vec4<f32>) -> CoverageOutput {
+    let color = undistort_color(position);
+    return CoverageOutput(color, vec4<f32>(coverage, 0.0, 0.0, 1.0));
 }
@@ -0,0 +1,115 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
+
+// Compute shader version of `undistort_points`, same math as `undistort_point` and `distort_point` in wgpu_undistort.wgsl
+
+struct PointsGlobals {
+    count: u32;
+    rotations_count: u32; // 1 - shared matrix, otherwise one per point
+    super_fisheye: u32;
+    lens_correction_amount: f32;
+    f: vec2<f32>;
+    c: vec2<f32>;
+    k: vec4<f32>;
+};
+
+@group(0) @binding(0) var<uniform> globals: PointsGlobals;
+@group(0) @binding(1) var<storage, read> points: array<vec2<f32>>;
+@group(0) @binding(2) var<storage, read> rotations: array<f32>; // 3x3 row-major
+@group(0) @binding(3) var<storage, read_write> result: array<vec2<f32>>;
+
+let NOT_CONVERGED: f32 = -1000000.0;
+
+// Returns undistorted normalized point, or NOT_CONVERGED
+fn undistort_point(pos: vec2<f32>, k: vec4<f32>) -> vec2<f32> {
+    let theta_d = min(max(length(pos), -1.5707963267948966), 1.5707963267948966); // PI/2
+
+    var converged = false;
+    var theta = theta_d;
+
+    var scale = 1.0;
+
+    if (abs(theta_d) > 1e-6) {
+        for (var i: i32 = 0; i < 10; i = i + 1) {
+            let theta2 = theta*theta;
+            let theta4 = theta2*theta2;
+            let theta6 = theta4*theta2;
+            let theta8 = theta6*theta2;
+            let k0_theta2 = k.x * theta2;
+            let k1_theta4 = k.y * theta4;
+            let k2_theta6 = k.z * theta6;
+            let k3_theta8 = k.w * theta8;
+            // new_theta = theta - theta_fix, theta_fix = f0(theta) / f0'(theta)
+            let theta_fix = (theta * (1.0 + k0_theta2 + k1_theta4 + k2_theta6 + k3_theta8) - theta_d)
+                            /
+                            (1.0 + 3.0 * k0_theta2 + 5.0 * k1_theta4 + 7.0 * k2_theta6 + 9.0 * k3_theta8);
+
+            theta -= theta_fix;
+            if (abs(theta_fix) < 1e-6) {
+                converged = true;
+                break;
+            }
+        }
+
+        scale = tan(theta) / theta_d;
+    } else {
+        converged = true;
+    }
+    let theta_flipped = (theta_d < 0.0 && theta > 0.0) || (theta_d > 0.0 && theta < 0.0);
+
+    if (converged && !theta_flipped) {
+        return pos * scale;
+    }
+    if (theta_flipped && globals.super_fisheye > 0u) {
+        // Super fisheye: use the pre-flip estimate, clamped below PI/2 to keep tan() finite
+        return pos * (tan(min(theta_d, 1.55)) / theta_d);
+    }
+    return vec2<f32>(NOT_CONVERGED, NOT_CONVERGED);
+}
+
+fn distort_point(pos: vec2<f32>, f: vec2<f32>, c: vec2<f32>, k: vec4<f32>, amount: f32) -> vec2<f32> {
+    let r = length(pos);
+
+    let theta = atan(r);
+    let theta2 = theta*theta;
+    let theta4 = theta2*theta2;
+    let theta6 = theta4*theta2;
+    let theta8 = theta4*theta4;
+
+    let theta_d = theta * (1.0 + dot(k, vec4<f32>(theta2, theta4, theta6, theta8)));
+
+    var scale: f32 = 1.0;
+    if (r != 0.0) {
+        scale = theta_d / r;
+    }
+    scale = max(1.0 + (scale - 1.0) * (1.0 - amount), 0.0);
+    return f * pos * scale + c;
+}
+
+@stage(compute) @workgroup_size(64)
+fn undistort_points(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.x;
+    if (i >= globals.count) { return; }
+
+    let f = globals.f;
+    let c = globals.c;
+
+    let pt = undistort_point((points[i] - c) / f, globals.k);
+    if (pt.x <= NOT_CONVERGED) {
+        result[i] = pt;
+        return;
+    }
+
+    // Reproject
+    var idx = 0u;
+    if (globals.rotations_count > 1u) { idx = min(i, globals.rotations_count - 1u) * 9u; }
+    let x = rotations[idx + 0u] * pt.x + rotations[idx + 1u] * pt.y + rotations[idx + 2u];
+    let y = rotations[idx + 3u] * pt.x + rotations[idx + 4u] * pt.y + rotations[idx + 5u];
+    let w = rotations[idx + 6u] * pt.x + rotations[idx + 7u] * pt.y + rotations[idx + 8u];
+    var res = vec2<f32>(x, y) / w;
+
+    if (globals.lens_correction_amount < 1.0) {
+        res = distort_point((res - c) / f, f, c, globals.k, globals.lens_correction_amount);
+    }
+    result[i] = res;
+}
@@ -0,0 +1,66 @@
+#ifndef GYROFLOW_CORE_H
+#define GYROFLOW_CORE_H
+
+/* Generated with cbindgen, do not edit manually */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#if (defined(GYROFLOW_CAPI) && defined(GYROFLOW_GPU))
+typedef struct GyroflowWgpu GyroflowWgpu;
+#endif
+
+#if defined(GYROFLOW_CAPI)
+// Undistorts one RGBA8 frame on the CPU. Returns false if any of the pointers is null or the parameters are invalid.
+bool gyroflow_undistort_image_cpu_rgba8(const uint8_t *pixels,
+                                        uintptr_t width,
+                                        uintptr_t height,
+                                        uintptr_t stride,
+                                        uint8_t *out_pixels,
+                                        uintptr_t output_width,
+                                        uintptr_t output_height,
+                                        uintptr_t output_stride,
+                                        const float *params,
+                                        uintptr_t params_count,
+                                        const float *bg,
+                                        uint32_t interpolation);
+#endif
+
+#if (defined(GYROFLOW_CAPI) && defined(GYROFLOW_GPU))
+// Creates the wgpu context for RGBA8 frames of the given sizes. Returns null on failure.
+GyroflowWgpu *gyroflow_wgpu_create(uintptr_t width,
+                                   uintptr_t height,
+                                   uintptr_t stride,
+                                   uintptr_t output_width,
+                                   uintptr_t output_height,
+                                   uintptr_t output_stride,
+                                   const float *bg,
+                                   uint32_t interpolation);
+#endif
+
+#if (defined(GYROFLOW_CAPI) && defined(GYROFLOW_GPU))
+// 0 - none, 1 - crc32, 2 - perceptual
+void gyroflow_wgpu_set_verify(GyroflowWgpu *handle, int32_t mode);
+#endif
+
+#if (defined(GYROFLOW_CAPI) && defined(GYROFLOW_GPU))
+// Undistorts one frame. Buffer sizes must be `stride * height` and `output_stride * output_height` of the ones passed to `gyroflow_wgpu_create`.
+// Returns false if any of the pointers is null, the sizes or the parameters are invalid, or the render failed.
+// `out_hash` (can be null) receives the output hash when verification is enabled, 0 otherwise.
+bool gyroflow_wgpu_undistort(GyroflowWgpu *handle,
+                             const uint8_t *pixels,
+                             uintptr_t pixels_len,
+                             uint8_t *out_pixels,
+                             uintptr_t out_pixels_len,
+                             const float *params,
+                             uintptr_t params_count,
+                             uint64_t *out_hash);
+#endif
+
+#if (defined(GYROFLOW_CAPI) && defined(GYROFLOW_GPU))
+void gyroflow_wgpu_free(GyroflowWgpu *handle);
+#endif
+
+#endif  /* GYROFLOW_CORE_H */
@@ -38,6 +38,9 @@ pub struct LensProfile {
     pub gyro_lpf: Option<f64>,
 
     pub input_horizontal_stretch: f64,
+
+    // Full diagonal field of view of the lens in degrees, used to limit the undistortion of circular fisheye beyond 180°
+    pub max_fov: Option<f64>,
     pub num_images: usize,
 
     pub fps: f64,
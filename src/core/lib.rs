@@ -20,6 +20,10 @@ pub mod gpu;
 
 pub mod util;
 pub mod stabilization_params;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 
 use std::{sync::Arc, collections::BTreeMap};
 use std::sync::atomic::AtomicU64;
@@ -187,7 +191,7 @@ impl<T: PixelType> StabilizationManager<T> {
     fn init_size(&self) {
         let (w, h, ow, oh, bg) = {
             let params = self.params.read();
-            (params.size.0, params.size.1, params.output_size.0, params.output_size.1, params.background)
+            (params.size.0, params.size.1, params.output_size.0, params.output_size.1, params.effective_background())
         };
 
         let s = w * T::COUNT * T::SCALAR_BYTES;
@@ -417,7 +421,7 @@ impl<T: PixelType> StabilizationManager<T> {
             let frame = frame_at_timestamp(timestamp_us as f64 / 1000.0, fps) as usize; // used only to draw features and OF
             //////////////////////////// Draw detected features ////////////////////////////
             // TODO: maybe handle other types than RGBA8?
-            if T::COUNT == 4 && T::SCALAR_BYTES == 1 {
+            if T::COUNT == 4 && T::SCALAR_BYTES == 1 && !T::PACKED {
                 if let Some(pxs) = self.get_features_pixels(frame) {
                     for (x, mut y, _) in pxs {
                         if framebuffer_inverted { y = height as i32 - y; }
@@ -486,6 +490,23 @@ impl<T: PixelType> StabilizationManager<T> {
                     }
                 }
                 //////////////////////////// Draw zooming debug pixels ////////////////////////////
+
+                //////////////////////////// Draw safe zone ////////////////////////////
+                if p.show_safe_zone && T::COUNT == 4 && T::SCALAR_BYTES == 1 && !T::PACKED {
+                    if let Some(itm) = undist.get_undistortion_data(timestamp_us) {
+                        for (x, y) in undistortion::coverage_contour(&itm.params, width, height, out_width, out_height) {
+                            let (x, y) = (x.round(), y.round());
+                            if x < 0.0 || y < 0.0 || x >= out_width as f32 || y >= out_height as f32 { continue; }
+                            let pos = y as usize * out_stride + x as usize * (T::COUNT * T::SCALAR_BYTES);
+                            if out_pixels.len() > pos + 2 {
+                                out_pixels[pos + 0] = 0x00; // R
+                                out_pixels[pos + 1] = 0xd0; // G
+                                out_pixels[pos + 2] = 0xff; // B
+                            }
+                        }
+                    }
+                }
+                //////////////////////////// Draw safe zone ////////////////////////////
             }
             self.current_fov_10000.store((undist.current_fov * 10000.0) as u64, SeqCst);
             ret
@@ -495,18 +516,46 @@ impl<T: PixelType> StabilizationManager<T> {
     }
 
     pub fn set_video_rotation(&self, v: f64) { self.params.write().video_rotation = v; }
+    pub fn set_pixel_aspect_ratio(&self, v: f64) { self.params.write().pixel_aspect_ratio = v; self.invalidate_zooming(); }
 
     pub fn set_trim_start(&self, v: f64) { self.params.write().trim_start = v; self.invalidate_smoothing(); }
     pub fn set_trim_end  (&self, v: f64) { self.params.write().trim_end   = v; self.invalidate_smoothing(); }
 
     pub fn set_show_detected_features(&self, v: bool) { self.params.write().show_detected_features = v; }
     pub fn set_show_optical_flow     (&self, v: bool) { self.params.write().show_optical_flow      = v; }
+    pub fn set_show_rolling_shutter  (&self, v: bool) { self.params.write().show_rolling_shutter   = v; }
+    pub fn set_show_safe_zone        (&self, v: bool) { self.params.write().show_safe_zone         = v; }
     pub fn set_stab_enabled          (&self, v: bool) { self.params.write().stab_enabled           = v; }
     pub fn set_frame_readout_time    (&self, v: f64)  { self.params.write().frame_readout_time     = v; }
     pub fn set_adaptive_zoom         (&self, v: f64)  { self.params.write().adaptive_zoom_window   = v; }
     pub fn set_fov                   (&self, v: f64)  { self.params.write().fov                    = v; }
-    pub fn set_lens_correction_amount(&self, v: f64)  { self.params.write().lens_correction_amount = v; self.invalidate_zooming(); }
+    pub fn set_fov_keyframes         (&self, mut v: Vec<(f64, f64)>) { v.sort_by(|a, b| a.0.total_cmp(&b.0)); self.params.write().fov_keyframes = v; }
+    pub fn set_breathing_curve       (&self, mut v: Vec<(f64, f64)>) { v.sort_by(|a, b| a.0.total_cmp(&b.0)); self.params.write().breathing_curve = v; }
+    pub fn set_distortion_keyframes  (&self, mut v: Vec<(f64, [f64; 4])>) { v.sort_by(|a, b| a.0.total_cmp(&b.0)); self.params.write().distortion_keyframes = v; self.invalidate_zooming(); }
+    pub fn set_rotation_quaternions  (&self, v: Option<Arc<gyro_source::TimeQuat>>) { self.params.write().rotation_quats = v.filter(|x| !x.is_empty()); self.invalidate_zooming(); }
+    pub fn set_lens_correction_amount(&self, v: f64)  { self.params.write().lens_correction_amount = if v.is_finite() { v.max(-1.0).min(1.0) } else { 1.0 }; self.invalidate_zooming(); }
     pub fn set_background_mode       (&self, v: i32)  { self.params.write().background_mode = stabilization_params::BackgroundMode::from(v); }
+    pub fn set_supersample           (&self, v: i32)  { self.params.write().supersample = match v { 2 | 4 => v as u8, _ => 1 }; }
+    pub fn set_coordinate_convention (&self, v: i32)  { self.params.write().coordinate_convention = stabilization_params::CoordinateConvention::from(v); self.invalidate_zooming(); }
+    pub fn set_readout_direction     (&self, v: i32)  { self.params.write().readout_direction = stabilization_params::ReadoutDirection::from(v); self.invalidate_zooming(); }
+    pub fn set_input_alpha_mode      (&self, v: i32)  { self.params.write().input_alpha_mode = stabilization_params::InputAlphaMode::from(v); }
+    pub fn set_input_orientation     (&self, v: i32)  { self.params.write().input_orientation = stabilization_params::Rotation90::from(v); self.invalidate_zooming(); }
+    pub fn set_aspect_fit            (&self, v: i32)  { self.params.write().aspect_fit = stabilization_params::AspectFit::from(v); self.invalidate_zooming(); }
+    pub fn set_interlacing           (&self, v: i32)  { self.params.write().interlacing = stabilization_params::Interlacing::from(v); }
+    pub fn set_identity_threshold    (&self, v: f64)  { self.params.write().identity_threshold = v as f32; }
+    pub fn set_dither                (&self, v: bool) { self.params.write().dither = v; }
+    pub fn set_super_fisheye         (&self, v: bool) { self.params.write().super_fisheye = v; self.invalidate_zooming(); }
+    pub fn set_distortion_enabled    (&self, v: bool) { self.params.write().distortion_enabled = v; }
+    pub fn set_sharpen_amount        (&self, v: f64)  { self.params.write().sharpen_amount = if v.is_finite() { v.max(0.0) as f32 } else { 0.0 }; }
+    pub fn set_kernel_radius         (&self, v: f64)  { self.params.write().kernel_radius = if v.is_finite() { v.max(0.0) as f32 } else { 0.0 }; }
+    pub fn set_defect_mask           (&self, v: Option<Arc<Vec<bool>>>) { self.params.write().defect_mask = v.filter(|x| x.iter().any(|d| *d)); }
+    pub fn set_vignette_gain         (&self, v: Option<Vec<f32>>) { self.params.write().vignette_gain = v.filter(|x| x.len() > 1); }
+    pub fn set_color_transform       (&self, v: Option<undistortion::ColorTransform>) { self.params.write().color_transform = v; }
+    pub fn set_color_lut             (&self, v: Option<Arc<undistortion::ColorLut>>) { self.params.write().color_lut = v; }
+    pub fn set_background_gradient   (&self, v: Option<(Vector4<f32>, Vector4<f32>)>) { self.params.write().bg_gradient = v; }
+    pub fn set_background_keyframes  (&self, mut v: Vec<(f64, Vector4<f32>)>) { v.sort_by(|a, b| a.0.total_cmp(&b.0)); self.params.write().background_keyframes = v; }
+    pub fn set_background_regions    (&self, v: Option<Arc<undistortion::BackgroundRegions>>) { self.params.write().bg_regions = v; }
+    pub fn set_chromatic_aberration  (&self, v: Option<[f32; 3]>) { self.params.write().chromatic_aberration = v.filter(|x| x.iter().all(|s| *s > 0.0) && x.iter().any(|s| *s != 1.0)); }
 
     pub fn get_scaling_ratio         (&self) -> f64 { let params = self.params.read(); params.video_size.0 as f64 / params.video_output_size.0 as f64 }
     pub fn get_current_fov           (&self) -> f64 { self.current_fov_10000.load(SeqCst) as f64 / 10000.0 }
@@ -582,8 +631,23 @@ impl<T: PixelType> StabilizationManager<T> {
         }
     }
 
+    // Also clears the background preset
     pub fn set_background_color(&self, bg: Vector4<f32>) {
-        self.params.write().background = bg;
+        {
+            let mut params = self.params.write();
+            params.background = bg;
+            params.bg_preset = None;
+        }
+        self.undistortion.write().set_background(bg);
+    }
+    // Background at the black level of the log curve, with the alpha of the background color. It's kept until `set_background_color`
+    // and converted again for the pixel format on every size change and render. None goes back to the background color
+    pub fn set_background_preset(&self, preset: Option<stabilization_params::LogProfile>) {
+        let bg = {
+            let mut params = self.params.write();
+            params.bg_preset = preset;
+            params.effective_background()
+        };
         self.undistortion.write().set_background(bg);
     }
 
@@ -631,13 +695,13 @@ impl<T: PixelType> StabilizationManager<T> {
     }
 
     pub fn clear(&self) {
-        let (stab_enabled, show_detected_features, show_optical_flow, background, adaptive_zoom_window, framebuffer_inverted, lens_correction_amount, background_mode) = {
+        let (stab_enabled, show_detected_features, show_optical_flow, background, bg_preset, adaptive_zoom_window, framebuffer_inverted, lens_correction_amount, background_mode) = {
             let params = self.params.read();
-            (params.stab_enabled, params.show_detected_features, params.show_optical_flow, params.background, params.adaptive_zoom_window, params.framebuffer_inverted, params.lens_correction_amount, params.background_mode)
+            (params.stab_enabled, params.show_detected_features, params.show_optical_flow, params.background, params.bg_preset, params.adaptive_zoom_window, params.framebuffer_inverted, params.lens_correction_amount, params.background_mode)
         };
 
         *self.params.write() = StabilizationParams {
-            stab_enabled, show_detected_features, show_optical_flow, background, adaptive_zoom_window, framebuffer_inverted, lens_correction_amount, background_mode, ..Default::default()
+            stab_enabled, show_detected_features, show_optical_flow, background, bg_preset, adaptive_zoom_window, framebuffer_inverted, lens_correction_amount, background_mode, ..Default::default()
         };
         if !self.gyro.read().prevent_next_load {
             *self.gyro.write() = GyroSource::new();
@@ -8,6 +8,9 @@ pub enum BackgroundMode {
     SolidColor = 0,
     RepeatPixels = 1,
     MirrorPixels = 2,
+    SourcePassthrough = 3, // Unmapped pixels show the source frame, to visualize the crop
+    WrapHorizontal = 4, // 360° equirectangular input, sampling wraps around horizontally and clamps vertically
+    Transparent = 5, // Unmapped pixels are left untouched, for compositing onto a canvas. `out_pixels` has to be initialized by the caller. CPU only
 }
 impl Default for BackgroundMode {
     fn default() -> Self { Self::SolidColor }
@@ -17,11 +20,178 @@ impl From<i32> for BackgroundMode {
         match v {
             1 => Self::RepeatPixels,
             2 => Self::MirrorPixels,
+            3 => Self::SourcePassthrough,
+            4 => Self::WrapHorizontal,
+            5 => Self::Transparent,
             _ => Self::SolidColor
         }
     }
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum CoordinateConvention {
+    RightHanded = 0,
+    LeftHanded = 1,
+}
+impl Default for CoordinateConvention {
+    fn default() -> Self { Self::RightHanded }
+}
+impl From<i32> for CoordinateConvention {
+    fn from(v: i32) -> Self {
+        match v {
+            1 => Self::LeftHanded,
+            _ => Self::RightHanded
+        }
+    }
+}
+
+// Clockwise rotation of the stored frame relative to the sensor, e.g. from the EXIF/container orientation flag
+#[derive(Clone, Copy, PartialEq)]
+pub enum Rotation90 {
+    None = 0,
+    Cw90 = 1,
+    Cw180 = 2,
+    Cw270 = 3,
+}
+impl Rotation90 {
+    pub fn swaps_axes(&self) -> bool { matches!(self, Self::Cw90 | Self::Cw270) }
+}
+impl Default for Rotation90 {
+    fn default() -> Self { Self::None }
+}
+impl From<i32> for Rotation90 {
+    fn from(v: i32) -> Self {
+        match v {
+            1 => Self::Cw90,
+            2 => Self::Cw180,
+            3 => Self::Cw270,
+            _ => Self::None
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ReadoutDirection {
+    TopToBottom = 0,
+    BottomToTop = 1, // Also for sensors mounted upside down
+}
+impl Default for ReadoutDirection {
+    fn default() -> Self { Self::TopToBottom }
+}
+impl From<i32> for ReadoutDirection {
+    fn from(v: i32) -> Self {
+        match v {
+            1 => Self::BottomToTop,
+            _ => Self::TopToBottom
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum InputAlphaMode {
+    Straight = 0,      // Premultiplied before interpolation and divided back after, so transparent pixels don't bleed their color into the edges
+    Premultiplied = 1, // Interpolated as is
+}
+impl Default for InputAlphaMode {
+    fn default() -> Self { Self::Premultiplied }
+}
+impl From<i32> for InputAlphaMode {
+    fn from(v: i32) -> Self {
+        match v {
+            0 => Self::Straight,
+            _ => Self::Premultiplied
+        }
+    }
+}
+
+// Field order of interlaced footage. Interlaced frames are undistorted field by field (CPU only), so the warp doesn't comb the fields together
+#[derive(Clone, Copy, PartialEq)]
+pub enum Interlacing {
+    Progressive = 0,
+    TopFieldFirst = 1,
+    BottomFieldFirst = 2,
+}
+impl Default for Interlacing {
+    fn default() -> Self { Self::Progressive }
+}
+impl From<i32> for Interlacing {
+    fn from(v: i32) -> Self {
+        match v {
+            1 => Self::TopFieldFirst,
+            2 => Self::BottomFieldFirst,
+            _ => Self::Progressive
+        }
+    }
+}
+
+// Framing of the content when the output aspect ratio differs from the input
+#[derive(Clone, Copy, PartialEq)]
+pub enum AspectFit {
+    Stretch = 0,    // Input frame is scaled to the output independently per axis
+    Fit = 1,        // Whole input frame is visible, background bars on the sides that don't match
+    Fill = 2,       // Output is fully covered, the input is cropped on the sides that don't match
+    MatchWidth = 3, // Input width maps to the output width, the height is letterboxed or cropped. Same as before the framing modes were added
+}
+impl Default for AspectFit {
+    fn default() -> Self { Self::MatchWidth }
+}
+impl From<i32> for AspectFit {
+    fn from(v: i32) -> Self {
+        match v {
+            0 => Self::Stretch,
+            1 => Self::Fit,
+            2 => Self::Fill,
+            _ => Self::MatchWidth
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogProfile {
+    SLog2,
+    SLog3,
+    VLog,
+    CLog,
+    LogC,
+    FLog,
+}
+impl LogProfile {
+    // Normalized (0-1) code value of 0% reflectance black for the log curve
+    pub fn black_level(&self) -> f32 {
+        match self {
+            Self::SLog2 => 90.0 / 1023.0,
+            Self::SLog3 => 95.0 / 1023.0,
+            Self::VLog  => 128.0 / 1023.0,
+            Self::CLog  => 0.073,
+            Self::LogC  => 0.092809,
+            Self::FLog  => 95.0 / 1023.0,
+        }
+    }
+
+    // Background at the black level in the code values of one plane. `ind` are the YUV components stored in the plane, like in `PixelType::from_rgb_color`,
+    // empty for RGB(A) planes. `max_val` is the full scale of the plane, `alpha` is in 0-255 range
+    pub fn background(&self, ind: &[usize], max_val: f32, alpha: f32) -> Vector4<f32> {
+        let black = self.black_level() * max_val;
+        let alpha = alpha / 255.0 * max_val;
+        if ind.is_empty() { return Vector4::new(black, black, black, alpha); }
+        // Luma at the black level, neutral chroma
+        let component = |i: usize| match i { 0 => black, 3 => alpha, _ => 0.5 * max_val };
+        Vector4::new(component(ind[0]), ind.get(1).map_or(0.0, |&i| component(i)), 0.0, 0.0)
+    }
+}
+impl From<i32> for LogProfile {
+    fn from(v: i32) -> Self {
+        match v {
+            1 => Self::SLog3,
+            2 => Self::VLog,
+            3 => Self::CLog,
+            4 => Self::LogC,
+            5 => Self::FLog,
+            _ => Self::SLog2
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct StabilizationParams {
     pub size: (usize, usize), // Processing input size
@@ -30,10 +200,15 @@ pub struct StabilizationParams {
     pub video_output_size: (usize, usize), // Full resoution output size
 
     pub background: Vector4<f32>,
+    pub bg_preset: Option<LogProfile>, // Overrides `background` with the black level of the log curve, converted for each plane format
 
     pub frame_readout_time: f64,
     pub adaptive_zoom_window: f64,
     pub fov: f64,
+    pub fov_keyframes: Vec<(f64, f64)>, // (timestamp_ms, fov), sorted. Replaces `fov` when not empty
+    pub breathing_curve: Vec<(f64, f64)>, // (timestamp_ms, focal length multiplier), sorted. Focus breathing of the lens, compensated so the output FOV stays constant
+    pub distortion_keyframes: Vec<(f64, [f64; 4])>, // (timestamp_ms, distortion coefficients), sorted. Zoom lenses, replaces the lens profile coefficients when not empty
+    pub rotation_quats: Option<std::sync::Arc<crate::gyro_source::TimeQuat>>, // (timestamp_us, correction rotation), replaces `org⁻¹(frame) * org * smoothed` from the gyro. Slerped per sensor row
     pub fovs: Vec<f64>,
     pub min_fov: f64,
     pub fps: f64,
@@ -45,9 +220,31 @@ pub struct StabilizationParams {
     pub trim_end: f64,
 
     pub video_rotation: f64,
+    pub pixel_aspect_ratio: f64,
 
-    pub lens_correction_amount: f64,
+    pub lens_correction_amount: f64, // 1 - fully corrected, 0 - native lens distortion, -1 - distortion doubled (exaggerated fisheye look)
     pub background_mode: BackgroundMode,
+    pub coordinate_convention: CoordinateConvention,
+    pub readout_direction: ReadoutDirection,
+    pub input_alpha_mode: InputAlphaMode,
+    pub input_orientation: Rotation90,
+    pub aspect_fit: AspectFit,
+    pub interlacing: Interlacing,
+    pub identity_threshold: f32, // in pixels, 0 to disable
+    pub dither: bool,
+    pub super_fisheye: bool, // Approximate mapping instead of background where the lens model breaks down (> 180° FOV)
+    pub distortion_enabled: bool, // false for a fast rotation only preview, the final render should always use the lens model
+    pub sharpen_amount: f32, // Unsharp mask strength to counter the resampling blur, 0 to disable. Typical values are 0.2 - 0.5
+    pub kernel_radius: f32, // Interpolation kernel radius in source pixels for heavily zoomed out shots, up to 4 (Lanczos4) uses the built-in tables. CPU only and much slower above 4
+    pub defect_mask: Option<std::sync::Arc<Vec<bool>>>, // Dead/hot sensor pixels, row-major in the input buffer (`width * height`), interpolated over in the resample. CPU only
+    pub vignette_gain: Option<Vec<f32>>, // Radial gain, sampled uniformly from the optical axis (0°) to 90°
+    pub color_transform: Option<crate::undistortion::ColorTransform>, // Output color space conversion, None for no change
+    pub color_lut: Option<std::sync::Arc<crate::undistortion::ColorLut>>, // 3D LUT applied after `color_transform`
+    pub bg_gradient: Option<(Vector4<f32>, Vector4<f32>)>, // Radial background (center, edge color) instead of the flat `background`, same 0-255 range
+    pub background_keyframes: Vec<(f64, Vector4<f32>)>, // (timestamp_ms, color), sorted. Animated flat background, replaces `background` when not empty
+    pub bg_regions: Option<std::sync::Arc<crate::undistortion::BackgroundRegions>>, // Background palette selected per output region, overrides `background` and `bg_gradient`
+    pub chromatic_aberration: Option<[f32; 3]>, // Lateral chromatic aberration correction, R, G, B scale of the source position around the lens center
+    pub supersample: u8,
 
     pub framebuffer_inverted: bool,
     pub is_calibrator: bool,
@@ -55,6 +252,8 @@ pub struct StabilizationParams {
     pub stab_enabled: bool,
     pub show_detected_features: bool,
     pub show_optical_flow: bool,
+    pub show_rolling_shutter: bool, // Tint the output rows by the horizontal shift of their rolling shutter correction
+    pub show_safe_zone: bool, // Outline the output area covered by the source, to see how much crop margin the stabilization has left
 
     pub zooming_debug_points: std::collections::BTreeMap<i64, Vec<(f64, f64)>>
 }
@@ -63,10 +262,16 @@ impl Default for StabilizationParams {
         Self {
             fov: 1.0,
             min_fov: 1.0,
+            fov_keyframes: vec![],
+            breathing_curve: vec![],
+            distortion_keyframes: vec![],
+            rotation_quats: None,
             fovs: vec![],
             stab_enabled: true,
             show_detected_features: true,
             show_optical_flow: true,
+            show_rolling_shutter: false,
+            show_safe_zone: false,
             frame_readout_time: 0.0, 
             adaptive_zoom_window: 0.0, 
 
@@ -76,9 +281,31 @@ impl Default for StabilizationParams {
             video_output_size: (0, 0),
 
             video_rotation: 0.0,
+            pixel_aspect_ratio: 1.0,
 
             lens_correction_amount: 1.0,
             background_mode: BackgroundMode::SolidColor,
+            coordinate_convention: CoordinateConvention::RightHanded,
+            readout_direction: ReadoutDirection::TopToBottom,
+            input_alpha_mode: InputAlphaMode::Premultiplied,
+            input_orientation: Rotation90::None,
+            aspect_fit: AspectFit::MatchWidth,
+            interlacing: Interlacing::Progressive,
+            identity_threshold: 0.01,
+            dither: false,
+            super_fisheye: false,
+            distortion_enabled: true,
+            sharpen_amount: 0.0,
+            kernel_radius: 0.0,
+            defect_mask: None,
+            vignette_gain: None,
+            color_transform: None,
+            color_lut: None,
+            bg_gradient: None,
+            background_keyframes: vec![],
+            bg_regions: None,
+            chromatic_aberration: None,
+            supersample: 1,
             
             framebuffer_inverted: false,
             is_calibrator: false,
@@ -89,6 +316,7 @@ impl Default for StabilizationParams {
             zooming_debug_points: BTreeMap::new(),
         
             background: Vector4::new(0.0, 0.0, 0.0, 0.0),
+            bg_preset: None,
     
             fps: 0.0,
             fps_scale: None,
@@ -99,6 +327,14 @@ impl Default for StabilizationParams {
 }
 
 impl StabilizationParams {
+    // Background in 0-255 RGB range, from `bg_preset` when set
+    pub fn effective_background(&self) -> Vector4<f32> {
+        match self.bg_preset {
+            Some(preset) => preset.background(&[], 255.0, self.background[3]),
+            None => self.background
+        }
+    }
+
     pub fn get_scaled_duration_ms(&self) -> f64 {
         match self.fps_scale {
             Some(scale) => self.duration_ms / scale,
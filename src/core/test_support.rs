@@ -0,0 +1,44 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
+
+// Helpers for writing undistortion tests without a video, gyro data or lens profile file.
+// Enabled with the `test-support` feature, so downstream crates can use them in their integration tests too.
+
+use nalgebra::Matrix3;
+use crate::undistortion::{ ComputeParams, FrameTransform };
+
+// Equidistant fisheye lens with horizontal field of view `fov` (degrees) and distortion coefficients `k`, centered on a `width` x `height` frame.
+// Output has the same size as the input and there is no rotation or rolling shutter, so the result is deterministic.
+// Returns the compute params and the matching `FrameTransform` params, which are accepted as-is by the CPU path and by `WgpuWrapper::undistort_image_with_params`
+pub fn synthetic_profile(fov: f64, k: [f32; 4], width: usize, height: usize) -> (ComputeParams, Vec<[f32; 9]>) {
+    let width = width.max(1);
+    let height = height.max(1);
+    let f = (width as f64 / 2.0) / (fov.max(0.1) / 2.0).to_radians();
+
+    let params = ComputeParams {
+        frame_count: 1,
+        fov_scale: 1.0,
+        width,
+        height,
+        output_width: width,
+        output_height: height,
+        video_width: width,
+        video_height: height,
+        video_output_width: width,
+        video_output_height: height,
+        pixel_aspect_ratio: 1.0,
+        camera_matrix: Matrix3::new(
+            f,   0.0, width as f64 / 2.0,
+            0.0, f,   height as f64 / 2.0,
+            0.0, 0.0, 1.0
+        ),
+        distortion_coeffs: [k[0] as f64, k[1] as f64, k[2] as f64, k[3] as f64],
+        lens_correction_amount: 1.0,
+        supersample: 1,
+        scaled_fps: 30.0,
+        ..Default::default()
+    };
+
+    let transform = FrameTransform::from_row_rotations(&params, 0, |_| Matrix3::identity());
+    (params, transform.params)
+}
@@ -0,0 +1,157 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering::Relaxed };
+use std::task::{ Context, Poll, Waker };
+use crossbeam_channel::{ Receiver, Sender, TryRecvError };
+use futures_core::Stream;
+use nalgebra::Vector4;
+use parking_lot::Mutex;
+
+use super::{ Frame, PixelType, Interpolation, FrameTransform };
+use crate::gpu::wgpu::WgpuWrapper;
+
+// Async counterpart of `spawn_cpu_pipeline` on the wgpu backend, for apps running on an async executor (tokio, async-std etc.).
+// Frames are pulled from `frames` and undistorted on a worker thread which owns the `WgpuWrapper`, while the caller decodes the next ones.
+// At most `max_in_flight` frames are between the input and the output at any time, so the memory stays bounded when the consumer is slow.
+// Returns None if the format isn't supported by wgpu or there's no adapter.
+//
+// Cancellation: once `cancel_flag` is set, the stream ends on the next poll. Frames yielded before are complete, frames still in flight are dropped without being yielded.
+// Ending or dropping the stream wakes the worker, which exits after at most the frame currently on the GPU and releases the GPU resources.
+// The release is asynchronous, use `ClipStream::close` to wait for it, e.g. before tearing down the device or the process
+pub fn process_clip_async<T: PixelType, S, F>(frames: S, mut params_provider: F, cancel_flag: Arc<AtomicBool>, size: (usize, usize, usize), output_size: (usize, usize, usize), bg: Vector4<f32>, interpolation: Interpolation, max_in_flight: usize) -> Option<ClipStream<S>>
+    where S: Stream<Item = Frame> + Unpin, F: FnMut(&Frame) -> Option<FrameTransform> + Send + 'static
+{
+    let (width, height, stride) = size;
+    let (output_width, output_height, output_stride) = output_size;
+    let mut wgpu = WgpuWrapper::new(width, height, stride, output_width, output_height, output_stride, bg, interpolation.gpu_taps(), T::wgpu_format()?)?;
+
+    let max_in_flight = max_in_flight.max(1);
+    // Both channels hold `max_in_flight` frames, so neither side ever blocks on send
+    let (to_worker, worker_rx) = crossbeam_channel::bounded::<Frame>(max_in_flight);
+    let (worker_tx, from_worker) = crossbeam_channel::bounded::<Frame>(max_in_flight);
+    // Never sent to, the worker wakes up when the stream drops the sender
+    let (cancel_tx, cancel_rx) = crossbeam_channel::bounded::<()>(0);
+    let waker = Arc::new(Mutex::new(None::<Waker>));
+
+    let worker = {
+        let waker = waker.clone();
+        let cancel_flag = cancel_flag.clone();
+        let wake = move || if let Some(w) = waker.lock().take() { w.wake(); };
+        std::thread::spawn(move || {
+            'worker: loop {
+                let mut frame = crossbeam_channel::select! {
+                    recv(worker_rx) -> frame => match frame { Ok(frame) => frame, Err(_) => break 'worker },
+                    recv(cancel_rx) -> _ => break 'worker
+                };
+                if cancel_flag.load(Relaxed) { break; }
+                frame.output.resize(output_stride * output_height, 0);
+                frame.undistorted = match params_provider(&frame) {
+                    Some(itm) => {
+                        wgpu.set_background(itm.bg.unwrap_or(bg));
+                        match wgpu.try_undistort_image(&mut frame.pixels, &mut frame.output, &itm) {
+                            Ok(_) => true,
+                            // Driver reset, recreate the device and render the frame again. Frames which still fail are yielded with `undistorted = false`
+                            Err(crate::gpu::wgpu::WgpuError::DeviceLost) => {
+                                log::warn!("wgpu device lost, recreating");
+                                match wgpu.recover() {
+                                    Ok(()) => wgpu.try_undistort_image(&mut frame.pixels, &mut frame.output, &itm).is_ok(),
+                                    Err(e) => { log::error!("Failed to recover the wgpu device: {}", e); false }
+                                }
+                            },
+                            Err(_) => false
+                        }
+                    },
+                    None => false
+                };
+                if worker_tx.send(frame).is_err() { break; }
+                wake();
+            }
+            drop(wgpu);
+            wake();
+        })
+    };
+
+    Some(ClipStream {
+        frames,
+        input_done: false,
+        in_flight: 0,
+        max_in_flight,
+        to_worker: Some(to_worker),
+        from_worker,
+        waker,
+        cancel_flag,
+        cancel_tx: Some(cancel_tx),
+        worker: Some(worker),
+    })
+}
+
+// Output of `process_clip_async`, yields the frames in input order
+pub struct ClipStream<S> {
+    frames: S,
+    input_done: bool,
+    in_flight: usize,
+    max_in_flight: usize,
+    to_worker: Option<Sender<Frame>>, // None after the stream ended, which stops the worker
+    from_worker: Receiver<Frame>,
+    waker: Arc<Mutex<Option<Waker>>>,
+    cancel_flag: Arc<AtomicBool>,
+    cancel_tx: Option<Sender<()>>, // Dropped when the stream ends, which wakes the worker
+    worker: Option<std::thread::JoinHandle<()>>, // Not joined on drop, so ending the stream never blocks the executor
+}
+
+impl<S> ClipStream<S> {
+    fn finish(&mut self) -> Poll<Option<Frame>> {
+        self.to_worker = None;
+        self.cancel_tx = None;
+        self.input_done = true;
+        self.in_flight = 0;
+        Poll::Ready(None)
+    }
+
+    // Ends the stream and blocks until the worker exited and released the GPU resources, which takes at most the frame currently on the GPU.
+    // Call it from a blocking context, e.g. `spawn_blocking` on tokio
+    pub fn close(mut self) {
+        let _ = self.finish();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl<S: Stream<Item = Frame> + Unpin> Stream for ClipStream<S> {
+    type Item = Frame;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Frame>> {
+        let this = &mut *self;
+        if this.to_worker.is_none() || this.cancel_flag.load(Relaxed) { return this.finish(); }
+
+        // Registered before checking the results, so a frame finished in between still wakes us
+        *this.waker.lock() = Some(cx.waker().clone());
+
+        while !this.input_done && this.in_flight < this.max_in_flight {
+            match Pin::new(&mut this.frames).poll_next(cx) {
+                Poll::Ready(Some(frame)) => {
+                    if this.to_worker.as_ref().map_or(true, |tx| tx.send(frame).is_err()) { return this.finish(); }
+                    this.in_flight += 1;
+                },
+                Poll::Ready(None) => this.input_done = true,
+                Poll::Pending => break
+            }
+        }
+
+        match this.from_worker.try_recv() {
+            Ok(frame) => { this.in_flight -= 1; Poll::Ready(Some(frame)) },
+            Err(TryRecvError::Empty) if this.in_flight == 0 && this.input_done => this.finish(),
+            Err(TryRecvError::Empty) => Poll::Pending,
+            Err(TryRecvError::Disconnected) => this.finish() // Worker exited after a cancellation
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (lo, hi) = if self.input_done { (0, Some(0)) } else { self.frames.size_hint() };
+        (lo + self.in_flight, hi.map(|x| x + self.in_flight))
+    }
+}
@@ -4,7 +4,7 @@
 use super::StabilizationManager;
 use super::PixelType;
 use crate::GyroSource;
-use nalgebra::Matrix3;
+use nalgebra::{ Matrix3, Vector4 };
 
 #[derive(Default, Clone)]
 pub struct ComputeParams {
@@ -13,6 +13,10 @@ pub struct ComputeParams {
 
     pub frame_count: usize,
     pub fov_scale: f64,
+    pub fov_keyframes: Vec<(f64, f64)>, // (timestamp_ms, fov), sorted
+    pub breathing_curve: Vec<(f64, f64)>, // (timestamp_ms, focal length multiplier), sorted
+    pub distortion_keyframes: Vec<(f64, [f64; 4])>, // (timestamp_ms, k), sorted. Overrides `distortion_coeffs` when not empty
+    pub rotation_quats: Option<std::sync::Arc<crate::gyro_source::TimeQuat>>, // Supplied correction rotations, replace the ones computed from `gyro`
     pub lens_fov_adjustment: f64,
     pub width: usize,
     pub height: usize,
@@ -23,11 +27,35 @@ pub struct ComputeParams {
     pub video_width: usize,
     pub video_height: usize,
     pub video_rotation: f64,
+    pub pixel_aspect_ratio: f64,
     pub camera_matrix: Matrix3<f64>,
     pub distortion_coeffs: [f64; 4],
     pub radial_distortion_limit: f64,
+    pub max_theta_d: f64,
     pub lens_correction_amount: f64,
     pub background_mode: crate::stabilization_params::BackgroundMode,
+    pub coordinate_convention: crate::stabilization_params::CoordinateConvention,
+    pub readout_direction: crate::stabilization_params::ReadoutDirection,
+    pub input_alpha_mode: crate::stabilization_params::InputAlphaMode,
+    pub input_orientation: crate::stabilization_params::Rotation90,
+    pub aspect_fit: crate::stabilization_params::AspectFit,
+    pub interlacing: crate::stabilization_params::Interlacing,
+    pub identity_threshold: f32,
+    pub dither: bool,
+    pub super_fisheye: bool,
+    pub show_rolling_shutter: bool,
+    pub skip_distortion: bool, // Rotation only preview, skips the lens model in the per-pixel remap. Inverted from `StabilizationParams::distortion_enabled`, so the default keeps the lens model
+    pub sharpen_amount: f32, // Unsharp mask fused into the resample, 0 - disabled
+    pub kernel_radius: f32, // See `FrameParams::kernel_radius`
+    pub defect_mask: Option<std::sync::Arc<Vec<bool>>>,
+    pub vignette_gain: Option<Vec<f32>>,
+    pub color_transform: Option<super::ColorTransform>,
+    pub color_lut: Option<std::sync::Arc<super::ColorLut>>,
+    pub bg_gradient: Option<(Vector4<f32>, Vector4<f32>)>, // center, edge
+    pub background_keyframes: Vec<(f64, Vector4<f32>)>, // (timestamp_ms, color), sorted
+    pub bg_regions: Option<std::sync::Arc<super::BackgroundRegions>>,
+    pub chromatic_aberration: Option<[f32; 3]>,
+    pub supersample: u8,
     pub frame_readout_time: f64,
     pub trim_start: f64,
     pub trim_end: f64,
@@ -38,6 +66,53 @@ pub struct ComputeParams {
     pub zooming_debug_points: bool
 }
 impl ComputeParams {
+    // `fov_scale`, or the keyframed FOV linearly interpolated at `timestamp_ms`, held constant before the first and after the last keyframe
+    pub fn fov_at_timestamp(&self, timestamp_ms: f64) -> f64 {
+        Self::interpolate_curve(&self.fov_keyframes, timestamp_ms, |a, b, t| a + (b - a) * t).unwrap_or(self.fov_scale)
+    }
+
+    // Focal length multiplier of the lens at `timestamp_ms` (focus breathing), 1.0 without a breathing curve
+    pub fn focal_scale_at_timestamp(&self, timestamp_ms: f64) -> f64 {
+        Self::interpolate_curve(&self.breathing_curve, timestamp_ms, |a, b, t| a + (b - a) * t).filter(|x| x.is_finite() && *x > 0.0).unwrap_or(1.0)
+    }
+
+    // Lens distortion coefficients at `timestamp_ms`, e.g. for zoom lenses. Linearly interpolated between the keyframes, `distortion_coeffs` without keyframes
+    pub fn distortion_coeffs_at_timestamp(&self, timestamp_ms: f64) -> [f64; 4] {
+        Self::interpolate_curve(&self.distortion_keyframes, timestamp_ms, |a, b, t| [0, 1, 2, 3].map(|j| a[j] + (b[j] - a[j]) * t)).unwrap_or(self.distortion_coeffs)
+    }
+
+    // Keyframed background color linearly interpolated at `timestamp_ms`, held constant before the first and after the last keyframe. None without keyframes
+    pub fn background_at_timestamp(&self, timestamp_ms: f64) -> Option<Vector4<f32>> {
+        Self::interpolate_curve(&self.background_keyframes, timestamp_ms, |a, b, t| a + (b - a) * t as f32)
+    }
+
+    // Supplied correction rotation at `timestamp_ms`, slerped between the two nearest quaternions and clamped to the first and last one.
+    // None without `rotation_quats`
+    pub fn rotation_quat_at_timestamp(&self, timestamp_ms: f64) -> Option<crate::gyro_source::Quat64> {
+        let quats = self.rotation_quats.as_ref()?;
+        let (&first_ts, &last_ts) = (quats.keys().next()?, quats.keys().next_back()?);
+        let lookup_ts = ((timestamp_ms * 1000.0) as i64).min(last_ts).max(first_ts);
+        let (ts1, q1) = quats.range(..=lookup_ts).next_back()?;
+        if *ts1 == lookup_ts { return Some(*q1); }
+        let (ts2, q2) = quats.range(lookup_ts..).next()?;
+        Some(q1.slerp(q2, (lookup_ts - ts1) as f64 / (ts2 - ts1) as f64))
+    }
+
+    // Value of the (timestamp_ms, value) keyframes at `timestamp_ms`, `lerp(a, b, t)` between the surrounding keyframes, held constant before the first and after the last one.
+    // None without keyframes
+    fn interpolate_curve<V: Copy, F: Fn(V, V, f64) -> V>(kf: &[(f64, V)], timestamp_ms: f64, lerp: F) -> Option<V> {
+        match kf.iter().position(|x| x.0 > timestamp_ms) {
+            _ if kf.is_empty() => None,
+            Some(0) => Some(kf[0].1),
+            Some(i) => {
+                let (a, b) = (kf[i - 1], kf[i]);
+                let t = (timestamp_ms - a.0) / (b.0 - a.0).max(1e-9);
+                Some(lerp(a.1, b.1, t))
+            },
+            None => Some(kf[kf.len() - 1].1)
+        }
+    }
+
     pub fn from_manager<T: PixelType>(mgr: &StabilizationManager<T>) -> Self {
         let params = mgr.params.read();
 
@@ -46,6 +121,13 @@ impl ComputeParams {
         let distortion_coeffs = lens.get_distortion_coeffs();
         let distortion_coeffs = [distortion_coeffs[0], distortion_coeffs[1], distortion_coeffs[2], distortion_coeffs[3]];
         let radial_distortion_limit = lens.fisheye_params.radial_distortion_limit.unwrap_or_default();
+        // Distorted angle at the edge of the image circle, 0 if the lens FOV is unknown
+        let max_theta_d = lens.max_fov.filter(|x| *x > 0.0).map(|fov| {
+            let theta = (fov / 2.0).to_radians();
+            let theta2 = theta * theta;
+            let k = &distortion_coeffs;
+            theta * (1.0 + k[0]*theta2 + k[1]*theta2.powi(2) + k[2]*theta2.powi(3) + k[3]*theta2.powi(4))
+        }).filter(|x| x.is_finite() && *x > 0.0).unwrap_or_default();
 
         let (calib_width, calib_height) = if lens.calib_dimension.w > 0 && lens.calib_dimension.h > 0 {
             (lens.calib_dimension.w as f64, lens.calib_dimension.h as f64)
@@ -65,6 +147,10 @@ impl ComputeParams {
 
             frame_count: params.frame_count,
             fov_scale: params.fov,
+            fov_keyframes: params.fov_keyframes.clone(),
+            breathing_curve: params.breathing_curve.clone(),
+            distortion_keyframes: params.distortion_keyframes.clone(),
+            rotation_quats: params.rotation_quats.clone(),
             lens_fov_adjustment: lens.optimal_fov.unwrap_or(1.0),
             fovs: params.fovs.clone(),
             width: params.size.0.max(1),
@@ -77,9 +163,33 @@ impl ComputeParams {
             output_height: params.output_size.1.max(1),
             camera_matrix,
             video_rotation: params.video_rotation,
+            pixel_aspect_ratio: params.pixel_aspect_ratio,
             distortion_coeffs,
             radial_distortion_limit,
+            max_theta_d,
             background_mode: params.background_mode,
+            coordinate_convention: params.coordinate_convention,
+            readout_direction: params.readout_direction,
+            input_alpha_mode: params.input_alpha_mode,
+            input_orientation: params.input_orientation,
+            aspect_fit: params.aspect_fit,
+            interlacing: params.interlacing,
+            identity_threshold: params.identity_threshold,
+            dither: params.dither,
+            super_fisheye: params.super_fisheye,
+            show_rolling_shutter: params.show_rolling_shutter,
+            skip_distortion: !params.distortion_enabled,
+            sharpen_amount: params.sharpen_amount,
+            kernel_radius: params.kernel_radius,
+            defect_mask: params.defect_mask.clone(),
+            vignette_gain: params.vignette_gain.clone(),
+            color_transform: params.color_transform,
+            color_lut: params.color_lut.clone(),
+            bg_gradient: params.bg_gradient,
+            background_keyframes: params.background_keyframes.clone(),
+            bg_regions: params.bg_regions.clone(),
+            chromatic_aberration: params.chromatic_aberration,
+            supersample: params.supersample,
             lens_correction_amount: params.lens_correction_amount,
             framebuffer_inverted: params.framebuffer_inverted,
             frame_readout_time: params.frame_readout_time,
@@ -92,3 +202,23 @@ impl ComputeParams {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distortion_coeffs_between_keyframes() {
+        let params = ComputeParams {
+            distortion_coeffs: [0.5, 0.5, 0.5, 0.5],
+            distortion_keyframes: vec![(1000.0, [0.1, -0.02, 0.0, 0.004]), (2000.0, [0.3, 0.02, 0.01, 0.0])],
+            ..Default::default()
+        };
+        let close = |a: [f64; 4], b: [f64; 4]| a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-12);
+        assert!(close(params.distortion_coeffs_at_timestamp(1500.0), [0.2, 0.0, 0.005, 0.002]));
+        // Held constant outside of the keyframes
+        assert!(close(params.distortion_coeffs_at_timestamp(0.0), [0.1, -0.02, 0.0, 0.004]));
+        assert!(close(params.distortion_coeffs_at_timestamp(3000.0), [0.3, 0.02, 0.01, 0.0]));
+        assert!(close(ComputeParams { distortion_keyframes: Vec::new(), ..params }.distortion_coeffs_at_timestamp(1500.0), [0.5; 4]));
+    }
+}
@@ -1,9 +1,11 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
 
-use super::{ PixelType, Undistortion, ComputeParams, FrameTransform };
-use nalgebra::{ Vector4, Matrix3 };
-use rayon::{ prelude::ParallelSliceMut, iter::{ ParallelIterator, IndexedParallelIterator } };
+use super::{ PixelType, Undistortion, ComputeParams, FrameTransform, validate_buffer, min_stride };
+use nalgebra::{ Vector2, Vector3, Vector4, Matrix2, Matrix3 };
+use rayon::{ prelude::{ ParallelSlice, ParallelSliceMut }, iter::{ ParallelIterator, IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, IntoParallelRefMutIterator } };
+use std::ops::Range;
+use std::sync::{ Arc, atomic::{ AtomicBool, AtomicUsize, Ordering::{ Relaxed, SeqCst } } };
 
 pub const COEFFS: [f32; 64+128+256] = [
     // Bilinear
@@ -52,7 +54,8 @@ pub const COEFFS: [f32; 64+128+256] = [
      0.998265, -0.027053,  0.009625, -0.002981
 ];
 
-fn undistort_point<T: num_traits::Float>(point: (T, T), k: &[T], amount: T) -> Option<(T, T)> {
+// `max_theta_d` is the distorted angle at the edge of the lens image circle, <= 0 falls back to PI
+fn undistort_point<T: num_traits::Float>(point: (T, T), k: &[T], amount: T, super_fisheye: bool, max_theta_d: T) -> Option<(T, T)> {
     let t_0 = T::from(0.0f32).unwrap();
     let t_1 = T::from(1.0f32).unwrap();
     let t_3 = T::from(3.0f32).unwrap();
@@ -60,6 +63,7 @@ fn undistort_point<T: num_traits::Float>(point: (T, T), k: &[T], amount: T) -> O
     let t_7 = T::from(7.0f32).unwrap();
     let t_9 = T::from(9.0f32).unwrap();
     let t_fpi = T::from(std::f64::consts::PI).unwrap();
+    let t_half_pi = T::from(std::f64::consts::FRAC_PI_2).unwrap();
     let t_eps = T::from(1e-6f64).unwrap();
     
     let t_max_fix = T::from(0.9f32).unwrap();
@@ -69,7 +73,11 @@ fn undistort_point<T: num_traits::Float>(point: (T, T), k: &[T], amount: T) -> O
     // the current camera model is only valid up to 180 FOV
     // for larger FOV the loop below does not converge
     // clip values so we still get plausible results for super fisheye images > 180 grad
-    theta_d = theta_d.max(-t_fpi).min(t_fpi);
+    // The limit comes from the lens FOV when known (e.g. 220° circular fisheye). Raising it lets the corners map instead of
+    // becoming background, but the polynomial isn't guaranteed to be monotonic that far, so Newton can still diverge or flip -
+    // those cases are caught below by `converged`/`theta_flipped` and the super fisheye fallback
+    let t_max_theta_d = if max_theta_d > t_0 { max_theta_d } else { t_fpi };
+    theta_d = theta_d.max(-t_max_theta_d).min(t_max_theta_d);
 
     let mut converged = false;
     let mut theta = theta_d;
@@ -112,167 +120,1815 @@ fn undistort_point<T: num_traits::Float>(point: (T, T), k: &[T], amount: T) -> O
     // if theta has flipped, it might converge due to symmetry but on the opposite of the camera center
     // so we can check whether theta has changed the sign during the optimization
     let theta_flipped = (theta_d < t_0 && theta > t_0) || (theta_d > t_0 && theta < t_0);
+    // Rays at or beyond 90° point behind the camera, tan() is no longer valid there
+    let behind_camera = theta.abs() >= t_half_pi;
 
-    if converged && !theta_flipped {
-        // Apply only requested amount
-        scale = t_1 + (scale - t_1) * (t_1 - amount);
+    if converged && !theta_flipped && !behind_camera {
+        // Apply only requested amount. Negative amount exaggerates the distortion, which can push the scale below 0 (mirrored image) - clamp it
+        scale = (t_1 + (scale - t_1) * (t_1 - amount)).max(t_0);
+        if !scale.is_finite() { return None; }
+
+        return Some((point.0 * scale, point.1 * scale));
+    }
+    if (theta_flipped || behind_camera) && super_fisheye {
+        // Use the pre-flip estimate (theta = theta_d) instead of leaving a hole, clamped below PI/2 to keep tan() finite
+        let theta = theta_d.min(T::from(SUPER_FISHEYE_MAX_THETA).unwrap());
+        scale = theta.tan() / theta_d;
+        scale = (t_1 + (scale - t_1) * (t_1 - amount)).max(t_0);
 
         return Some((point.0 * scale, point.1 * scale));
     }
     None
 }
 
-fn distort_point<T: num_traits::Float>(point: (T, T), f: (T, T), c: (T, T), k: &[T], amount: T) -> (T, T) {
-    let t_0 = T::from(0.0f32).unwrap();
-    let t_1 = T::from(1.0f32).unwrap();
+const SUPER_FISHEYE_MAX_THETA: f64 = 1.55;
+
+fn distort_point<T: num_traits::Float>(point: (T, T), f: (T, T), c: (T, T), k: &[T], amount: T) -> (T, T) {
+    let t_0 = T::from(0.0f32).unwrap();
+    let t_1 = T::from(1.0f32).unwrap();
+
+    let r = (point.0 * point.0 + point.1 * point.1).sqrt();
+
+    let theta = r.atan();
+    let theta2 = theta*theta;
+    let theta4 = theta2*theta2;
+    let theta6 = theta4*theta2;
+    let theta8 = theta4*theta4;
+
+    let theta_d = theta * (t_1 + k[0]*theta2 + k[1]*theta4 + k[2]*theta6 + k[3]*theta8);
+    // Degenerate coefficients, `max` below would turn NaN into 0 and map the point to the center
+    if theta_d.is_nan() { return (T::nan(), T::nan()); }
+
+    let mut scale = if r == t_0 { t_1 } else { theta_d / r };
+    scale = (t_1 + (scale - t_1) * (t_1 - amount)).max(t_0);
+
+    (
+        f.0 * point.0 * scale + c.0,
+        f.1 * point.1 * scale + c.1
+    )
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum YuvLayout {
+    Nv12, // Y plane + interleaved UV plane
+    I420  // Y plane + U plane + V plane
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Tonemap {
+    Clamp,     // No curve, values above 1.0 clip
+    Reinhard,  // x / (1 + x)
+    AcesApprox // Narkowicz 2015 fit of the ACES filmic curve
+}
+impl Tonemap {
+    // Maps linear scene value to [0, 1]. NaN maps to 0
+    #[inline]
+    pub fn apply(self, x: f32) -> f32 {
+        let x = x.max(0.0);
+        let y = match self {
+            Tonemap::Clamp      => x,
+            Tonemap::Reinhard   => x / (1.0 + x),
+            Tonemap::AcesApprox => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+        };
+        y.max(0.0).min(1.0)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ColorTransfer {
+    Linear = 0, // Matrix is applied to the values as they are
+    Bt709  = 1, // BT.709 / BT.2020 OETF
+    Srgb   = 2
+}
+impl ColorTransfer {
+    #[inline]
+    pub fn to_linear(self, v: f32) -> f32 {
+        let v = v.max(0.0);
+        match self {
+            ColorTransfer::Linear => v,
+            ColorTransfer::Bt709  => if v < 0.081   { v / 4.5   } else { ((v + 0.099) / 1.099).powf(1.0 / 0.45) },
+            ColorTransfer::Srgb   => if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) },
+        }
+    }
+    #[inline]
+    pub fn from_linear(self, v: f32) -> f32 {
+        let v = v.max(0.0);
+        match self {
+            ColorTransfer::Linear => v,
+            ColorTransfer::Bt709  => if v < 0.018      { v * 4.5   } else { 1.099 * v.powf(0.45) - 0.099 },
+            ColorTransfer::Srgb   => if v <= 0.0031308 { v * 12.92 } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 },
+        }
+    }
+}
+
+// Color space conversion of the output, applied to the interpolated value before the store. Alpha passes through unmodified.
+// With a transfer function other than `Linear` the values are decoded before the matrix and encoded again after it
+#[derive(Clone, Copy, PartialEq)]
+pub struct ColorTransform {
+    pub matrix: [f32; 9], // Row-major
+    pub transfer: ColorTransfer,
+    pub max_value: f32, // Pixel value of 1.0, e.g. 255 for 8-bit formats
+}
+impl Default for ColorTransform {
+    fn default() -> Self { Self { matrix: Self::IDENTITY, transfer: ColorTransfer::Linear, max_value: 255.0 } }
+}
+impl ColorTransform {
+    pub const IDENTITY: [f32; 9] = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0];
+    pub const BT709_TO_BT2020: [f32; 9] = [0.6274, 0.3293, 0.0433, 0.0691, 0.9195, 0.0114, 0.0164, 0.0880, 0.8956];
+
+    #[inline]
+    pub fn apply(&self, v: Vector4<f32>) -> Vector4<f32> {
+        let m = &self.matrix;
+        let s = self.max_value.max(1e-6);
+        let (r, g, b) = (self.transfer.to_linear(v[0] / s), self.transfer.to_linear(v[1] / s), self.transfer.to_linear(v[2] / s));
+        Vector4::new(
+            self.transfer.from_linear(m[0] * r + m[1] * g + m[2] * b) * s,
+            self.transfer.from_linear(m[3] * r + m[4] * g + m[5] * b) * s,
+            self.transfer.from_linear(m[6] * r + m[7] * g + m[8] * b) * s,
+            v[3]
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum ColorLutError {
+    MissingSize,
+    InvalidLine(usize),                 // line number
+    SizeMismatch((usize, usize)),       // entries, expected
+    Unsupported(String),                // keyword
+}
+impl std::fmt::Display for ColorLutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ColorLutError::MissingSize     => write!(f, "LUT_3D_SIZE is missing"),
+            ColorLutError::InvalidLine(v)  => write!(f, "Invalid LUT entry at line {}", v),
+            ColorLutError::SizeMismatch(v) => write!(f, "LUT has {} entries, expected {}", v.0, v.1),
+            ColorLutError::Unsupported(v)  => write!(f, "Unsupported LUT keyword: {}", v),
+        }
+    }
+}
+impl std::error::Error for ColorLutError { }
+
+// 3D color LUT, e.g. a creative grade from a .cube file. Applied to the interpolated value after `ColorTransform`, with trilinear interpolation.
+// Input is clamped to [0, 1] (after dividing by `max_value`). Alpha passes through unmodified
+#[derive(Clone, PartialEq)]
+pub struct ColorLut {
+    pub size: usize, // N, the table has N³ entries
+    pub data: Vec<[f32; 3]>, // Red changes fastest, then green, then blue, same as in .cube files
+    pub max_value: f32, // Pixel value of 1.0, e.g. 255 for 8-bit formats
+}
+impl ColorLut {
+    pub fn new(size: usize, data: Vec<[f32; 3]>, max_value: f32) -> Result<Self, ColorLutError> {
+        if size < 2 { return Err(ColorLutError::MissingSize); }
+        if data.len() != size * size * size { return Err(ColorLutError::SizeMismatch((data.len(), size * size * size))); }
+        Ok(Self { size, data, max_value })
+    }
+
+    // Parses the Adobe/Resolve .cube format. Only 3D LUTs with the default [0, 1] domain are supported
+    pub fn from_cube(text: &str, max_value: f32) -> Result<Self, ColorLutError> {
+        let mut size = 0;
+        let mut data = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+            let mut parts = line.split_whitespace();
+            let first = parts.next().unwrap_or_default();
+            match first {
+                "TITLE" => { },
+                "LUT_3D_SIZE" => {
+                    size = parts.next().and_then(|x| x.parse::<usize>().ok()).ok_or(ColorLutError::InvalidLine(i + 1))?;
+                    data.reserve(size * size * size);
+                },
+                "DOMAIN_MIN" | "DOMAIN_MAX" => {
+                    let expected = if first == "DOMAIN_MIN" { 0.0 } else { 1.0 };
+                    if !parts.all(|x| x.parse::<f32>().map(|v| v == expected).unwrap_or(false)) {
+                        return Err(ColorLutError::Unsupported(first.to_string()));
+                    }
+                },
+                _ if first.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                    return Err(ColorLutError::Unsupported(first.to_string()));
+                },
+                _ => {
+                    let mut rgb = [0.0f32; 3];
+                    let mut values = line.split_whitespace().map(|x| x.parse::<f32>());
+                    for v in rgb.iter_mut() {
+                        *v = values.next().and_then(|x| x.ok()).ok_or(ColorLutError::InvalidLine(i + 1))?;
+                    }
+                    data.push(rgb);
+                }
+            }
+        }
+        Self::new(size, data, max_value)
+    }
+
+    #[inline]
+    pub fn apply(&self, v: Vector4<f32>) -> Vector4<f32> {
+        let n = self.size;
+        let s = self.max_value.max(1e-6);
+        let scale = (n - 1) as f32;
+        // `max` first, so NaN maps to 0
+        let pos = |x: f32| -> (usize, f32) {
+            let p = (x / s).max(0.0).min(1.0) * scale;
+            let i = (p as usize).min(n - 2);
+            (i, p - i as f32)
+        };
+        let ((r, tr), (g, tg), (b, tb)) = (pos(v[0]), pos(v[1]), pos(v[2]));
+        let at = |r: usize, g: usize, b: usize| -> [f32; 3] { self.data[r + n * (g + n * b)] };
+        let lerp = |a: [f32; 3], b: [f32; 3], t: f32| -> [f32; 3] { [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t] };
+
+        let c00 = lerp(at(r, g,     b    ), at(r + 1, g,     b    ), tr);
+        let c10 = lerp(at(r, g + 1, b    ), at(r + 1, g + 1, b    ), tr);
+        let c01 = lerp(at(r, g,     b + 1), at(r + 1, g,     b + 1), tr);
+        let c11 = lerp(at(r, g + 1, b + 1), at(r + 1, g + 1, b + 1), tr);
+        let c = lerp(lerp(c00, c10, tg), lerp(c01, c11, tg), tb);
+        Vector4::new(c[0] * s, c[1] * s, c[2] * s, v[3])
+    }
+}
+
+// Palette size limit, the colors are passed to the shader in the uniform buffer
+pub const MAX_BACKGROUND_REGIONS: usize = 8;
+
+#[derive(Debug)]
+pub enum BackgroundRegionsError {
+    SizeMismatch((usize, usize)),       // indices, width * height
+    TooManyColors(usize),               // palette size
+}
+impl std::fmt::Display for BackgroundRegionsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BackgroundRegionsError::SizeMismatch(v)  => write!(f, "Region mask has {} entries, expected {}", v.0, v.1),
+            BackgroundRegionsError::TooManyColors(v) => write!(f, "Region palette has {} colors, at most {} are supported", v, MAX_BACKGROUND_REGIONS),
+        }
+    }
+}
+impl std::error::Error for BackgroundRegionsError { }
+
+// Low resolution map of background palette indices, stretched over the output and sampled with nearest neighbor.
+// Output pixels that don't map to the source take the color of their region. Indices past the palette keep the flat or gradient background
+#[derive(Clone, PartialEq)]
+pub struct BackgroundRegions {
+    pub width: usize,
+    pub height: usize,
+    pub indices: Vec<u8>, // Row-major, `width * height` entries
+    pub palette: Vec<Vector4<f32>>, // Same range as the background color
+}
+impl BackgroundRegions {
+    pub fn new(width: usize, height: usize, indices: Vec<u8>, palette: Vec<Vector4<f32>>) -> Result<Self, BackgroundRegionsError> {
+        if width == 0 || height == 0 || indices.len() != width * height { return Err(BackgroundRegionsError::SizeMismatch((indices.len(), width * height))); }
+        if palette.len() > MAX_BACKGROUND_REGIONS { return Err(BackgroundRegionsError::TooManyColors(palette.len())); }
+        Ok(Self { width, height, indices, palette })
+    }
+
+    // Palette color at the output position, None if the region index isn't in the palette
+    pub fn color_at(&self, pt: (f32, f32), output_size: (f32, f32)) -> Option<Vector4<f32>> {
+        let x = ((pt.0 + 0.5) / output_size.0.max(1.0) * self.width as f32).max(0.0).min(self.width as f32 - 1.0) as usize;
+        let y = ((pt.1 + 0.5) / output_size.1.max(1.0) * self.height as f32).max(0.0).min(self.height as f32 - 1.0) as usize;
+        self.palette.get(self.indices[y * self.width + x] as usize).copied()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ChromaSiting {
+    Left,  // MPEG-2, H.264
+    Center // JPEG, MPEG-1
+}
+
+const BAYER_4X4: [[f32; 4]; 4] = [[0.0, 8.0, 2.0, 10.0], [12.0, 4.0, 14.0, 6.0], [3.0, 11.0, 1.0, 9.0], [15.0, 7.0, 13.0, 5.0]];
+const SUPERSAMPLE_THRESHOLD: f32 = 2.828427; // 2 source pixels per output pixel, along the diagonal
+const SUPERSAMPLE_OFFSETS: [(f32, f32); 4] = [(-0.25, -0.25), (0.25, 0.25), (0.25, -0.25), (-0.25, 0.25)];
+
+// Interpolation const parameter of `Interpolation::Adaptive`, the tap count is chosen per pixel by `adaptive_taps`.
+// Supported by the functions built on `undistort_pixel`, e.g. `undistort_image_cpu`, `undistort_image_cpu_banded` and `undistort_image_cpu_yuv420`
+pub const INTERPOLATION_ADAPTIVE: i32 = 1;
+const ADAPTIVE_BILINEAR_MAX_SCALE: f32 = 1.25; // Source pixels per output pixel, up to a slight minification bilinear doesn't alias visibly
+const ADAPTIVE_BICUBIC_MAX_SCALE: f32 = 2.0;
+
+// Tap count for `INTERPOLATION_ADAPTIVE` from the number of source pixels per output pixel
+#[inline]
+fn adaptive_taps(scale: f32) -> i32 {
+    if scale <= ADAPTIVE_BILINEAR_MAX_SCALE { 2 } else if scale <= ADAPTIVE_BICUBIC_MAX_SCALE { 4 } else { 8 }
+}
+
+// Radius of the Lanczos4 table, a larger `FrameParams::kernel_radius` is evaluated at runtime by `sample_input_wide`
+pub const MAX_TABLE_KERNEL_RADIUS: f32 = 4.0;
+const MAX_KERNEL_RADIUS: usize = 32;
+
+// Lanczos4 stretched to `radius` source pixels, i.e. the table kernel with its cutoff lowered by `radius / 4`.
+// Writes the normalized weights of the `2 * ceil(radius)` taps around `v` and returns the first tap and the tap count
+fn wide_kernel_taps(v: f32, radius: f32, weights: &mut [f32; 2 * MAX_KERNEL_RADIUS]) -> (i32, usize) {
+    let r = radius.ceil() as i32;
+    let first = v.floor() as i32 - (r - 1);
+    let n = 2 * r as usize;
+    let scale = MAX_TABLE_KERNEL_RADIUS / radius;
+    let mut sum = 0.0;
+    for (i, w) in weights[..n].iter_mut().enumerate() {
+        let d = (v - (first + i as i32) as f32) * scale;
+        *w = if d.abs() < 1e-5 {
+            1.0
+        } else if d.abs() >= MAX_TABLE_KERNEL_RADIUS {
+            0.0
+        } else {
+            let pd = std::f32::consts::PI * d;
+            MAX_TABLE_KERNEL_RADIUS * pd.sin() * (pd / MAX_TABLE_KERNEL_RADIUS).sin() / (pd * pd)
+        };
+        sum += *w;
+    }
+    if sum.abs() > 1e-6 {
+        for w in &mut weights[..n] { *w /= sum; }
+    }
+    (first, n)
+}
+
+// Source rows and edge handling of the `sample_input_*` functions
+#[derive(Clone, Copy)]
+struct SampleSource<'a> {
+    pixels: &'a [u8],
+    first_row: usize, // `pixels` contains only the source rows starting at `first_row`, taps outside of them are treated as background
+    width: usize,
+    height: usize,
+    stride: usize,
+    edge_repeat: bool,
+    edge_mirror: bool,
+    edge_wrap: bool,
+    bg: Vector4<f32>,
+    straight_alpha: bool,        // Premultiply the taps before blending and divide the result back, only for 4 channel formats
+    defects: Option<&'a [bool]>, // Dead/hot source pixels, row-major `width * height`. Their taps are dropped, see `renormalize_defects`
+}
+impl<'a> SampleSource<'a> {
+    fn new(pixels: &'a [u8], first_row: usize, width: usize, height: usize, stride: usize, bg: Vector4<f32>) -> Self {
+        Self { pixels, first_row, width, height, stride, edge_repeat: false, edge_mirror: false, edge_wrap: false, bg, straight_alpha: false, defects: None }
+    }
+
+    // Resolves the options for the pixel type `T`, called once per sample before reading the taps
+    #[inline]
+    fn prepare<T: PixelType>(self) -> Self {
+        let straight_alpha = self.straight_alpha && T::COUNT == 4;
+        let prepared = Self { straight_alpha, defects: self.defects.filter(|x| x.len() == self.width * self.height), ..self };
+        Self { bg: prepared.premultiply(self.bg), ..prepared }
+    }
+
+    #[inline]
+    fn premultiply(&self, v: Vector4<f32>) -> Vector4<f32> {
+        if self.straight_alpha { Vector4::new(v[0] * v[3], v[1] * v[3], v[2] * v[3], v[3]) } else { v }
+    }
+
+    #[inline]
+    fn unpremultiply(&self, v: Vector4<f32>) -> Vector4<f32> {
+        if self.straight_alpha && v[3].abs() > 1e-6 { Vector4::new(v[0] / v[3], v[1] / v[3], v[2] / v[3], v[3]) } else { v }
+    }
+
+    // One past the last source row available in `pixels`
+    #[inline]
+    fn last_row(&self) -> i32 {
+        (self.first_row + (self.pixels.len() + self.stride - 1) / self.stride).min(self.height) as i32
+    }
+
+    // Premultiplied tap at row `ty` (within `first_row..last_row`) and column `tx`, the background outside of the source.
+    // None for a defective pixel. Taps across the seam fetch from the other side
+    #[inline]
+    fn tap<T: PixelType>(&self, ty: i32, tx: i32) -> Option<Vector4<f32>> {
+        let tx = if self.edge_wrap { tx.rem_euclid(self.width as i32) } else { tx };
+        if tx < 0 || tx >= self.width as i32 {
+            return Some(self.bg);
+        }
+        if self.defects.map_or(false, |d| d[ty as usize * self.width + tx as usize]) {
+            return None;
+        }
+        let bytes_per_pixel = T::COUNT * T::SCALAR_BYTES;
+        let px_index = (ty as usize - self.first_row) * self.stride + bytes_per_pixel * tx as usize;
+        let px1: &T = bytemuck::from_bytes(&self.pixels[px_index..px_index + bytes_per_pixel]);
+        Some(self.premultiply(PixelType::to_float(*px1)))
+    }
+}
+
+// The skipped weight of the defective taps is spread over the remaining ones, otherwise the output would darken around a dead pixel.
+// When the remaining weight is close to 0 (the sample is right on a dead pixel, or the negative lobes of bicubic/Lanczos cancel out), the plain average of the remaining taps is used instead
+#[inline]
+fn renormalize_defects(sum: Vector4<f32>, weight_sum: f32, tap_sum: Vector4<f32>, tap_count: usize) -> Vector4<f32> {
+    if weight_sum > 0.1 {
+        sum / weight_sum
+    } else if tap_count > 0 {
+        tap_sum / tap_count as f32
+    } else {
+        sum
+    }
+}
+
+#[derive(Debug)]
+pub enum ParamsError {
+    TooShort(usize),                // length
+    RowCountMismatch((usize, usize)), // matrix count, sensor height
+}
+impl std::fmt::Display for ParamsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParamsError::TooShort(v)         => write!(f, "Params array has {} rows, at least 4 are required", v),
+            ParamsError::RowCountMismatch(v) => write!(f, "Params array has {} row matrices, expected 1 or {} (sensor height)", v.0, v.1),
+        }
+    }
+}
+impl std::error::Error for ParamsError { }
+
+// The params array has 3 header rows followed by either a single matrix (no rolling shutter correction), or one matrix per sensor row.
+// Any other count means the per-row lookup would silently use wrong matrices for some rows
+pub fn validate_params(undistortion_params: &[[f32; 9]], height: usize) -> Result<(), ParamsError> {
+    if undistortion_params.len() < 4 { return Err(ParamsError::TooShort(undistortion_params.len())); }
+    let sensor_height = if undistortion_params[1][7] > 0.0 { undistortion_params[1][7] as usize } else { height };
+    let matrices = undistortion_params.len() - 3;
+    if matrices != 1 && matrices != sensor_height {
+        return Err(ParamsError::RowCountMismatch((matrices, sensor_height)));
+    }
+    Ok(())
+}
+
+// Params of one field of interlaced footage, rendered as a half-height frame. `parity` 0 is the top field (even rows), `second` is set for the field read out later.
+// Output field rows are mapped to full frame rows in the matrices, the source side is halved vertically in the camera matrix.
+// The fields are read out one after another, so the rolling shutter matrices of the first field come from the first half of the frame time and vice versa
+fn field_params(params: &[[f32; 9]], height: usize, parity: usize, second: bool) -> Vec<[f32; 9]> {
+    let p = parity as f32;
+    let sensor_height = if params[1][7] > 0.0 { params[1][7] as usize } else { height };
+    let field_height = (sensor_height + 1 - parity) / 2;
+    let matrices = params.len() - 3;
+
+    let mut out = params[..3].to_vec();
+    out[0][1] /= 2.0; // fy
+    out[0][3] = (out[0][3] - p) / 2.0; // cy
+    if params[1][7] > 0.0 { out[1][7] = field_height as f32; }
+
+    let rows = if matrices == 1 { 1 } else { field_height };
+    let offset = if second { matrices / 2 } else { 0 };
+    out.extend((0..rows).map(|i| {
+        let mut m = params[3 + (offset + i).min(matrices - 1)];
+        // y = 2 * field_y + parity
+        for r in [0, 3, 6] {
+            m[r + 2] += m[r + 1] * p;
+            m[r + 1] *= 2.0;
+        }
+        m
+    }));
+    out
+}
+
+// Normalized output radius -> source scale table, for transforms without rotation where the remap only depends on the distance from the center.
+// See `FrameParams::build_radial_lut`
+pub struct RadialLut {
+    step: f32,
+    values: Vec<(f32, f32)>, // scale, squared source radius for the vignette gain. NaN scale where the exact mapping has to be used
+}
+
+// Max distance between the table and the exact mapping, in source pixels
+const RADIAL_LUT_TOLERANCE: f32 = 0.05;
+
+// How the output samples of a frame were resolved, see `undistort_image_cpu_with_stats`. With supersampling, each sub-sample is counted
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RemapStats {
+    pub non_convergent: usize, // Lens correction add-back didn't converge, the sample fell back to the output center
+    pub out_of_fov: usize,     // Outside of the lens model, filled with the background
+    pub sampled: usize,        // Mapped to the source
+}
+
+// Shared by the rayon workers while rendering, converted to `RemapStats` after the frame
+#[derive(Default)]
+pub(crate) struct RemapCounters {
+    non_convergent: AtomicUsize,
+    out_of_fov: AtomicUsize,
+    sampled: AtomicUsize,
+}
+impl RemapCounters {
+    fn stats(&self) -> RemapStats {
+        RemapStats {
+            non_convergent: self.non_convergent.load(SeqCst),
+            out_of_fov: self.out_of_fov.load(SeqCst),
+            sampled: self.sampled.load(SeqCst),
+        }
+    }
+}
+
+// Per-frame lens parameters unpacked from the `FrameTransform` params array
+#[derive(Clone, Copy)]
+pub struct FrameParams<'a> {
+    pub params: &'a [[f32; 9]],
+    pub f: (f32, f32),
+    pub c: (f32, f32),
+    pub k: &'a [f32],
+    pub r_limit: f32,
+    pub lens_correction_amount: f32,
+    pub background_mode: f32,
+    pub fov: f32,
+    pub supersample: usize,
+    pub orientation: i32,
+    pub sensor_size: (f32, f32),
+    pub identity_threshold: f32,
+    pub dither: bool,
+    pub super_fisheye: bool,
+    pub max_theta_d: f32,
+    pub interlacing: i32, // 0 - progressive, 1 - top field first, 2 - bottom field first. Fields are rendered separately
+    pub show_rolling_shutter: bool,
+    pub straight_alpha: bool,
+    pub distortion_enabled: bool, // false - rotation only preview, the lens model is replaced with a pinhole projection
+    pub sharpen: f32, // Unsharp mask strength applied in the resample, 0 - disabled
+    pub kernel_radius: f32, // Interpolation kernel radius in source pixels. Above `MAX_TABLE_KERNEL_RADIUS` the weights are computed per sample, see `sample_input_wide`
+    pub vignette_gain: Option<&'a [f32]>,
+    pub radial_lut: Option<&'a RadialLut>,
+    pub color_transform: Option<&'a ColorTransform>,
+    pub color_lut: Option<&'a ColorLut>,
+    pub stmap: Option<&'a [(f32, f32)]>, // Source position of each output pixel (row-major), replaces the lens model and rotation. NaN for background
+    pub bg_gradient: Option<(Vector4<f32>, Vector4<f32>)>, // Radial background from the output center to the corners, replaces the flat `bg`
+    pub bg_regions: Option<&'a BackgroundRegions>, // Background color per output region, takes precedence over `bg_gradient`
+    pub chromatic_aberration: Option<[f32; 3]>, // R, G, B magnification of the source position around the lens center, 1.0 - unchanged
+    pub source_x: usize, // First column of the view in the input buffer, e.g. the right eye of side-by-side stereo. `width` is the width of the view
+    pub defect_mask: Option<&'a [bool]>, // Dead/hot pixels of the input buffer, row-major `width * height`. Flagged pixels are excluded from the interpolation
+    pub(crate) counters: Option<&'a RemapCounters>,
+    pub f2: (f32, f32),
+    pub out_c: (f32, f32),
+    pub height: usize,
+}
+
+impl<'a> FrameParams<'a> {
+    // `undistortion_params` have to pass `validate_params`, public entry points check it and return early instead
+    pub fn new(undistortion_params: &'a [[f32; 9]], height: usize, output_width: usize, output_height: usize) -> Self {
+        let f = (undistortion_params[0][0], undistortion_params[0][1]);
+        let lens_correction_amount = undistortion_params[1][0];
+        let fov = undistortion_params[1][2];
+        let pixel_aspect = if undistortion_params[1][3] > 0.0 { undistortion_params[1][3] } else { 1.0 };
+
+        let factor = (1.0 - lens_correction_amount).max(0.001); // FIXME: this is close but wrong
+        let f2 = ((f.0 / fov / factor * pixel_aspect), (f.1 / fov / factor));
+        let c = (undistortion_params[0][2], undistortion_params[0][3]);
+        let out_c = (output_width as f32 / 2.0, output_height as f32 / 2.0);
+
+        Self {
+            params: undistortion_params,
+            f,
+            c,
+            k: &undistortion_params[0][4..8],
+            r_limit: undistortion_params[0][8],
+            lens_correction_amount,
+            background_mode: undistortion_params[1][1],
+            fov,
+            supersample: (undistortion_params[1][4].max(1.0) as usize).min(4),
+            orientation: undistortion_params[1][5] as i32,
+            identity_threshold: undistortion_params[1][8],
+            dither: undistortion_params[2][0] > 0.5,
+            super_fisheye: undistortion_params[2][1] > 0.5,
+            max_theta_d: undistortion_params[2][4],
+            interlacing: undistortion_params[2][8] as i32,
+            show_rolling_shutter: undistortion_params[2][2] > 0.5,
+            straight_alpha: undistortion_params[2][3] > 0.5,
+            distortion_enabled: undistortion_params[2][5] < 0.5,
+            sharpen: undistortion_params[2][6].max(0.0),
+            kernel_radius: 0.0,
+            vignette_gain: None,
+            radial_lut: None,
+            color_transform: None,
+            color_lut: None,
+            stmap: None,
+            bg_gradient: None,
+            bg_regions: None,
+            chromatic_aberration: None,
+            source_x: 0,
+            defect_mask: None,
+            counters: None,
+            sensor_size: if undistortion_params[1][7] > 0.0 { (undistortion_params[1][6], undistortion_params[1][7]) } else { (0.0, height as f32) },
+            f2,
+            out_c,
+            height,
+        }
+    }
+
+    // Calculate source `y` for rolling shutter
+    pub fn source_row(&self, x: f32, y: f32) -> usize {
+        let mut sy = y.max(0.0) as usize;
+        if self.params.len() > 4 {
+            let undistortion_params = self.params[3 + (self.params.len() - 3) / 2]; // Use middle matrix
+            let _x = y * undistortion_params[1] + undistortion_params[2] + (x * undistortion_params[0]);
+            let _y = y * undistortion_params[4] + undistortion_params[5] + (x * undistortion_params[3]);
+            let _w = y * undistortion_params[7] + undistortion_params[8] + (x * undistortion_params[6]);
+            if _w > 0.0 {
+                let posx = _x / _w;
+                let posy = _y / _w;
+                let pt = self.project((posx, posy));
+                sy = (pt.1.round() as i32).min(self.sensor_size.1 as i32).max(0) as usize;
+            }
+        }
+        sy
+    }
+
+    // Background at the output position. The distance is normalized to the output corner, so both colors are reached regardless of the aspect ratio
+    pub fn background_at(&self, pt: (f32, f32), bg: Vector4<f32>) -> Vector4<f32> {
+        if let Some(color) = self.bg_regions.and_then(|x| x.color_at(pt, (self.out_c.0 * 2.0, self.out_c.1 * 2.0))) {
+            return color;
+        }
+        match self.bg_gradient {
+            Some((center, edge)) => {
+                let t = ((pt.0 - self.out_c.0).hypot(pt.1 - self.out_c.1) / self.out_c.0.hypot(self.out_c.1).max(1e-6)).min(1.0);
+                center + (edge - center) * t
+            },
+            None => bg
+        }
+    }
+
+    // Normalized camera coordinates to sensor pixels, through the lens model unless it's disabled for the rotation only preview
+    fn project(&self, pos: (f32, f32)) -> (f32, f32) {
+        if self.distortion_enabled {
+            distort_point(pos, self.f, self.c, self.k, 0.0)
+        } else {
+            (pos.0 * self.f.0 + self.c.0, pos.1 * self.f.1 + self.c.1)
+        }
+    }
+
+    // Add lens distortion back. Returns None if the inverse didn't converge
+    pub fn undistort_output(&self, pt: (f32, f32)) -> Option<(f32, f32)> {
+        if self.lens_correction_amount < 1.0 && self.distortion_enabled {
+            // The output camera matrix puts the principal point at the output center, so the distortion is centered there
+            let pt = ((pt.0 - self.out_c.0) / self.f2.0, (pt.1 - self.out_c.1) / self.f2.1);
+            let pt = undistort_point(pt, self.k, self.lens_correction_amount, self.super_fisheye, self.max_theta_d)?;
+            return Some(((pt.0 * self.f2.0) + self.out_c.0, (pt.1 * self.f2.1) + self.out_c.1));
+        }
+        Some(pt)
+    }
+
+    // Direction of the output pixel in the stabilized camera space, unnormalized. Same mapping as `undistort_output` + `rotate_and_distort`,
+    // but kept homogeneous, so a ray past 90° off the lens axis (super fisheye) points backwards instead of being clamped.
+    // Where the lens model can't be inverted, the super fisheye fallback of `undistort_point` (theta = theta_d) is used unclamped
+    fn output_ray(&self, pt: (f32, f32)) -> Option<Vector3<f32>> {
+        let h = if self.lens_correction_amount < 1.0 && self.distortion_enabled {
+            let p = ((pt.0 - self.out_c.0) / self.f2.0, (pt.1 - self.out_c.1) / self.f2.1);
+            match undistort_point(p, self.k, self.lens_correction_amount, false, self.max_theta_d) {
+                Some(u) => Vector3::new(u.0 * self.f2.0 + self.out_c.0, u.1 * self.f2.1 + self.out_c.1, 1.0),
+                None if self.super_fisheye => {
+                    let theta = p.0.hypot(p.1);
+                    let (sin, cos) = theta.sin_cos();
+                    let s = if theta > 1e-6 { sin / theta } else { 1.0 };
+                    Vector3::new(self.out_c.0 * cos + self.f2.0 * p.0 * s, self.out_c.1 * cos + self.f2.1 * p.1 * s, cos)
+                },
+                None => return None
+            }
+        } else {
+            Vector3::new(pt.0, pt.1, 1.0)
+        };
+        let m = &self.params[(self.source_row(pt.0, pt.1) + 3).min(self.params.len() - 1)];
+        let ray = Vector3::new(m[0] * h.x + m[1] * h.y + m[2] * h.z, m[3] * h.x + m[4] * h.y + m[5] * h.z, m[6] * h.x + m[7] * h.y + m[8] * h.z);
+        if ray.iter().all(|v| v.is_finite()) && ray.norm() > 0.0 { Some(ray) } else { None }
+    }
+
+    // Returns source pixel position, or None if the point is outside of the lens model
+    pub fn rotate_and_distort(&self, pt: (f32, f32), row: usize) -> Option<(f32, f32)> {
+        self.rotate_and_distort_r2(pt, row).map(|x| x.0)
+    }
+
+    // Same as `rotate_and_distort`, but also returns the squared radius in normalized camera coordinates
+    fn rotate_and_distort_r2(&self, pt: (f32, f32), row: usize) -> Option<((f32, f32), f32)> {
+        let undistortion_params = &self.params[(row + 3).min(self.params.len() - 1)];
+        let _x = pt.1 * undistortion_params[1] + undistortion_params[2] + (pt.0 * undistortion_params[0]);
+        let _y = pt.1 * undistortion_params[4] + undistortion_params[5] + (pt.0 * undistortion_params[3]);
+        let _w = pt.1 * undistortion_params[7] + undistortion_params[8] + (pt.0 * undistortion_params[6]);
+
+        if _w > 0.0 {
+            let posx = _x / _w;
+            let posy = _y / _w;
+            let r2 = posx*posx + posy*posy;
+
+            if self.r_limit > 0.0 && r2 > self.r_limit*self.r_limit {
+                return None;
+            }
+
+            // Degenerate lens coefficients can produce NaN/Inf, treat it as outside of the lens model
+            let src = self.project((posx, posy));
+            if !src.0.is_finite() || !src.1.is_finite() {
+                return None;
+            }
+            return Some((self.to_buffer(src), r2));
+        }
+        None
+    }
+
+    // True if the output pixel maps inside the `width` x `height` source, ignoring the edge background modes
+    pub fn maps_inside(&self, pt: (f32, f32), width: usize, height: usize) -> bool {
+        let row = self.source_row(pt.0, pt.1);
+        let src = self.undistort_output(pt).and_then(|pt| self.rotate_and_distort(pt, row));
+        matches!(src, Some(src) if src.0 >= 0.0 && src.1 >= 0.0 && src.0 <= width as f32 - 1.0 && src.1 <= height as f32 - 1.0)
+    }
+
+    // Converts sensor coordinates to the coordinates in the stored (rotated) input buffer
+    pub fn to_buffer(&self, pt: (f32, f32)) -> (f32, f32) {
+        let (w, h) = self.sensor_size;
+        match self.orientation {
+            1 => (h - 1.0 - pt.1, pt.0),
+            2 => (w - 1.0 - pt.0, h - 1.0 - pt.1),
+            3 => (pt.1, w - 1.0 - pt.0),
+            _ => pt
+        }
+    }
+
+    // Source position from the STMap at the nearest output pixel, None for background
+    fn stmap_lookup(&self, map: &[(f32, f32)], pt: (f32, f32)) -> Option<(f32, f32)> {
+        let (w, h) = ((self.out_c.0 * 2.0) as usize, (self.out_c.1 * 2.0) as usize);
+        if w == 0 || h == 0 { return None; }
+        let x = (pt.0.round().max(0.0) as usize).min(w - 1);
+        let y = (pt.1.round().max(0.0) as usize).min(h - 1);
+        map.get(y * w + x).copied().filter(|v| v.0.is_finite() && v.1.is_finite())
+    }
+
+    // Same as `map_coord`, but uses the given rolling shutter row and falls back to the center if the lens correction doesn't converge
+    pub fn map_output(&self, pt: (f32, f32), row: usize) -> Option<(f32, f32)> {
+        if let Some(map) = self.stmap { return self.stmap_lookup(map, pt); }
+        self.rotate_and_distort(self.undistort_output(pt).unwrap_or(self.out_c), row)
+    }
+
+    // `map_output` with the vignette gain at the source position, 1.0 if there's no gain table
+    pub fn map_output_with_gain(&self, pt: (f32, f32), row: usize) -> Option<((f32, f32), f32)> {
+        if let Some(map) = self.stmap { return self.stmap_lookup(map, pt).map(|src| (src, 1.0)); }
+        if let Some(res) = self.radial_lut.and_then(|lut| self.map_radial(lut, pt)) {
+            return Some(res);
+        }
+        let (src, r2) = self.rotate_and_distort_r2(self.undistort_output(pt).unwrap_or(self.out_c), row)?;
+        Some((src, self.vignette_gain.map(|lut| Self::lookup_gain(lut, r2)).unwrap_or(1.0)))
+    }
+
+    // Without rolling shutter and rotation the output center maps to the lens center and the remap is radially symmetric,
+    // so it can be sampled once along the radius instead of per pixel. Returns None if the transform isn't symmetric,
+    // or if the table doesn't match the exact mapping within `RADIAL_LUT_TOLERANCE` on a test grid
+    pub fn build_radial_lut(&self) -> Option<RadialLut> {
+        if self.params.len() != 4 || !self.distortion_enabled || self.stmap.is_some() { return None; }
+        let m = &self.params[3];
+        if m[0] <= 0.0 || m[4] <= 0.0 || (m[8] - 1.0).abs() > 1e-6 { return None; }
+        if [m[1], m[3], m[6], m[7]].iter().any(|v| v.abs() > m[0] * 1e-4) { return None; }
+        // Center has to map to the optical axis and both axes need the same scale after `undistort_output`
+        if (self.out_c.0 * m[0] + m[2]).abs() > m[0] * 0.01 || (self.out_c.1 * m[4] + m[5]).abs() > m[4] * 0.01 { return None; }
+        let (ax, ay) = (self.f2.0 * m[0], self.f2.1 * m[4]);
+        if (ax - ay).abs() > ax.abs() * 1e-5 { return None; }
+
+        // Half pixel steps up to the corner, with a margin for the supersampling offsets
+        let max_r = ((self.out_c.0 + 1.0) / self.f2.0).hypot((self.out_c.1 + 1.0) / self.f2.1);
+        let samples = (self.out_c.0.hypot(self.out_c.1) * 2.0) as usize + 4;
+        let step = max_r / (samples - 1) as f32;
+
+        let values = (0..samples).map(|i| {
+            let r = (i as f32 * step).max(step * 1e-3); // Scale is a ratio, so it's evaluated slightly off the center
+            let pt = match self.undistort_output((self.out_c.0 + r * self.f2.0, self.out_c.1)) {
+                Some(pt) => pt,
+                None => return (f32::NAN, 0.0)
+            };
+            let pos = (pt.0 * m[0] + m[2], pt.1 * m[4] + m[5]);
+            let r2 = pos.0 * pos.0 + pos.1 * pos.1;
+            if self.r_limit > 0.0 && r2 > self.r_limit * self.r_limit {
+                return (f32::NAN, r2);
+            }
+            let src = distort_point(pos, self.f, self.c, self.k, 0.0);
+            let scale = (src.0 - self.c.0) / (self.f.0 * r);
+            (if scale.is_finite() { scale } else { f32::NAN }, r2)
+        }).collect();
+        let lut = RadialLut { step, values };
+
+        let out_size = (self.out_c.0 * 2.0, self.out_c.1 * 2.0);
+        for gy in 0..5 {
+            for gx in 0..5 {
+                let pt = ((out_size.0 - 1.0) * gx as f32 / 4.0, (out_size.1 - 1.0) * gy as f32 / 4.0);
+                if let (Some(a), Some(b)) = (self.map_radial(&lut, pt), self.rotate_and_distort(self.undistort_output(pt).unwrap_or(self.out_c), 0)) {
+                    if (a.0.0 - b.0).powi(2) + (a.0.1 - b.1).powi(2) > RADIAL_LUT_TOLERANCE * RADIAL_LUT_TOLERANCE {
+                        return None;
+                    }
+                }
+            }
+        }
+        Some(lut)
+    }
+
+    // Source position and vignette gain from the radial table, None if the exact mapping has to be used
+    fn map_radial(&self, lut: &RadialLut, pt: (f32, f32)) -> Option<((f32, f32), f32)> {
+        let q = ((pt.0 - self.out_c.0) / self.f2.0, (pt.1 - self.out_c.1) / self.f2.1);
+        let pos = q.0.hypot(q.1) / lut.step;
+        let i = pos as usize;
+        if i + 1 >= lut.values.len() { return None; }
+        let (g0, r0) = lut.values[i];
+        let (g1, r1) = lut.values[i + 1];
+        if g0.is_nan() || g1.is_nan() { return None; }
+        let t = pos - i as f32;
+        let g = g0 + (g1 - g0) * t;
+        let src = self.to_buffer((self.c.0 + self.f.0 * q.0 * g, self.c.1 + self.f.1 * q.1 * g));
+        Some((src, self.vignette_gain.map(|gain| Self::lookup_gain(gain, r0 + (r1 - r0) * t)).unwrap_or(1.0)))
+    }
+
+    // Table is sampled uniformly in angle from the optical axis (0°) to 90°, linearly interpolated
+    fn lookup_gain(lut: &[f32], r2: f32) -> f32 {
+        if lut.is_empty() { return 1.0; }
+        let pos = (r2.sqrt().atan() / std::f32::consts::FRAC_PI_2 * (lut.len() - 1) as f32).max(0.0);
+        let i = (pos as usize).min(lut.len() - 1);
+        let next = (i + 1).min(lut.len() - 1);
+        let t = pos - i as f32;
+        lut[i] + (lut[next] - lut[i]) * t.min(1.0)
+    }
+
+    // Horizontal source shift (in pixels) of the rolling shutter correction for output row `y`, relative to the middle matrix
+    pub fn rolling_shutter_shift(&self, y: usize) -> f32 {
+        if self.params.len() <= 4 { return 0.0; }
+        let pt = self.undistort_output((self.out_c.0, y as f32)).unwrap_or(self.out_c);
+        let row = self.source_row(self.out_c.0, y as f32);
+        match (self.rotate_and_distort(pt, row), self.rotate_and_distort(pt, (self.params.len() - 3) / 2)) {
+            (Some(a), Some(b)) => a.0 - b.0,
+            _ => 0.0
+        }
+    }
+
+    // Source position of the output pixel without any rotation or lens correction, for `BackgroundMode::SourcePassthrough`
+    pub fn passthrough_point(&self, pt: (f32, f32), width: usize) -> (f32, f32) {
+        let out_size = (self.out_c.0 * 2.0, self.out_c.1 * 2.0);
+        (
+            (pt.0 * width as f32 / out_size.0.max(1.0)).max(0.0).min(width as f32 - 1.0),
+            (pt.1 * self.height as f32 / out_size.1.max(1.0)).max(0.0).min(self.height as f32 - 1.0)
+        )
+    }
+
+    // True if every row matrix maps the output within `identity_threshold` pixels of the plain (scaled) input position.
+    // Checked on a 3x3 grid, which is enough for a projective transform with smooth lens distortion
+    pub fn is_near_identity(&self, width: usize) -> bool {
+        if self.identity_threshold <= 0.0 || self.params.len() < 4 || self.stmap.is_some() { return false; }
+        let out_size = (self.out_c.0 * 2.0, self.out_c.1 * 2.0);
+        let scale = (width as f32 / out_size.0.max(1.0), self.height as f32 / out_size.1.max(1.0));
+        let threshold_sq = self.identity_threshold * self.identity_threshold;
+        for row in 0..self.params.len() - 3 {
+            for gy in 0..3 {
+                for gx in 0..3 {
+                    let pt = ((out_size.0 - 1.0) * gx as f32 / 2.0, (out_size.1 - 1.0) * gy as f32 / 2.0);
+                    match self.undistort_output(pt).and_then(|x| self.rotate_and_distort(x, row)) {
+                        Some(src) if (src.0 - pt.0 * scale.0).powi(2) + (src.1 - pt.1 * scale.1).powi(2) <= threshold_sq => { },
+                        _ => return false
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    // Interlaced footage is undistorted field by field, see `Undistortion::render_frame`
+    fn renders_fields(&self, height: usize, output_height: usize) -> bool {
+        (self.interlacing == 1 || self.interlacing == 2) && self.orientation == 0 && self.stmap.is_none() && height >= 2 && output_height >= 2
+    }
+
+    // No per-pixel stages and the mapping is within `identity_threshold`, so the frame is rendered as a straight copy or a plain resize
+    fn is_plain_resize(&self, width: usize) -> bool {
+        self.vignette_gain.is_none() && self.color_transform.is_none() && self.color_lut.is_none() && self.counters.is_none() && self.sharpen <= 0.0 && self.chromatic_aberration.is_none() && self.defect_mask.is_none() && self.source_x == 0 && self.is_near_identity(width)
+    }
+
+    // Conservative range of source rows needed for the output rows, estimated on a grid and padded for the interpolation taps and the curvature between samples
+    pub fn source_rows(&self, output_rows: Range<usize>, output_width: usize, width: usize, taps: usize) -> Range<usize> {
+        const STEP: usize = 16;
+        let height = self.height as f32;
+        let mut min_y = f32::MAX;
+        let mut max_y = f32::MIN;
+        let ys = output_rows.clone().step_by(STEP / 4).chain(std::iter::once(output_rows.end.max(1) - 1));
+        for y in ys {
+            let xs = (0..output_width).step_by(STEP).chain(std::iter::once(output_width.max(1) - 1));
+            for x in xs {
+                let pt = (x as f32, y as f32);
+                let src = self.map_output(pt, self.source_row(pt.0, pt.1)).unwrap_or_else(|| self.passthrough_point(pt, width));
+                min_y = min_y.min(src.1);
+                max_y = max_y.max(src.1);
+            }
+        }
+        if min_y > max_y { return 0..self.height; }
+
+        if let Some(ca) = self.chromatic_aberration {
+            // Red and blue are sampled at a different magnification around the lens center
+            let cy = self.to_buffer(self.c).1;
+            let (lo, hi) = (min_y, max_y);
+            for s in ca {
+                min_y = min_y.min(cy + (lo - cy) * s);
+                max_y = max_y.max(cy + (hi - cy) * s);
+            }
+        }
+        let edge_mirror = self.background_mode > 1.9 && self.background_mode < 2.1; // 2
+        if edge_mirror {
+            // Mirrored rows come from the other side of the edge
+            if min_y < 3.0 { max_y = max_y.max(6.0 - min_y); }
+            if max_y > height - 3.0 { min_y = min_y.min(2.0 * (height - 3.0) - max_y); }
+        }
+        let margin = (taps / 2 + STEP / 2) as f32;
+        let start = (min_y - margin).floor().max(0.0).min(height) as usize;
+        let end = (max_y + margin).ceil().max(0.0).min(height) as usize;
+        start..end.max(start + 1).min(self.height)
+    }
+
+    // Local minification at the output position from forward differences of the mapping, `src` is the already mapped position of `pt`.
+    // Costs two extra coordinate evaluations per pixel. Lanczos4 on the boundary of the valid area
+    pub fn adaptive_taps(&self, pt: (f32, f32), src: (f32, f32), row: usize) -> i32 {
+        match (self.map_output((pt.0 + 1.0, pt.1), row), self.map_output((pt.0, pt.1 + 1.0), row)) {
+            (Some(px), Some(py)) => adaptive_taps((px.0 - src.0).hypot(px.1 - src.1).max((py.0 - src.0).hypot(py.1 - src.1))),
+            _ => 8
+        }
+    }
+
+    // Pixel is on the boundary of the valid area, or its source footprint is large
+    pub fn needs_supersampling(&self, pt: (f32, f32), row: usize) -> bool {
+        if self.stmap.is_some() { return false; } // One map entry per output pixel, sub-pixel offsets would sample the same entry
+        let corners = [
+            self.map_output((pt.0 - 0.5, pt.1 - 0.5), row),
+            self.map_output((pt.0 + 0.5, pt.1 - 0.5), row),
+            self.map_output((pt.0 - 0.5, pt.1 + 0.5), row),
+            self.map_output((pt.0 + 0.5, pt.1 + 0.5), row),
+        ];
+        let valid = corners[0].is_some();
+        if corners.iter().any(|x| x.is_some() != valid) { return true; }
+        if let [Some(c0), Some(c1), Some(c2), Some(c3)] = corners {
+            let d1 = ((c3.0 - c0.0).powi(2) + (c3.1 - c0.1).powi(2)).sqrt();
+            let d2 = ((c2.0 - c1.0).powi(2) + (c2.1 - c1.1).powi(2)).sqrt();
+            return d1.max(d2) > SUPERSAMPLE_THRESHOLD;
+        }
+        false
+    }
+
+    // Maps output pixel position to the source pixel position
+    pub fn map_coord(&self, x: f32, y: f32) -> Option<(f32, f32)> {
+        if let Some(map) = self.stmap { return self.stmap_lookup(map, (x, y)); }
+        let row = self.source_row(x, y);
+        let pt = self.undistort_output((x, y))?;
+        self.rotate_and_distort(pt, row)
+    }
+
+    // Derivative of the source position w.r.t. the output position, computed with central differences.
+    // Returns None if any of the neighboring points can't be mapped
+    pub fn jacobian(&self, pt: (f32, f32)) -> Option<Matrix2<f32>> {
+        const H: f32 = 0.5;
+        let px0 = self.map_coord(pt.0 - H, pt.1)?;
+        let px1 = self.map_coord(pt.0 + H, pt.1)?;
+        let py0 = self.map_coord(pt.0, pt.1 - H)?;
+        let py1 = self.map_coord(pt.0, pt.1 + H)?;
+        Some(Matrix2::new(
+            (px1.0 - px0.0) / (2.0 * H), (py1.0 - py0.0) / (2.0 * H),
+            (px1.1 - px0.1) / (2.0 * H), (py1.1 - py0.1) / (2.0 * H)
+        ))
+    }
+
+    // Output position that maps to the source position `src`, i.e. the inverse of `map_coord`, found with Newton iterations from `guess`.
+    // The result can be outside of the output frame. None if it doesn't converge, e.g. outside of the lens model or with an STMap
+    pub fn output_position(&self, src: (f32, f32), guess: (f32, f32)) -> Option<(f32, f32)> {
+        const TOLERANCE: f32 = 0.01;
+        let mut pt = guess;
+        for _ in 0..20 {
+            let cur = self.map_coord(pt.0, pt.1)?;
+            let err = Vector2::new(cur.0 - src.0, cur.1 - src.1);
+            if err.x.abs() < TOLERANCE && err.y.abs() < TOLERANCE { return Some(pt); }
+            let step = self.jacobian(pt)?.try_inverse()? * err;
+            pt = (pt.0 - step.x, pt.1 - step.y);
+            if !pt.0.is_finite() || !pt.1.is_finite() { return None; }
+        }
+        None
+    }
+}
+
+pub fn compute_jacobian(output_pt: (f32, f32), undistortion_params: &[[f32; 9]], height: usize, output_width: usize, output_height: usize) -> Option<Matrix2<f32>> {
+    if validate_params(undistortion_params, height).is_err() { return None; }
+    FrameParams::new(undistortion_params, height, output_width, output_height).jacobian(output_pt)
+}
+
+// Source positions of a `cols` x `rows` vertex grid spanning the output from the first to the last pixel, row-major. For mesh based renderers,
+// which approximate the remap by interpolating across the grid cells. Vertices where the lens correction doesn't converge or that are outside of the lens model are None
+pub fn compute_warp_grid(cols: usize, rows: usize, undistortion_params: &[[f32; 9]], height: usize, output_width: usize, output_height: usize) -> Vec<Option<(f32, f32)>> {
+    if cols < 2 || rows < 2 || validate_params(undistortion_params, height).is_err() || output_width == 0 || output_height == 0 { return Vec::new(); }
+    let frame = FrameParams::new(undistortion_params, height, output_width, output_height);
+    let step = ((output_width - 1) as f32 / (cols - 1) as f32, (output_height - 1) as f32 / (rows - 1) as f32);
+
+    (0..cols * rows).into_par_iter().map(|i| {
+        frame.map_coord((i % cols) as f32 * step.0, (i / cols) as f32 * step.1)
+    }).collect()
+}
+
+// Displacement of the source sample point of every output pixel (row-major) from the transform of frame N to the one of frame N + 1, in source pixels.
+// For temporal filters aligned to the stabilization warp. NaN where either transform doesn't converge or is outside of the lens model
+pub fn compute_motion_vectors(params_n: &[[f32; 9]], params_n1: &[[f32; 9]], height: usize, output_width: usize, output_height: usize) -> Vec<(f32, f32)> {
+    if validate_params(params_n, height).is_err() || validate_params(params_n1, height).is_err() { return Vec::new(); }
+    let frame_n = FrameParams::new(params_n, height, output_width, output_height);
+    let frame_n1 = FrameParams::new(params_n1, height, output_width, output_height);
+
+    (0..output_width * output_height).into_par_iter().map(|i| {
+        let (x, y) = ((i % output_width) as f32, (i / output_width) as f32);
+        match (frame_n.map_coord(x, y), frame_n1.map_coord(x, y)) {
+            (Some(a), Some(b)) => (b.0 - a.0, b.1 - a.1),
+            _ => (f32::NAN, f32::NAN)
+        }
+    }).collect()
+}
+
+// Tight bounding rectangle (x, y, width, height) of the output pixels that map inside the `width` x `height` source, or None if the whole output is background.
+// Only tests the inverse mapping, so it's much cheaper than rendering. Edge repeat/mirror background modes are ignored, i.e. the result is the same as for a solid background.
+pub fn valid_output_bounds(undistortion_params: &[[f32; 9]], width: usize, height: usize, output_width: usize, output_height: usize) -> Option<(usize, usize, usize, usize)> {
+    if validate_params(undistortion_params, height).is_err() { return None; }
+    let frame = FrameParams::new(undistortion_params, height, output_width, output_height);
+    let is_valid = |x: usize, y: usize| frame.maps_inside((x as f32, y as f32), width, height);
+
+    let (min_x, max_x, min_y, max_y) = (0..output_height).into_par_iter().filter_map(|y| {
+        let first = (0..output_width).find(|&x| is_valid(x, y))?;
+        let last = (first..output_width).rev().find(|&x| is_valid(x, y)).unwrap_or(first);
+        Some((first, last, y, y))
+    }).reduce_with(|a, b| (a.0.min(b.0), a.1.max(b.1), a.2.min(b.2), a.3.max(b.3)))?;
+
+    Some((min_x, min_y, max_x - min_x + 1, max_y - min_y + 1))
+}
+
+// Border of the `width` x `height` source projected to output coordinates, one point per source pixel along the edge, i.e. the edge of the covered area.
+// When the output is fully covered the contour is outside of the output frame and its distance to the output edge is the crop margin left, so callers have to clip it for drawing.
+// Border points that don't map to the output (outside of the lens model) are skipped
+pub fn coverage_contour(undistortion_params: &[[f32; 9]], width: usize, height: usize, output_width: usize, output_height: usize) -> Vec<(f32, f32)> {
+    if validate_params(undistortion_params, height).is_err() || width == 0 || height == 0 || output_width == 0 || output_height == 0 { return Vec::new(); }
+    let frame = FrameParams::new(undistortion_params, height, output_width, output_height);
+    let (w, h) = ((width - 1) as f32, (height - 1) as f32);
+    let scale = (output_width as f32 / width as f32, output_height as f32 / height as f32);
+
+    let border: Vec<(f32, f32)> = (0..width).flat_map(|x| [(x as f32, 0.0), (x as f32, h)])
+        .chain((1..height.saturating_sub(1)).flat_map(|y| [(0.0, y as f32), (w, y as f32)]))
+        .collect();
+    border.into_par_iter().filter_map(|src| frame.output_position(src, (src.0 * scale.0, src.1 * scale.1))).collect()
+}
+
+// Multiplier for the fov of `undistortion_params` at which the output is exactly covered by the `width` x `height` source, i.e. zero crop.
+// Below 1.0 the frame has to be zoomed in, above 1.0 there's margin left. The output border is scanned with the inverse mapping, and for each border point
+// the largest scale towards the output center that still maps inside the source is found, the result is the worst case over the border.
+// Scaling the output position around the center is the same as scaling the fov, so the value is comparable across frames with the same fov.
+// Assumes the covered area is star-shaped around the output center. Returns 0 if even the center doesn't map inside the source
+pub fn min_zoom_for_full_coverage(undistortion_params: &[[f32; 9]], width: usize, height: usize, output_width: usize, output_height: usize) -> f32 {
+    const MAX_ZOOM: f32 = 4.0;
+    const BORDER_STEP: usize = 8;
+    if validate_params(undistortion_params, height).is_err() || output_width == 0 || output_height == 0 { return 0.0; }
+    let frame = FrameParams::new(undistortion_params, height, output_width, output_height);
+    let c = ((output_width - 1) as f32 / 2.0, (output_height - 1) as f32 / 2.0);
+    let at_scale = |pt: (f32, f32), s: f32| (c.0 + (pt.0 - c.0) * s, c.1 + (pt.1 - c.1) * s);
+    if !frame.maps_inside(c, width, height) { return 0.0; }
+
+    let (w, h) = ((output_width - 1) as f32, (output_height - 1) as f32);
+    let xs = (0..output_width).step_by(BORDER_STEP).map(|x| x as f32).chain(std::iter::once(w));
+    let ys = (0..output_height).step_by(BORDER_STEP).map(|y| y as f32).chain(std::iter::once(h));
+    let border: Vec<(f32, f32)> = xs.flat_map(|x| [(x, 0.0), (x, h)]).chain(ys.flat_map(|y| [(0.0, y), (w, y)])).collect();
+
+    border.into_par_iter().map(|pt| {
+        if frame.maps_inside(at_scale(pt, MAX_ZOOM), width, height) { return MAX_ZOOM; }
+        let (mut lo, mut hi) = (0.0, MAX_ZOOM);
+        for _ in 0..20 {
+            let mid = (lo + hi) / 2.0;
+            if frame.maps_inside(at_scale(pt, mid), width, height) { lo = mid; } else { hi = mid; }
+        }
+        lo
+    }).reduce(|| MAX_ZOOM, f32::min)
+}
+
+// Horizontal and vertical field of view of the output in degrees, after the lens correction, zoom and fov adjustments.
+// The output edge midpoints are mapped back to camera rays and the angle is measured from each edge to the center ray, so it's not limited to 180°
+// for a super fisheye output. Each half is measured separately, so a principal point off the output center still gives the full extent.
+// The background is not taken into account, i.e. it's the fov of the output frame, not of the covered area. 0 for an axis whose edges don't map to a ray
+pub fn effective_fov(undistortion_params: &[[f32; 9]], height: usize, output_width: usize, output_height: usize) -> (f32, f32) {
+    if validate_params(undistortion_params, height).is_err() || output_width == 0 || output_height == 0 { return (0.0, 0.0); }
+    let frame = FrameParams::new(undistortion_params, height, output_width, output_height);
+    let (w, h) = (output_width as f32, output_height as f32);
+    let c = frame.out_c;
+    let center = match frame.output_ray(c) {
+        Some(x) => x,
+        None => return (0.0, 0.0)
+    };
+    let angle = |pt: (f32, f32)| frame.output_ray(pt).map(|ray| ray.cross(&center).norm().atan2(ray.dot(&center)));
+    let span = |a: (f32, f32), b: (f32, f32)| match (angle(a), angle(b)) {
+        (Some(a), Some(b)) => (a + b).to_degrees(),
+        _ => 0.0
+    };
+    (span((0.0, c.1), (w, c.1)), span((c.0, 0.0), (c.0, h)))
+}
+
+// Alpha mask of the output for compositing, one byte per output pixel: 255 where the source is sampled, 0 for background and passthrough.
+// Edge repeat/mirror/wrap background modes count as covered. Supersampled pixels get the covered share of the samples, same as `WgpuWrapper::undistort_image_with_coverage`
+pub fn coverage_mask(undistortion_params: &[[f32; 9]], width: usize, height: usize, output_width: usize, output_height: usize) -> Vec<u8> {
+    if validate_params(undistortion_params, height).is_err() { return vec![0; output_width * output_height]; }
+    let frame = FrameParams::new(undistortion_params, height, output_width, output_height);
+    let edge_mode = matches!(frame.background_mode.round() as i32, 1 | 2 | 4);
+    let is_covered = |pt: (f32, f32), row: usize| match frame.map_output(pt, row) {
+        Some(src) => edge_mode || (src.0 >= 0.0 && src.1 >= 0.0 && src.0 <= width as f32 - 1.0 && src.1 <= height as f32 - 1.0),
+        None => false
+    };
+
+    (0..output_width * output_height).into_par_iter().map(|i| {
+        let pt = ((i % output_width) as f32, (i / output_width) as f32);
+        let row = frame.source_row(pt.0, pt.1);
+        if frame.supersample > 1 && frame.needs_supersampling(pt, row) {
+            let offsets = &SUPERSAMPLE_OFFSETS[..frame.supersample];
+            let covered = offsets.iter().filter(|o| is_covered((pt.0 + o.0, pt.1 + o.1), row)).count();
+            ((covered * 255 + offsets.len() / 2) / offsets.len()) as u8
+        } else if is_covered(pt, row) {
+            255
+        } else {
+            0
+        }
+    }).collect()
+}
+
+// Converts a normalized STMap (as written by Nuke: u, v in 0-1 at pixel centers, v pointing up) to source pixel positions for `undistort_image_cpu_stmap`
+pub fn stmap_from_normalized(uv: &[(f32, f32)], width: usize, height: usize) -> Vec<(f32, f32)> {
+    uv.iter().map(|&(u, v)| (u * width as f32 - 0.5, (1.0 - v) * height as f32 - 0.5)).collect()
+}
+
+// Clamps, mirrors or wraps the source position for the edge background modes
+#[inline]
+fn adjust_edges(mut pt: (f32, f32), width: usize, height: usize, edge_repeat: bool, edge_mirror: bool, edge_wrap: bool) -> (f32, f32) {
+    let width_f = width as f32;
+    let height_f = height as f32;
+    if edge_wrap {
+        // 360° equirectangular input, horizontal axis wraps around, vertical is clamped
+        pt = (
+            pt.0.rem_euclid(width_f),
+            pt.1.max(0.0).min(height_f - 1.0),
+        );
+    } else if edge_repeat {
+        pt = (
+            pt.0.max(0.0).min(width_f - 1.0),
+            pt.1.max(0.0).min(height_f - 1.0),
+        );
+    } else if edge_mirror {
+        let rx = pt.0.round();
+        let ry = pt.1.round();
+        let width3 = width_f - 3.0;
+        let height3 = height_f - 3.0;
+        if rx > width3  { pt.0 = width3  - (rx - width3); }
+        if rx < 3.0     { pt.0 = 3.0 + width_f - (width3  + rx); }
+        if ry > height3 { pt.1 = height3 - (ry - height3); }
+        if ry < 3.0     { pt.1 = 3.0 + height_f - (height3 + ry); }
+    }
+    pt
+}
+
+// Returns the first tap and the `I` weights along one axis for source coordinate `v`
+#[inline]
+fn axis_taps<const I: i32>(v: f32) -> (i32, &'static [f32]) {
+    const INTER_BITS: usize = 5;
+    const INTER_TAB_SIZE: usize = 1 << INTER_BITS;
+
+    let shift = (I >> 2) + 1;
+    let offset = [0.0, 1.0, 3.0][I as usize >> 2];
+    let ind = [0, 64, 64 + 128][I as usize >> 2];
+
+    let s0 = ((v - offset) * INTER_TAB_SIZE as f32).round() as i32;
+
+    (s0 >> INTER_BITS, &COEFFS[ind + ((s0 as usize & (INTER_TAB_SIZE - 1)) << shift)..])
+}
+
+// Returns the top-left tap and the `IX` horizontal and `IY` vertical weights for source position `pt`
+#[inline]
+fn interpolation_taps<const IX: i32, const IY: i32>(pt: (f32, f32)) -> (i32, i32, &'static [f32], &'static [f32]) {
+    let (sx, coeffs_x) = axis_taps::<IX>(pt.0);
+    let (sy, coeffs_y) = axis_taps::<IY>(pt.1);
+    (sx, sy, coeffs_x, coeffs_y)
+}
+
+// Undistorts planar f32 data (one tightly packed `width * height` plane per channel), e.g. radiometric data kept as separate channels.
+// The mapping and interpolation weights are computed once per pixel and shared by all channels.
+// `bg` holds the background value per channel, missing values are 0. Returns false if the plane counts or sizes don't match.
+pub fn undistort_image_cpu_planar<const I: i32>(channels: &[&[f32]], outputs: &mut [&mut [f32]], width: usize, height: usize, output_width: usize, output_height: usize, undistortion_params: &[[f32; 9]], bg: &[f32]) -> bool {
+    if channels.len() != outputs.len() || validate_params(undistortion_params, height).is_err() { return false; }
+    if channels.iter().any(|c| c.len() < width * height) || outputs.iter().any(|c| c.len() < output_width * output_height) { return false; }
+
+    let frame = FrameParams::new(undistortion_params, height, output_width, output_height);
+    let edge_repeat = frame.background_mode > 0.9 && frame.background_mode < 1.1; // 1
+    let edge_mirror = frame.background_mode > 1.9 && frame.background_mode < 2.1; // 2
+    let passthrough = frame.background_mode > 2.9 && frame.background_mode < 3.1; // 3
+    let edge_wrap   = frame.background_mode > 3.9 && frame.background_mode < 4.1; // 4
+    let transparent = frame.background_mode > 4.9 && frame.background_mode < 5.1; // 5
+    let bg_at = |c: usize| bg.get(c).copied().unwrap_or(0.0);
+
+    // Row slices of all planes, so the rows can be processed in parallel
+    let mut rows: Vec<Vec<&mut [f32]>> = (0..output_height).map(|_| Vec::with_capacity(outputs.len())).collect();
+    for plane in outputs.iter_mut() {
+        for (y, row) in plane.chunks_mut(output_width).take(output_height).enumerate() {
+            rows[y].push(row);
+        }
+    }
+
+    rows.par_iter_mut().enumerate().for_each(|(y, row)| {
+        for x in 0..output_width {
+            let sy_row = frame.source_row(x as f32, y as f32);
+            let (src, repeat, mirror, wrap) = match frame.map_output((x as f32, y as f32), sy_row) {
+                Some(src) => (src, edge_repeat, edge_mirror, edge_wrap),
+                None if passthrough => (frame.passthrough_point((x as f32, y as f32), width), true, false, false),
+                None if transparent => continue,
+                None => {
+                    for (c, out) in row.iter_mut().enumerate() { out[x] = bg_at(c); }
+                    continue;
+                }
+            };
+            let (sx, sy, coeffs_x, coeffs_y) = interpolation_taps::<I, I>(adjust_edges(src, width, height, repeat, mirror, wrap));
+
+            for (c, out) in row.iter_mut().enumerate() {
+                let plane = channels[c];
+                let mut sum = 0.0;
+                for yp in 0..I {
+                    if sy + yp >= 0 && sy + yp < height as i32 {
+                        let line = &plane[(sy + yp) as usize * width..];
+                        let mut xsum = 0.0;
+                        for xp in 0..I {
+                            let tx = if wrap { (sx + xp).rem_euclid(width as i32) } else { sx + xp };
+                            let v = if tx >= 0 && tx < width as i32 { line[tx as usize] } else { bg_at(c) };
+                            xsum += v * coeffs_x[xp as usize];
+                        }
+                        sum += xsum * coeffs_y[yp as usize];
+                    } else {
+                        sum += bg_at(c) * coeffs_y[yp as usize];
+                    }
+                }
+                out[x] = sum;
+            }
+        }
+    });
+    true
+}
+
+impl<T: PixelType> Undistortion<T> {
+    // Samples the input at source position `pt` using `I`-tap interpolation
+    #[inline]
+    pub fn sample_input<const I: i32>(pixels: &[u8], width: usize, height: usize, stride: usize, pt: (f32, f32), edge_repeat: bool, edge_mirror: bool, bg: Vector4<f32>) -> Vector4<f32> {
+        Self::sample_input_rows::<I, I>(SampleSource { edge_repeat, edge_mirror, ..SampleSource::new(pixels, 0, width, height, stride, bg) }, pt, 0.0)
+    }
+
+    // Same as `sample_input`, with the rows, edge handling, straight alpha and defects of `source`.
+    // `sharpen` > 0 applies an unsharp mask from the same taps: the difference to their box average is added back, clamped to the tap range to avoid halos.
+    // Defective taps are dropped and the remaining weights renormalized, so they're interpolated over
+    #[inline]
+    fn sample_input_rows<const IX: i32, const IY: i32>(source: SampleSource, pt: (f32, f32), sharpen: f32) -> Vector4<f32> {
+        if !pt.0.is_finite() || !pt.1.is_finite() { return source.bg; }
+        let source = source.prepare::<T>();
+        let bg = source.bg;
+
+        let pt = adjust_edges(pt, source.width, source.height, source.edge_repeat, source.edge_mirror, source.edge_wrap);
+        let (sx, sy, coeffs_x, coeffs_y) = interpolation_taps::<IX, IY>(pt);
+
+        let mut sum = Vector4::from_element(0.0);
+        let last_row = source.last_row();
+
+        // Unsharp mask, box average and range of the taps
+        let mut box_sum = Vector4::<f32>::from_element(0.0);
+        let mut box_count = 0;
+        let mut lo = Vector4::<f32>::from_element(f32::MAX);
+        let mut hi = Vector4::<f32>::from_element(f32::MIN);
+        let mut add_tap = |v: &Vector4<f32>| if sharpen > 0.0 { box_sum += v; box_count += 1; lo = lo.inf(v); hi = hi.sup(v); };
+
+        // Sum of the weights of the taps used, only differs from 1 when defective pixels were skipped
+        let mut weight_sum = 0.0;
+        let mut skipped = false;
+        // Plain average of the taps used, the fallback when the skipped taps carried almost all of the weight
+        let (mut tap_sum, mut tap_count) = (Vector4::<f32>::from_element(0.0), 0);
+
+        for yp in 0..IY {
+            if sy + yp >= source.first_row as i32 && sy + yp < last_row {
+                let mut xsum = Vector4::<f32>::from_element(0.0);
+                let mut xweight = 0.0;
+                for xp in 0..IX {
+                    let pixel = match source.tap::<T>(sy + yp, sx + xp) {
+                        Some(v) => v,
+                        None => { skipped = true; continue; }
+                    };
+                    add_tap(&pixel);
+                    tap_sum += pixel;
+                    tap_count += 1;
+                    xsum += pixel * coeffs_x[xp as usize];
+                    xweight += coeffs_x[xp as usize];
+                }
+
+                sum += xsum * coeffs_y[yp as usize];
+                weight_sum += xweight * coeffs_y[yp as usize];
+            } else {
+                for _ in 0..IX { add_tap(&bg); }
+                tap_sum += bg * IX as f32;
+                tap_count += IX;
+                sum += bg * coeffs_y[yp as usize];
+                weight_sum += coeffs_y[yp as usize];
+            }
+        }
+        if skipped {
+            sum = renormalize_defects(sum, weight_sum, tap_sum, tap_count as usize);
+        }
+        if sharpen > 0.0 && box_count > 0 {
+            let mean = box_sum / box_count as f32;
+            for c in 0..3 {
+                sum[c] = (sum[c] + (sum[c] - mean[c]) * sharpen).max(lo[c]).min(hi[c]);
+            }
+        }
+        source.unpremultiply(sum)
+    }
+
+    // `sample_input_rows` with `taps` used for `INTERPOLATION_ADAPTIVE`, other modes ignore it
+    #[inline]
+    fn sample_input_taps<const IX: i32, const IY: i32>(taps: i32, source: SampleSource, pt: (f32, f32), sharpen: f32) -> Vector4<f32> {
+        if IX != INTERPOLATION_ADAPTIVE {
+            return Self::sample_input_rows::<IX, IY>(source, pt, sharpen);
+        }
+        match taps {
+            2 => Self::sample_input_rows::<2, 2>(source, pt, sharpen),
+            4 => Self::sample_input_rows::<4, 4>(source, pt, sharpen),
+            _ => Self::sample_input_rows::<8, 8>(source, pt, sharpen),
+        }
+    }
+
+    // Same as `sample_input_rows`, with a Lanczos window of `radius` source pixels evaluated per sample instead of the `COEFFS` tables,
+    // so heavily minified output doesn't alias. There's no unsharp mask, the wide kernel is meant to soften.
+    // Performance drops sharply: the taps grow with the square of the radius (radius 8 reads 256 source pixels per sample, 4x Lanczos4, radius 16 reads 1024)
+    // and each weight costs two `sin` instead of a table read. Intended for final renders of zoomed out shots, not for preview
+    fn sample_input_wide(source: SampleSource, pt: (f32, f32), radius: f32) -> Vector4<f32> {
+        if !pt.0.is_finite() || !pt.1.is_finite() { return source.bg; }
+        let source = source.prepare::<T>();
+        let bg = source.bg;
+
+        let pt = adjust_edges(pt, source.width, source.height, source.edge_repeat, source.edge_mirror, source.edge_wrap);
+        let radius = radius.min(MAX_KERNEL_RADIUS as f32);
+        let (mut weights_x, mut weights_y) = ([0.0; 2 * MAX_KERNEL_RADIUS], [0.0; 2 * MAX_KERNEL_RADIUS]);
+        let (sx, n) = wide_kernel_taps(pt.0, radius, &mut weights_x);
+        let (sy, _) = wide_kernel_taps(pt.1, radius, &mut weights_y);
+
+        let mut sum = Vector4::from_element(0.0);
+        let (mut weight_sum, mut skipped) = (0.0, false);
+        let (mut tap_sum, mut tap_count) = (Vector4::<f32>::from_element(0.0), 0);
+        let last_row = source.last_row();
+        for yp in 0..n {
+            let ty = sy + yp as i32;
+            if ty >= source.first_row as i32 && ty < last_row {
+                let mut xsum = Vector4::<f32>::from_element(0.0);
+                let mut xweight = 0.0;
+                for xp in 0..n {
+                    let pixel = match source.tap::<T>(ty, sx + xp as i32) {
+                        Some(v) => v,
+                        None => { skipped = true; continue; }
+                    };
+                    tap_sum += pixel;
+                    tap_count += 1;
+                    xsum += pixel * weights_x[xp];
+                    xweight += weights_x[xp];
+                }
+                sum += xsum * weights_y[yp];
+                weight_sum += xweight * weights_y[yp];
+            } else {
+                tap_sum += bg * n as f32;
+                tap_count += n;
+                sum += bg * weights_y[yp];
+                weight_sum += weights_y[yp];
+            }
+        }
+        if skipped {
+            sum = renormalize_defects(sum, weight_sum, tap_sum, tap_count);
+        }
+        source.unpremultiply(sum)
+    }
+
+    // Adapted from OpenCV: initUndistortRectifyMap + remap 
+    // https://github.com/opencv/opencv/blob/4.x/modules/calib3d/src/fisheye.cpp#L454
+    // https://github.com/opencv/opencv/blob/4.x/modules/imgproc/src/opencl/remap.cl#L390
+    //
+    // Minimum supported size is 1x1 for both input and output. Interpolation taps outside of the frame are treated as background,
+    // so tiny frames (1x1, 2x2, Nx1) are blended with the background by bicubic and lanczos, bilinear samples the pixels exactly.
+    // Returns false without touching the output for zero-sized frames or buffers smaller than `stride * height`.
+    // With `BackgroundMode::Transparent` unmapped pixels aren't written at all, so `out_pixels` has to be initialized by the caller (e.g. with the canvas to composite onto).
+    // wgpu and OpenCL backends need at least 4 rows, see `WgpuWrapper::try_new`
+    pub fn undistort_image_cpu<const I: i32>(pixels: &[u8], out_pixels: &mut [u8], width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, undistortion_params: &[[f32; 9]], bg: Vector4<f32>) -> bool {
+        Self::undistort_image_cpu_with_vignette::<I>(pixels, out_pixels, width, height, stride, output_width, output_height, output_stride, undistortion_params, bg, None)
+    }
+
+    // Same as `undistort_image_cpu`, with the radial `vignette_gain` table applied to the sampled color, see `ComputeParams::vignette_gain`
+    pub fn undistort_image_cpu_with_vignette<const I: i32>(pixels: &[u8], out_pixels: &mut [u8], width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, undistortion_params: &[[f32; 9]], bg: Vector4<f32>, vignette_gain: Option<&[f32]>) -> bool {
+        if width == 0 || height == 0 || output_width == 0 || output_height == 0 || validate_params(undistortion_params, height).is_err() { return false; }
+
+        let mut frame = FrameParams::new(undistortion_params, height, output_width, output_height);
+        frame.vignette_gain = vignette_gain;
+        Self::undistort_image_cpu_with_frame::<I>(frame, pixels, out_pixels, width, height, stride, output_width, output_height, output_stride, bg)
+    }
+
+    // Same as `undistort_image_cpu`, but also counts how the output samples were resolved, e.g. to flag frames with a bad calibration in batch jobs.
+    // Returns None if the frame wasn't rendered, see `undistort_image_cpu`
+    pub fn undistort_image_cpu_with_stats<const I: i32>(pixels: &mut [u8], out_pixels: &mut [u8], width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, undistortion_params: &[[f32; 9]], bg: Vector4<f32>) -> Option<RemapStats> {
+        if width == 0 || height == 0 || output_width == 0 || output_height == 0 || validate_params(undistortion_params, height).is_err() { return None; }
+        let counters = RemapCounters::default();
+        let mut frame = FrameParams::new(undistortion_params, height, output_width, output_height);
+        frame.counters = Some(&counters);
+        if !Self::undistort_image_cpu_with_frame::<I>(frame, pixels, out_pixels, width, height, stride, output_width, output_height, output_stride, bg) { return None; }
+        Some(counters.stats())
+    }
+
+    // Same as `undistort_image_cpu`, with the optional per-pixel stages (vignette gain, color transform, color LUT) already set on `frame`
+    pub fn undistort_image_cpu_with_frame<const I: i32>(frame: FrameParams, pixels: &[u8], out_pixels: &mut [u8], width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, bg: Vector4<f32>) -> bool {
+        Self::undistort_frame::<I, I>(frame, pixels, out_pixels, width, height, stride, output_width, output_height, output_stride, bg)
+    }
+
+    // Same as `undistort_image_cpu`, with separate horizontal (`IX`) and vertical (`IY`) interpolation, e.g. lanczos only along the squeezed axis
+    // of anamorphic footage. The interpolation is separable, so each axis uses its own `COEFFS` table and tap count
+    pub fn undistort_image_cpu_separable<const IX: i32, const IY: i32>(pixels: &mut [u8], out_pixels: &mut [u8], width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, undistortion_params: &[[f32; 9]], bg: Vector4<f32>) -> bool {
+        if width == 0 || height == 0 || output_width == 0 || output_height == 0 || validate_params(undistortion_params, height).is_err() { return false; }
+        let frame = FrameParams::new(undistortion_params, height, output_width, output_height);
+        Self::undistort_frame::<IX, IY>(frame, pixels, out_pixels, width, height, stride, output_width, output_height, output_stride, bg)
+    }
+
+    // Same as `undistort_image_cpu`, but the source position of each output pixel is read from `stmap` (`output_width * output_height` entries, row-major)
+    // instead of being computed from the lens model, e.g. a correction exported from a compositing app, see `stmap_from_normalized`.
+    // `undistortion_params` still provide the background mode, the header rows are enough. Returns false if the map size doesn't match the output
+    pub fn undistort_image_cpu_stmap<const I: i32>(pixels: &mut [u8], out_pixels: &mut [u8], width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, undistortion_params: &[[f32; 9]], bg: Vector4<f32>, stmap: &[(f32, f32)]) -> bool {
+        if width == 0 || height == 0 || output_width == 0 || output_height == 0 { return false; }
+        if stmap.len() != output_width * output_height || undistortion_params.len() < 3 { return false; }
+        let mut frame = FrameParams::new(undistortion_params, height, output_width, output_height);
+        frame.stmap = Some(stmap);
+        Self::undistort_frame::<I, I>(frame, pixels, out_pixels, width, height, stride, output_width, output_height, output_stride, bg)
+    }
+
+    // Side-by-side stereo: the left and right halves of `pixels` (`width` is the full frame) are remapped to `out_left` and `out_right`
+    // with their own params, which should share the stabilization rotation (see `FrameTransform::stereo_at_timestamp`).
+    // `disparity` (`output_width * output_height`, row-major) receives the horizontal source offset left - right in eye pixels, NaN where either eye is background
+    pub fn undistort_image_cpu_stereo<const I: i32>(pixels: &mut [u8], out_left: &mut [u8], out_right: &mut [u8], width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, params_left: &[[f32; 9]], params_right: &[[f32; 9]], bg: Vector4<f32>, disparity: Option<&mut [f32]>) -> bool {
+        let eye_width = width / 2;
+        if eye_width == 0 || validate_params(params_left, height).is_err() || validate_params(params_right, height).is_err() { return false; }
+        if disparity.as_ref().map_or(false, |d| d.len() != output_width * output_height) { return false; }
+
+        if validate_buffer::<T>(pixels, width, height, stride).is_err() { return false; }
+        if !Self::undistort_image_cpu::<I>(pixels, out_left, eye_width, height, stride, output_width, output_height, output_stride, params_left, bg) { return false; }
+        // The right eye is addressed with `source_x` over the full buffer, a slice starting at the right half would be shorter than `stride * height`
+        let mut right = FrameParams::new(params_right, height, output_width, output_height);
+        right.source_x = eye_width;
+        if !Self::undistort_image_cpu_with_frame::<I>(right, pixels, out_right, eye_width, height, stride, output_width, output_height, output_stride, bg) { return false; }
+
+        if let Some(disparity) = disparity {
+            let left = FrameParams::new(params_left, height, output_width, output_height);
+            let right = FrameParams::new(params_right, height, output_width, output_height);
+            disparity.par_chunks_mut(output_width).enumerate().for_each(|(y, row)| {
+                for (x, d) in row.iter_mut().enumerate() {
+                    *d = match (left.map_coord(x as f32, y as f32), right.map_coord(x as f32, y as f32)) {
+                        (Some(l), Some(r)) => l.0 - r.0,
+                        _ => f32::NAN
+                    };
+                }
+            });
+        }
+        true
+    }
+
+    fn undistort_frame<const IX: i32, const IY: i32>(frame: FrameParams, pixels: &[u8], out_pixels: &mut [u8], width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, bg: Vector4<f32>) -> bool {
+        if width == 0 || height == 0 || output_width == 0 || output_height == 0 { return false; }
+        if validate_buffer::<T>(pixels, frame.source_x + width, height, stride).is_err() || validate_buffer::<T>(out_pixels, output_width, output_height, output_stride).is_err() { return false; }
+        Self::render_frame::<IX, IY>(frame, pixels, out_pixels, width, height, stride, output_width, output_height, output_stride, bg);
+        true
+    }
+
+    // `undistort_frame` after the buffer checks. The fields of interlaced footage are rendered with a doubled stride from an offset into the validated buffer,
+    // so the last row of a field has no padding after it and the field buffer is shorter than `stride * height`
+    fn render_frame<const IX: i32, const IY: i32>(frame: FrameParams, pixels: &[u8], out_pixels: &mut [u8], width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, bg: Vector4<f32>) {
+        if frame.renders_fields(height, output_height) {
+            // Each field is undistorted as a half-height frame (every other row), so the interpolation doesn't mix the fields
+            for parity in 0..2 {
+                let second = (parity == 0) != (frame.interlacing == 1);
+                let params = field_params(frame.params, height, parity, second);
+                let (field_height, field_output_height) = ((height + 1 - parity) / 2, (output_height + 1 - parity) / 2);
+                let field_mask = frame.defect_mask.filter(|x| x.len() == width * height).map(|x| x.chunks(width).skip(parity).step_by(2).flatten().copied().collect::<Vec<bool>>());
+                let field = FrameParams {
+                    vignette_gain: frame.vignette_gain,
+                    color_transform: frame.color_transform,
+                    color_lut: frame.color_lut,
+                    bg_gradient: frame.bg_gradient,
+                    bg_regions: frame.bg_regions,
+                    chromatic_aberration: frame.chromatic_aberration,
+                    kernel_radius: frame.kernel_radius,
+                    source_x: frame.source_x,
+                    defect_mask: field_mask.as_deref(),
+                    counters: frame.counters,
+                    interlacing: 0,
+                    ..FrameParams::new(&params, field_height, output_width, field_output_height)
+                };
+                Self::render_frame::<IX, IY>(field, &pixels[parity * stride..], &mut out_pixels[parity * output_stride..], width, field_height, stride * 2, output_width, field_output_height, output_stride * 2, bg);
+            }
+            return;
+        }
+
+        if frame.is_plain_resize(width) {
+            if width == output_width && height == output_height {
+                // Straight copy
+                let row_size = width * T::COUNT * T::SCALAR_BYTES;
+                if stride == output_stride && pixels.len() == out_pixels.len() {
+                    out_pixels.copy_from_slice(pixels);
+                } else {
+                    out_pixels.par_chunks_mut(output_stride).zip(pixels.par_chunks(stride)).for_each(|(dst, src)| {
+                        dst[..row_size].copy_from_slice(&src[..row_size]);
+                    });
+                }
+                return;
+            }
+            // Plain resize. The taps past the source edge follow the background mode, like in the full remap
+            let edge_repeat = frame.background_mode > 0.9 && frame.background_mode < 1.1; // 1
+            let edge_mirror = frame.background_mode > 1.9 && frame.background_mode < 2.1; // 2
+            let edge_wrap   = frame.background_mode > 3.9 && frame.background_mode < 4.1; // 4
+            let source = SampleSource { edge_repeat, edge_mirror, edge_wrap, straight_alpha: frame.straight_alpha, ..SampleSource::new(pixels, 0, width, height, stride, bg) };
+            let scale = (width as f32 / output_width as f32, height as f32 / output_height as f32);
+            out_pixels.par_chunks_mut(output_stride).enumerate().for_each(|(y, row_bytes)| {
+                row_bytes.chunks_mut(T::COUNT * T::SCALAR_BYTES).enumerate().for_each(|(x, pix_chunk)| {
+                    if y < output_height && x < output_width {
+                        let pix_out: &mut T = bytemuck::from_bytes_mut(pix_chunk);
+                        *pix_out = PixelType::from_float(Self::sample_input_taps::<IX, IY>(adaptive_taps(scale.0.max(scale.1)), source, (x as f32 * scale.0, y as f32 * scale.1), 0.0));
+                    }
+                });
+            });
+            return;
+        }
+
+        // Lens correction only, no stabilization
+        let radial_lut = frame.build_radial_lut();
+        let frame = FrameParams { radial_lut: radial_lut.as_ref(), ..frame };
+
+        out_pixels.par_chunks_mut(output_stride).enumerate().for_each(|(y, row_bytes)| { // Parallel iterator over buffer rows
+            Self::undistort_row::<IX, IY>(&frame, pixels, 0, width, height, stride, output_width, output_height, y, row_bytes, bg);
+        });
+    }
+
+    // Same as `undistort_image_cpu` (same checks and code path), but runs in a single thread pool, so the rows are processed serially and the output is bit-reproducible run to run.
+    // Much slower, intended for golden-image tests.
+    pub fn undistort_image_cpu_deterministic<const I: i32>(pixels: &[u8], out_pixels: &mut [u8], width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, undistortion_params: &[[f32; 9]], bg: Vector4<f32>) -> bool {
+        match rayon::ThreadPoolBuilder::new().num_threads(1).build() {
+            Ok(pool) => pool.install(|| Self::undistort_image_cpu::<I>(pixels, out_pixels, width, height, stride, output_width, output_height, output_stride, undistortion_params, bg)),
+            Err(e) => { log::error!("Failed to create thread pool: {}", e); false }
+        }
+    }
 
-    let r = (point.0 * point.0 + point.1 * point.1).sqrt();
+    // Processes the output in bands of `band_height` rows, so only the source rows needed for the current band have to be in memory.
+    // `read_rows` fills the buffer with the requested source rows (`stride` bytes each), `write_band` receives the first output row and the band pixels.
+    // The output is the same as from `undistort_image_cpu`. Interlaced and near-identity frames take the same path as there, which needs the whole frame, so all rows are requested at once.
+    // Returns false for zero-sized frames, invalid params, or if `read_rows` didn't provide `stride` bytes for every requested row (the bands written until then are kept).
+    pub fn undistort_image_cpu_banded<const I: i32, R: FnMut(Range<usize>, &mut Vec<u8>), W: FnMut(usize, &[u8])>(mut read_rows: R, mut write_band: W, band_height: usize, width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, undistortion_params: &[[f32; 9]], bg: Vector4<f32>) -> bool {
+        if width == 0 || height == 0 || output_width == 0 || output_height == 0 || validate_params(undistortion_params, height).is_err() { return false; }
+        if output_stride < min_stride::<T>(output_width) { return false; }
+        let frame = FrameParams::new(undistortion_params, height, output_width, output_height);
+        let band_height = band_height.max(1);
 
-    let theta = r.atan();
-    let theta2 = theta*theta;
-    let theta4 = theta2*theta2;
-    let theta6 = theta4*theta2;
-    let theta8 = theta4*theta4;
+        let mut src = Vec::new();
+        let mut out = Vec::new();
+        if frame.renders_fields(height, output_height) || frame.is_plain_resize(width) {
+            read_rows(0..height, &mut src);
+            out.resize(output_stride * output_height, 0);
+            if !Self::undistort_frame::<I, I>(frame, &src, &mut out, width, height, stride, output_width, output_height, output_stride, bg) { return false; }
+            for (i, band) in out.chunks(output_stride * band_height).enumerate() {
+                write_band(i * band_height, band);
+            }
+            return true;
+        }
 
-    let theta_d = theta * (t_1 + k[0]*theta2 + k[1]*theta4 + k[2]*theta6 + k[3]*theta8);
+        let radial_lut = frame.build_radial_lut();
+        let frame = FrameParams { radial_lut: radial_lut.as_ref(), ..frame };
 
-    let mut scale = if r == t_0 { t_1 } else { theta_d / r };
-    scale = t_1 + (scale - t_1) * (t_1 - amount);
+        for y0 in (0..output_height).step_by(band_height) {
+            let y1 = (y0 + band_height).min(output_height);
+            let rows = frame.source_rows(y0..y1, output_width, width, if I == INTERPOLATION_ADAPTIVE { 8 } else { I as usize });
 
-    (
-        f.0 * point.0 * scale + c.0,
-        f.1 * point.1 * scale + c.1
-    )
-}
+            src.clear();
+            read_rows(rows.clone(), &mut src);
+            if validate_buffer::<T>(&src, width, rows.len(), stride).is_err() { return false; }
 
-impl<T: PixelType> Undistortion<T> {
-    // Adapted from OpenCV: initUndistortRectifyMap + remap 
-    // https://github.com/opencv/opencv/blob/4.x/modules/calib3d/src/fisheye.cpp#L454
-    // https://github.com/opencv/opencv/blob/4.x/modules/imgproc/src/opencl/remap.cl#L390
-    pub fn undistort_image_cpu<const I: i32>(pixels: &mut [u8], out_pixels: &mut [u8], width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, undistortion_params: &[[f32; 9]], bg: Vector4<f32>) {
-        let bg_t: T = PixelType::from_float(bg);
-        
-        const INTER_BITS: usize = 5;
-        const INTER_TAB_SIZE: usize = 1 << INTER_BITS;
+            out.clear();
+            out.resize(output_stride * (y1 - y0), 0);
+            out.par_chunks_mut(output_stride).enumerate().for_each(|(i, row_bytes)| {
+                Self::undistort_row::<I, I>(&frame, &src, rows.start, width, height, stride, output_width, output_height, y0 + i, row_bytes, bg);
+            });
+            write_band(y0, &out);
+        }
+        true
+    }
 
-        let f = (undistortion_params[0][0], undistortion_params[0][1]);
-        let c = (undistortion_params[0][2], undistortion_params[0][3]);
-        let k = &undistortion_params[0][4..8];
-        let r_limit = undistortion_params[0][8];
-        let lens_correction_amount = undistortion_params[1][0];
-        let background_mode = undistortion_params[1][1];
-        let fov = undistortion_params[1][2];
-        let edge_repeat = background_mode > 0.9 && background_mode < 1.1; // 1
-        let edge_mirror = background_mode > 1.9 && background_mode < 2.1; // 2
+    #[inline]
+    fn undistort_row<const IX: i32, const IY: i32>(frame: &FrameParams, pixels: &[u8], first_row: usize, width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, y: usize, row_bytes: &mut [u8], bg: Vector4<f32>) {
+        // Debug: red for rows shifted right, blue for rows shifted left, full tint at 16 px
+        let rs_tint = if frame.show_rolling_shutter && y < output_height { (frame.rolling_shutter_shift(y) / 16.0).max(-1.0).min(1.0) } else { 0.0 };
 
-        let factor = (1.0 - lens_correction_amount).max(0.001); // FIXME: this is close but wrong
-        let f2 = ((f.0 / fov / factor), (f.1 / fov / factor));
-        let out_c = (output_width as f32 / 2.0, output_height as f32 / 2.0);
+        row_bytes.chunks_mut(T::COUNT * T::SCALAR_BYTES).enumerate().for_each(|(x, pix_chunk)| { // iterator over row pixels
+            if y < output_height && x < output_width {
+                assert!(pix_chunk.len() == std::mem::size_of::<T>());
+                let pix_out: &mut T = bytemuck::from_bytes_mut(pix_chunk); // treat this byte chunk as `T`
+                let mut pix = match Self::undistort_pixel_opt::<IX, IY>(frame, pixels, first_row, width, height, stride, x, y, bg) {
+                    Some(pix) => pix,
+                    None => return // Transparent background, keep the existing output
+                };
+                if let Some(color) = frame.color_transform {
+                    pix = color.apply(pix);
+                }
+                if let Some(lut) = frame.color_lut {
+                    pix = lut.apply(pix);
+                }
+                if rs_tint != 0.0 {
+                    pix = Vector4::new(pix[0] * (1.0 + rs_tint.max(0.0)), pix[1] * (1.0 - rs_tint.abs() * 0.5), pix[2] * (1.0 - rs_tint.min(0.0)), pix[3]);
+                }
+                if frame.dither && !T::FLOAT {
+                    // Ordered dither, `from_float` truncates so the threshold is added before the store
+                    let d = (BAYER_4X4[y % 4][x % 4] + 0.5) / 16.0;
+                    pix = Vector4::new(pix[0] + d, pix[1] + d, pix[2] + d, pix[3]);
+                }
+                *pix_out = PixelType::from_float(pix);
+            }
+        });
+    }
 
-        let bytes_per_pixel = T::COUNT * T::SCALAR_BYTES;
-        let shift = (I >> 2) + 1;
-        let offset = [0.0, 1.0, 3.0][I as usize >> 2];
-        let ind = [0, 64, 64 + 128][I as usize >> 2];
+    // Computes a single output pixel, including supersampling and background handling
+    #[inline]
+    fn undistort_pixel<const IX: i32, const IY: i32>(frame: &FrameParams, pixels: &[u8], first_row: usize, width: usize, height: usize, stride: usize, x: usize, y: usize, bg: Vector4<f32>) -> Vector4<f32> {
+        Self::undistort_pixel_opt::<IX, IY>(frame, pixels, first_row, width, height, stride, x, y, bg).unwrap_or(bg)
+    }
 
-        out_pixels.par_chunks_mut(output_stride).enumerate().for_each(|(y, row_bytes)| { // Parallel iterator over buffer rows
-            row_bytes.chunks_mut(T::COUNT * T::SCALAR_BYTES).enumerate().for_each(|(x, pix_chunk)| { // iterator over row pixels
-                if y < output_height && x < output_width {
-                    assert!(pix_chunk.len() == std::mem::size_of::<T>());
-                    ///////////////////////////////////////////////////////////////////
-                    // Calculate source `y` for rolling shutter
-                    let mut sy = y;
-                    if undistortion_params.len() > 3 {
-                        let undistortion_params = undistortion_params[2 + (undistortion_params.len() - 2) / 2]; // Use middle matrix
-                        let _x = y as f32 * undistortion_params[1] + undistortion_params[2] + (x as f32 * undistortion_params[0]);
-                        let _y = y as f32 * undistortion_params[4] + undistortion_params[5] + (x as f32 * undistortion_params[3]);
-                        let _w = y as f32 * undistortion_params[7] + undistortion_params[8] + (x as f32 * undistortion_params[6]);
-                        if _w > 0.0 {
-                            let posx = _x / _w;
-                            let posy = _y / _w;
-                            let pt = distort_point((posx, posy), f, c, k, 0.0);
-                            sy = (pt.1.round() as i32).min(height as i32).max(0) as usize;
+    // Same as `undistort_pixel`, but returns None with the transparent background if none of the samples map to the source.
+    // Partially covered (supersampled) pixels are still blended with `bg`
+    #[inline]
+    fn undistort_pixel_opt<const IX: i32, const IY: i32>(frame: &FrameParams, pixels: &[u8], first_row: usize, width: usize, height: usize, stride: usize, x: usize, y: usize, bg: Vector4<f32>) -> Option<Vector4<f32>> {
+        // The samplers index the view from column 0, the buffer length was validated for `source_x + width` columns
+        let pixels = pixels.get(min_stride::<T>(frame.source_x)..).unwrap_or(&[]);
+        let edge_repeat = frame.background_mode > 0.9 && frame.background_mode < 1.1; // 1
+        let edge_mirror = frame.background_mode > 1.9 && frame.background_mode < 2.1; // 2
+        let passthrough = frame.background_mode > 2.9 && frame.background_mode < 3.1; // 3
+        let edge_wrap   = frame.background_mode > 3.9 && frame.background_mode < 4.1; // 4
+        let transparent = frame.background_mode > 4.9 && frame.background_mode < 5.1; // 5
+
+        let sy = frame.source_row(x as f32, y as f32);
+        let wide_kernel = frame.kernel_radius > MAX_TABLE_KERNEL_RADIUS;
+        // With a gradient, the background is evaluated per sample, so the supersampled edge and the taps outside of the source blend into the local color
+        let source = SampleSource { edge_repeat, edge_mirror, edge_wrap, straight_alpha: frame.straight_alpha, defects: frame.defect_mask, ..SampleSource::new(pixels, first_row, width, height, stride, bg) };
+        let sample = |pt: (f32, f32)| {
+            let bg = frame.background_at(pt, bg);
+            let source = SampleSource { bg, ..source };
+            let mapped = frame.map_output_with_gain(pt, sy);
+            if let Some(counters) = frame.counters {
+                let counter = if frame.undistort_output(pt).is_none() { &counters.non_convergent } else if mapped.is_none() { &counters.out_of_fov } else { &counters.sampled };
+                counter.fetch_add(1, Relaxed);
+            }
+            match mapped {
+                Some((src, gain)) => {
+                    let taps = if IX == INTERPOLATION_ADAPTIVE { frame.adaptive_taps(pt, src, sy) } else { IX };
+                    let sample_at = |src| if wide_kernel {
+                        Self::sample_input_wide(source, src, frame.kernel_radius)
+                    } else {
+                        Self::sample_input_taps::<IX, IY>(taps, source, src, frame.sharpen)
+                    };
+                    let mut pix = sample_at(src);
+                    if let Some(ca) = frame.chromatic_aberration {
+                        // Lateral chromatic aberration is a per channel magnification, i.e. the same as evaluating the lens model with a per channel focal length
+                        let c = frame.to_buffer(frame.c);
+                        for ch in 0..3 {
+                            if ca[ch] != 1.0 {
+                                pix[ch] = sample_at((c.0 + (src.0 - c.0) * ca[ch], c.1 + (src.1 - c.1) * ca[ch]))[ch];
+                            }
                         }
                     }
-                    ///////////////////////////////////////////////////////////////////
-                    let mut pt = (x as f32, y as f32);
-                    if lens_correction_amount < 1.0 {
-                        // Add lens distortion back         
-                        pt = ((pt.0 - out_c.0) / f2.0, (pt.1 - out_c.1) / f2.1);
-                        pt = undistort_point(pt, k, lens_correction_amount).unwrap_or_default();
-                        pt = ((pt.0 * f2.0) + out_c.0, (pt.1 * f2.1) + out_c.1);
-                    }
+                    Some(if gain != 1.0 { Vector4::new(pix[0] * gain, pix[1] * gain, pix[2] * gain, pix[3]) } else { pix })
+                },
+                None if passthrough => Some(Self::sample_input_taps::<IX, IY>(adaptive_taps(width as f32 / (frame.out_c.0 * 2.0).max(1.0)), SampleSource { edge_repeat: true, edge_mirror: false, edge_wrap: false, ..source }, frame.passthrough_point(pt, width), frame.sharpen)),
+                None => None
+            }
+        };
 
-                    let undistortion_params = &undistortion_params[(sy + 2).min(undistortion_params.len() - 1)];
-                    let _x = pt.1 * undistortion_params[1] + undistortion_params[2] + (pt.0 * undistortion_params[0]);
-                    let _y = pt.1 * undistortion_params[4] + undistortion_params[5] + (pt.0 * undistortion_params[3]);
-                    let _w = pt.1 * undistortion_params[7] + undistortion_params[8] + (pt.0 * undistortion_params[6]);
-                
-                    let pix_out = bytemuck::from_bytes_mut(pix_chunk); // treat this byte chunk as `T`
+        if frame.supersample > 1 && frame.needs_supersampling((x as f32, y as f32), sy) {
+            let mut sum = Vector4::from_element(0.0);
+            let mut covered = false;
+            for offs in &SUPERSAMPLE_OFFSETS[..frame.supersample] {
+                let pt = (x as f32 + offs.0, y as f32 + offs.1);
+                let v = sample(pt);
+                covered |= v.is_some();
+                sum += v.unwrap_or_else(|| frame.background_at(pt, bg));
+            }
+            if !covered && transparent { return None; }
+            return Some(sum / frame.supersample as f32);
+        }
+        match sample((x as f32, y as f32)) {
+            None if !transparent => Some(frame.background_at((x as f32, y as f32), bg)),
+            v => v
+        }
+    }
 
-                    if _w > 0.0 {
-                        let posx = _x / _w;
-                        let posy = _y / _w;
+    // Undistorts float input (e.g. `RGBAf16`) straight to RGBA8, applying `tonemap` to the interpolated value, so there's no intermediate float frame.
+    // Input is expected linear with 1.0 as the reference white, no transfer function is applied after the curve. Alpha is only clamped.
+    pub fn undistort_image_cpu_tonemapped<const I: i32>(pixels: &mut [u8], out_pixels: &mut [u8], width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, undistortion_params: &[[f32; 9]], bg: Vector4<f32>, tonemap: Tonemap) {
+        use super::RGBA8;
+        if validate_params(undistortion_params, height).is_err() { return; }
+        let frame = FrameParams::new(undistortion_params, height, output_width, output_height);
 
-                        if r_limit > 0.0 && (posx*posx + posy*posy) > r_limit*r_limit {
-                            *pix_out = bg_t;
-                            return;
-                        }
+        out_pixels.par_chunks_mut(output_stride).enumerate().for_each(|(y, row_bytes)| {
+            row_bytes.chunks_mut(std::mem::size_of::<RGBA8>()).enumerate().for_each(|(x, pix_chunk)| {
+                if y < output_height && x < output_width {
+                    let pix = Self::undistort_pixel::<I, I>(&frame, pixels, 0, width, height, stride, x, y, bg);
+                    let d = if frame.dither { (BAYER_4X4[y % 4][x % 4] + 0.5) / 16.0 } else { 0.5 };
+                    let pix_out: &mut RGBA8 = bytemuck::from_bytes_mut(pix_chunk);
+                    *pix_out = PixelType::from_float(Vector4::new(
+                        tonemap.apply(pix[0]) * 255.0 + d,
+                        tonemap.apply(pix[1]) * 255.0 + d,
+                        tonemap.apply(pix[2]) * 255.0 + d,
+                        Tonemap::Clamp.apply(pix[3]) * 255.0 + 0.5
+                    ).map(|v| v.min(255.0)));
+                }
+            });
+        });
+    }
 
-                        let mut pt = distort_point((posx, posy), f, c, k, 0.0);
-                        let width_f = width as f32;
-                        let height_f = height as f32;
-                        if edge_repeat {
-                            pt = (
-                                pt.0.max(0.0).min(width_f - 1.0),
-                                pt.1.max(0.0).min(height_f - 1.0),
-                            );
-                        } else if edge_mirror {
-                            let rx = pt.0.round();
-                            let ry = pt.1.round();
-                            let width3 = width_f - 3.0;
-                            let height3 = height_f - 3.0;
-                            if rx > width3  { pt.0 = width3  - (rx - width3); }
-                            if rx < 3.0     { pt.0 = 3.0 + width_f - (width3  + rx); }
-                            if ry > height3 { pt.1 = height3 - (ry - height3); }
-                            if ry < 3.0     { pt.1 = 3.0 + height_f - (height3 + ry); }
-                        }
+    // Writes 8-bit YUV 4:2:0 for direct encoder handoff, converted with BT.709 limited range.
+    // Luma goes to `out_y`, chroma to `out_chroma`: interleaved UV rows for `YuvLayout::Nv12`, or the U plane followed by the V plane (`output_height / 2` rows each) for `YuvLayout::I420`.
+    // Each chroma sample is averaged from two luma rows. `ChromaSiting::Left` (MPEG-2, H.264 default) filters horizontally with [1, 2, 1] around the even column,
+    // `ChromaSiting::Center` (JPEG, MPEG-1) averages the 2x2 block.
+    // Input is expected to be RGB(A), output width and height must be even.
+    pub fn undistort_image_cpu_yuv420<const I: i32>(pixels: &mut [u8], out_y: &mut [u8], y_stride: usize, out_chroma: &mut [u8], chroma_stride: usize, width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, undistortion_params: &[[f32; 9]], bg: Vector4<f32>, layout: YuvLayout, siting: ChromaSiting) -> bool {
+        if output_width % 2 != 0 || output_height % 2 != 0 || validate_params(undistortion_params, height).is_err() { return false; }
+        let chroma_rows = output_height / 2;
+        let chroma_width = output_width / 2;
+        let chroma_row_bytes = if layout == YuvLayout::Nv12 { output_width } else { chroma_width };
+        let chroma_planes = if layout == YuvLayout::Nv12 { 1 } else { 2 };
+        if out_y.len() < y_stride * output_height || y_stride < output_width { return false; }
+        if out_chroma.len() < chroma_stride * chroma_rows * chroma_planes || chroma_stride < chroma_row_bytes { return false; }
 
-                        let u = pt.0 - offset;
-                        let v = pt.1 - offset;
-                
-                        let sx0 = (u * INTER_TAB_SIZE as f32).round() as i32;
-                        let sy0 = (v * INTER_TAB_SIZE as f32).round() as i32;
-
-                        let sx = sx0 >> INTER_BITS;
-                        let sy = sy0 >> INTER_BITS;
-
-                        let coeffs_x = &COEFFS[ind + ((sx0 as usize & (INTER_TAB_SIZE - 1)) << shift)..];
-                        let coeffs_y = &COEFFS[ind + ((sy0 as usize & (INTER_TAB_SIZE - 1)) << shift)..];
-                
-                        let mut sum = Vector4::from_element(0.0);
-                        let mut src_index = (sy * stride as i32 + sx * bytes_per_pixel as i32) as isize;
-
-                        for yp in 0..I {
-                            if sy + yp >= 0 && sy + yp < height as i32 {
-                                let mut xsum = Vector4::<f32>::from_element(0.0);
-                                for xp in 0..I {
-                                    let pixel = if sx + xp >= 0 && sx + xp < width as i32 {
-                                        let px1: &T = bytemuck::from_bytes(&pixels[src_index as usize + (bytes_per_pixel * xp as usize)..src_index as usize + bytes_per_pixel * (xp as usize + 1)]); 
-                                        PixelType::to_float(*px1)
-                                    } else {
-                                        bg
-                                    };
-                                    xsum += pixel * coeffs_x[xp as usize];
-                                }
-
-                                sum += xsum * coeffs_y[yp as usize];
-                            } else {
-                                sum += bg * coeffs_y[yp as usize];
-                            }
-                            src_index += stride as isize;
-                        }
-                        *pix_out = PixelType::from_float(sum);
-                    } else {
+        let frame = FrameParams::new(undistortion_params, height, output_width, output_height);
+        let max_val = if T::PACKED { 1023.0 } else if T::FLOAT { 1.0 } else { match T::SCALAR_BYTES { 1 => 255.0, 2 => 65535.0, _ => 1.0 } };
+        let scale = 255.0 / max_val;
+
+        let (out_u, out_v) = out_chroma.split_at_mut(chroma_stride * chroma_rows * (chroma_planes - 1));
+        let (out_u, out_v) = if layout == YuvLayout::Nv12 { (out_v, None) } else { (out_u, Some(out_v)) };
+
+        let mut v_rows: Vec<Option<&mut [u8]>> = match out_v {
+            Some(out_v) => out_v.chunks_mut(chroma_stride).map(Some).collect(),
+            None => (0..chroma_rows).map(|_| None).collect()
+        };
+
+        let to_u8 = |v: f32| v.round().max(0.0).min(255.0) as u8;
+
+        out_y.par_chunks_mut(y_stride * 2).zip(out_u.par_chunks_mut(chroma_stride)).zip(v_rows.par_iter_mut()).enumerate().for_each(|(cy, ((y_rows, u_row), v_row))| {
+            if cy >= chroma_rows { return; }
+            let rgb: Vec<Vec<Vector4<f32>>> = (0..2).map(|i| {
+                (0..output_width).map(|x| Self::undistort_pixel::<I, I>(&frame, pixels, 0, width, height, stride, x, cy * 2 + i, bg) * scale).collect()
+            }).collect();
+
+            for (i, row) in rgb.iter().enumerate() {
+                let y_row = &mut y_rows[i * y_stride..i * y_stride + output_width];
+                for (x, v) in row.iter().enumerate() {
+                    y_row[x] = to_u8(16.0 + (0.2126 * v[0] + 0.7152 * v[1] + 0.0722 * v[2]) * (219.0 / 255.0));
+                }
+            }
+
+            for cx in 0..chroma_width {
+                let x = cx * 2;
+                let mut v = Vector4::from_element(0.0);
+                for row in &rgb {
+                    v += match siting {
+                        ChromaSiting::Center => (row[x] + row[x + 1]) * 0.5,
+                        ChromaSiting::Left   => (row[x.max(1) - 1] + row[x] * 2.0 + row[x + 1]) * 0.25,
+                    };
+                }
+                v *= 0.5;
+                let u = to_u8(128.0 + (-0.1146 * v[0] - 0.3854 * v[1] + 0.5000 * v[2]) * (224.0 / 255.0));
+                let w = to_u8(128.0 + ( 0.5000 * v[0] - 0.4542 * v[1] - 0.0458 * v[2]) * (224.0 / 255.0));
+                if let Some(v_row) = v_row {
+                    u_row[cx] = u;
+                    v_row[cx] = w;
+                } else {
+                    u_row[x] = u;
+                    u_row[x + 1] = w;
+                }
+            }
+        });
+
+        true
+    }
+
+    // Absolute difference between `A`-tap and `B`-tap interpolation of the same frame, multiplied by `gain`, e.g. to see where Lanczos4 differs from bicubic.
+    // Alpha is taken from the `A` result, so the background stays distinguishable
+    pub fn undistort_image_cpu_difference<const A: i32, const B: i32>(pixels: &mut [u8], out_pixels: &mut [u8], width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, undistortion_params: &[[f32; 9]], bg: Vector4<f32>, gain: f32) -> bool {
+        if width == 0 || height == 0 || output_width == 0 || output_height == 0 { return false; }
+        if validate_buffer::<T>(pixels, width, height, stride).is_err() || validate_buffer::<T>(out_pixels, output_width, output_height, output_stride).is_err() { return false; }
+        if validate_params(undistortion_params, height).is_err() { return false; }
+        let frame = FrameParams::new(undistortion_params, height, output_width, output_height);
+
+        out_pixels.par_chunks_mut(output_stride).enumerate().for_each(|(y, row_bytes)| {
+            row_bytes.chunks_mut(T::COUNT * T::SCALAR_BYTES).enumerate().for_each(|(x, pix_chunk)| {
+                if y < output_height && x < output_width {
+                    let a = Self::undistort_pixel::<A, A>(&frame, pixels, 0, width, height, stride, x, y, bg);
+                    let b = Self::undistort_pixel::<B, B>(&frame, pixels, 0, width, height, stride, x, y, bg);
+                    let pix_out: &mut T = bytemuck::from_bytes_mut(pix_chunk);
+                    *pix_out = PixelType::from_float(Vector4::new((a[0] - b[0]).abs() * gain, (a[1] - b[1]).abs() * gain, (a[2] - b[2]).abs() * gain, a[3]));
+                }
+            });
+        });
+        true
+    }
+
+    // Crossfades between two lens profiles (e.g. during a lens switch) by interpolating the source coordinates with `t` before sampling.
+    // If only one of the profiles can map a pixel, that one is used.
+    pub fn undistort_image_cpu_blend<const I: i32>(pixels: &mut [u8], out_pixels: &mut [u8], width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, params_a: &[[f32; 9]], params_b: &[[f32; 9]], t: f32, bg: Vector4<f32>) {
+        if validate_params(params_a, height).is_err() || validate_params(params_b, height).is_err() { return; }
+        let bg_t: T = PixelType::from_float(bg);
+
+        let frame_a = FrameParams::new(params_a, height, output_width, output_height);
+        let frame_b = FrameParams::new(params_b, height, output_width, output_height);
+        let edge_repeat = frame_a.background_mode > 0.9 && frame_a.background_mode < 1.1; // 1
+        let edge_mirror = frame_a.background_mode > 1.9 && frame_a.background_mode < 2.1; // 2
+        let edge_wrap   = frame_a.background_mode > 3.9 && frame_a.background_mode < 4.1; // 4
+        let transparent = frame_a.background_mode > 4.9 && frame_a.background_mode < 5.1; // 5
+        let t = t.max(0.0).min(1.0);
+
+        out_pixels.par_chunks_mut(output_stride).enumerate().for_each(|(y, row_bytes)| { // Parallel iterator over buffer rows
+            row_bytes.chunks_mut(T::COUNT * T::SCALAR_BYTES).enumerate().for_each(|(x, pix_chunk)| { // iterator over row pixels
+                if y < output_height && x < output_width {
+                    let pix_out = bytemuck::from_bytes_mut(pix_chunk); // treat this byte chunk as `T`
+
+                    let pt = match (frame_a.map_coord(x as f32, y as f32), frame_b.map_coord(x as f32, y as f32)) {
+                        (Some(a), Some(b)) => Some((a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t)),
+                        (Some(a), None) => Some(a),
+                        (None, Some(b)) => Some(b),
+                        (None, None) => None
+                    };
+                    if let Some(pt) = pt {
+                        *pix_out = PixelType::from_float(Self::sample_input_rows::<I, I>(SampleSource { edge_repeat, edge_mirror, edge_wrap, ..SampleSource::new(pixels, 0, width, height, stride, bg) }, pt, 0.0));
+                    } else if !transparent {
                         *pix_out = bg_t;
                     }
                 }
@@ -288,6 +1944,154 @@ pub fn undistort_points_with_rolling_shutter(distorted: &[(f64, f64)], timestamp
     undistort_points(distorted, camera_matrix, &distortion_coeffs, rotations[0], Some(Matrix3::identity()), Some(rotations), Some(params))
 }
 
+// Complement of `undistort_points_with_rolling_shutter`: maps output (stabilized) pixel positions back to source (distorted) pixel positions,
+// with the same rotate + distort path as the renderer, including the rolling shutter row of each point and the lens correction amount.
+// Points that can't be mapped are (-1000000, -1000000), like in `undistort_points`
+pub fn distort_points(points: &[(f64, f64)], params: &ComputeParams, timestamp_ms: f64) -> Vec<(f64, f64)> {
+    if points.is_empty() { return Vec::new(); }
+    let frame = crate::frame_at_timestamp(timestamp_ms, params.gyro.fps) as usize; // Only for FOVs
+    let transform = FrameTransform::at_timestamp(params, timestamp_ms, frame);
+    let frame = FrameParams::new(&transform.params, params.height, params.output_width, params.output_height);
+
+    points.iter().map(|&(x, y)| {
+        match frame.map_coord(x as f32, y as f32) {
+            Some(pt) => (pt.0 as f64, pt.1 as f64),
+            None => (-1000000.0, -1000000.0)
+        }
+    }).collect()
+}
+
+// Maps a closed polygon from source (distorted) pixel space to output space, e.g. for drawing overlays on the stabilized frame.
+// Edges are subdivided to at most `max_segment_px` source pixels before mapping, so straight source edges come out curved.
+// Vertices that fall outside of the lens model are dropped
+pub fn warp_polygon(points: &[(f64, f64)], params: &ComputeParams, timestamp_ms: f64, max_segment_px: f64) -> Vec<(f64, f64)> {
+    if points.is_empty() { return Vec::new(); }
+    let max_segment_px = max_segment_px.max(1.0);
+
+    let mut dense = Vec::with_capacity(points.len());
+    for (i, a) in points.iter().enumerate() {
+        let b = points[(i + 1) % points.len()];
+        let len = ((b.0 - a.0).powi(2) + (b.1 - a.1).powi(2)).sqrt();
+        let steps = (len / max_segment_px).ceil().max(1.0) as usize;
+        for s in 0..steps {
+            let t = s as f64 / steps as f64;
+            dense.push((a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t));
+        }
+        if points.len() == 1 { break; }
+    }
+
+    undistort_points_with_rolling_shutter(&dense, timestamp_ms, params)
+        .into_iter()
+        .filter(|p| p.0 > -99999.0 && p.1 > -99999.0)
+        .collect()
+}
+
+// Undistorts multiple point sets, each at its own timestamp. Returns None if cancelled
+pub fn undistort_points_batch<F: Fn(usize, usize) + Send + Sync>(batch: &[(Vec<(f64, f64)>, f64)], params: &ComputeParams, cancel_flag: Arc<AtomicBool>, progress_cb: F) -> Option<Vec<Vec<(f64, f64)>>> {
+    let total = batch.len();
+    let processed = AtomicUsize::new(0);
+    batch.par_iter().map(|(points, timestamp_ms)| {
+        if cancel_flag.load(Relaxed) { return None; }
+        let ret = undistort_points_with_rolling_shutter(points, *timestamp_ms, params);
+        progress_cb(processed.fetch_add(1, SeqCst) + 1, total);
+        Some(ret)
+    }).collect()
+}
+
+#[derive(Debug)]
+pub enum ProfileWarning {
+    InvalidCameraMatrix,
+    InvalidCoefficients,
+    NonMonotonic(f64),  // relative radius, 1.0 is the frame corner
+    NotConverging(f64), // relative radius, 1.0 is the frame corner
+}
+
+impl std::fmt::Display for ProfileWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProfileWarning::InvalidCameraMatrix => write!(f, "Invalid camera matrix, focal length must be positive"),
+            ProfileWarning::InvalidCoefficients => write!(f, "Distortion coefficients contain NaN or infinity"),
+            ProfileWarning::NonMonotonic(r)     => write!(f, "Distortion non-monotonic beyond r={:.2}", r),
+            ProfileWarning::NotConverging(r)    => write!(f, "Undistortion doesn't converge beyond r={:.2}", r),
+        }
+    }
+}
+impl std::error::Error for ProfileWarning { }
+
+// Sanity checks the lens profile from the optical center to the farthest frame corner:
+// distorted angle has to increase with the ray angle and the inverse has to converge
+pub fn validate_profile(params: &ComputeParams) -> Result<(), ProfileWarning> {
+    const STEPS: usize = 100;
+
+    let f = (params.camera_matrix[(0, 0)], params.camera_matrix[(1, 1)]);
+    let c = (params.camera_matrix[(0, 2)], params.camera_matrix[(1, 2)]);
+    let k = &params.distortion_coeffs;
+    if !(f.0 > 0.0 && f.1 > 0.0) { return Err(ProfileWarning::InvalidCameraMatrix); }
+    if k.iter().any(|x| !x.is_finite()) { return Err(ProfileWarning::InvalidCoefficients); }
+
+    // Farthest corner in normalized coordinates, i.e. the largest distorted angle in the frame
+    let (w, h) = (params.video_width as f64, params.video_height as f64);
+    let theta_d_max = [(0.0, 0.0), (w, 0.0), (0.0, h), (w, h)].iter().map(|&(x, y)| {
+        (((x - c.0) / f.0).powi(2) + ((y - c.1) / f.1).powi(2)).sqrt()
+    }).fold(0.0, f64::max);
+    if theta_d_max <= 0.0 { return Ok(()); }
+
+    // d(theta_d)/d(theta) has to stay positive until the corner is reached
+    for i in 1..=STEPS {
+        let theta = std::f64::consts::FRAC_PI_2 * i as f64 / STEPS as f64;
+        let theta2 = theta * theta;
+        let theta_d = theta * (1.0 + k[0]*theta2 + k[1]*theta2.powi(2) + k[2]*theta2.powi(3) + k[3]*theta2.powi(4));
+        let derivative = 1.0 + 3.0*k[0]*theta2 + 5.0*k[1]*theta2.powi(2) + 7.0*k[2]*theta2.powi(3) + 9.0*k[3]*theta2.powi(4);
+        if derivative <= 0.0 {
+            return Err(ProfileWarning::NonMonotonic(theta_d.max(0.0) / theta_d_max));
+        }
+        if theta_d >= theta_d_max { break; }
+    }
+
+    for i in 1..=STEPS {
+        let r = i as f64 / STEPS as f64;
+        if undistort_point((r * theta_d_max, 0.0), k, 0.0, false, 0.0).is_none() {
+            return Err(ProfileWarning::NotConverging((i - 1) as f64 / STEPS as f64));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum CoeffsError {
+    WrongCount(usize),   // coefficient count
+    PinholeModel(usize), // coefficient count
+    NonFinite,
+}
+impl std::fmt::Display for CoeffsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CoeffsError::WrongCount(v)   => write!(f, "Expected 4 fisheye distortion coefficients, got {}", v),
+            CoeffsError::PinholeModel(v) => write!(f, "{} coefficients look like the OpenCV pinhole model (k1, k2, p1, p2, k3, ...), expected the fisheye model (k1, k2, k3, k4)", v),
+            CoeffsError::NonFinite       => write!(f, "Distortion coefficients contain NaN or infinity"),
+        }
+    }
+}
+impl std::error::Error for CoeffsError { }
+
+// OpenCV fisheye `D = [k1, k2, k3, k4]` maps directly to `distortion_coeffs[0..4]`:
+// theta_d = theta * (1 + k1*theta^2 + k2*theta^4 + k3*theta^6 + k4*theta^8), i.e. `k[0]` is k1, not a constant term.
+// `D` from `cv2.fisheye.calibrate` is a 4x1 array, pass it flattened.
+// The pinhole model (`cv2.calibrateCamera`, 5, 8, 12 or 14 coefficients) is a different projection and can't be converted here.
+pub fn coeffs_from_opencv(k: &[f64]) -> Result<[f64; 4], CoeffsError> {
+    match k.len() {
+        4 => { }
+        5 | 8 | 12 | 14 => return Err(CoeffsError::PinholeModel(k.len())),
+        n => return Err(CoeffsError::WrongCount(n)),
+    }
+    if k.iter().any(|x| !x.is_finite()) { return Err(CoeffsError::NonFinite); }
+    Ok([k[0], k[1], k[2], k[3]])
+}
+// Inverse of `coeffs_from_opencv`, usable as `D` for `cv2.fisheye.*` functions
+pub fn coeffs_to_opencv(k: &[f64; 4]) -> [f64; 4] {
+    [k[0], k[1], k[2], k[3]]
+}
+
 // Ported from OpenCV: https://github.com/opencv/opencv/blob/4.x/modules/calib3d/src/fisheye.cpp#L321
 pub fn undistort_points(distorted: &[(f64, f64)], camera_matrix: Matrix3<f64>, distortion_coeffs: &[f64], rotation: Matrix3<f64>, p: Option<Matrix3<f64>>, rot_per_point: Option<Vec<Matrix3<f64>>>, params: Option<&ComputeParams>) -> Vec<(f64, f64)> {
     let f = (camera_matrix[(0, 0)], camera_matrix[(1, 1)]);
@@ -305,7 +2109,7 @@ pub fn undistort_points(distorted: &[(f64, f64)], camera_matrix: Matrix3<f64>, d
 
         let rot = rot_per_point.as_ref().and_then(|v| v.get(index)).unwrap_or(&rr);
 
-        if let Some(mut pt) = undistort_point(pw, k, 0.0) {
+        if let Some(mut pt) = undistort_point(pw, k, 0.0, params.map(|x| x.super_fisheye).unwrap_or_default(), params.map(|x| x.max_theta_d).unwrap_or_default()) {
             // reproject
             let pr = rot * nalgebra::Vector3::new(pt.0, pt.1, 1.0); // rotated point optionally multiplied by new camera matrix
             pt = (pr[0] / pr[2], pr[1] / pr[2]);
@@ -323,3 +2127,534 @@ pub fn undistort_points(distorted: &[(f64, f64)], camera_matrix: Matrix3<f64>, d
         }
     }).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::synthetic_profile;
+    use crate::undistortion::RGBA8;
+    use crate::stabilization_params::{ InputAlphaMode, BackgroundMode };
+
+    fn rgba8_frame<F: Fn(usize, usize) -> [u8; 4]>(width: usize, height: usize, f: F) -> Vec<u8> {
+        let f = &f;
+        (0..height).flat_map(|y| (0..width).flat_map(move |x| f(x, y))).collect()
+    }
+
+    fn rgba8_at(buf: &[u8], width: usize, x: usize, y: usize) -> [u8; 4] {
+        let i = (y * width + x) * 4;
+        [buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]
+    }
+
+    #[test]
+    fn stereo_right_eye_with_exact_buffer() {
+        let (eye_width, height) = (16, 8);
+        let stride = eye_width * 2 * 4;
+        let mut pixels = rgba8_frame(eye_width * 2, height, |x, _| if x < eye_width { [50, 50, 50, 255] } else { [200, 200, 200, 255] });
+        assert_eq!(pixels.len(), stride * height);
+
+        let (_, params) = synthetic_profile(90.0, [0.0; 4], eye_width, height);
+        let (mut left, mut right) = (vec![0u8; eye_width * 4 * height], vec![0u8; eye_width * 4 * height]);
+        let bg = Vector4::new(0.0, 0.0, 0.0, 255.0);
+        assert!(Undistortion::<RGBA8>::undistort_image_cpu_stereo::<2>(&mut pixels, &mut left, &mut right, eye_width * 2, height, stride, eye_width, height, eye_width * 4, &params, &params, bg, None));
+
+        let (l, r) = (rgba8_at(&left, eye_width, eye_width / 2, height / 2), rgba8_at(&right, eye_width, eye_width / 2, height / 2));
+        assert!((l[0] as i32 - 50).abs() <= 1, "left eye {:?}", l);
+        assert!((r[0] as i32 - 200).abs() <= 1, "right eye {:?}", r);
+    }
+
+    // Uniform `width` x `height` frame remapped with the edge repeat background, so every tap outside of the frame reads the edge color
+    fn render_uniform<const I: i32>(width: usize, height: usize, color: [u8; 4]) -> Vec<u8> {
+        let mut pixels = rgba8_frame(width, height, |_, _| color);
+        let mut out = vec![0u8; width * 4 * height];
+        let (_, params) = synthetic_profile(90.0, [0.0; 4], width, height);
+        let mut frame = FrameParams::new(&params, height, width, height);
+        frame.background_mode = 1.0;
+        assert!(Undistortion::<RGBA8>::undistort_image_cpu_with_frame::<I>(frame, &mut pixels, &mut out, width, height, width * 4, width, height, width * 4, Vector4::new(0.0, 0.0, 0.0, 255.0)));
+        out
+    }
+
+    #[test]
+    fn tiny_frames() {
+        let color = [100, 150, 200, 255];
+        for (width, height) in [(1, 1), (2, 2), (8, 1), (1, 8)] {
+            for out in [render_uniform::<2>(width, height, color), render_uniform::<4>(width, height, color), render_uniform::<8>(width, height, color)] {
+                for y in 0..height {
+                    for x in 0..width {
+                        let px = rgba8_at(&out, width, x, y);
+                        assert!(px.iter().zip(color).all(|(a, b)| (*a as i32 - b as i32).abs() <= 1), "{}x{} at {},{}: {:?}", width, height, x, y, px);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn zero_sized_frames_are_rejected() {
+        let (_, params) = synthetic_profile(90.0, [0.0; 4], 4, 4);
+        let mut pixels = vec![0u8; 4 * 4 * 4];
+        let mut out = vec![7u8; 4 * 4 * 4];
+        let bg = Vector4::new(0.0, 0.0, 0.0, 255.0);
+        assert!(!Undistortion::<RGBA8>::undistort_image_cpu::<2>(&mut pixels, &mut out, 0, 4, 16, 4, 4, 16, &params, bg));
+        assert!(!Undistortion::<RGBA8>::undistort_image_cpu::<2>(&mut pixels, &mut out, 4, 4, 16, 4, 0, 16, &params, bg));
+        assert!(out.iter().all(|x| *x == 7));
+    }
+
+    fn render_dead_pixel<const I: i32>(mask: Option<&[bool]>) -> Vec<u8> {
+        let (width, height) = (8, 8);
+        let mut pixels = rgba8_frame(width, height, |x, y| if (x, y) == (4, 4) { [255, 0, 255, 255] } else { [100, 100, 100, 255] });
+        let mut out = vec![0u8; width * 4 * height];
+        let (_, params) = synthetic_profile(90.0, [0.0; 4], width, height);
+        let mut frame = FrameParams::new(&params, height, width, height);
+        frame.background_mode = 1.0;
+        frame.defect_mask = mask;
+        assert!(Undistortion::<RGBA8>::undistort_image_cpu_with_frame::<I>(frame, &mut pixels, &mut out, width, height, width * 4, width, height, width * 4, Vector4::new(0.0, 0.0, 0.0, 255.0)));
+        out
+    }
+
+    #[test]
+    fn dead_pixel_is_interpolated_over() {
+        let mut mask = vec![false; 8 * 8];
+        mask[4 * 8 + 4] = true;
+
+        let unmasked = render_dead_pixel::<2>(None);
+        assert!(unmasked.chunks(4).any(|px| (px[1] as i32 - 100).abs() > 10), "the dead pixel isn't sampled");
+
+        for out in [render_dead_pixel::<2>(Some(&mask)), render_dead_pixel::<4>(Some(&mask)), render_dead_pixel::<8>(Some(&mask))] {
+            for (i, px) in out.chunks(4).enumerate() {
+                assert!(px[..3].iter().all(|x| (*x as i32 - 100).abs() <= 1), "at {},{}: {:?}", i % 8, i / 8, px);
+            }
+        }
+    }
+
+    #[test]
+    fn degenerate_coefficients_give_background() {
+        let (width, height) = (8, 8);
+        let bg = Vector4::new(10.0, 20.0, 30.0, 255.0);
+        for k in [[f32::NAN; 4], [f32::INFINITY, 0.0, 0.0, 0.0], [0.0, f32::NEG_INFINITY, 0.0, f32::INFINITY]] {
+            let mut pixels = rgba8_frame(width, height, |_, _| [200, 200, 200, 255]);
+            let mut out = vec![0u8; width * 4 * height];
+            let (_, params) = synthetic_profile(90.0, k, width, height);
+            assert!(Undistortion::<RGBA8>::undistort_image_cpu::<4>(&mut pixels, &mut out, width, height, width * 4, width, height, width * 4, &params, bg));
+            for (i, px) in out.chunks(4).enumerate() {
+                assert_eq!(px, [10, 20, 30, 255], "k {:?} at {},{}", k, i % width, i / width);
+            }
+        }
+    }
+
+    fn render_fields(interlacing: f32) -> Vec<u8> {
+        let (width, height) = (16, 16);
+        let mut pixels = rgba8_frame(width, height, |_, y| if y % 2 == 0 { [50, 50, 50, 255] } else { [200, 200, 200, 255] });
+        let mut out = vec![0u8; width * 4 * height];
+        let (_, mut params) = synthetic_profile(90.0, [0.0; 4], width, height);
+        params[1][1] = 1.0; // Edge repeat
+        params[2][8] = interlacing;
+        assert!(Undistortion::<RGBA8>::undistort_image_cpu::<2>(&mut pixels, &mut out, width, height, width * 4, width, height, width * 4, &params, Vector4::new(0.0, 0.0, 0.0, 255.0)));
+        out
+    }
+
+    #[test]
+    fn interlaced_fields_are_not_mixed() {
+        // Progressive interpolates between the rows of both fields
+        let progressive = render_fields(0.0);
+        assert!(progressive.chunks(4).any(|px| px[0] > 60 && px[0] < 190));
+
+        for interlacing in [1.0, 2.0] {
+            let out = render_fields(interlacing);
+            for (i, px) in out.chunks(4).enumerate() {
+                let (x, y) = (i % 16, i / 16);
+                let expected = if y % 2 == 0 { 50 } else { 200 };
+                assert!((px[0] as i32 - expected).abs() <= 1, "interlacing {} at {},{}: {:?}", interlacing, x, y, px);
+            }
+        }
+    }
+
+    #[test]
+    fn deterministic_matches_parallel() {
+        let (width, height) = (32, 24);
+        let pixels = rgba8_frame(width, height, |x, y| [(x * 8) as u8, (y * 10) as u8, ((x + y) * 4) as u8, 255]);
+        let (_, mut params) = synthetic_profile(120.0, [0.05, -0.02, 0.01, 0.0], width, height);
+        params[1][4] = 4.0; // Supersample
+        let bg = Vector4::new(0.0, 0.0, 0.0, 255.0);
+        let (mut parallel, mut serial) = (vec![0u8; width * 4 * height], vec![0u8; width * 4 * height]);
+        assert!(Undistortion::<RGBA8>::undistort_image_cpu::<4>(&pixels, &mut parallel, width, height, width * 4, width, height, width * 4, &params, bg));
+        assert!(Undistortion::<RGBA8>::undistort_image_cpu_deterministic::<4>(&pixels, &mut serial, width, height, width * 4, width, height, width * 4, &params, bg));
+        assert_eq!(parallel, serial);
+
+        assert!(!Undistortion::<RGBA8>::undistort_image_cpu_deterministic::<4>(&pixels, &mut serial, 0, height, width * 4, width, height, width * 4, &params, bg));
+        assert!(!Undistortion::<RGBA8>::undistort_image_cpu_deterministic::<4>(&pixels[..width * 4], &mut serial, width, height, width * 4, width, height, width * 4, &params, bg));
+    }
+
+    #[test]
+    fn coverage_contour_is_the_projected_source_border() {
+        let (width, height) = (64, 48);
+        let (mut compute, _) = synthetic_profile(90.0, [0.05, -0.02, 0.01, 0.0], width, height);
+        let inside = |pt: &(f32, f32)| pt.0 >= 0.0 && pt.1 >= 0.0 && pt.0 <= (width - 1) as f32 && pt.1 <= (height - 1) as f32;
+        for (fov_scale, covered) in [(0.5, true), (2.0, false)] {
+            compute.fov_scale = fov_scale;
+            let params = FrameTransform::from_row_rotations(&compute, 0, |_| Matrix3::identity()).params;
+            let frame = FrameParams::new(&params, height, width, height);
+
+            let contour = coverage_contour(&params, width, height, width, height);
+            assert_eq!(contour.len(), (width + height - 2) * 2, "fov scale {}", fov_scale);
+            for pt in &contour {
+                // Zoomed in, the border and the headroom are outside of the output. Zoomed out, the background shows around the border
+                assert_eq!(inside(pt), !covered, "fov scale {} at {:?}", fov_scale, pt);
+                let src = frame.map_coord(pt.0, pt.1).unwrap();
+                let to_edge = src.0.abs().min(src.1.abs()).min((src.0 - (width - 1) as f32).abs()).min((src.1 - (height - 1) as f32).abs());
+                assert!(to_edge < 0.05, "fov scale {}: {:?} maps to {:?}", fov_scale, pt, src);
+            }
+        }
+    }
+
+    fn render_banded(pixels: &[u8], width: usize, height: usize, params: &[[f32; 9]], band_height: usize) -> Option<Vec<u8>> {
+        let mut out = vec![0u8; width * 4 * height];
+        let read_rows = |rows: Range<usize>, buf: &mut Vec<u8>| buf.extend_from_slice(&pixels[rows.start * width * 4..rows.end * width * 4]);
+        let write_band = |y0: usize, band: &[u8]| out[y0 * width * 4..y0 * width * 4 + band.len()].copy_from_slice(band);
+        Undistortion::<RGBA8>::undistort_image_cpu_banded::<4, _, _>(read_rows, write_band, band_height, width, height, width * 4, width, height, width * 4, params, Vector4::new(0.0, 0.0, 0.0, 255.0)).then_some(out)
+    }
+
+    #[test]
+    fn banded_matches_full_frame() {
+        let (width, height) = (32, 24);
+        let pixels = rgba8_frame(width, height, |x, y| [(x * 8) as u8, (y * 10) as u8, ((x + y) * 4) as u8, 255]);
+        let (_, mut params) = synthetic_profile(120.0, [0.05, -0.02, 0.01, 0.0], width, height);
+        for interlacing in [0.0, 1.0] {
+            params[2][8] = interlacing;
+            let mut full = vec![0u8; width * 4 * height];
+            assert!(Undistortion::<RGBA8>::undistort_image_cpu::<4>(&pixels, &mut full, width, height, width * 4, width, height, width * 4, &params, Vector4::new(0.0, 0.0, 0.0, 255.0)));
+            for band_height in [1, 5, 64] {
+                assert!(render_banded(&pixels, width, height, &params, band_height) == Some(full.clone()), "interlacing {}, band height {}", interlacing, band_height);
+            }
+        }
+    }
+
+    #[test]
+    fn banded_rejects_short_rows() {
+        let (width, height) = (16, 16);
+        let (_, params) = synthetic_profile(120.0, [0.05, -0.02, 0.01, 0.0], width, height);
+        let read_rows = |rows: Range<usize>, buf: &mut Vec<u8>| buf.resize((rows.end - rows.start) * width * 4 - 1, 0);
+        assert!(!Undistortion::<RGBA8>::undistort_image_cpu_banded::<2, _, _>(read_rows, |_, _| { }, 4, width, height, width * 4, width, height, width * 4, &params, Vector4::new(0.0, 0.0, 0.0, 255.0)));
+        assert!(render_banded(&[], 0, height, &params, 4).is_none());
+    }
+
+    // Width of the bright area along the center row and column, from the summed intensity so the interpolated edge counts proportionally
+    fn center_extent(buf: &[u8], width: usize, height: usize) -> (f32, f32) {
+        let row: f32 = (0..width).map(|x| rgba8_at(buf, width, x, height / 2)[0] as f32 / 255.0).sum();
+        let column: f32 = (0..height).map(|y| rgba8_at(buf, width, width / 2, y)[0] as f32 / 255.0).sum();
+        (row, column)
+    }
+
+    // Circle of radius 8 stored with a 2:1 horizontal squeeze, rendered with `pixel_aspect_ratio`
+    fn render_squeezed_circle(pixel_aspect_ratio: f64) -> (f32, f32) {
+        let (width, height) = (64, 64);
+        let mut pixels = rgba8_frame(width, height, |x, y| if ((x as f32 - 32.0) * 2.0).hypot(y as f32 - 32.0) <= 8.0 { [255, 255, 255, 255] } else { [0, 0, 0, 255] });
+        let (mut compute, _) = synthetic_profile(40.0, [0.0; 4], width, height);
+        compute.pixel_aspect_ratio = pixel_aspect_ratio;
+        let params = FrameTransform::from_row_rotations(&compute, 0, |_| Matrix3::identity()).params;
+        let mut out = vec![0u8; width * 4 * height];
+        assert!(Undistortion::<RGBA8>::undistort_image_cpu::<2>(&mut pixels, &mut out, width, height, width * 4, width, height, width * 4, &params, Vector4::new(0.0, 0.0, 0.0, 255.0)));
+        center_extent(&out, width, height)
+    }
+
+    #[test]
+    fn anamorphic_circle_is_round() {
+        // The stored ellipse is rasterized on a half resolution grid horizontally, so the widths differ by up to a pixel
+        let (w, h) = render_squeezed_circle(2.0);
+        assert!((w / h - 1.0).abs() < 0.1, "desqueezed {} x {}", w, h);
+        let (w, h) = render_squeezed_circle(1.0);
+        assert!((w / h - 0.5).abs() < 0.1, "squeezed {} x {}", w, h);
+    }
+
+    // 8 px checkerboard in the sensor orientation
+    fn checkerboard(x: usize, y: usize) -> [u8; 4] {
+        let v = if (x / 8 + y / 8) % 2 == 0 { 40 } else { 220 };
+        [v, v, v, 255]
+    }
+
+    #[test]
+    fn rotated_input_matches_sensor_orientation() {
+        let (width, height) = (64, 48);
+        let k = [0.05, -0.02, 0.01, 0.0];
+        let bg = Vector4::new(0.0, 0.0, 0.0, 255.0);
+        let (_, params) = synthetic_profile(100.0, k, width, height);
+        let mut expected = vec![0u8; width * 4 * height];
+        assert!(Undistortion::<RGBA8>::undistort_image_cpu::<2>(&rgba8_frame(width, height, checkerboard), &mut expected, width, height, width * 4, width, height, width * 4, &params, bg));
+
+        // Same frame stored rotated 90° clockwise, `height` x `width`. The lens profile and the output stay in the sensor orientation
+        let (mut compute, _) = synthetic_profile(100.0, k, width, height);
+        compute.input_orientation = crate::stabilization_params::Rotation90::Cw90;
+        compute.width = height;
+        compute.height = width;
+        compute.video_width = height;
+        compute.video_height = width;
+        let params = FrameTransform::from_row_rotations(&compute, 0, |_| Matrix3::identity()).params;
+        let rotated = rgba8_frame(height, width, |x, y| checkerboard(y, height - 1 - x));
+        let mut out = vec![0u8; width * 4 * height];
+        assert!(Undistortion::<RGBA8>::undistort_image_cpu::<2>(&rotated, &mut out, height, width, height * 4, width, height, width * 4, &params, bg));
+
+        for (i, (a, b)) in out.chunks(4).zip(expected.chunks(4)).enumerate() {
+            assert!(a.iter().zip(b).all(|(a, b)| (*a as i32 - *b as i32).abs() <= 1), "at {},{}: {:?} vs {:?}", i % width, i / width, a, b);
+        }
+    }
+
+    // 200° fisheye kept uncorrected (lens correction amount 0), so the output corners are past 90° off axis
+    fn render_super_fisheye(super_fisheye: bool) -> Vec<u8> {
+        let (width, height) = (64, 48);
+        // Periphery of the image circle in a different color than the center
+        let pixels = rgba8_frame(width, height, |x, y| if (x as f32 - 32.0).hypot(y as f32 - 24.0) > 20.0 { [200, 200, 200, 255] } else { [50, 50, 50, 255] });
+        let (mut compute, _) = synthetic_profile(200.0, [0.0; 4], width, height);
+        compute.lens_correction_amount = 0.0;
+        compute.super_fisheye = super_fisheye;
+        let params = FrameTransform::from_row_rotations(&compute, 0, |_| Matrix3::identity()).params;
+        let mut out = vec![0u8; width * 4 * height];
+        assert!(Undistortion::<RGBA8>::undistort_image_cpu::<2>(&pixels, &mut out, width, height, width * 4, width, height, width * 4, &params, Vector4::new(255.0, 0.0, 0.0, 255.0)));
+        out
+    }
+
+    #[test]
+    fn super_fisheye_fills_the_corners() {
+        let corners = [(0, 0), (63, 0), (0, 47), (63, 47)];
+        // The corners map to the periphery, about 28 px from the center
+        let out = render_super_fisheye(true);
+        for (x, y) in corners {
+            let px = rgba8_at(&out, 64, x, y);
+            assert!((px[0] as i32 - 200).abs() <= 1 && px[1] == px[0], "at {},{}: {:?}", x, y, px);
+        }
+        // Without the fallback the lens model can't be inverted there and the corners repeat the center
+        let out = render_super_fisheye(false);
+        for (x, y) in corners {
+            let px = rgba8_at(&out, 64, x, y);
+            assert!((px[0] as i32 - 50).abs() <= 1 && px[1] == px[0], "at {},{}: {:?}", x, y, px);
+        }
+    }
+
+    // Vertical edge between opaque and transparent pixels, zoomed out so the edge is sampled between pixels. Bicubic for the negative lobes
+    fn render_alpha_edge(alpha_mode: InputAlphaMode, opaque: [u8; 4]) -> Vec<u8> {
+        let (width, height) = (32, 16);
+        let pixels = rgba8_frame(width, height, |x, _| if x < 13 { opaque } else { [0, 0, 0, 0] });
+        let (mut compute, _) = synthetic_profile(90.0, [0.1, 0.0, 0.0, 0.0], width, height);
+        compute.fov_scale = 1.3;
+        compute.input_alpha_mode = alpha_mode;
+        let params = FrameTransform::from_row_rotations(&compute, 0, |_| Matrix3::identity()).params;
+        let mut out = vec![0u8; width * 4 * height];
+        assert!(Undistortion::<RGBA8>::undistort_image_cpu::<4>(&pixels, &mut out, width, height, width * 4, width, height, width * 4, &params, Vector4::new(0.0, 0.0, 0.0, 0.0)));
+        out
+    }
+
+    #[test]
+    fn alpha_edge_is_not_darkened() {
+        // Premultiplied white at half alpha, color and alpha are interpolated alike so the color stays equal to the alpha
+        let out = render_alpha_edge(InputAlphaMode::Premultiplied, [128, 128, 128, 128]);
+        assert!(out.chunks(4).any(|px| px[3] > 10 && px[3] < 118), "the edge isn't interpolated");
+        for (i, px) in out.chunks(4).enumerate() {
+            assert!((px[0] as i32 - px[3] as i32).abs() <= 1, "at {},{}: {:?}", i % 32, i / 32, px);
+        }
+        // Straight white, premultiplied before interpolation so the black of the transparent pixels doesn't bleed into the edge
+        let out = render_alpha_edge(InputAlphaMode::Straight, [255, 255, 255, 128]);
+        assert!(out.chunks(4).any(|px| px[3] > 10 && px[3] < 118), "the edge isn't interpolated");
+        for (i, px) in out.chunks(4).enumerate().filter(|(_, px)| px[3] > 2) {
+            assert!(px[0] >= 253 && px[1] == px[0], "at {},{}: {:?}", i % 32, i / 32, px);
+        }
+    }
+
+    // Horizontally periodic pattern, continuous across the seam of the 360° frame
+    fn equirect_pattern(x: f32) -> f32 {
+        128.0 + 100.0 * (x / 64.0 * std::f32::consts::TAU).sin()
+    }
+
+    #[test]
+    fn panned_equirect_is_continuous_across_the_seam() {
+        let (width, height) = (64, 32);
+        let pixels = rgba8_frame(width, height, |x, _| { let v = equirect_pattern(x as f32).round() as u8; [v, v, v, 255] });
+        let (mut compute, _) = synthetic_profile(90.0, [0.0; 4], width, height);
+        compute.background_mode = BackgroundMode::WrapHorizontal;
+        let pan = nalgebra::Rotation3::from_axis_angle(&Vector3::y_axis(), 30.0f32.to_radians()).into_inner();
+        let params = FrameTransform::from_row_rotations(&compute, 0, |_| pan).params;
+        let mut out = vec![0u8; width * 4 * height];
+        assert!(Undistortion::<RGBA8>::undistort_image_cpu::<2>(&pixels, &mut out, width, height, width * 4, width, height, width * 4, &params, Vector4::new(255.0, 0.0, 0.0, 255.0)));
+
+        // Every output pixel has the pattern value at its source position, including the ones sampled across the seam
+        let frame = FrameParams::new(&params, height, width, height);
+        let mut across_seam = 0;
+        for y in 0..height {
+            for x in 0..width {
+                let src = frame.map_coord(x as f32, y as f32).unwrap();
+                if src.0 < 0.0 || src.0 > width as f32 - 1.0 { across_seam += 1; }
+                let px = rgba8_at(&out, width, x, y);
+                assert!((px[0] as f32 - equirect_pattern(src.0)).abs() <= 2.0 && px[1] == px[0], "at {},{} (source x {}): {:?}", x, y, src.0, px);
+            }
+        }
+        assert!(across_seam > 0, "the pan doesn't reach the seam");
+    }
+
+    #[test]
+    fn decentered_lens_correction_is_symmetric() {
+        let (width, height) = (64, 48);
+        let (mut compute, _) = synthetic_profile(90.0, [0.1, 0.0, 0.0, 0.0], width, height);
+        compute.camera_matrix[(0, 2)] += 4.0;
+        compute.camera_matrix[(1, 2)] -= 3.0;
+        compute.lens_correction_amount = 0.5;
+        let params = FrameTransform::from_row_rotations(&compute, 0, |_| Matrix3::identity()).params;
+        let frame = FrameParams::new(&params, height, width, height);
+        // Output points symmetric around the center map to source points symmetric around the principal point
+        for (dx, dy) in [(10.0, 0.0), (25.0, 0.0), (0.0, 15.0), (20.0, 18.0)] {
+            let a = frame.map_coord(32.0 + dx, 24.0 + dy).unwrap();
+            let b = frame.map_coord(32.0 - dx, 24.0 - dy).unwrap();
+            let asymmetry = (a.0 - frame.c.0 + b.0 - frame.c.0, a.1 - frame.c.1 + b.1 - frame.c.1);
+            assert!(asymmetry.0.abs() < 0.01 && asymmetry.1.abs() < 0.01, "at ±{},{}: {:?} and {:?} around {:?}", dx, dy, a, b, frame.c);
+        }
+    }
+
+    // Largest difference between the color channels of any output pixel, for a 6 px wide dark to bright edge 18 px right of the lens center.
+    // The lens magnifies red by 5% and blue by -5% around the center, so the edge is 1 px apart in red, green and blue
+    fn edge_fringe(chromatic_aberration: Option<[f32; 3]>) -> i32 {
+        let (width, height) = (64, 48);
+        let edge = |x: f32| ((x - 50.0) / 6.0 * 255.0).max(0.0).min(255.0);
+        let pixels = rgba8_frame(width, height, |x, _| {
+            let x = x as f32 - 32.0;
+            [edge(32.0 + x / 1.05).round() as u8, edge(32.0 + x).round() as u8, edge(32.0 + x / 0.95).round() as u8, 255]
+        });
+        let (_, params) = synthetic_profile(90.0, [0.0; 4], width, height);
+        let mut frame = FrameParams::new(&params, height, width, height);
+        frame.chromatic_aberration = chromatic_aberration;
+        let mut out = vec![0u8; width * 4 * height];
+        assert!(Undistortion::<RGBA8>::undistort_image_cpu_with_frame::<2>(frame, &pixels, &mut out, width, height, width * 4, width, height, width * 4, Vector4::new(0.0, 0.0, 0.0, 255.0)));
+        out.chunks(4).map(|px| (0..3).map(|i| (px[i] as i32 - px[(i + 1) % 3] as i32).abs()).max().unwrap()).max().unwrap()
+    }
+
+    #[test]
+    fn chromatic_aberration_removes_the_fringe() {
+        let fringe = edge_fringe(None);
+        assert!(fringe > 60, "{}", fringe);
+        // Each channel is sampled at its own magnification, the edge lines up again. What's left is the bilinear error on the ramp
+        let corrected = edge_fringe(Some([1.05, 1.0, 0.95]));
+        assert!(corrected < 16, "{}", corrected);
+    }
+
+    #[test]
+    fn plain_resize_follows_the_background_mode() {
+        // Pinhole lens without rotation rendered at twice the size, so the mapping is a plain 2x upscale
+        let (width, height) = (32, 16);
+        let pixels = rgba8_frame(width, height, |x, y| [(x * 8) as u8, (y * 16) as u8, 128, 255]);
+        for mode in [BackgroundMode::SolidColor, BackgroundMode::RepeatPixels, BackgroundMode::MirrorPixels, BackgroundMode::WrapHorizontal] {
+            let (mut compute, _) = synthetic_profile(90.0, [0.0; 4], width, height);
+            compute.output_width = width * 2;
+            compute.output_height = height * 2;
+            compute.skip_distortion = true;
+            compute.identity_threshold = 0.01;
+            compute.background_mode = mode;
+            let mut params = FrameTransform::from_row_rotations(&compute, 0, |_| Matrix3::identity()).params;
+            assert!(FrameParams::new(&params, height, width * 2, height * 2).is_near_identity(width));
+            // The bicubic taps of the last output row and column are past the source edge
+            let render = |params: &[[f32; 9]]| {
+                let mut out = vec![0u8; width * 2 * 4 * height * 2];
+                assert!(Undistortion::<RGBA8>::undistort_image_cpu::<4>(&pixels, &mut out, width, height, width * 4, width * 2, height * 2, width * 2 * 4, params, Vector4::new(255.0, 0.0, 255.0, 255.0)));
+                out
+            };
+            let resized = render(&params);
+            // Same frame through the full remap
+            params[1][8] = 0.0;
+            let remapped = render(&params);
+            for (i, (a, b)) in resized.chunks(4).zip(remapped.chunks(4)).enumerate() {
+                assert!(a.iter().zip(b).all(|(a, b)| (*a as i32 - *b as i32).abs() <= 1), "mode {} at {},{}: {:?} vs {:?}", mode as i32, i % (width * 2), i / (width * 2), a, b);
+            }
+        }
+    }
+
+    // Comparison with OpenCV, which the lens model and the bilinear remap are adapted from (fisheye.cpp initUndistortRectifyMap + remap).
+    //
+    // `testdata/opencv_fisheye_remap.pgm` holds the result of these OpenCV 4.5 calls for the same calibration, written as a Python script:
+    //   K = [[f, 0, cx], [0, f, cy], [0, 0, 1]]  # params[0] of `synthetic_profile(100.0, K_COEFFS, 64, 48)`
+    //   P = inverse of params[3]                 # output camera matrix, no rotation
+    //   map1, map2 = cv2.fisheye.initUndistortRectifyMap(K, K_COEFFS, np.eye(3), P, (64, 48), cv2.CV_32FC1)
+    //   ref = cv2.remap(src, map1, map2, cv2.INTER_LINEAR, borderMode=cv2.BORDER_CONSTANT, borderValue=0)
+    // where `src` is the 8-bit gray `test_pattern`. It's stored as an ASCII PGM so it can be reviewed in a diff. The current file was computed with a line by line
+    // transcription of the two functions (including the fixed-point bilinear weights of remap), `reference_is_up_to_date` checks it against the library.
+    // OpenCV quantizes the remap position to 1/32 px and our bilinear table to its own step, so the tolerance is a couple of levels on the smooth pattern.
+    // Pixels sampling within 1 px of the source border are skipped, the border handling differs.
+    //
+    // With the `opencv-reference` feature (needs the OpenCV libraries like `use-opencv`), `reference_is_up_to_date` renders the reference with OpenCV
+    // again and compares it with the stored one. Run it with `GYROFLOW_UPDATE_OPENCV_REFERENCE=1` to rewrite the file instead, e.g. when a newer OpenCV rounds differently
+    mod opencv_reference {
+        use super::*;
+
+        const K_COEFFS: [f32; 4] = [0.05, -0.02, 0.01, 0.0];
+        const TOLERANCE: i32 = 2;
+        const WIDTH: usize = 64;
+        const HEIGHT: usize = 48;
+
+        fn test_pattern(x: usize, y: usize) -> u8 {
+            (128.0 + 80.0 * (x as f32 * 0.2).sin() * (y as f32 * 0.15).cos()).round() as u8
+        }
+
+        // Gray values of the stored reference, row-major. The PGM header is the magic, width, height and max value
+        fn stored_reference() -> Vec<u8> {
+            let values: Vec<usize> = include_str!("testdata/opencv_fisheye_remap.pgm").split_whitespace().skip(1).map(|x| x.parse().unwrap()).collect();
+            assert_eq!(&values[..3], &[WIDTH, HEIGHT, 255]);
+            assert_eq!(values.len(), 3 + WIDTH * HEIGHT);
+            values[3..].iter().map(|x| *x as u8).collect()
+        }
+
+        #[test]
+        fn matches_opencv_remap() {
+            let (_, params) = synthetic_profile(100.0, K_COEFFS, WIDTH, HEIGHT);
+
+            let pixels = rgba8_frame(WIDTH, HEIGHT, |x, y| { let v = test_pattern(x, y); [v, v, v, 255] });
+            let mut out = vec![0u8; WIDTH * 4 * HEIGHT];
+            assert!(Undistortion::<RGBA8>::undistort_image_cpu_deterministic::<2>(&pixels, &mut out, WIDTH, HEIGHT, WIDTH * 4, WIDTH, HEIGHT, WIDTH * 4, &params, Vector4::new(0.0, 0.0, 0.0, 255.0)));
+            let reference = stored_reference();
+
+            let frame = FrameParams::new(&params, HEIGHT, WIDTH, HEIGHT);
+            let mut compared = 0;
+            let mut worst = (0, 0, 0);
+            for y in 0..HEIGHT {
+                for x in 0..WIDTH {
+                    let (sx, sy) = frame.map_coord(x as f32, y as f32).unwrap_or((-1.0, -1.0));
+                    if !(sx >= 1.0 && sy >= 1.0 && sx <= WIDTH as f32 - 2.0 && sy <= HEIGHT as f32 - 2.0) { continue; }
+                    let diff = (rgba8_at(&out, WIDTH, x, y)[0] as i32 - reference[y * WIDTH + x] as i32).abs();
+                    if diff > worst.0 { worst = (diff, x, y); }
+                    compared += 1;
+                }
+            }
+            assert!(compared > WIDTH * HEIGHT / 2, "only {} pixels map inside of the source", compared);
+            assert!(worst.0 <= TOLERANCE, "differs from OpenCV by {} at {},{}", worst.0, worst.1, worst.2);
+        }
+
+        #[cfg(feature = "opencv-reference")]
+        #[test]
+        fn reference_is_up_to_date() {
+            use opencv::core::{ Mat, Size, Scalar, CV_8UC1, CV_32FC1, BORDER_CONSTANT };
+            use opencv::prelude::MatTraitConst;
+            use std::ffi::c_void;
+
+            let (_, params) = synthetic_profile(100.0, K_COEFFS, WIDTH, HEIGHT);
+            let p = Matrix3::from_row_slice(&params[3]).try_inverse().expect("output camera matrix");
+            let to_f64 = |m: Matrix3<f32>| -> [[f64; 3]; 3] { [0, 1, 2].map(|r| [0, 1, 2].map(|c| m[(r, c)] as f64)) };
+            let k = Mat::from_slice_2d(&[
+                [params[0][0] as f64, 0.0, params[0][2] as f64],
+                [0.0, params[0][1] as f64, params[0][3] as f64],
+                [0.0, 0.0, 1.0]
+            ]).unwrap();
+            let d = Mat::from_slice(&K_COEFFS.map(|x| x as f64)).unwrap();
+            let r = Mat::from_slice_2d(&to_f64(Matrix3::identity())).unwrap();
+            let p = Mat::from_slice_2d(&to_f64(p)).unwrap();
+            let (mut map1, mut map2) = (Mat::default(), Mat::default());
+            opencv::calib3d::fisheye_init_undistort_rectify_map(&k, &d, &r, &p, Size::new(WIDTH as i32, HEIGHT as i32), CV_32FC1, &mut map1, &mut map2).unwrap();
+
+            let mut gray: Vec<u8> = (0..HEIGHT).flat_map(|y| (0..WIDTH).map(move |x| test_pattern(x, y))).collect();
+            let src = unsafe { Mat::new_size_with_data(Size::new(WIDTH as i32, HEIGHT as i32), CV_8UC1, gray.as_mut_ptr() as *mut c_void, WIDTH) }.unwrap();
+            let mut reference = Mat::default();
+            opencv::imgproc::remap(&src, &mut reference, &map1, &map2, opencv::imgproc::INTER_LINEAR, BORDER_CONSTANT, Scalar::all(0.0)).unwrap();
+            let rendered: Vec<u8> = (0..HEIGHT).flat_map(|y| (0..WIDTH).map(move |x| (x, y))).map(|(x, y)| *reference.at_2d::<u8>(y as i32, x as i32).unwrap()).collect();
+
+            if std::env::var_os("GYROFLOW_UPDATE_OPENCV_REFERENCE").is_some() {
+                let rows = rendered.chunks(WIDTH).map(|row| row.iter().map(|v| format!("{:3}", v)).collect::<Vec<_>>().join(" ")).collect::<Vec<_>>().join("\n");
+                std::fs::write(concat!(env!("CARGO_MANIFEST_DIR"), "/undistortion/testdata/opencv_fisheye_remap.pgm"), format!("P2\n{} {}\n255\n{}\n", WIDTH, HEIGHT, rows)).unwrap();
+                return;
+            }
+            let stored = stored_reference();
+            let mismatch = (0..WIDTH * HEIGHT).find(|i| rendered[*i] != stored[*i]);
+            assert!(mismatch.is_none(), "the stored reference differs from OpenCV at {:?}, regenerate it with GYROFLOW_UPDATE_OPENCV_REFERENCE=1", mismatch.map(|i| (i % WIDTH, i / WIDTH)));
+        }
+    }
+}
@@ -1,14 +1,16 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
 
-use nalgebra::Matrix3;
+use nalgebra::{ Matrix3, Vector4 };
 use super::ComputeParams;
+use crate::stabilization_params::{ AspectFit, CoordinateConvention, InputAlphaMode, ReadoutDirection };
 use rayon::iter::{ ParallelIterator, IntoParallelIterator };
 
 #[derive(Default, Clone)]
 pub struct FrameTransform {
     pub params: Vec<[f32; 9]>,
     pub fov: f64,
+    pub bg: Option<Vector4<f32>>, // Background of this frame from `ComputeParams::background_keyframes`, None - the backend's `set_background` color
 }
 
 impl FrameTransform {
@@ -17,6 +19,10 @@ impl FrameTransform {
         if can_invert && params.framebuffer_inverted {
             frame_readout_time *= -1.0;
         }
+        if params.readout_direction == ReadoutDirection::BottomToTop {
+            // Last row is read first, so the per-row matrices are indexed from the bottom
+            frame_readout_time *= -1.0;
+        }
         frame_readout_time / 2.0
     }
     fn get_new_k(params: &ComputeParams, fov: f64) -> Matrix3<f64> {
@@ -25,42 +31,89 @@ impl FrameTransform {
         let out_dim = (params.output_width as f64, params.output_height as f64);
         //let focal_center = (params.video_width as f64 / 2.0, params.video_height as f64 / 2.0);
 
+        let scale = Self::get_aspect_scale(params);
+
         let mut new_k = params.camera_matrix;
-        new_k[(0, 0)] = new_k[(0, 0)] * img_dim_ratio / fov;
-        new_k[(1, 1)] = new_k[(1, 1)] * img_dim_ratio / fov;
+        new_k[(0, 0)] = new_k[(0, 0)] * img_dim_ratio / fov * Self::get_pixel_aspect_ratio(params) * scale.0;
+        new_k[(1, 1)] = new_k[(1, 1)] * img_dim_ratio / fov * scale.1;
         new_k[(0, 2)] = /*(params.video_width  as f64 / 2.0 - focal_center.0) * img_dim_ratio / fov + */out_dim.0 / 2.0;
         new_k[(1, 2)] = /*(params.video_height as f64 / 2.0 - focal_center.1) * img_dim_ratio / fov + */out_dim.1 / 2.0;
         new_k
     }
+    fn get_pixel_aspect_ratio(params: &ComputeParams) -> f64 {
+        // Desqueeze anamorphic input, so the output has square pixels
+        if params.pixel_aspect_ratio > 0.0 { params.pixel_aspect_ratio } else { 1.0 }
+    }
+    // Output focal length multipliers (x, y) for `aspect_fit`. The fov maps the input width to the output width,
+    // so the input height spans `in_aspect / out_aspect` of the output height before the adjustment
+    fn get_aspect_scale(params: &ComputeParams) -> (f64, f64) {
+        let (w, h) = Self::get_sensor_size(params);
+        if w == 0 || h == 0 || params.output_width == 0 || params.output_height == 0 { return (1.0, 1.0); }
+        let in_aspect = w as f64 * Self::get_pixel_aspect_ratio(params) / h as f64;
+        let out_aspect = params.output_width as f64 / params.output_height as f64;
+        let k = in_aspect / out_aspect;
+        match params.aspect_fit {
+            AspectFit::MatchWidth => (1.0, 1.0),
+            AspectFit::Stretch => (1.0, k),
+            AspectFit::Fit     => (k.min(1.0), k.min(1.0)),
+            AspectFit::Fill    => (k.max(1.0), k.max(1.0)),
+        }
+    }
+    fn get_sensor_size(params: &ComputeParams) -> (usize, usize) {
+        // Lens model and rolling shutter rows are in sensor orientation, which differs from the buffer for 90° rotated input
+        if params.input_orientation.swaps_axes() { (params.height, params.width) } else { (params.width, params.height) }
+    }
+    fn get_rotation_matrix(params: &ComputeParams, quat: &crate::gyro_source::Quat64) -> Matrix3<f64> {
+        let r = *quat.to_rotation_matrix().matrix();
+        match params.coordinate_convention {
+            CoordinateConvention::RightHanded => r,
+            CoordinateConvention::LeftHanded => {
+                // Mirror the y axis: S * R * S, where S = diag(1, -1, 1)
+                let s = Matrix3::from_diagonal(&nalgebra::Vector3::new(1.0, -1.0, 1.0));
+                s * r * s
+            }
+        }
+    }
+    fn get_lens_k(params: &ComputeParams, focal_scale: f64) -> Matrix3<f64> {
+        let mut k = params.camera_matrix * Self::get_ratio(params);
+        k[(0, 0)] *= focal_scale;
+        k[(1, 1)] *= focal_scale;
+        k
+    }
     fn get_ratio(params: &ComputeParams) -> f64 {
         params.width as f64 / params.video_width.max(1) as f64
     }
-    fn get_fov(params: &ComputeParams, frame: usize, use_fovs: bool) -> f64 {
-        let mut fov = if use_fovs && params.fovs.len() > frame { params.fovs[frame] * params.fov_scale } else { params.fov_scale }.max(0.001);
+    fn get_fov(params: &ComputeParams, frame: usize, use_fovs: bool, fov_scale: f64) -> f64 {
+        let mut fov = if use_fovs && params.fovs.len() > frame { params.fovs[frame] * fov_scale } else { fov_scale }.max(0.001);
         //fov *= params.video_width as f64 / params.video_output_width.max(1) as f64;
-        fov *= params.width as f64 / params.output_width.max(1) as f64;
+        fov *= Self::get_sensor_size(params).0 as f64 / params.output_width.max(1) as f64; // Output is in the sensor orientation
         fov
     }
 
     pub fn at_timestamp(params: &ComputeParams, timestamp_ms: f64, frame: usize) -> Self {
-        let img_dim_ratio = Self::get_ratio(params);
-        let mut fov = Self::get_fov(params, frame, true);
-        let mut ui_fov = fov / (params.width as f64 / params.output_width.max(1) as f64);
-        if params.lens_fov_adjustment > 0.0001 {
-            if params.fovs.is_empty() {
-                fov *= params.lens_fov_adjustment;
-            } else {
-                ui_fov /= params.lens_fov_adjustment;
-            }
-        }
-    
-        let scaled_k = params.camera_matrix * img_dim_ratio;
-        let new_k = Self::get_new_k(params, fov);
-        
+        let mut itm = Self::with_row_rotations(params, frame, params.fov_at_timestamp(timestamp_ms), params.focal_scale_at_timestamp(timestamp_ms), params.distortion_coeffs_at_timestamp(timestamp_ms), Self::row_rotations(params, timestamp_ms));
+        itm.bg = params.background_at_timestamp(timestamp_ms);
+        itm
+    }
+
+    // Transforms of both eyes of a stereo rig. The stabilization rotation and the FOV come from `left` (the rig has a single gyro),
+    // so both eyes are rotated identically and the stereo window stays aligned. Only the lens profiles differ, the adaptive zoom `fovs` should be the same for both
+    pub fn stereo_at_timestamp(left: &ComputeParams, right: &ComputeParams, timestamp_ms: f64, frame: usize) -> (Self, Self) {
+        let rotation = Self::row_rotations(left, timestamp_ms);
+        let fov = left.fov_at_timestamp(timestamp_ms);
+        let bg = left.background_at_timestamp(timestamp_ms);
+        (
+            Self { bg, ..Self::with_row_rotations(left,  frame, fov, left.focal_scale_at_timestamp(timestamp_ms),  left.distortion_coeffs_at_timestamp(timestamp_ms),  &rotation) },
+            Self { bg, ..Self::with_row_rotations(right, frame, fov, right.focal_scale_at_timestamp(timestamp_ms), right.distortion_coeffs_at_timestamp(timestamp_ms), &rotation) }
+        )
+    }
+
+    // Stabilization rotation for every sensor row at the given timestamp, in the framebuffer convention
+    fn row_rotations(params: &ComputeParams, timestamp_ms: f64) -> impl Fn(usize) -> Matrix3<f64> + Sync + '_ {
         // ----------- Rolling shutter correction -----------
         let frame_readout_time = Self::get_frame_readout_time(params, true);
 
-        let row_readout_time = frame_readout_time / params.height as f64;
+        let row_readout_time = frame_readout_time / Self::get_sensor_size(params).1 as f64;
         let start_ts = timestamp_ms - (frame_readout_time / 2.0);
         // ----------- Rolling shutter correction -----------
 
@@ -68,20 +121,15 @@ impl FrameTransform {
 
         let quat1 = params.gyro.org_quat_at_timestamp(timestamp_ms).inverse();
 
-        // Only compute 1 matrix if not using rolling shutter correction
-        let rows = if frame_readout_time.abs() > 0.0 { params.height } else { 1 };
-
-        let mut transform_params = (0..rows).into_par_iter().map(|y| {
+        move |y| {
             let quat_time = if frame_readout_time.abs() > 0.0 && timestamp_ms > 0.0 {
                 start_ts + row_readout_time * y as f64
             } else {
                 timestamp_ms
             };
-            let quat = quat1
-                     * params.gyro.org_quat_at_timestamp(quat_time)
-                     * params.gyro.smoothed_quat_at_timestamp(quat_time);
+            let quat = Self::correction_quat(params, &quat1, quat_time);
 
-            let mut r = image_rotation * *quat.to_rotation_matrix().matrix();
+            let mut r = image_rotation * Self::get_rotation_matrix(params, &quat);
             if params.framebuffer_inverted {
                 r[(0, 2)] *= -1.0; r[(1, 2)] *= -1.0;
                 r[(2, 0)] *= -1.0; r[(2, 1)] *= -1.0;
@@ -89,7 +137,49 @@ impl FrameTransform {
                 r[(0, 1)] *= -1.0; r[(0, 2)] *= -1.0;
                 r[(1, 0)] *= -1.0; r[(2, 0)] *= -1.0;
             }
-            
+            r
+        }
+    }
+
+    // Correction rotation at `quat_time`, `quat1` is the inverse camera orientation at the frame timestamp.
+    // Supplied rotations are slerped per row, so the rows stay orthonormal even for fast rotations, unlike interpolated matrices
+    fn correction_quat(params: &ComputeParams, quat1: &crate::gyro_source::Quat64, quat_time: f64) -> crate::gyro_source::Quat64 {
+        params.rotation_quat_at_timestamp(quat_time).unwrap_or_else(|| quat1
+            * params.gyro.org_quat_at_timestamp(quat_time)
+            * params.gyro.smoothed_quat_at_timestamp(quat_time))
+    }
+
+    // Builds the transform from caller-supplied rotations, evaluated lazily for every sensor row (or only row 0 without rolling shutter correction).
+    // The matrix is expected in the final framebuffer convention, i.e. after `video_rotation` and the axis flips done in `at_timestamp`.
+    // Note: the closure is called `height` times per frame from multiple threads, so any expensive lookup should be cached by the caller.
+    pub fn from_row_rotations<F: Fn(usize) -> Matrix3<f32> + Sync>(params: &ComputeParams, frame: usize, rotation: F) -> Self {
+        Self::with_row_rotations(params, frame, params.fov_scale, 1.0, params.distortion_coeffs, |y| nalgebra::convert(rotation(y)))
+    }
+
+    // Focus breathing: the lens focal length is scaled by `focal_scale`, while the output projection (`new_k`, and `f2` through the fov slot) keeps the nominal one,
+    // so the output field of view doesn't change with the focus
+    fn with_row_rotations<F: Fn(usize) -> Matrix3<f64> + Sync>(params: &ComputeParams, frame: usize, fov_scale: f64, focal_scale: f64, distortion_coeffs: [f64; 4], rotation: F) -> Self {
+        let mut fov = Self::get_fov(params, frame, true, fov_scale);
+        let mut ui_fov = fov / (Self::get_sensor_size(params).0 as f64 / params.output_width.max(1) as f64);
+        if params.lens_fov_adjustment > 0.0001 {
+            if params.fovs.is_empty() {
+                fov *= params.lens_fov_adjustment;
+            } else {
+                ui_fov /= params.lens_fov_adjustment;
+            }
+        }
+    
+        let scaled_k = Self::get_lens_k(params, focal_scale);
+        let new_k = Self::get_new_k(params, fov);
+
+        // Only compute 1 matrix if not using rolling shutter correction
+        let sensor_size = Self::get_sensor_size(params);
+        let aspect_scale = Self::get_aspect_scale(params);
+        let rows = if Self::get_frame_readout_time(params, true).abs() > 0.0 { sensor_size.1 } else { 1 };
+
+        let mut transform_params = (0..rows).into_par_iter().map(|y| {
+            let r = rotation(y);
+
             let i_r = (new_k * r).pseudo_inverse(0.000001);
             if let Err(err) = i_r {
                 log::error!("Failed to multiply matrices: {:?} * {:?}: {}", new_k, r, err);
@@ -107,10 +197,10 @@ impl FrameTransform {
             scaled_k[(0, 0)] as f32, scaled_k[(1, 1)] as f32, // 1, 2 - f
             scaled_k[(0, 2)] as f32, scaled_k[(1, 2)] as f32, // 3, 4 - c
     
-            params.distortion_coeffs[0] as f32, // 5
-            params.distortion_coeffs[1] as f32, // 6
-            params.distortion_coeffs[2] as f32, // 7
-            params.distortion_coeffs[3] as f32, // 8
+            distortion_coeffs[0] as f32, // 5
+            distortion_coeffs[1] as f32, // 6
+            distortion_coeffs[2] as f32, // 7
+            distortion_coeffs[3] as f32, // 8
             params.radial_distortion_limit as f32
         ]);
 
@@ -118,21 +208,36 @@ impl FrameTransform {
         transform_params.insert(1, [
             params.lens_correction_amount as f32,
             params.background_mode as i32 as f32, 
-            fov as f32, 
-            0.0, 0.0, 0.0, 0.0, 0.0, 0.0 // unused
+            (fov * focal_scale / aspect_scale.1) as f32, // `f2` is derived from the lens focal length, so it's compensated here
+            (Self::get_pixel_aspect_ratio(params) * aspect_scale.0 / aspect_scale.1) as f32,
+            params.supersample.max(1) as f32,
+            params.input_orientation as i32 as f32,
+            sensor_size.0 as f32, sensor_size.1 as f32,
+            params.identity_threshold
+        ]);
+        transform_params.insert(2, [
+            if params.dither { 1.0 } else { 0.0 },
+            if params.super_fisheye { 1.0 } else { 0.0 },
+            if params.show_rolling_shutter { 1.0 } else { 0.0 },
+            if params.input_alpha_mode == InputAlphaMode::Straight { 1.0 } else { 0.0 }, // premultiply input
+            params.max_theta_d as f32, // 0 - default limit
+            if params.skip_distortion { 1.0 } else { 0.0 }, // skip lens distortion
+            params.sharpen_amount.max(0.0),
+            0.0, // unused
+            params.interlacing as i32 as f32, // CPU only
         ]);
 
         Self {
             params: transform_params,
-            fov: ui_fov
+            fov: ui_fov,
+            bg: None
         }
     }
 
     pub fn at_timestamp_for_points(params: &ComputeParams, points: &[(f64, f64)], timestamp_ms: f64) -> (Matrix3<f64>, [f64; 4], Matrix3<f64>, Vec<Matrix3<f64>>) { // camera_matrix, dist_coeffs, p, rotations_per_point
-        let img_dim_ratio = Self::get_ratio(params);
-        let fov = Self::get_fov(params, 0, false);
+        let fov = Self::get_fov(params, 0, false, params.fov_at_timestamp(timestamp_ms));
 
-        let scaled_k = params.camera_matrix * img_dim_ratio;
+        let scaled_k = Self::get_lens_k(params, params.focal_scale_at_timestamp(timestamp_ms));
         let new_k = Self::get_new_k(params, fov);
 
         // ----------- Rolling shutter correction -----------
@@ -155,17 +260,162 @@ impl FrameTransform {
             } else {
                 timestamp_ms
             };
-            let quat = quat1
-                     * params.gyro.org_quat_at_timestamp(quat_time)
-                     * params.gyro.smoothed_quat_at_timestamp(quat_time);
+            let quat = Self::correction_quat(params, &quat1, quat_time);
 
-            let mut r = image_rotation * *quat.to_rotation_matrix().matrix();
+            let mut r = image_rotation * Self::get_rotation_matrix(params, &quat);
             r[(0, 1)] *= -1.0; r[(0, 2)] *= -1.0;
             r[(1, 0)] *= -1.0; r[(2, 0)] *= -1.0;
             
             new_k * r
         }).collect();
 
-        (scaled_k, params.distortion_coeffs, new_k, rotations)
+        (scaled_k, params.distortion_coeffs_at_timestamp(timestamp_ms), new_k, rotations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gyro_source::{ Quat64, TimeQuat };
+    use crate::test_support::synthetic_profile;
+    use nalgebra::Vector3;
+
+    fn rotation_angle(r: &Matrix3<f64>) -> f64 {
+        ((r.trace() - 1.0) / 2.0).max(-1.0).min(1.0).acos()
+    }
+
+    // 90° about the optical axis within one second
+    fn fast_rotation() -> (ComputeParams, Quat64, Quat64) {
+        let (mut params, _) = synthetic_profile(90.0, [0.0; 4], 64, 48);
+        let (q1, q2) = (Quat64::identity(), Quat64::from_axis_angle(&Vector3::z_axis(), std::f64::consts::FRAC_PI_2));
+        let mut quats = TimeQuat::new();
+        quats.insert(0, q1);
+        quats.insert(1_000_000, q2);
+        params.rotation_quats = Some(std::sync::Arc::new(quats));
+        (params, q1, q2)
+    }
+
+    #[test]
+    fn supplied_rotations_slerp_stays_orthonormal() {
+        let (params, q1, q2) = fast_rotation();
+        let r = params.rotation_quat_at_timestamp(500.0).unwrap().to_rotation_matrix().into_inner();
+        assert!((r.transpose() * r - Matrix3::identity()).norm() < 1e-9);
+        assert!((rotation_angle(&r) - std::f64::consts::FRAC_PI_4).abs() < 1e-9);
+
+        // Interpolating the matrices instead shrinks the frame, det = 0.5 halfway through a 90° turn
+        let lerp = (q1.to_rotation_matrix().into_inner() + q2.to_rotation_matrix().into_inner()) * 0.5;
+        assert!((lerp.determinant() - 0.5).abs() < 1e-9);
+    }
+
+    // Size of the input frame in the output, relative to the output size, for a 16:9 input rendered to 1:1
+    fn framing_16_9_to_1_1(aspect_fit: AspectFit) -> (f64, f64) {
+        let (mut params, _) = synthetic_profile(90.0, [0.0; 4], 160, 90);
+        params.output_width = 90;
+        params.output_height = 90;
+        params.aspect_fit = AspectFit::MatchWidth;
+        let base = FrameTransform::get_new_k(&params, 1.0);
+        params.aspect_fit = aspect_fit;
+        let k = FrameTransform::get_new_k(&params, 1.0);
+        // Without the adjustment the input width spans the output width and the height 90/160 of it
+        (k[(0, 0)] / base[(0, 0)], k[(1, 1)] / base[(1, 1)] * 90.0 / 160.0)
+    }
+
+    #[test]
+    fn aspect_fit_framing() {
+        let close = |a: (f64, f64), b: (f64, f64)| (a.0 - b.0).abs() < 1e-9 && (a.1 - b.1).abs() < 1e-9;
+        // Letterboxed, the input height covers 56% of the output
+        assert!(close(framing_16_9_to_1_1(AspectFit::MatchWidth), (1.0, 0.5625)));
+        assert!(close(framing_16_9_to_1_1(AspectFit::Fit), (1.0, 0.5625)));
+        // Height fills the output, the sides are cropped
+        assert!(close(framing_16_9_to_1_1(AspectFit::Fill), (160.0 / 90.0, 1.0)));
+        // Both axes fill the output
+        assert!(close(framing_16_9_to_1_1(AspectFit::Stretch), (1.0, 1.0)));
+        assert!(AspectFit::default() == AspectFit::MatchWidth);
+    }
+
+    // Source position of the output point 10 px right of the center, with the correction `roll` (radians) about the optical axis reported in `convention`
+    fn rolled_point(roll: f64, convention: CoordinateConvention) -> (f32, f32) {
+        let (mut params, _) = synthetic_profile(90.0, [0.0; 4], 64, 48);
+        let mut quats = TimeQuat::new();
+        quats.insert(0, Quat64::from_axis_angle(&Vector3::z_axis(), roll));
+        params.rotation_quats = Some(std::sync::Arc::new(quats));
+        params.coordinate_convention = convention;
+        let itm = FrameTransform::at_timestamp(&params, 0.0, 0);
+        crate::undistortion::FrameParams::new(&itm.params, 48, 64, 48).map_coord(42.0, 24.0).unwrap()
+    }
+
+    #[test]
+    fn roll_direction_in_both_conventions() {
+        let roll = 10.0f64.to_radians();
+        // Right-handed: with a positive roll the point right of the center samples below the center row (y down)
+        let right = rolled_point(roll, CoordinateConvention::RightHanded);
+        assert!(right.1 > 24.0 + 1.0 && right.0 < 42.0, "{:?}", right);
+        // The same camera motion from a left-handed gyro has the opposite sign, the stabilization has to be the same
+        let left = rolled_point(-roll, CoordinateConvention::LeftHanded);
+        assert!((left.0 - right.0).abs() < 1e-3 && (left.1 - right.1).abs() < 1e-3, "{:?} vs {:?}", left, right);
+        // Reading a left-handed roll as right-handed mirrors the correction
+        let mirrored = rolled_point(roll, CoordinateConvention::LeftHanded);
+        assert!((mirrored.1 - (48.0 - right.1)).abs() < 1e-3 && (mirrored.0 - right.0).abs() < 1e-3, "{:?} vs {:?}", mirrored, right);
+    }
+
+    // Horizontal source offset between the output rows 44 and 4 in the center column, during a 50°/s pan with 200 ms rolling shutter readout
+    fn pan_skew(direction: ReadoutDirection) -> f32 {
+        let (mut params, _) = synthetic_profile(90.0, [0.0; 4], 64, 48);
+        let mut quats = TimeQuat::new();
+        quats.insert(400_000, Quat64::from_axis_angle(&Vector3::y_axis(), -5.0f64.to_radians()));
+        quats.insert(600_000, Quat64::from_axis_angle(&Vector3::y_axis(), 5.0f64.to_radians()));
+        params.rotation_quats = Some(std::sync::Arc::new(quats));
+        params.frame_readout_time = 200.0;
+        params.readout_direction = direction;
+        let itm = FrameTransform::at_timestamp(&params, 500.0, 0);
+        assert_eq!(itm.params.len(), 3 + 48);
+        let frame = crate::undistortion::FrameParams::new(&itm.params, 48, 64, 48);
+        frame.map_coord(32.0, 44.0).unwrap().0 - frame.map_coord(32.0, 4.0).unwrap().0
+    }
+
+    #[test]
+    fn rolling_shutter_skew_in_both_readout_directions() {
+        // Rows read later see more of the pan, the correction shears the frame against it
+        let top_to_bottom = pan_skew(ReadoutDirection::TopToBottom);
+        assert!(top_to_bottom > 2.0, "{}", top_to_bottom);
+        // The bottom row is read first, so the skew and its correction are mirrored
+        let bottom_to_top = pan_skew(ReadoutDirection::BottomToTop);
+        assert!((bottom_to_top + top_to_bottom).abs() < 0.01, "{} vs {}", bottom_to_top, top_to_bottom);
+    }
+
+    // Source position of the output point `pt` at `timestamp_ms`, for a lens whose focal length breathes from 1x at 0 ms to 1.25x at 1000 ms
+    fn breathing_point(timestamp_ms: f64, lens_correction_amount: f64, pt: (f32, f32)) -> (f32, f32) {
+        let (mut params, _) = synthetic_profile(90.0, [0.05, 0.0, 0.0, 0.0], 64, 48);
+        let mut quats = TimeQuat::new();
+        quats.insert(0, Quat64::identity());
+        params.rotation_quats = Some(std::sync::Arc::new(quats));
+        params.breathing_curve = vec![(0.0, 1.0), (1000.0, 1.25)];
+        params.lens_correction_amount = lens_correction_amount;
+        let itm = FrameTransform::at_timestamp(&params, timestamp_ms, 0);
+        crate::undistortion::FrameParams::new(&itm.params, 48, 64, 48).map_coord(pt.0, pt.1).unwrap()
+    }
+
+    #[test]
+    fn breathing_keeps_the_field_of_view() {
+        // Each output pixel sees the same ray along the whole curve, so its distance from the principal point in the source grows with the focal length.
+        // With partial correction the added back distortion has to stay the same too
+        for amount in [1.0, 0.5] {
+            for pt in [(0.0, 24.0), (60.0, 40.0), (40.0, 0.0)] {
+                let base = breathing_point(0.0, amount, pt);
+                for (ts, scale) in [(500.0, 1.125), (1000.0, 1.25)] {
+                    let src = breathing_point(ts, amount, pt);
+                    let expected = (32.0 + (base.0 - 32.0) * scale, 24.0 + (base.1 - 24.0) * scale);
+                    assert!((src.0 - expected.0).abs() < 1e-3 && (src.1 - expected.1).abs() < 1e-3, "{:?} at {} ms with amount {}: {:?} vs {:?}", pt, ts, amount, src, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn points_use_supplied_rotations() {
+        let (params, _, _) = fast_rotation();
+        let (_, _, p, rotations) = FrameTransform::at_timestamp_for_points(&params, &[(32.0, 24.0)], 500.0);
+        let r = p.try_inverse().unwrap() * rotations[0];
+        assert!((rotation_angle(&r) - std::f64::consts::FRAC_PI_4).abs() < 1e-6);
     }
 }
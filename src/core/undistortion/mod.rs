@@ -7,6 +7,7 @@ use nalgebra::Vector4;
 
 #[cfg(feature = "use-opencl")]
 use super::gpu::opencl;
+#[cfg(feature = "gpu")]
 use super::gpu::wgpu;
 use super::StabilizationManager;
 
@@ -14,20 +15,36 @@ mod compute_params;
 mod frame_transform;
 mod cpu_undistort;
 mod pixel_formats;
+mod pipeline;
+#[cfg(feature = "gpu")]
+mod clip_stream;
 pub use pixel_formats::*;
+pub use pipeline::{ spawn_cpu_pipeline, Frame };
+#[cfg(feature = "gpu")]
+pub use clip_stream::{ process_clip_async, ClipStream };
 pub use compute_params::ComputeParams;
 pub use frame_transform::FrameTransform;
-pub use cpu_undistort::{ undistort_points, undistort_points_with_rolling_shutter, COEFFS };
+pub use cpu_undistort::{ undistort_points, undistort_points_with_rolling_shutter, distort_points, undistort_points_batch, warp_polygon, compute_jacobian, compute_warp_grid, compute_motion_vectors, valid_output_bounds, coverage_contour, coverage_mask, min_zoom_for_full_coverage, effective_fov, stmap_from_normalized, validate_profile, ProfileWarning, coeffs_from_opencv, coeffs_to_opencv, CoeffsError, validate_params, ParamsError, undistort_image_cpu_planar, FrameParams, RadialLut, YuvLayout, ChromaSiting, Tonemap, ColorTransform, ColorTransfer, ColorLut, ColorLutError, BackgroundRegions, BackgroundRegionsError, MAX_BACKGROUND_REGIONS, RemapStats, INTERPOLATION_ADAPTIVE, MAX_TABLE_KERNEL_RADIUS, COEFFS };
 
 #[derive(Clone, Copy)]
 pub enum Interpolation {
     Bilinear = 2,
     Bicubic = 4, 
-    Lanczos4 = 8
+    Lanczos4 = 8,
+    Adaptive = 1 // Tap count per pixel from the local minification, see `FrameParams::adaptive_taps`. CPU only, the GPU backends use Lanczos4
 }
 impl Default for Interpolation {
     fn default() -> Self { Interpolation::Bilinear }
 }
+impl Interpolation {
+    // Tap count passed to the GPU backends, which don't implement `Adaptive`
+    pub fn gpu_taps(self) -> u32 {
+        match self {
+            Interpolation::Adaptive => Interpolation::Lanczos4 as u32,
+            x => x as u32
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct Undistortion<T: PixelType> {
@@ -39,9 +56,22 @@ pub struct Undistortion<T: PixelType> {
 
     pub interpolation: Interpolation,
 
+    // Prefer the integrated GPU for wgpu, e.g. for preview on battery
+    low_power_gpu: bool,
+
+    // Lower quality wgpu shader for real-time preview, see `wgpu::Quality`. Not used by the CPU and OpenCL paths
+    fast_preview: bool,
+
+    // Pool for the CPU path, so it doesn't take all cores from the decoder. Global rayon pool if None
+    thread_pool: Option<std::sync::Arc<rayon::ThreadPool>>,
+
+    // Linear ceiling for float (HDR) output, the floor is always 0. Removes negative ringing and NaNs before tone mapping
+    pub hdr_max: Option<f32>,
+
     #[cfg(feature = "use-opencl")]
     cl: Option<opencl::OclWrapper>,
 
+    #[cfg(feature = "gpu")]
     wgpu: Option<wgpu::WgpuWrapper>,
 
     backend_initialized: bool,
@@ -76,9 +106,41 @@ impl<T: PixelType> Undistortion<T> {
         &self.empty_frame_transform
     }
 
+    // Drops everything derived from previously processed frames, so a random access seek gives the same output as sequential playback.
+    // The transform of the target frame is computed right away
+    pub fn seek(&mut self, frame_index: usize) {
+        self.stab_data.clear();
+        #[cfg(feature = "gpu")]
+        if let Some(ref mut wgpu) = self.wgpu {
+            wgpu.invalidate_input();
+        }
+        let fps = self.compute_params.gyro.fps;
+        if fps > 0.0 {
+            let timestamp_ms = crate::timestamp_at_frame(frame_index as i32, fps);
+            self.get_stab_data_at_timestamp((timestamp_ms * 1000.0).round() as i64);
+        }
+    }
+
     pub fn init_size(&mut self, bg: Vector4<f32>, size: (usize, usize), stride: usize, output_size: (usize, usize), output_stride: usize) {
         self.background = bg;
-        self.backend_initialized = false;
+
+        #[cfg(feature = "use-opencl")]
+        let has_cl = self.cl.is_some();
+        #[cfg(not(feature = "use-opencl"))]
+        let has_cl = false;
+
+        // Only the output changed, resize in place instead of recreating the wgpu backend
+        let same_input = self.size == (size.0, size.1, stride);
+        #[cfg(feature = "gpu")]
+        let resized = !has_cl && same_input && self.backend_initialized && self.wgpu.as_mut().map(|wgpu| {
+            wgpu.set_background(bg);
+            wgpu.resize_output(output_size.0, output_size.1, output_stride)
+        }).unwrap_or(false);
+        #[cfg(not(feature = "gpu"))]
+        let resized = { let _ = (has_cl, same_input); false };
+        if !resized {
+            self.backend_initialized = false;
+        }
 
         self.size = (size.0, size.1, stride);
         self.output_size = (output_size.0, output_size.1, output_stride);
@@ -87,6 +149,7 @@ impl<T: PixelType> Undistortion<T> {
 
     pub fn set_background(&mut self, bg: Vector4<f32>) {
         self.background = bg;
+        #[cfg(feature = "gpu")]
         if let Some(ref mut wgpu) = self.wgpu {
             wgpu.set_background(bg);
         }
@@ -96,6 +159,52 @@ impl<T: PixelType> Undistortion<T> {
         }
     }
 
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.interpolation = interpolation;
+        #[cfg(feature = "gpu")]
+        if let Some(ref mut wgpu) = self.wgpu {
+            wgpu.set_interpolation(interpolation);
+        }
+        #[cfg(feature = "use-opencl")]
+        if self.cl.is_some() {
+            // OpenCL kernel has the interpolation compiled in
+            self.backend_initialized = false;
+        }
+    }
+
+    pub fn set_low_power_gpu(&mut self, low_power: bool) {
+        if self.low_power_gpu != low_power {
+            self.low_power_gpu = low_power;
+            // Device has to be created on the other adapter
+            #[cfg(feature = "gpu")]
+            { self.wgpu = None; }
+            self.backend_initialized = false;
+        }
+    }
+
+    pub fn set_fast_preview(&mut self, fast: bool) {
+        self.fast_preview = fast;
+        #[cfg(feature = "gpu")]
+        if let Some(ref mut wgpu) = self.wgpu {
+            wgpu.set_quality(if fast { wgpu::Quality::FastPreview } else { wgpu::Quality::Full });
+        }
+    }
+
+    pub fn set_thread_pool(&mut self, pool: Option<std::sync::Arc<rayon::ThreadPool>>) {
+        self.thread_pool = pool;
+    }
+    // Builds a dedicated pool with `threads` threads for the CPU path, 0 to use the global pool
+    pub fn set_cpu_threads(&mut self, threads: usize) {
+        self.thread_pool = if threads > 0 {
+            match rayon::ThreadPoolBuilder::new().num_threads(threads).thread_name(|i| format!("undistort-{}", i)).build() {
+                Ok(pool) => Some(std::sync::Arc::new(pool)),
+                Err(e) => { log::error!("Failed to create thread pool: {}", e); None }
+            }
+        } else {
+            None
+        };
+    }
+
     pub fn get_undistortion_data(&mut self, timestamp_us: i64) -> Option<&FrameTransform> {
         let itm = self.get_stab_data_at_timestamp(timestamp_us);
         if itm.params.is_empty() { return None; }
@@ -103,12 +212,12 @@ impl<T: PixelType> Undistortion<T> {
     }
 
     pub fn init_backends(&mut self) {
-        let interp = self.interpolation as u32;
+        let interp = self.interpolation.gpu_taps();
         if !self.backend_initialized {
             let mut gpu_initialized = false;
 
             #[cfg(feature = "use-opencl")]
-            if std::env::var("NO_OPENCL").unwrap_or_default().is_empty() {
+            if std::env::var("NO_OPENCL").unwrap_or_default().is_empty() && !T::PACKED && (!T::BIG_ENDIAN || cfg!(target_endian = "big")) {
                 let cl = std::panic::catch_unwind(|| {
                     opencl::OclWrapper::new(self.size.0, self.size.1, self.size.2, T::COUNT * T::SCALAR_BYTES, self.output_size.0, self.output_size.1, self.output_size.2, T::COUNT, T::ocl_names(), self.background, interp)
                 });
@@ -126,12 +235,18 @@ impl<T: PixelType> Undistortion<T> {
                     }
                 }
             }
+            #[cfg(feature = "gpu")]
             if !gpu_initialized && T::wgpu_format().is_some() && std::env::var("NO_WGPU").unwrap_or_default().is_empty() {
+                let power = if self.low_power_gpu { ::wgpu::PowerPreference::LowPower } else { ::wgpu::PowerPreference::HighPerformance };
                 let wgpu = std::panic::catch_unwind(|| {
-                    wgpu::WgpuWrapper::new(self.size.0, self.size.1, self.size.2, self.output_size.0, self.output_size.1, self.output_size.2, self.background, interp, T::wgpu_format().unwrap())
+                    wgpu::WgpuWrapper::try_new_with_power_preference(self.size.0, self.size.1, self.size.2, self.output_size.0, self.output_size.1, self.output_size.2, self.background, interp, T::wgpu_format().unwrap(), power)
                 });
                 match wgpu {
-                    Ok(Some(wgpu)) => { self.wgpu = Some(wgpu); },
+                    Ok(Ok(mut wgpu)) => {
+                        if self.fast_preview { wgpu.set_quality(wgpu::Quality::FastPreview); }
+                        self.wgpu = Some(wgpu);
+                    },
+                    Ok(Err(e)) => { log::error!("Failed to initialize wgpu: {}", e); },
                     Err(e) => {
                         if let Some(s) = e.downcast_ref::<&str>() {
                             log::error!("Failed to initialize wgpu {}", s);
@@ -140,8 +255,7 @@ impl<T: PixelType> Undistortion<T> {
                         } else {
                             log::error!("Failed to initialize wgpu {:?}", e);
                         }
-                    },
-                    _ => { log::error!("Failed to initialize wgpu"); }
+                    }
                 }
             }
 
@@ -149,40 +263,257 @@ impl<T: PixelType> Undistortion<T> {
         }
     }
 
+    // GPU backends need at least 4 rows, so smaller frames are rejected here. Use `undistort_image_cpu` directly for tiny frames (1x1 and up)
     pub fn process_pixels(&mut self, timestamp_us: i64, width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, pixels: &mut [u8], out_pixels: &mut [u8]) -> bool {
         if self.size.0 != width || self.size.1 != height || self.output_size.0 != output_width || self.output_size.1 != output_height || height < 4 || output_height < 4 { return false; }
 
+        if let Err(e) = validate_buffer::<T>(pixels, width, height, stride).and_then(|_| validate_buffer::<T>(out_pixels, output_width, output_height, output_stride)) {
+            log::error!("Invalid buffer: {}", e);
+            return false;
+        }
+
         let itm = self.get_stab_data_at_timestamp(timestamp_us).clone(); // TODO: get rid of this clone
         if itm.params.is_empty() { return false; }
+        if let Err(e) = validate_params(&itm.params, height) {
+            log::error!("Invalid params: {}", e);
+            return false;
+        }
 
         self.init_backends();
 
-        // OpenCL path
+        self.undistort_with_backend(&itm, width, height, stride, output_width, output_height, output_stride, pixels, out_pixels);
+
+        if let Some(max) = self.hdr_max {
+            match &self.thread_pool {
+                Some(pool) => pool.install(|| Self::clamp_hdr_output(out_pixels, max)),
+                None => Self::clamp_hdr_output(out_pixels, max)
+            }
+        }
+
+        true
+    }
+
+    fn undistort_with_backend(&mut self, itm: &FrameTransform, width: usize, height: usize, stride: usize, output_width: usize, output_height: usize, output_stride: usize, pixels: &mut [u8], out_pixels: &mut [u8]) {
+        // Rolling shutter visualization, the transparent background, field by field processing, the wide kernel, the defect mask and the vignette gain are only implemented on the CPU
+        let debug_rs = itm.params.len() > 2 && itm.params[2][2] > 0.5;
+        let cpu_only = debug_rs || self.compute_params.background_mode == crate::stabilization_params::BackgroundMode::Transparent
+                    || self.compute_params.interlacing != crate::stabilization_params::Interlacing::Progressive
+                    || self.compute_params.kernel_radius > MAX_TABLE_KERNEL_RADIUS
+                    || self.compute_params.defect_mask.is_some()
+                    || self.compute_params.vignette_gain.is_some();
+        let color = self.compute_params.color_transform;
+        let bg_gradient = self.compute_params.bg_gradient;
+        let bg_regions = self.compute_params.bg_regions.as_ref();
+        let chromatic_aberration = self.compute_params.chromatic_aberration;
+        let lut = self.compute_params.color_lut.as_ref();
+        let bg = itm.bg.unwrap_or(self.background);
+
+        // OpenCL path, without color transform, LUT, sharpening, background gradient, region, chromatic aberration and supersampling support
         #[cfg(feature = "use-opencl")]
-        if let Some(ref mut cl) = self.cl {
-            if let Err(err) = cl.undistort_image(pixels, out_pixels, &itm) {
+        if let Some(cl) = self.cl.as_mut().filter(|_| !cpu_only && color.is_none() && lut.is_none() && self.compute_params.sharpen_amount <= 0.0 && bg_gradient.is_none() && bg_regions.is_none() && chromatic_aberration.is_none() && self.compute_params.supersample <= 1) {
+            if let Err(err) = cl.set_background(bg).and_then(|_| cl.undistort_image(pixels, out_pixels, itm)) {
                 log::error!("OpenCL error: {:?}", err);
             } else {
-                return true;
+                return;
             }
         }
 
         // wgpu path
-        if let Some(ref mut wgpu) = self.wgpu {
-            wgpu.undistort_image(pixels, out_pixels, &itm);
-            return true;
+        #[cfg(feature = "gpu")]
+        if let Some(wgpu) = self.wgpu.as_mut().filter(|_| !cpu_only) {
+            wgpu.set_color_transform(color.as_ref());
+            wgpu.set_color_lut(lut);
+            wgpu.set_background(bg);
+            wgpu.set_background_gradient(bg_gradient);
+            wgpu.set_background_regions(bg_regions);
+            wgpu.set_chromatic_aberration(chromatic_aberration);
+            match wgpu.try_undistort_image(pixels, out_pixels, itm) {
+                Ok(_) => return,
+                Err(wgpu::WgpuError::DeviceLost) => {
+                    // Driver reset, recreate the device and render the frame again. If that fails too, this frame goes through the CPU path
+                    log::warn!("wgpu device lost, recreating");
+                    match wgpu.recover() {
+                        Ok(()) => if wgpu.try_undistort_image(pixels, out_pixels, itm).is_ok() { return; },
+                        Err(e) => log::error!("Failed to recover the wgpu device: {}", e)
+                    }
+                },
+                Err(e) => log::error!("wgpu render failed, using the CPU path: {}", e)
+            }
         }
 
         // CPU path
-        match self.interpolation {
-            Interpolation::Bilinear => { Self::undistort_image_cpu::<2>(pixels, out_pixels, width, height, stride, output_width, output_height, output_stride, &itm.params, self.background); },
-            Interpolation::Bicubic  => { Self::undistort_image_cpu::<4>(pixels, out_pixels, width, height, stride, output_width, output_height, output_stride, &itm.params, self.background); },
-            Interpolation::Lanczos4 => { Self::undistort_image_cpu::<8>(pixels, out_pixels, width, height, stride, output_width, output_height, output_stride, &itm.params, self.background); },
+        let mut frame = FrameParams::new(&itm.params, height, output_width, output_height);
+        frame.vignette_gain = self.compute_params.vignette_gain.as_deref();
+        frame.color_transform = color.as_ref();
+        frame.color_lut = lut.map(|x| x.as_ref());
+        frame.bg_gradient = bg_gradient;
+        frame.bg_regions = bg_regions.map(|x| x.as_ref());
+        frame.chromatic_aberration = chromatic_aberration;
+        frame.kernel_radius = self.compute_params.kernel_radius;
+        frame.defect_mask = self.compute_params.defect_mask.as_ref().map(|x| x.as_slice()).filter(|x| x.len() == width * height);
+        let interpolation = self.interpolation;
+        let run = || match interpolation {
+            Interpolation::Bilinear => { Self::undistort_image_cpu_with_frame::<2>(frame, pixels, out_pixels, width, height, stride, output_width, output_height, output_stride, bg); },
+            Interpolation::Bicubic  => { Self::undistort_image_cpu_with_frame::<4>(frame, pixels, out_pixels, width, height, stride, output_width, output_height, output_stride, bg); },
+            Interpolation::Lanczos4 => { Self::undistort_image_cpu_with_frame::<8>(frame, pixels, out_pixels, width, height, stride, output_width, output_height, output_stride, bg); },
+            Interpolation::Adaptive => { Self::undistort_image_cpu_with_frame::<INTERPOLATION_ADAPTIVE>(frame, pixels, out_pixels, width, height, stride, output_width, output_height, output_stride, bg); },
+        };
+        match &self.thread_pool {
+            Some(pool) => pool.install(run),
+            None => run()
         }
+    }
 
-        true
+    // Measures one frame with the given configuration on a blank frame, e.g. to estimate the processing time of a clip before starting.
+    // GPU backends are timed on the second frame, so the context, shader compilation and first upload aren't included.
+    // Returns None if the backend can't be initialized for this format and size
+    pub fn estimate_frame_cost(backend: Backend, params: &[[f32; 9]], width: usize, height: usize, output_width: usize, output_height: usize, interpolation: Interpolation) -> Option<std::time::Duration> {
+        if validate_params(params, height).is_err() { return None; }
+        let stride = min_stride::<T>(width);
+        let output_stride = min_stride::<T>(output_width);
+        let mut pixels = vec![0u8; stride * height];
+        let mut out_pixels = vec![0u8; output_stride * output_height];
+        let bg = Vector4::new(0.0, 0.0, 0.0, 0.0);
+        let itm = FrameTransform { params: params.to_vec(), fov: 1.0, bg: None };
+
+        match backend {
+            Backend::Cpu => {
+                let start = std::time::Instant::now();
+                let ok = match interpolation {
+                    Interpolation::Bilinear => Self::undistort_image_cpu::<2>(&mut pixels, &mut out_pixels, width, height, stride, output_width, output_height, output_stride, params, bg),
+                    Interpolation::Bicubic  => Self::undistort_image_cpu::<4>(&mut pixels, &mut out_pixels, width, height, stride, output_width, output_height, output_stride, params, bg),
+                    Interpolation::Lanczos4 => Self::undistort_image_cpu::<8>(&mut pixels, &mut out_pixels, width, height, stride, output_width, output_height, output_stride, params, bg),
+                    Interpolation::Adaptive => Self::undistort_image_cpu::<INTERPOLATION_ADAPTIVE>(&mut pixels, &mut out_pixels, width, height, stride, output_width, output_height, output_stride, params, bg),
+                };
+                if ok { Some(start.elapsed()) } else { None }
+            },
+            #[cfg(feature = "use-opencl")]
+            Backend::OpenCL => {
+                if T::PACKED { return None; }
+                let mut cl = opencl::OclWrapper::new(width, height, stride, T::COUNT * T::SCALAR_BYTES, output_width, output_height, output_stride, T::COUNT, T::ocl_names(), bg, interpolation.gpu_taps()).ok()?;
+                cl.undistort_image(&mut pixels, &mut out_pixels, &itm).ok()?;
+                let start = std::time::Instant::now();
+                cl.undistort_image(&mut pixels, &mut out_pixels, &itm).ok()?;
+                Some(start.elapsed())
+            },
+            #[cfg(not(feature = "use-opencl"))]
+            Backend::OpenCL => None,
+            #[cfg(feature = "gpu")]
+            Backend::Wgpu => {
+                let mut wgpu = wgpu::WgpuWrapper::new(width, height, stride, output_width, output_height, output_stride, bg, interpolation.gpu_taps(), T::wgpu_format()?)?;
+                wgpu.undistort_image(&mut pixels, &mut out_pixels, &itm);
+                let start = std::time::Instant::now();
+                wgpu.undistort_image(&mut pixels, &mut out_pixels, &itm);
+                Some(start.elapsed())
+            },
+            #[cfg(not(feature = "gpu"))]
+            Backend::Wgpu => None
+        }
+    }
+
+    // Clamps float output to [0, max] and replaces NaNs with 0. Integer formats are already clamped by the conversion
+    pub fn clamp_hdr_output(out_pixels: &mut [u8], max: f32) {
+        if !T::FLOAT { return; }
+        use rayon::prelude::ParallelSliceMut;
+        use rayon::iter::ParallelIterator;
+        let clamp = |v: f32| if v.is_nan() { 0.0 } else { v.max(0.0).min(max) };
+        // Buffer may not be aligned to the scalar
+        out_pixels.par_chunks_mut(4096 * 4).for_each(|chunk| {
+            if T::SCALAR_BYTES == 2 {
+                for b in chunk.chunks_exact_mut(2) {
+                    b.copy_from_slice(&f32_to_f16(clamp(f16_to_f32(u16::from_ne_bytes([b[0], b[1]])))).to_ne_bytes());
+                }
+            } else {
+                for b in chunk.chunks_exact_mut(4) {
+                    b.copy_from_slice(&clamp(f32::from_ne_bytes([b[0], b[1], b[2], b[3]])).to_ne_bytes());
+                }
+            }
+        });
     }
 }
 
 unsafe impl<T: PixelType> Send for Undistortion<T> { }
 unsafe impl<T: PixelType> Sync for Undistortion<T> { }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::synthetic_profile;
+
+    const FPS: f64 = 30.0;
+
+    // Zoom keyframed across the first frames, so every frame has a different transform
+    fn zooming_undistortion<T: PixelType>(width: usize, height: usize) -> Undistortion<T> {
+        let (mut params, _) = synthetic_profile(120.0, [0.05, 0.01, 0.0, 0.0], width, height);
+        params.gyro.fps = FPS;
+        params.fov_keyframes = vec![(0.0, 1.0), (crate::timestamp_at_frame(6, FPS), 1.5)];
+        let mut undistortion = Undistortion::<T>::default();
+        undistortion.set_compute_params(params);
+        let stride = min_stride::<T>(width);
+        undistortion.init_size(Vector4::new(0.0, 0.0, 0.0, 255.0), (width, height), stride, (width, height), stride);
+        undistortion.backend_initialized = true; // CPU path only
+        undistortion
+    }
+
+    // Renders `frame` from a source where `f` returns the pixel at (x, y)
+    fn render_with<T: PixelType, F: Fn(usize, usize) -> Vector4<f32>>(undistortion: &mut Undistortion<T>, frame: usize, width: usize, height: usize, f: F) -> Vec<u8> {
+        let mut pixels: Vec<u8> = (0..width * height).flat_map(|i| bytemuck::bytes_of(&T::from_float(f(i % width, i / width))).to_vec()).collect();
+        let stride = min_stride::<T>(width);
+        let mut out = vec![0u8; stride * height];
+        let timestamp_us = (crate::timestamp_at_frame(frame as i32, FPS) * 1000.0).round() as i64;
+        assert!(undistortion.process_pixels(timestamp_us, width, height, stride, width, height, stride, &mut pixels, &mut out));
+        out
+    }
+
+    fn render(undistortion: &mut Undistortion<RGBA8>, frame: usize, width: usize, height: usize) -> Vec<u8> {
+        render_with(undistortion, frame, width, height, |x, y| Vector4::new((x * 5) as f32, (y * 7) as f32, ((x ^ y) * 16) as f32, 255.0))
+    }
+
+    #[test]
+    fn seek_matches_fresh_render() {
+        let (width, height) = (48, 32);
+        let fresh = |frame: usize| render(&mut zooming_undistortion(width, height), frame, width, height);
+        assert_ne!(fresh(1), fresh(4));
+
+        let mut undistortion = zooming_undistortion(width, height);
+        for frame in 0..6 {
+            assert!(render(&mut undistortion, frame, width, height) == fresh(frame), "sequential frame {}", frame);
+        }
+        // Backward, forward and onto the same frame again
+        for frame in [2, 5, 0, 5, 3] {
+            undistortion.seek(frame);
+            assert!(render(&mut undistortion, frame, width, height) == fresh(frame), "seek to frame {}", frame);
+        }
+    }
+
+    #[test]
+    fn clamp_hdr_highlight() {
+        let (width, height) = (48, 32);
+        let max = 4.0;
+        // Dark frame with a bright column right at the source edge, where Lanczos rings the most and the warp maps onto the border
+        let highlight = |x: usize, _y: usize| if x == width - 1 { Vector4::new(60.0, 60.0, 60.0, 1.0) } else { Vector4::new(0.01, 0.01, 0.01, 1.0) };
+        let check = |values: Vec<Vector4<f32>>| {
+            for v in values {
+                assert!(v.iter().all(|c| c.is_finite() && *c >= 0.0 && *c <= max), "{:?}", v);
+            }
+        };
+
+        let mut undistortion = zooming_undistortion::<RGBAf16>(width, height);
+        undistortion.interpolation = Interpolation::Lanczos4;
+        undistortion.hdr_max = Some(max);
+        let out = render_with(&mut undistortion, 2, width, height, highlight);
+        check(out.chunks_exact(8).map(|b| { let mut px = RGBAf16::default(); bytemuck::bytes_of_mut(&mut px).copy_from_slice(b); PixelType::to_float(px) }).collect());
+
+        let mut undistortion = zooming_undistortion::<RGBAf>(width, height);
+        undistortion.interpolation = Interpolation::Lanczos4;
+        undistortion.hdr_max = Some(max);
+        let out = render_with(&mut undistortion, 2, width, height, highlight);
+        check(out.chunks_exact(16).map(|b| { let mut px = RGBAf::default(); bytemuck::bytes_of_mut(&mut px).copy_from_slice(b); PixelType::to_float(px) }).collect());
+
+        // NaNs and negative ringing are replaced directly too
+        let mut buf: Vec<u8> = [f32::NAN, -0.5, 2.0, 100.0].iter().flat_map(|v| f32_to_f16(*v).to_ne_bytes()).collect();
+        Undistortion::<RGBAf16>::clamp_hdr_output(&mut buf, max);
+        let values: Vec<f32> = buf.chunks_exact(2).map(|b| f16_to_f32(u16::from_ne_bytes([b[0], b[1]]))).collect();
+        assert_eq!(values, vec![0.0, 0.0, 2.0, max]);
+    }
+}
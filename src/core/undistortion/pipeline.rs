@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
+
+use crossbeam_channel::{ Receiver, Sender };
+use nalgebra::Vector4;
+
+use super::{ Undistortion, PixelType, Interpolation, FrameTransform };
+
+// Unit of work of `spawn_cpu_pipeline`. The frame travels through the pipeline with its buffers, so nothing is copied between the stages
+#[derive(Default)]
+pub struct Frame {
+    pub timestamp_ms: f64,
+    pub frame: usize,
+    pub pixels: Vec<u8>, // Input, `stride * height` bytes
+    pub output: Vec<u8>, // Resized to `output_stride * output_height` by the pipeline, which doesn't allocate if the capacity is already there
+    pub undistorted: bool, // false if `params_provider` returned None, `output` is left unchanged then
+}
+
+// Runs `undistort_image_cpu` on a worker thread for each frame received from `rx` and sends it to `tx`, in the same order.
+// `params_provider` returns the transform of a frame, e.g. `FrameTransform::at_timestamp(&compute_params, frame.timestamp_ms, frame.frame)`.
+//
+// Backpressure comes from the channels, so create them with `crossbeam_channel::bounded`: the worker blocks on `tx` when the consumer is behind,
+// and the decoder blocks on `rx` when the worker is. A capacity of 2-3 is enough to overlap decoding with undistortion.
+//
+// To avoid allocating per frame, send the consumed frames back to the decoder over a third channel and decode into the returned `pixels`.
+// `output` keeps its capacity across frames, so after the first few frames the pipeline runs without any allocation.
+//
+// The worker exits when `rx` is disconnected (all senders dropped) or `tx` has no receiver. The undistortion itself is parallelized on the global rayon pool
+pub fn spawn_cpu_pipeline<T: PixelType + 'static, F>(rx: Receiver<Frame>, tx: Sender<Frame>, mut params_provider: F, size: (usize, usize, usize), output_size: (usize, usize, usize), bg: Vector4<f32>, interpolation: Interpolation) -> std::thread::JoinHandle<()>
+    where F: FnMut(&Frame) -> Option<FrameTransform> + Send + 'static
+{
+    let (width, height, stride) = size;
+    let (output_width, output_height, output_stride) = output_size;
+    std::thread::spawn(move || {
+        for mut frame in rx.iter() {
+            frame.output.resize(output_stride * output_height, 0);
+            frame.undistorted = match params_provider(&frame) {
+                Some(itm) => {
+                    let (pixels, out) = (&mut frame.pixels[..], &mut frame.output[..]);
+                    let bg = itm.bg.unwrap_or(bg);
+                    match interpolation {
+                        Interpolation::Bilinear => Undistortion::<T>::undistort_image_cpu::<2>(pixels, out, width, height, stride, output_width, output_height, output_stride, &itm.params, bg),
+                        Interpolation::Bicubic  => Undistortion::<T>::undistort_image_cpu::<4>(pixels, out, width, height, stride, output_width, output_height, output_stride, &itm.params, bg),
+                        Interpolation::Lanczos4 => Undistortion::<T>::undistort_image_cpu::<8>(pixels, out, width, height, stride, output_width, output_height, output_stride, &itm.params, bg),
+                        Interpolation::Adaptive => Undistortion::<T>::undistort_image_cpu::<{ super::INTERPOLATION_ADAPTIVE }>(pixels, out, width, height, stride, output_width, output_height, output_stride, &itm.params, bg),
+                    }
+                },
+                None => false
+            };
+            if tx.send(frame).is_err() { break; }
+        }
+    })
+}
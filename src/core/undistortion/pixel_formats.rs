@@ -6,6 +6,13 @@ use nalgebra::Vector4;
 pub trait PixelType: Default + Copy + Send + Sync + bytemuck::Pod {
     const COUNT: usize = 1;
     const SCALAR_BYTES: usize = 1;
+    // Multi-byte scalars are stored big-endian (e.g. ffmpeg `RGB48BE`), `to_float`/`from_float` swap them on little-endian hosts.
+    // GPU backends upload the raw bytes, so they are skipped for these formats unless the host is big-endian too
+    const BIG_ENDIAN: bool = false;
+    // Channels share one scalar and aren't byte aligned (e.g. `RGB10A2`). Byte-wise overlays and OpenCL are skipped for these
+    const PACKED: bool = false;
+    // Scalars are floats (normalized 0-1, HDR may exceed 1), regardless of their width. Dither, integer ranges and clamping don't apply
+    const FLOAT: bool = false;
     type Scalar: Default + bytemuck::Pod;
 
     fn to_float(v: Self) -> Vector4<f32>;
@@ -13,7 +20,8 @@ pub trait PixelType: Default + Copy + Send + Sync + bytemuck::Pod {
     fn from_rgb_color(v: Vector4<f32>, ind: &[usize], max_val: f32) -> Vector4<f32>;
 
     fn ocl_names() -> (&'static str, &'static str, &'static str, &'static str);
-    fn wgpu_format() -> Option<(wgpu::TextureFormat, &'static str, f64)>;
+    #[cfg(feature = "gpu")]
+    fn wgpu_format() -> Option<wgpu::TextureFormat>;
 }
 
 fn rgb_to_yuv(v: Vector4<f32>) -> Vector4<f32> {
@@ -32,8 +40,13 @@ fn rgb_to_yuv(v: Vector4<f32>) -> Vector4<f32> {
 #[derive(Default, Clone, Copy, PartialEq, PartialOrd)] pub struct RGB16(u16, u16, u16);
 #[derive(Default, Clone, Copy, PartialEq, PartialOrd)] pub struct RGBA16(u16, u16, u16, u16);
 #[derive(Default, Clone, Copy, PartialEq, PartialOrd)] pub struct RGBAf(f32, f32, f32, f32);
+#[derive(Default, Clone, Copy, PartialEq, PartialOrd)] pub struct RGBAf16(u16, u16, u16, u16); // IEEE 754 half floats
 #[derive(Default, Clone, Copy, PartialEq, PartialOrd)] pub struct UV8(u8, u8);
 #[derive(Default, Clone, Copy, PartialEq, PartialOrd)] pub struct UV16(u16, u16);
+#[derive(Default, Clone, Copy, PartialEq, PartialOrd)] pub struct Luma16BE(u16);
+#[derive(Default, Clone, Copy, PartialEq, PartialOrd)] pub struct RGB16BE(u16, u16, u16);
+#[derive(Default, Clone, Copy, PartialEq, PartialOrd)] pub struct RGBA16BE(u16, u16, u16, u16);
+#[derive(Default, Clone, Copy, PartialEq, PartialOrd)] pub struct RGB10A2(u32); // R in the low 10 bits, then G, B and 2 bits of alpha, same as `Rgb10a2Unorm`
 
 unsafe impl bytemuck::Zeroable for Luma8 { }
 unsafe impl bytemuck::Pod for Luma8 { }
@@ -45,7 +58,7 @@ impl PixelType for Luma8 {
     #[inline] fn from_float(v: Vector4<f32>) -> Self { Self(v[0] as Self::Scalar) }
     #[inline] fn from_rgb_color(v: Vector4<f32>, ind: &[usize], max_val: f32) -> Vector4<f32> { Vector4::new(rgb_to_yuv(v)[ind[0]] * max_val, 0.0, 0.0, 0.0) }
     #[inline] fn ocl_names() -> (&'static str, &'static str, &'static str, &'static str) { ("uchar", "convert_uchar_sat", "float", "convert_float") }
-    #[inline] fn wgpu_format() -> Option<(wgpu::TextureFormat, &'static str, f64)> { Some((wgpu::TextureFormat::R8Unorm, "f32", 255.0)) }
+    #[cfg(feature = "gpu")] #[inline] fn wgpu_format() -> Option<wgpu::TextureFormat> { Some(wgpu::TextureFormat::R8Unorm) }
 }
 unsafe impl bytemuck::Zeroable for Luma16 { }
 unsafe impl bytemuck::Pod for Luma16 { }
@@ -57,7 +70,7 @@ impl PixelType for Luma16 {
     #[inline] fn from_float(v: Vector4<f32>) -> Self { Self(v[0] as Self::Scalar) }
     #[inline] fn from_rgb_color(v: Vector4<f32>, ind: &[usize], max_val: f32) -> Vector4<f32> { Vector4::new(rgb_to_yuv(v)[ind[0]] * max_val, 0.0, 0.0, 0.0) }
     #[inline] fn ocl_names() -> (&'static str, &'static str, &'static str, &'static str) { ("ushort", "convert_ushort_sat", "float", "convert_float") }
-    #[inline] fn wgpu_format() -> Option<(wgpu::TextureFormat, &'static str, f64)> { Some((wgpu::TextureFormat::R16Uint, "u32", 1.0)) }
+    #[cfg(feature = "gpu")] #[inline] fn wgpu_format() -> Option<wgpu::TextureFormat> { Some(wgpu::TextureFormat::R16Uint) }
 }
 unsafe impl bytemuck::Zeroable for RGB8 { }
 unsafe impl bytemuck::Pod for RGB8 { }
@@ -69,7 +82,7 @@ impl PixelType for RGB8 {
     #[inline] fn from_float(v: Vector4<f32>) -> Self { Self(v[0] as Self::Scalar, v[1] as Self::Scalar, v[2] as Self::Scalar) }
     #[inline] fn from_rgb_color(v: Vector4<f32>, _ind: &[usize], _max_val: f32) -> Vector4<f32> { v }
     #[inline] fn ocl_names() -> (&'static str, &'static str, &'static str, &'static str) { ("uchar3", "convert_uchar3_sat", "float4", "convert_float4") }
-    #[inline] fn wgpu_format() -> Option<(wgpu::TextureFormat, &'static str, f64)> { None }
+    #[cfg(feature = "gpu")] #[inline] fn wgpu_format() -> Option<wgpu::TextureFormat> { None }
 }
 unsafe impl bytemuck::Zeroable for RGBA8 { }
 unsafe impl bytemuck::Pod for RGBA8 { }
@@ -81,7 +94,7 @@ impl PixelType for RGBA8 {
     #[inline] fn from_float(v: Vector4<f32>) -> Self { Self(v[0] as Self::Scalar, v[1] as Self::Scalar, v[2] as Self::Scalar, v[3] as Self::Scalar) }
     #[inline] fn from_rgb_color(v: Vector4<f32>, _ind: &[usize], _max_val: f32) -> Vector4<f32> { v }
     #[inline] fn ocl_names() -> (&'static str, &'static str, &'static str, &'static str) { ("uchar4", "convert_uchar4_sat", "float4", "convert_float4") }
-    #[inline] fn wgpu_format() -> Option<(wgpu::TextureFormat, &'static str, f64)> { Some((wgpu::TextureFormat::Rgba8Unorm, "f32", 255.0)) }
+    #[cfg(feature = "gpu")] #[inline] fn wgpu_format() -> Option<wgpu::TextureFormat> { Some(wgpu::TextureFormat::Rgba8Unorm) }
 }
 unsafe impl bytemuck::Zeroable for RGB16 { }
 unsafe impl bytemuck::Pod for RGB16 { }
@@ -93,7 +106,7 @@ impl PixelType for RGB16 {
     #[inline] fn from_float(v: Vector4<f32>) -> Self { Self(v[0] as Self::Scalar, v[1] as Self::Scalar, v[2] as Self::Scalar) }
     #[inline] fn from_rgb_color(v: Vector4<f32>, _ind: &[usize], _max_val: f32) -> Vector4<f32> { v }
     #[inline] fn ocl_names() -> (&'static str, &'static str, &'static str, &'static str) { ("ushort3", "convert_ushort3_sat", "float4", "convert_float4") }
-    #[inline] fn wgpu_format() -> Option<(wgpu::TextureFormat, &'static str, f64)> { None }
+    #[cfg(feature = "gpu")] #[inline] fn wgpu_format() -> Option<wgpu::TextureFormat> { None }
 }
 unsafe impl bytemuck::Zeroable for RGBA16 { }
 unsafe impl bytemuck::Pod for RGBA16 { }
@@ -105,20 +118,75 @@ impl PixelType for RGBA16 {
     #[inline] fn from_float(v: Vector4<f32>) -> Self { Self(v[0] as Self::Scalar, v[1] as Self::Scalar, v[2] as Self::Scalar, v[3] as Self::Scalar) }
     #[inline] fn from_rgb_color(v: Vector4<f32>, _ind: &[usize], _max_val: f32) -> Vector4<f32> { v }
     #[inline] fn ocl_names() -> (&'static str, &'static str, &'static str, &'static str) { ("ushort4", "convert_ushort4_sat", "float4", "convert_float4") }
-    #[inline] fn wgpu_format() -> Option<(wgpu::TextureFormat, &'static str, f64)> { Some((wgpu::TextureFormat::Rgba16Uint, "u32", 1.0)) }
+    #[cfg(feature = "gpu")] #[inline] fn wgpu_format() -> Option<wgpu::TextureFormat> { Some(wgpu::TextureFormat::Rgba16Uint) }
 }
 unsafe impl bytemuck::Zeroable for RGBAf { }
 unsafe impl bytemuck::Pod for RGBAf { }
 impl PixelType for RGBAf {
     const COUNT: usize = 4;
     const SCALAR_BYTES: usize = 4;
+    const FLOAT: bool = true;
     type Scalar = f32;
     #[inline] fn to_float(v: Self) -> Vector4<f32> { Vector4::new(v.0, v.1, v.2, v.3) }
     #[inline] fn from_float(v: Vector4<f32>) -> Self { Self(v[0], v[1], v[2], v[3]) }
     #[inline] fn from_rgb_color(v: Vector4<f32>, _ind: &[usize], _max_val: f32) -> Vector4<f32> { v }
     #[inline] fn ocl_names() -> (&'static str, &'static str, &'static str, &'static str) { ("float4", "convert_float4", "float4", "convert_float4") }
-    #[inline] fn wgpu_format() -> Option<(wgpu::TextureFormat, &'static str, f64)> { Some((wgpu::TextureFormat::Rgba32Float, "f32", 255.0)) }
+    #[cfg(feature = "gpu")] #[inline] fn wgpu_format() -> Option<wgpu::TextureFormat> { Some(wgpu::TextureFormat::Rgba32Float) }
 }
+unsafe impl bytemuck::Zeroable for RGBAf16 { }
+unsafe impl bytemuck::Pod for RGBAf16 { }
+impl PixelType for RGBAf16 {
+    const COUNT: usize = 4;
+    const SCALAR_BYTES: usize = 2;
+    const FLOAT: bool = true;
+    type Scalar = u16;
+    #[inline] fn to_float(v: Self) -> Vector4<f32> { Vector4::new(f16_to_f32(v.0), f16_to_f32(v.1), f16_to_f32(v.2), f16_to_f32(v.3)) }
+    #[inline] fn from_float(v: Vector4<f32>) -> Self { Self(f32_to_f16(v[0]), f32_to_f16(v[1]), f32_to_f16(v[2]), f32_to_f16(v[3])) }
+    #[inline] fn from_rgb_color(v: Vector4<f32>, _ind: &[usize], _max_val: f32) -> Vector4<f32> { v }
+    #[inline] fn ocl_names() -> (&'static str, &'static str, &'static str, &'static str) { ("half4", "convert_half4", "float4", "convert_float4") } // Requires cl_khr_fp16
+    #[cfg(feature = "gpu")] #[inline] fn wgpu_format() -> Option<wgpu::TextureFormat> { Some(wgpu::TextureFormat::Rgba16Float) }
+}
+
+#[inline]
+pub fn f16_to_f32(h: u16) -> f32 {
+    let sign = ((h & 0x8000) as u32) << 16;
+    let exp = ((h >> 10) & 0x1f) as u32;
+    let mant = (h & 0x3ff) as u32;
+    let bits = match exp {
+        0 if mant == 0 => sign,
+        0 => { // Subnormal, normalize the mantissa
+            let mut e = 127 - 15 + 1;
+            let mut m = mant;
+            while m & 0x400 == 0 { m <<= 1; e -= 1; }
+            sign | (e << 23) | ((m & 0x3ff) << 13)
+        },
+        0x1f => sign | 0x7f80_0000 | (mant << 13), // Inf, NaN
+        _ => sign | ((exp + 127 - 15) << 23) | (mant << 13)
+    };
+    f32::from_bits(bits)
+}
+
+#[inline]
+pub fn f32_to_f16(v: f32) -> u16 {
+    let x = v.to_bits();
+    let sign = ((x >> 16) & 0x8000) as u16;
+    let exp = ((x >> 23) & 0xff) as i32;
+    let mant = x & 0x7f_ffff;
+    if exp == 0xff { return sign | 0x7c00 | if mant != 0 { 0x200 } else { 0 }; } // Inf, NaN
+
+    let e = exp - 127 + 15;
+    if e >= 0x1f { return sign | 0x7c00; } // Overflow to inf
+    if e <= 0 {
+        // Subnormal or zero
+        if e < -10 { return sign; }
+        let m = mant | 0x80_0000;
+        let shift = (14 - e) as u32;
+        return sign | ((m >> shift) + ((m >> (shift - 1)) & 1)) as u16;
+    }
+    // Rounding may carry into the exponent, which is still the correct result
+    sign | ((((e as u32) << 10) | (mant >> 13)) + ((mant >> 12) & 1)) as u16
+}
+
 unsafe impl bytemuck::Zeroable for UV8 { }
 unsafe impl bytemuck::Pod for UV8 { }
 impl PixelType for UV8 {
@@ -129,7 +197,7 @@ impl PixelType for UV8 {
     #[inline] fn from_float(v: Vector4<f32>) -> Self { Self(v[0] as Self::Scalar, v[1] as Self::Scalar) }
     #[inline] fn from_rgb_color(v: Vector4<f32>, ind: &[usize], max_val: f32) -> Vector4<f32> { let yuv = rgb_to_yuv(v); Vector4::new(yuv[ind[0]] * max_val, yuv[ind[1]] * max_val, 0.0, 0.0) }
     #[inline] fn ocl_names() -> (&'static str, &'static str, &'static str, &'static str) { ("uchar2", "convert_uchar2_sat", "float2", "convert_float2") }
-    #[inline] fn wgpu_format() -> Option<(wgpu::TextureFormat, &'static str, f64)> { Some((wgpu::TextureFormat::Rg8Unorm, "f32", 255.0)) }
+    #[cfg(feature = "gpu")] #[inline] fn wgpu_format() -> Option<wgpu::TextureFormat> { Some(wgpu::TextureFormat::Rg8Unorm) }
 }
 unsafe impl bytemuck::Zeroable for UV16 { }
 unsafe impl bytemuck::Pod for UV16 { }
@@ -141,5 +209,195 @@ impl PixelType for UV16 {
     #[inline] fn from_float(v: Vector4<f32>) -> Self { Self(v[0] as Self::Scalar, v[1] as Self::Scalar) }
     #[inline] fn from_rgb_color(v: Vector4<f32>, ind: &[usize], max_val: f32) -> Vector4<f32> { let yuv = rgb_to_yuv(v); Vector4::new(yuv[ind[0]] * max_val, yuv[ind[1]] * max_val, 0.0, 0.0) }
     #[inline] fn ocl_names() -> (&'static str, &'static str, &'static str, &'static str) { ("ushort2", "convert_ushort2_sat", "float2", "convert_float2") }
-    #[inline] fn wgpu_format() -> Option<(wgpu::TextureFormat, &'static str, f64)> { Some((wgpu::TextureFormat::Rg16Uint, "u32", 1.0)) }
+    #[cfg(feature = "gpu")] #[inline] fn wgpu_format() -> Option<wgpu::TextureFormat> { Some(wgpu::TextureFormat::Rg16Uint) }
+}
+unsafe impl bytemuck::Zeroable for Luma16BE { }
+unsafe impl bytemuck::Pod for Luma16BE { }
+impl PixelType for Luma16BE {
+    const COUNT: usize = 1;
+    const SCALAR_BYTES: usize = 2;
+    const BIG_ENDIAN: bool = true;
+    type Scalar = u16;
+    #[inline] fn to_float(v: Self) -> Vector4<f32> { Vector4::new(u16::from_be(v.0) as f32, 0.0, 0.0, 0.0) }
+    #[inline] fn from_float(v: Vector4<f32>) -> Self { Self((v[0] as u16).to_be()) }
+    #[inline] fn from_rgb_color(v: Vector4<f32>, ind: &[usize], max_val: f32) -> Vector4<f32> { Vector4::new(rgb_to_yuv(v)[ind[0]] * max_val, 0.0, 0.0, 0.0) }
+    #[inline] fn ocl_names() -> (&'static str, &'static str, &'static str, &'static str) { ("ushort", "convert_ushort_sat", "float", "convert_float") }
+    #[cfg(feature = "gpu")] #[inline] fn wgpu_format() -> Option<wgpu::TextureFormat> { if cfg!(target_endian = "big") { Some(wgpu::TextureFormat::R16Uint) } else { None } }
+}
+unsafe impl bytemuck::Zeroable for RGB16BE { }
+unsafe impl bytemuck::Pod for RGB16BE { }
+impl PixelType for RGB16BE {
+    const COUNT: usize = 3;
+    const SCALAR_BYTES: usize = 2;
+    const BIG_ENDIAN: bool = true;
+    type Scalar = u16;
+    #[inline] fn to_float(v: Self) -> Vector4<f32> { Vector4::new(u16::from_be(v.0) as f32, u16::from_be(v.1) as f32, u16::from_be(v.2) as f32, 0.0) }
+    #[inline] fn from_float(v: Vector4<f32>) -> Self { Self((v[0] as u16).to_be(), (v[1] as u16).to_be(), (v[2] as u16).to_be()) }
+    #[inline] fn from_rgb_color(v: Vector4<f32>, _ind: &[usize], _max_val: f32) -> Vector4<f32> { v }
+    #[inline] fn ocl_names() -> (&'static str, &'static str, &'static str, &'static str) { ("ushort3", "convert_ushort3_sat", "float4", "convert_float4") }
+    #[cfg(feature = "gpu")] #[inline] fn wgpu_format() -> Option<wgpu::TextureFormat> { None }
+}
+unsafe impl bytemuck::Zeroable for RGBA16BE { }
+unsafe impl bytemuck::Pod for RGBA16BE { }
+impl PixelType for RGBA16BE {
+    const COUNT: usize = 4;
+    const SCALAR_BYTES: usize = 2;
+    const BIG_ENDIAN: bool = true;
+    type Scalar = u16;
+    #[inline] fn to_float(v: Self) -> Vector4<f32> { Vector4::new(u16::from_be(v.0) as f32, u16::from_be(v.1) as f32, u16::from_be(v.2) as f32, u16::from_be(v.3) as f32) }
+    #[inline] fn from_float(v: Vector4<f32>) -> Self { Self((v[0] as u16).to_be(), (v[1] as u16).to_be(), (v[2] as u16).to_be(), (v[3] as u16).to_be()) }
+    #[inline] fn from_rgb_color(v: Vector4<f32>, _ind: &[usize], _max_val: f32) -> Vector4<f32> { v }
+    #[inline] fn ocl_names() -> (&'static str, &'static str, &'static str, &'static str) { ("ushort4", "convert_ushort4_sat", "float4", "convert_float4") }
+    #[cfg(feature = "gpu")] #[inline] fn wgpu_format() -> Option<wgpu::TextureFormat> { if cfg!(target_endian = "big") { Some(wgpu::TextureFormat::Rgba16Uint) } else { None } }
+}
+unsafe impl bytemuck::Zeroable for RGB10A2 { }
+unsafe impl bytemuck::Pod for RGB10A2 { }
+impl PixelType for RGB10A2 {
+    const COUNT: usize = 4;
+    const SCALAR_BYTES: usize = 1; // 4 bytes per pixel
+    const PACKED: bool = true;
+    type Scalar = u32;
+    // All channels are in 0-1023, alpha is scaled from its 2 bits so the background and blending see the same range as RGB
+    #[inline] fn to_float(v: Self) -> Vector4<f32> { Vector4::new((v.0 & 0x3ff) as f32, ((v.0 >> 10) & 0x3ff) as f32, ((v.0 >> 20) & 0x3ff) as f32, (v.0 >> 30) as f32 * (1023.0 / 3.0)) }
+    #[inline] fn from_float(v: Vector4<f32>) -> Self {
+        let c = |x: f32| x.max(0.0).min(1023.0) as u32;
+        let a = (v[3] * (3.0 / 1023.0)).round().max(0.0).min(3.0) as u32;
+        Self(c(v[0]) | (c(v[1]) << 10) | (c(v[2]) << 20) | (a << 30))
+    }
+    #[inline] fn from_rgb_color(v: Vector4<f32>, _ind: &[usize], _max_val: f32) -> Vector4<f32> { v }
+    #[inline] fn ocl_names() -> (&'static str, &'static str, &'static str, &'static str) { ("uint", "convert_uint_sat", "float4", "convert_float4") } // Unused, OpenCL is skipped for packed formats
+    #[cfg(feature = "gpu")] #[inline] fn wgpu_format() -> Option<wgpu::TextureFormat> { Some(wgpu::TextureFormat::Rgb10a2Unorm) }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Backend {
+    Cpu,
+    OpenCL,
+    Wgpu
+}
+
+#[derive(Clone, Debug)]
+pub struct SupportedFormat {
+    pub name: &'static str,
+    #[cfg(feature = "gpu")]
+    pub wgpu_format: Option<wgpu::TextureFormat>,
+    pub count: usize,
+    pub scalar_bytes: usize,
+    pub supported: bool,
+}
+
+fn format_entry<T: PixelType>(name: &'static str, backend: Backend) -> SupportedFormat {
+    SupportedFormat {
+        name,
+        #[cfg(feature = "gpu")]
+        wgpu_format: T::wgpu_format(),
+        count: T::COUNT,
+        scalar_bytes: T::SCALAR_BYTES,
+        supported: match backend {
+            Backend::Cpu => true,
+            Backend::OpenCL => cfg!(feature = "use-opencl") && !T::PACKED && (!T::BIG_ENDIAN || cfg!(target_endian = "big")),
+            #[cfg(feature = "gpu")]
+            Backend::Wgpu => T::wgpu_format().map(crate::gpu::wgpu::is_format_supported).unwrap_or(false),
+            #[cfg(not(feature = "gpu"))]
+            Backend::Wgpu => false
+        }
+    }
+}
+
+// All pixel formats handled by the crate and whether they work on the given backend
+pub fn supported_formats(backend: Backend) -> Vec<SupportedFormat> {
+    vec![
+        format_entry::<Luma8> ("Luma8",  backend),
+        format_entry::<Luma16>("Luma16", backend),
+        format_entry::<RGB8>  ("RGB8",   backend),
+        format_entry::<RGBA8> ("RGBA8",  backend),
+        format_entry::<RGB16> ("RGB16",  backend),
+        format_entry::<RGBA16>("RGBA16", backend),
+        format_entry::<RGBAf> ("RGBAf",  backend),
+        format_entry::<RGBAf16>("RGBAf16", backend),
+        format_entry::<UV8>   ("UV8",    backend),
+        format_entry::<UV16>  ("UV16",   backend),
+        format_entry::<Luma16BE>("Luma16BE", backend),
+        format_entry::<RGB16BE> ("RGB16BE",  backend),
+        format_entry::<RGBA16BE>("RGBA16BE", backend),
+        format_entry::<RGB10A2> ("RGB10A2",  backend),
+    ]
+}
+
+#[derive(Debug)]
+pub enum BufferError {
+    StrideTooSmall((usize, usize)), // stride, minimum
+    BufferTooSmall((usize, usize)), // length, required
+}
+impl std::fmt::Display for BufferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BufferError::StrideTooSmall(v) => write!(f, "Stride {} is smaller than the row size {}", v.0, v.1),
+            BufferError::BufferTooSmall(v) => write!(f, "Buffer size mismatch! {} vs {}", v.0, v.1),
+        }
+    }
+}
+impl std::error::Error for BufferError { }
+
+// Bytes per row without padding
+pub fn min_stride<T: PixelType>(width: usize) -> usize {
+    // Not COUNT * SCALAR_BYTES, which is 1 for the 16-bit RGB formats
+    width * std::mem::size_of::<T>()
+}
+
+pub fn validate_buffer<T: PixelType>(buffer: &[u8], width: usize, height: usize, stride: usize) -> Result<(), BufferError> {
+    let min = min_stride::<T>(width);
+    if stride < min { return Err(BufferError::StrideTooSmall((stride, min))); }
+    if buffer.len() < stride * height { return Err(BufferError::BufferTooSmall((buffer.len(), stride * height))); }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Byte arrays aren't aligned for the 16-bit formats, so the pixel is copied instead of cast
+    fn from_bytes<T: PixelType>(bytes: &[u8]) -> T {
+        let mut px = T::default();
+        bytemuck::bytes_of_mut(&mut px).copy_from_slice(bytes);
+        px
+    }
+
+    #[test]
+    fn big_endian_round_trip() {
+        // Most significant byte first regardless of the host
+        let rgba: RGBA16BE = from_bytes(&[0x12, 0x34, 0xab, 0xcd, 0x00, 0xff, 0xff, 0x00]);
+        assert_eq!(PixelType::to_float(rgba), Vector4::new(4660.0, 43981.0, 255.0, 65280.0));
+        assert_eq!(bytemuck::bytes_of(&RGBA16BE::from_float(PixelType::to_float(rgba))), &[0x12, 0x34, 0xab, 0xcd, 0x00, 0xff, 0xff, 0x00]);
+
+        let rgb: RGB16BE = from_bytes(&[0x01, 0x02, 0x80, 0x00, 0xff, 0xfe]);
+        assert_eq!(PixelType::to_float(rgb), Vector4::new(258.0, 32768.0, 65534.0, 0.0));
+        assert_eq!(bytemuck::bytes_of(&RGB16BE::from_float(Vector4::new(258.0, 32768.0, 65534.0, 0.0))), &[0x01, 0x02, 0x80, 0x00, 0xff, 0xfe]);
+
+        let luma: Luma16BE = from_bytes(&[0x03, 0xe8]);
+        assert_eq!(PixelType::to_float(luma)[0], 1000.0);
+        assert_eq!(bytemuck::bytes_of(&Luma16BE::from_float(Vector4::new(1000.0, 0.0, 0.0, 0.0))), &[0x03, 0xe8]);
+
+        // Same values as the native formats
+        for v in [0u16, 1, 255, 256, 4660, 65535] {
+            let native = Luma16::from_float(Vector4::new(v as f32, 0.0, 0.0, 0.0));
+            assert_eq!(PixelType::to_float(Luma16BE::from_float(PixelType::to_float(native))), PixelType::to_float(native));
+        }
+    }
+
+    #[test]
+    fn rgb10a2_round_trip() {
+        // Every representable value survives the packing, and the channels don't bleed into each other
+        for v in 0..1024u32 {
+            let px = Vector4::new(v as f32, (1023 - v) as f32, ((v * 7) % 1024) as f32, (v % 4) as f32 * (1023.0 / 3.0));
+            let packed = RGB10A2::from_float(px);
+            assert_eq!(PixelType::to_float(packed), px);
+            assert_eq!(packed.0, v | ((1023 - v) << 10) | (((v * 7) % 1024) << 20) | ((v % 4) << 30));
+        }
+        // Alpha uses the same 0-1023 scale as RGB
+        assert_eq!(PixelType::to_float(RGB10A2(u32::MAX)), Vector4::from_element(1023.0));
+        assert_eq!(RGB10A2::from_float(Vector4::new(0.0, 0.0, 0.0, 600.0)).0 >> 30, 2);
+        // Out of range values are clamped instead of wrapping into the neighbor channel
+        assert_eq!(RGB10A2::from_float(Vector4::new(2000.0, -5.0, 1023.9, 5000.0)).0, 1023 | (1023 << 20) | (3 << 30));
+    }
 }
@@ -45,6 +45,7 @@ pub trait FieldOfViewAlgorithm {
 
 pub fn from_compute_params(mut compute_params: ComputeParams) -> Box<dyn ZoomingAlgorithm> {
     compute_params.fov_scale = 1.0;
+    compute_params.fov_keyframes.clear();
     compute_params.fovs.clear();
     
     // Use original video dimensions, because this is used to undistort points, and we need to find original image bounding box
@@ -82,6 +83,10 @@ pub fn get_checksum(zoom: &Box<dyn ZoomingAlgorithm>) -> u64 {
         hasher.write_u64(compute_params.distortion_coeffs[2].to_bits());
         hasher.write_u64(compute_params.distortion_coeffs[3].to_bits());
     }
+    for (ts, k) in &compute_params.distortion_keyframes {
+        hasher.write_u64(ts.to_bits());
+        k.iter().for_each(|x| hasher.write_u64(x.to_bits()));
+    }
     
     hasher.write_usize(compute_params.video_width);
     hasher.write_usize(compute_params.video_height);
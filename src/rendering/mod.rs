@@ -254,11 +254,15 @@ pub fn render<T: PixelType, F>(stab: Arc<StabilizationManager<T>>, progress: F,
                         params.output_size = (out_size.0, out_size.1);
                         params.video_size  = params.size;
                         params.video_output_size = params.output_size;
-                        params.background
+                        match params.bg_preset {
+                            // Black level in the code values of this plane, not a converted RGB color
+                            Some(preset) => preset.background(&$yuvi, $max_val, params.background[3]),
+                            None => <$t as PixelType>::from_rgb_color(params.background, &$yuvi, $max_val)
+                        }
                     };
                     let mut plane = Undistortion::<$t>::default();
                     plane.interpolation = Interpolation::Lanczos4;
-                    plane.init_size(<$t as PixelType>::from_rgb_color(bg, &$yuvi, $max_val), (in_size.0, in_size.1), in_size.2, (out_size.0, out_size.1), out_size.2);
+                    plane.init_size(bg, (in_size.0, in_size.1), in_size.2, (out_size.0, out_size.1), out_size.2);
                     plane.set_compute_params(ComputeParams::from_manager(&stab));
                     $planes.push(Box::new(move |timestamp_us: i64, in_frame_data: &mut Video, out_frame_data: &mut Video, plane_index: usize| {
                         let (w, h, s)    = ( in_frame_data.plane_width(plane_index) as usize,  in_frame_data.plane_height(plane_index) as usize,  in_frame_data.stride(plane_index) as usize);
@@ -335,8 +339,8 @@ pub fn render<T: PixelType, F>(stab: Arc<StabilizationManager<T>>, progress: F,
                 },
                 Pixel::RGB24    => { create_planes_proc!(planes, (RGB8,   input_frame, output_frame, 0, [], 255.0), ); },
                 Pixel::RGBA     => { create_planes_proc!(planes, (RGBA8,  input_frame, output_frame, 0, [], 255.0), ); },
-                Pixel::RGB48BE  => { create_planes_proc!(planes, (RGB16,  input_frame, output_frame, 0, [], 65535.0), ); },
-                Pixel::RGBA64BE => { create_planes_proc!(planes, (RGBA16, input_frame, output_frame, 0, [], 65535.0), ); },
+                Pixel::RGB48BE  => { create_planes_proc!(planes, (RGB16BE,  input_frame, output_frame, 0, [], 65535.0), ); },
+                Pixel::RGBA64BE => { create_planes_proc!(planes, (RGBA16BE, input_frame, output_frame, 0, [], 65535.0), ); },
                 format => { // All other convert to YUV444P16LE
                     ::log::info!("Unknown format {:?}, converting to YUV444P16LE", format);
                     // Go through 4:4:4 because of even plane dimensions
@@ -55,7 +55,7 @@ MenuItem {
         text: qsTr("Background mode");
         ComboBox {
             id: backgroundMode;
-            model: [QT_TRANSLATE_NOOP("Popup", "Solid color"), QT_TRANSLATE_NOOP("Popup", "Repeat edge pixels"), QT_TRANSLATE_NOOP("Popup", "Mirror edge pixels")];
+            model: [QT_TRANSLATE_NOOP("Popup", "Solid color"), QT_TRANSLATE_NOOP("Popup", "Repeat edge pixels"), QT_TRANSLATE_NOOP("Popup", "Mirror edge pixels"), QT_TRANSLATE_NOOP("Popup", "Show full source frame"), QT_TRANSLATE_NOOP("Popup", "Wrap around (360°)")];
             font.pixelSize: 12 * dpiScale;
             width: parent.width;
             currentIndex: 0;
@@ -65,6 +65,25 @@ MenuItem {
     Label {
         position: Label.Left;
         visible: backgroundMode.currentIndex == 0;
+        text: qsTr("Background preset");
+        ComboBox {
+            id: backgroundPreset;
+            model: [QT_TRANSLATE_NOOP("Popup", "None"), "S-Log2", "S-Log3", "V-Log", "C-Log", "LogC", "F-Log"];
+            font.pixelSize: 12 * dpiScale;
+            width: parent.width;
+            currentIndex: 0;
+            onCurrentIndexChanged: {
+                if (currentIndex > 0) {
+                    controller.set_background_preset(currentIndex - 1, window.videoArea.vid);
+                } else {
+                    controller.set_background_color(renderBackground.text, window.videoArea.vid);
+                }
+            }
+        }
+    }
+    Label {
+        position: Label.Left;
+        visible: backgroundMode.currentIndex == 0 && backgroundPreset.currentIndex == 0;
         text: qsTr("Render background");
 
         TextField {
@@ -445,7 +445,7 @@ MenuItem {
         text: qsTr("Lens correction strength");
         SliderWithField {
             id: correctionAmount;
-            from: 0.0;
+            from: -100.0;
             to: 100.0;
             value: 100.0;
             unit: "%";